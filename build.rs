@@ -0,0 +1,47 @@
+//! Embeds build information for `egnyte-fuse --version`: the git commit
+//! (GIT_HASH) and the resolved fuser and reqwest versions (FUSER_VERSION,
+//! REQWEST_VERSION). Each falls back to "unknown" outside a git checkout or
+//! without a Cargo.lock.
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash());
+    let lock = std::fs::read_to_string("Cargo.lock").unwrap_or_default();
+    for (package, var) in [("fuser", "FUSER_VERSION"), ("reqwest", "REQWEST_VERSION")] {
+        let version = locked_version(&lock, package).unwrap_or_else(|| "unknown".to_string());
+        println!("cargo:rustc-env={}={}", var, version);
+    }
+
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+}
+
+/// Short hash of the checked-out commit, with "-dirty" for local changes
+fn git_hash() -> String {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let Some(hash) = git(&["rev-parse", "--short", "HEAD"]) else {
+        return "unknown".to_string();
+    };
+    match git(&["status", "--porcelain", "--untracked-files=no"]) {
+        Some(changes) if !changes.is_empty() => format!("{}-dirty", hash),
+        _ => hash,
+    }
+}
+
+/// Version of `package` in Cargo.lock
+fn locked_version(lock: &str, package: &str) -> Option<String> {
+    let name = format!("name = \"{}\"", package);
+    let mut lines = lock.lines();
+    lines.find(|line| line.trim() == name)?;
+    let version = lines.next()?.trim().strip_prefix("version = \"")?;
+    Some(version.trim_end_matches('"').to_string())
+}
@@ -1,450 +1,4149 @@
+use crate::fs::budget;
+use crate::fs::egnyte_path;
+use crate::fs::error::ApiError;
+use crate::fs::fuse_ops::{
+    slice_range, EgnyteAPI, EventBatch, Permission, Validators, DEFAULT_ATTR_TTL,
+};
 use anyhow::{Context, Result};
+use dashmap::DashMap;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use serde::Deserialize;
+use sha2::{Digest, Sha512};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Semaphore;
 use tokio::time::{sleep, Duration, Instant};
+use tracing::{debug, info, warn};
 
 /// Configuration loaded from ~/.config/egnyte-desktop/config.json
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 struct Config {
     domain: Option<String>,
+    /// Address of the API for tenants outside `*.egnyte.com`; overrides `domain`
+    base_url: Option<String>,
     client_id: Option<String>,
-    #[serde(flatten)]
-    extra: HashMap<String, serde_json::Value>,
-}
-
-/// Python tokens.json format: access_token, expires_in, issued_at (refresh_token in keyring)
-#[derive(Debug, Deserialize)]
-struct TokenFile {
-    access_token: Option<String>,
-    expires_in: Option<u64>,
-    issued_at: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_count")]
+    max_entries: Option<usize>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    verify_checksums: Option<bool>,
+    /// List online documents (see [`is_online_doc`]) as empty files
+    /// instead of hiding them
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    show_online_docs: Option<bool>,
+    /// Seconds the kernel may cache names
+    #[serde(default, deserialize_with = "deserialize_count")]
+    entry_ttl: Option<usize>,
+    /// Seconds the kernel may cache attributes
+    #[serde(default, deserialize_with = "deserialize_count")]
+    attr_ttl: Option<usize>,
+    /// Seconds the kernel may cache a name as nonexistent
+    #[serde(default, deserialize_with = "deserialize_count")]
+    negative_ttl: Option<usize>,
+    /// Bytes of kernel readahead per file
+    #[serde(default, deserialize_with = "deserialize_count")]
+    max_readahead: Option<usize>,
+    /// Largest write request from the kernel, in bytes
+    #[serde(default, deserialize_with = "deserialize_count")]
+    max_write: Option<usize>,
+    /// Idle connections kept open for reuse
+    #[serde(default, deserialize_with = "deserialize_count")]
+    pool_size: Option<usize>,
+    /// Most API requests in flight at once
+    #[serde(default, deserialize_with = "deserialize_count")]
+    max_concurrent_requests: Option<usize>,
+    /// Most range downloads of one file in flight at once
+    #[serde(default, deserialize_with = "deserialize_count")]
+    max_ranges_per_file: Option<usize>,
+    /// Requests allowed in a burst before the rate limit spaces them out
+    #[serde(default, deserialize_with = "deserialize_count")]
+    rate_limit_burst: Option<usize>,
+    /// Longest wait, in seconds, between retries of a rate-limited request
+    #[serde(default, deserialize_with = "deserialize_count")]
+    max_backoff: Option<usize>,
+    /// Seconds a metadata request may take, response body included
+    #[serde(default, deserialize_with = "deserialize_count")]
+    metadata_timeout: Option<usize>,
+    /// Appended to the User-Agent, to tell installations apart in Egnyte's logs
+    user_agent_suffix: Option<String>,
 }
 
-/// Get refresh_token from system keyring (egnyte-desktop / refresh_token)
-fn get_refresh_token_from_keyring() -> Result<Option<String>> {
-    let entry = keyring::Entry::new("egnyte-desktop", "refresh_token")?;
-    match entry.get_password() {
-        Ok(pwd) if !pwd.is_empty() => Ok(Some(pwd)),
-        _ => Ok(None),
+/// Boolean config value, also accepting the strings `egnyte-cli config set` writes
+fn deserialize_flag<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawFlag {
+        Bool(bool),
+        Text(String),
     }
-}
 
-/// Get client_secret from system keyring (egnyte-desktop / client_secret)
-fn get_client_secret_from_keyring() -> Result<Option<String>> {
-    let entry = keyring::Entry::new("egnyte-desktop", "client_secret")?;
-    match entry.get_password() {
-        Ok(pwd) if !pwd.is_empty() => Ok(Some(pwd)),
-        _ => Ok(None),
+    match Option::<RawFlag>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(RawFlag::Bool(value)) => Ok(Some(value)),
+        Some(RawFlag::Text(text)) => match text.trim().to_ascii_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Ok(Some(true)),
+            "false" | "no" | "off" | "0" => Ok(Some(false)),
+            _ => Err(serde::de::Error::custom(format!("Invalid flag: {}", text))),
+        },
     }
 }
 
-/// Egnyte API entry (file or folder)
-#[derive(Debug, Clone, Deserialize)]
-pub struct EgnyteEntry {
-    pub name: String,
-    pub path: String,
-    #[serde(rename = "isFolder")]
-    pub is_folder: bool,
-    #[serde(rename = "size", default)]
-    pub size: u64,
-    #[serde(rename = "lastModified", deserialize_with = "deserialize_timestamp")]
-    pub modified_time: SystemTime,
-}
-
-fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<SystemTime, D::Error>
+/// `egnyte-cli config set` stores values as strings, so accept "500" as well as 500
+fn deserialize_count<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
-    let timestamp_ms: u64 = serde::Deserialize::deserialize(deserializer)?;
-    let timestamp = timestamp_ms / 1000; // Convert ms to seconds
-    let duration = Duration::from_secs(timestamp);
-    SystemTime::UNIX_EPOCH
-        .checked_add(duration)
-        .ok_or_else(|| serde::de::Error::custom("Invalid timestamp"))
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawCount {
+        Number(usize),
+        Text(String),
+    }
+
+    match Option::<RawCount>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(RawCount::Number(value)) => Ok(Some(value)),
+        Some(RawCount::Text(text)) => text
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("Invalid count: {}", text))),
+    }
 }
 
-/// Real Egnyte API client implementation
-pub struct EgnyteAPIClient {
-    client: Client,
-    base_url: String,
-    domain: String,
-    config_dir: PathBuf,
-    inner: tokio::sync::RwLock<ClientInner>,
-    rate_limiter: RateLimiter,
+/// Read and parse config.json at `config_file`
+async fn load_config(config_file: &Path) -> Result<Config> {
+    if !config_file.exists() {
+        return Err(anyhow::anyhow!(
+            "Config file {} not found. Please run 'egnyte-cli config set domain YOUR_DOMAIN'",
+            config_file.display()
+        ));
+    }
+    let content = tokio::fs::read_to_string(config_file)
+        .await
+        .context("Failed to read config file")?;
+    serde_json::from_str(&content).context("Failed to parse config file")
 }
 
-struct ClientInner {
+/// Settings and secrets from `EGNYTE_*` environment variables, for
+/// deployments (containers, CI) with no config.json and no keyring.
+///
+/// Precedence, highest first: these variables, then config.json and the
+/// keyring. `EGNYTE_DOMAIN` or `EGNYTE_BASE_URL` replaces config.json
+/// altogether; `EGNYTE_ACCESS_TOKEN` replaces tokens.json and is used until
+/// Egnyte rejects it, then refreshed if a refresh token is at hand.
+#[derive(Debug, Default, Clone)]
+struct EnvSettings {
+    domain: Option<String>,
+    base_url: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    refresh_token: Option<String>,
     access_token: Option<String>,
-    token_expires_at: Option<Instant>,
 }
 
-struct RateLimiter {
-    min_interval: Duration,
-    last_call: tokio::sync::Mutex<Option<Instant>>,
-}
+impl EnvSettings {
+    fn from_env() -> Self {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
 
-impl RateLimiter {
-    fn new(qps: f64) -> Self {
+    /// Settings from `var`, ignoring variables that are set but empty
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let get = |name: &str| var(name).filter(|value| !value.trim().is_empty());
         Self {
-            min_interval: Duration::from_secs_f64(1.0 / qps),
-            last_call: tokio::sync::Mutex::new(None),
+            domain: get("EGNYTE_DOMAIN"),
+            base_url: get("EGNYTE_BASE_URL"),
+            client_id: get("EGNYTE_CLIENT_ID"),
+            client_secret: get(CLIENT_SECRET_VAR),
+            refresh_token: get(REFRESH_TOKEN_VAR),
+            access_token: get("EGNYTE_ACCESS_TOKEN"),
         }
     }
 
-    async fn wait_if_needed(&self) {
-        let mut last = self.last_call.lock().await;
-        if let Some(last_call) = *last {
-            let elapsed = last_call.elapsed();
-            if elapsed < self.min_interval {
-                sleep(self.min_interval - elapsed).await;
-            }
+    /// Whether the environment names the tenant, so config.json is not read
+    fn replaces_config(&self) -> bool {
+        self.domain.is_some() || self.base_url.is_some()
+    }
+}
+
+/// The config in effect: from the environment if it names the tenant, else
+/// config.json with `EGNYTE_CLIENT_ID` taking precedence over its client_id
+async fn load_settings(config_file: &Path, env: &EnvSettings) -> Result<Config> {
+    let mut config = if env.replaces_config() {
+        Config {
+            domain: env.domain.clone(),
+            base_url: env.base_url.clone(),
+            ..Config::default()
         }
-        *last = Some(Instant::now());
+    } else {
+        load_config(config_file).await?
+    };
+    if env.client_id.is_some() {
+        config.client_id = env.client_id.clone();
     }
+    Ok(config)
 }
 
-impl EgnyteAPIClient {
-    /// Create a new API client, loading config and tokens from ~/.config/egnyte-desktop/
-    pub async fn new() -> Result<Self> {
-        let config_dir = dirs::home_dir()
-            .context("Could not find home directory")?
-            .join(".config")
-            .join("egnyte-desktop");
-
-        // Load config
-        let config_file = config_dir.join("config.json");
-        let config: Config = if config_file.exists() {
-            let content = tokio::fs::read_to_string(&config_file)
-                .await
-                .context("Failed to read config file")?;
-            serde_json::from_str(&content).context("Failed to parse config file")?
-        } else {
-            return Err(anyhow::anyhow!(
-                "Config file not found. Please run 'egnyte-cli config set domain YOUR_DOMAIN'"
-            ));
-        };
+/// ~/.config/egnyte-desktop, shared with the Python CLI
+fn default_config_dir() -> Result<PathBuf> {
+    Ok(dirs::home_dir()
+        .context("Could not find home directory")?
+        .join(".config")
+        .join("egnyte-desktop"))
+}
 
-        let domain = config
-            .domain
-            .context("Domain not configured. Run: egnyte-cli config set domain YOUR_DOMAIN")?;
+/// `config_file`, or config.json in [`default_config_dir`]
+fn config_file_or_default(config_file: Option<&Path>) -> Result<PathBuf> {
+    match config_file {
+        Some(path) => Ok(path.to_path_buf()),
+        None => Ok(default_config_dir()?.join("config.json")),
+    }
+}
 
-        let base_url = format!("https://{}.egnyte.com", domain);
+/// Outcome of one `doctor` step: what was found, or what is wrong
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub result: Result<String, String>,
+}
 
-        // Load tokens from tokens.json (Python format: access_token, expires_in, issued_at)
-        let token_file = config_dir.join("tokens.json");
-        let token_data: TokenFile = if token_file.exists() {
-            let content = tokio::fs::read_to_string(&token_file)
-                .await
-                .context("Failed to read token file")?;
-            serde_json::from_str(&content).unwrap_or_else(|_| TokenFile {
-                access_token: None,
-                expires_in: None,
-                issued_at: None,
-            })
-        } else {
-            return Err(anyhow::anyhow!(
-                "Not authenticated. Please run 'egnyte-cli auth login'"
-            ));
-        };
+impl Check {
+    fn new(name: &'static str, result: Result<String>) -> Self {
+        Self {
+            name,
+            result: result.map_err(|e| format!("{:#}", e)),
+        }
+    }
 
-        // Refresh token is in keyring (Python stores it there)
-        let _refresh_token = get_refresh_token_from_keyring()?;
-        if _refresh_token.is_none() {
-            return Err(anyhow::anyhow!(
-                "No refresh token in keyring. Please run 'egnyte-cli auth login'"
-            ));
+    /// Mark a step that could not run because an earlier one failed
+    fn skipped(name: &'static str) -> Self {
+        Self {
+            name,
+            result: Err("skipped, fix the failures above first".to_string()),
         }
+    }
 
-        let access_token = token_data.access_token.clone();
-        let token_expires_at = token_data
-            .issued_at
-            .and_then(|issued| {
-                token_data.expires_in.map(|expires_in| {
-                    let expires_at_secs = issued as u64 + expires_in;
-                    let now = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs();
-                    if expires_at_secs > now {
-                        Instant::now() + Duration::from_secs(expires_at_secs - now)
-                    } else {
-                        Instant::now() // Already expired
-                    }
-                })
-            });
+    pub fn passed(&self) -> bool {
+        self.result.is_ok()
+    }
+}
 
-        let client = Client::builder()
-            .timeout(Duration::from_secs(30))
-            .build()
-            .context("Failed to create HTTP client")?;
+/// Names of the steps of [`write_test`]
+const WRITE_TEST_STEPS: [&str; 3] = [
+    "write test: upload",
+    "write test: read back",
+    "write test: delete",
+];
 
-        Ok(Self {
-            client,
-            base_url,
-            domain,
-            config_dir,
-            inner: tokio::sync::RwLock::new(ClientInner {
-                access_token,
-                token_expires_at,
-            }),
-            rate_limiter: RateLimiter::new(10.0), // 10 QPS default
-        })
-    }
+/// Check that the setup the mount depends on is in place, one step at a
+/// time: config.json (or `config_file`), the secrets, a token refresh, and
+/// a listing of the root folder; then, given `write_test_folder`, the
+/// [`write_test`] in it.
+pub async fn run_checks(config_file: Option<&Path>, write_test_folder: Option<&str>) -> Vec<Check> {
+    let mut checks = Vec::new();
 
-    /// Get a valid access token, refreshing if necessary
-    async fn get_valid_token(&self) -> Result<String> {
-        // Check if token is expired or about to expire (within 60 seconds)
-        let needs_refresh = {
-            let inner = self.inner.read().await;
-            inner.token_expires_at.map_or(true, |exp| {
-                exp.saturating_duration_since(Instant::now()) < Duration::from_secs(60)
-            })
-        };
+    let env = EnvSettings::from_env();
+    let config = match config_file_or_default(config_file) {
+        Ok(path) => load_settings(&path, &env).await,
+        Err(e) => Err(e),
+    };
+    let config = config.and_then(|config| describe_config(&config));
+    let config_ok = config.is_ok();
+    checks.push(Check::new("config.json", config));
 
-        if needs_refresh {
-            self.refresh_token().await?;
+    let refresh_token = get_refresh_token(&env).and_then(|token| {
+        token
+            .map(|_| "present".to_string())
+            .context(NO_REFRESH_TOKEN)
+    });
+    let secrets_ok = refresh_token.is_ok();
+    checks.push(Check::new("refresh token", refresh_token));
+
+    let client_secret = get_client_secret(&env).and_then(|secret| {
+        secret
+            .map(|_| "present".to_string())
+            .context(NO_CLIENT_SECRET)
+    });
+    let secrets_ok = secrets_ok && client_secret.is_ok();
+    checks.push(Check::new("client_secret", client_secret));
+
+    let skip_write_test = |checks: &mut Vec<Check>| {
+        if write_test_folder.is_some() {
+            checks.extend(WRITE_TEST_STEPS.map(Check::skipped));
         }
+    };
+    if !(config_ok && secrets_ok) {
+        checks.push(Check::skipped("token refresh"));
+        checks.push(Check::skipped("list /"));
+        skip_write_test(&mut checks);
+        return checks;
+    }
 
-        let inner = self.inner.read().await;
-        inner
-            .access_token
-            .clone()
-            .context("No access token available")
+    let client = match EgnyteAPIClient::with_config_file(config_file).await {
+        Ok(client) => client,
+        Err(e) => {
+            checks.push(Check::new("token refresh", Err(e)));
+            checks.push(Check::skipped("list /"));
+            skip_write_test(&mut checks);
+            return checks;
+        }
+    };
+    let refreshed = client.refresh_token().await;
+    let refresh_ok = refreshed.is_ok();
+    checks.push(Check::new(
+        "token refresh",
+        refreshed.map(|()| "new access token saved".to_string()),
+    ));
+
+    if refresh_ok {
+        let listing = crate::fs::fuse_ops::EgnyteAPI::list_folder(&client, "/").await;
+        checks.push(Check::new(
+            "list /",
+            listing.map(|entries| format!("{} entries", entries.len())),
+        ));
+    } else {
+        checks.push(Check::skipped("list /"));
+    }
+    match write_test_folder {
+        Some(folder) if refresh_ok => checks.extend(write_test(&client, folder).await),
+        _ => skip_write_test(&mut checks),
     }
+    checks
+}
 
-    /// Refresh the access token
-    async fn refresh_token(&self) -> Result<()> {
-        let refresh_token = get_refresh_token_from_keyring()?
-            .context("No refresh token in keyring. Please run 'egnyte-cli auth login'")?;
+/// Content of the write test's file: every byte value, so encoding
+/// problems show as well as truncation
+fn write_test_content() -> Vec<u8> {
+    (0..=255u8).cycle().take(4096).collect()
+}
 
-        let client_secret = get_client_secret_from_keyring()?
-            .context("No client_secret in keyring. Run: egnyte-cli config set client_secret YOUR_SECRET")?;
+/// Write a temporary file into `folder`, read it back, compare, and delete
+/// it: the path every save through the mount takes. A step that cannot run
+/// after a failure is reported skipped; the file is deleted whenever the
+/// upload got through.
+pub async fn write_test(api: &dyn EgnyteAPI, folder: &str) -> Vec<Check> {
+    let [upload, read_back, delete] = WRITE_TEST_STEPS;
+    let stamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let path = format!(
+        "{}/.egnyte-fuse-write-test-{}-{}",
+        egnyte_path::normalize(folder).trim_end_matches('/'),
+        std::process::id(),
+        stamp
+    );
+    let content = write_test_content();
 
-        let config_file = self.config_dir.join("config.json");
-        let config: Config = if config_file.exists() {
-            let content = tokio::fs::read_to_string(&config_file)
-                .await
-                .context("Failed to read config file")?;
-            serde_json::from_str(&content).context("Failed to parse config file")?
+    let uploaded = api.upload_file(&path, &content).await;
+    let upload_ok = uploaded.is_ok();
+    let mut checks = vec![Check::new(
+        upload,
+        uploaded.map(|_| format!("{} bytes to {}", content.len(), path)),
+    )];
+    if !upload_ok {
+        checks.push(Check::skipped(read_back));
+        checks.push(Check::skipped(delete));
+        return checks;
+    }
+
+    let downloaded = api.download_file(&path).await.and_then(|data| {
+        if data == content {
+            Ok("content matches".to_string())
         } else {
-            return Err(anyhow::anyhow!("Config file not found"));
-        };
+            Err(anyhow::anyhow!(
+                "got {} bytes back that differ from the {} written",
+                data.len(),
+                content.len()
+            ))
+        }
+    });
+    checks.push(Check::new(read_back, downloaded));
+    let deleted = api.delete_entry(&path).await;
+    checks.push(Check::new(delete, deleted.map(|()| "removed".to_string())));
+    checks
+}
 
-        let client_id = config
-            .client_id
-            .context("Client ID not configured")?;
+/// The Egnyte domain name in a configured `domain`, which users also give as
+/// `acme.egnyte.com` or `https://acme.egnyte.com/`
+fn normalize_domain(domain: &str) -> Result<String> {
+    let trimmed = domain.trim();
+    let lower = trimmed.to_ascii_lowercase();
+    let name = ["https://", "http://"]
+        .iter()
+        .find_map(|scheme| lower.strip_prefix(scheme))
+        .unwrap_or(&lower);
+    let name = name.trim_end_matches('/');
+    let name = name.strip_suffix(".egnyte.com").unwrap_or(name);
+    let valid = !name.is_empty()
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid {
+        anyhow::bail!(
+            "Invalid domain {:?}: expected your Egnyte domain name, e.g. acme for \
+             acme.egnyte.com. Run: egnyte-cli config set domain YOUR_DOMAIN",
+            domain
+        );
+    }
+    Ok(name.to_string())
+}
 
-        let refresh_url = format!("https://{}.egnyte.com/puboauth/token", self.domain);
+/// Whether `url` points at this machine, where plain HTTP (a local proxy or
+/// test server) exposes nothing on the network
+fn is_loopback(url: &reqwest::Url) -> bool {
+    let host = url.host_str().unwrap_or_default().trim_matches(['[', ']']);
+    host == "localhost"
+        || host
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
 
-        let params = [
-            ("grant_type", "refresh_token"),
-            ("refresh_token", refresh_token.as_str()),
-            ("client_id", client_id.as_str()),
-            ("client_secret", client_secret.as_str()),
-        ];
+/// A configured `base_url`: an HTTPS URL (or HTTP to this machine), returned
+/// without a trailing slash
+fn validate_base_url(base_url: &str) -> Result<String> {
+    let invalid = |why: &str| anyhow::anyhow!("Invalid base_url {:?}: {}", base_url, why);
+    let url = reqwest::Url::parse(base_url.trim()).map_err(|e| invalid(&e.to_string()))?;
+    if url.scheme() != "https" && !(url.scheme() == "http" && is_loopback(&url)) {
+        return Err(invalid("must start with https://"));
+    }
+    if url.host_str().is_none_or(str::is_empty) {
+        return Err(invalid("has no host name"));
+    }
+    if url.query().is_some() || url.fragment().is_some() || !url.username().is_empty() {
+        return Err(invalid("must not have credentials, a query or a fragment"));
+    }
+    Ok(url.as_str().trim_end_matches('/').to_string())
+}
 
-        let response = self
-            .client
-            .post(&refresh_url)
-            .form(&params)
-            .send()
-            .await
-            .context("Failed to refresh token")?;
+/// Where the API lives: `base_url` if configured, else the domain's
+/// `https://<domain>.egnyte.com`
+fn configured_base_url(config: &Config) -> Result<String> {
+    if let Some(base_url) = &config.base_url {
+        return validate_base_url(base_url);
+    }
+    let domain = config
+        .domain
+        .as_deref()
+        .context("Domain not configured. Run: egnyte-cli config set domain YOUR_DOMAIN")?;
+    Ok(format!("https://{}.egnyte.com", normalize_domain(domain)?))
+}
 
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!(
-                "Token refresh failed. Please run 'egnyte-cli auth login'"
-            ));
-        }
+/// What `doctor` reports about a parsed config, or what it is missing
+fn describe_config(config: &Config) -> Result<String> {
+    let base_url = configured_base_url(config)?;
+    config
+        .client_id
+        .as_deref()
+        .context("Client ID not configured. Run: egnyte-cli config set client_id YOUR_CLIENT_ID")?;
+    match &config.base_url {
+        Some(_) => Ok(format!("base URL {}", base_url)),
+        None => Ok(format!(
+            "domain {}",
+            normalize_domain(config.domain.as_deref().unwrap_or_default())?
+        )),
+    }
+}
 
-        let token_data: serde_json::Value = response
-            .json()
-            .await
-            .context("Failed to parse token response")?;
+/// Idle connections to Egnyte kept for reuse by default
+const DEFAULT_POOL_SIZE: usize = 16;
 
-        let new_access_token = token_data
-            .get("access_token")
-            .and_then(|v| v.as_str())
-            .map(|s| s.to_string())
-            .context("No access_token in response")?;
+/// How long an idle pooled connection is kept before closing it: under the
+/// 60 seconds after which load balancers commonly drop idle connections,
+/// so the pool lets go of them first instead of handing out dead ones
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(50);
 
-        let expires_in = token_data
-            .get("expires_in")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(3600);
+/// Interval of TCP keepalive probes on open connections
+const TCP_KEEPALIVE: Duration = Duration::from_secs(30);
 
-        let issued_at = SystemTime::now()
+/// Product token every request is sent with
+const USER_AGENT: &str = concat!("egnyte-client-linux/", env!("CARGO_PKG_VERSION"));
+
+/// User-Agent header: [`USER_AGENT`], then `suffix` if there is one
+fn user_agent(suffix: Option<&str>) -> String {
+    match suffix.map(str::trim).filter(|suffix| !suffix.is_empty()) {
+        Some(suffix) => format!("{} {}", USER_AGENT, suffix),
+        None => USER_AGENT.to_string(),
+    }
+}
+
+/// A new X-Request-Id: unique to this process, and across processes by
+/// starting from the process id and start time
+fn request_id() -> String {
+    static SESSION: std::sync::OnceLock<u64> = std::sync::OnceLock::new();
+    static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+    let session = SESSION.get_or_init(|| {
+        let nanos = SystemTime::now()
             .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        nanos ^ (u64::from(std::process::id()) << 32)
+    });
+    let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    format!("{:016x}-{:x}", session, n)
+}
 
-        // Save tokens in Python format (access_token, expires_in, issued_at)
-        #[derive(serde::Serialize)]
-        struct TokenFileOut {
-            access_token: String,
-            expires_in: u64,
-            #[serde(rename = "token_type")]
-            token_type: String,
-            issued_at: i64,
-        }
-        let new_tokens = TokenFileOut {
-            access_token: new_access_token.clone(),
-            expires_in,
-            token_type: "Bearer".to_string(),
-            issued_at,
-        };
+/// HTTP client tuned for many small requests to one host: up to
+/// `pool_size` idle connections are kept for reuse, with TCP keepalive so
+/// NATs and load balancers do not drop them between bursts (the client
+/// speaks HTTP/1.1 only, so there are no HTTP/2 pings). Bodies sent with
+/// `Content-Encoding: gzip` are decoded, so content is always the file's
+/// own bytes.
+fn http_client(pool_size: usize, user_agent: &str) -> Result<Client> {
+    Client::builder()
+        .user_agent(user_agent)
+        .gzip(true)
+        .pool_max_idle_per_host(pool_size)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .tcp_keepalive(TCP_KEEPALIVE)
+        .build()
+        .context("Failed to create HTTP client")
+}
 
-        let token_file = self.config_dir.join("tokens.json");
-        let token_json = serde_json::to_string_pretty(&new_tokens)
-            .context("Failed to serialize tokens")?;
-        tokio::fs::write(&token_file, token_json)
-            .await
-            .context("Failed to write token file")?;
+/// Most memory reserved up front for a download, whatever size it announces
+const DOWNLOAD_PREALLOCATE: u64 = 64 * 1024 * 1024;
 
-        // Update inner state
-        {
-            let mut inner = self.inner.write().await;
-            inner.access_token = Some(new_access_token);
-            inner.token_expires_at = Some(Instant::now() + Duration::from_secs(expires_in));
-        }
+/// Time a metadata request (listing, stat, move...) may take by default,
+/// body included, so a hung server fails `ls` quickly instead of freezing
+/// the shell
+const DEFAULT_METADATA_TIMEOUT: Duration = Duration::from_secs(10);
 
-        Ok(())
-    }
+/// How long a content transfer (download, upload, chunk) may go without
+/// moving a byte, or wait for the answer once all is sent. The transfer as
+/// a whole takes as long as the link needs.
+const CONTENT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
-    /// Make an authenticated API request
-    async fn request(
-        &self,
-        method: reqwest::Method,
-        endpoint: &str,
-    ) -> Result<reqwest::Response> {
-        self.rate_limiter.wait_if_needed().await;
+/// When a content transfer last moved bytes
+#[derive(Clone)]
+struct Activity(Arc<std::sync::Mutex<Instant>>);
 
-        let token = self.get_valid_token().await?;
-        let url = format!("{}{}", self.base_url, endpoint);
+impl Activity {
+    fn new() -> Self {
+        Self(Arc::new(std::sync::Mutex::new(Instant::now())))
+    }
 
-        let mut retries = 5;
-        let mut backoff = Duration::from_millis(500);
+    /// Record that bytes moved just now
+    fn touch(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
 
+    /// Resolve once no bytes have moved for `idle`
+    async fn stalled(&self, idle: Duration) {
         loop {
-            let response = self
-                .client
-                .request(method.clone(), &url)
-                .header("Authorization", format!("Bearer {}", token))
-                .header("Content-Type", "application/json")
-                .send()
-                .await
-                .context("API request failed")?;
-
-            if response.status() == 401 {
-                // Token might be invalid, try refreshing
-                self.refresh_token().await?;
-                let new_token = self.get_valid_token().await?;
-                let response = self
-                    .client
-                    .request(method.clone(), &url)
-                    .header("Authorization", format!("Bearer {}", new_token))
-                    .header("Content-Type", "application/json")
-                    .send()
-                    .await
-                    .context("API request failed after refresh")?;
-                
-                if response.status().is_success() {
-                    return Ok(response);
-                }
+            let quiet = self.0.lock().unwrap().elapsed();
+            if quiet >= idle {
+                return;
             }
+            sleep(idle - quiet).await;
+        }
+    }
+}
 
-            if response.status() == 429 {
-                // Rate limited, back off and retry
-                if retries > 0 {
-                    retries -= 1;
-                    sleep(backoff).await;
-                    backoff = backoff * 2;
-                    continue;
+/// Read a content response to the end, reporting the bytes received to
+/// `progress` if given. A dropped connection is [`ApiError::Truncated`], and
+/// nothing arriving for `idle` is [`ApiError::Timeout`].
+async fn read_content(
+    path: &str,
+    mut response: reqwest::Response,
+    progress: Option<&ProgressSender>,
+    idle: Duration,
+) -> Result<Vec<u8>> {
+    let total = response.content_length();
+    let mut data = Vec::with_capacity(total.unwrap_or(0).min(DOWNLOAD_PREALLOCATE) as usize);
+    loop {
+        let Ok(chunk) = tokio::time::timeout(idle, response.chunk()).await else {
+            return Err(ApiError::Timeout(path.to_string()).into());
+        };
+        let chunk = match chunk {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            // The connection dropped mid-body
+            Err(e) => {
+                debug!("Download of {} failed: {}", path, e);
+                return Err(ApiError::Truncated {
+                    path: path.to_string(),
+                    expected: total,
+                    received: data.len() as u64,
                 }
+                .into());
             }
+        };
+        data.extend_from_slice(&chunk);
+        if let Some(progress) = progress {
+            // Nobody listening is fine
+            let _ = progress.send(Progress {
+                transferred: data.len() as u64,
+                total,
+            });
+        }
+    }
+    check_length(path, total, data.len())?;
+    Ok(data)
+}
 
-            if !response.status().is_success() {
-                return Err(anyhow::anyhow!(
-                    "API request failed: {} {}",
-                    response.status(),
-                    response.text().await.unwrap_or_default()
-                ));
-            }
-
-            return Ok(response);
+/// Fail with [`ApiError::Truncated`] unless the content of `path` has the
+/// `Content-Length` its response announced. A decoded (gzip) or chunked
+/// response announces none, and is taken as is.
+fn check_length(path: &str, expected: Option<u64>, received: usize) -> Result<()> {
+    match expected {
+        Some(length) if length != received as u64 => Err(ApiError::Truncated {
+            path: path.to_string(),
+            expected,
+            received: received as u64,
         }
+        .into()),
+        _ => Ok(()),
     }
 }
 
-#[async_trait::async_trait]
-impl crate::fs::fuse_ops::EgnyteAPI for EgnyteAPIClient {
-    async fn list_folder(&self, path: &str) -> Result<Vec<crate::fs::fuse_ops::EgnyteEntry>> {
-        let endpoint = format!("/pubapi/v1/fs{}", path);
-        let response = self.request(reqwest::Method::GET, &endpoint).await?;
-        let data: serde_json::Value = response.json().await.context("Failed to parse response")?;
+/// Whether a request failed on its connection (refused, reset, or closed
+/// before the response) rather than at the server. A pooled connection that
+/// went stale while the mount sat idle fails like this; a new one works.
+fn is_connection_error(err: &reqwest::Error) -> bool {
+    err.is_connect() || (err.is_request() && !err.is_timeout())
+}
 
-        let mut entries = Vec::new();
+/// Whether an endpoint moves file content rather than metadata
+fn is_content_endpoint(endpoint: &str) -> bool {
+    endpoint.starts_with("/pubapi/v1/fs-content")
+}
 
-        // Process folders
-        if let Some(folders) = data.get("folders").and_then(|v| v.as_array()) {
-            for folder in folders {
-                if let Ok(entry) = serde_json::from_value::<EgnyteEntry>(folder.clone()) {
-                    entries.push(crate::fs::fuse_ops::EgnyteEntry {
-                        name: entry.name,
-                        path: entry.path,
-                        is_folder: true,
-                        size: 0,
-                        modified_time: entry.modified_time,
-                    });
+/// A byte count from config.json; the kernel clamps oversized values anyway
+fn saturating_u32(value: usize) -> u32 {
+    u32::try_from(value).unwrap_or(u32::MAX)
+}
+
+/// Python tokens.json format: access_token, expires_in, issued_at (refresh_token in keyring)
+#[derive(Debug, Deserialize)]
+struct TokenFile {
+    access_token: Option<String>,
+    expires_in: Option<u64>,
+    issued_at: Option<i64>,
+}
+
+const NO_REFRESH_TOKEN: &str = "No refresh token in keyring or EGNYTE_REFRESH_TOKEN. \
+     Please run 'egnyte-cli auth login'";
+
+const NO_CLIENT_SECRET: &str = "No client_secret in keyring or EGNYTE_CLIENT_SECRET. \
+     Run: egnyte-cli config set client_secret YOUR_SECRET";
+
+/// How long an access token from the environment is used before refreshing
+/// it unasked; a 401 refreshes it sooner
+const ENV_TOKEN_TRUST: Duration = Duration::from_secs(24 * 3600);
+
+/// Environment variable that stands in for the keyring's refresh_token
+const REFRESH_TOKEN_VAR: &str = "EGNYTE_REFRESH_TOKEN";
+
+/// Environment variable that stands in for the keyring's client_secret
+const CLIENT_SECRET_VAR: &str = "EGNYTE_CLIENT_SECRET";
+
+/// A secret the Python CLI keeps in the keyring (egnyte-desktop / `name`).
+///
+/// Precedence: `from_env`, the value of the environment variable `var`,
+/// then the keyring. Where no keyring backend runs (containers, CI,
+/// headless servers without Secret Service) that says so and names `var`,
+/// rather than failing with the backend's own error.
+fn get_secret(name: &str, var: &str, from_env: Option<String>) -> Result<Option<String>> {
+    let from_keyring = || keyring::Entry::new("egnyte-desktop", name)?.get_password();
+    pick_secret(name, var, from_env, from_keyring)
+}
+
+/// [`get_secret`] given a way to ask the keyring
+fn pick_secret(
+    name: &str,
+    var: &str,
+    from_env: Option<String>,
+    from_keyring: impl FnOnce() -> keyring::Result<String>,
+) -> Result<Option<String>> {
+    if let Some(secret) = from_env.filter(|secret| !secret.is_empty()) {
+        debug!("Using {} from {}", name, var);
+        return Ok(Some(secret));
+    }
+    match from_keyring() {
+        Ok(secret) if !secret.is_empty() => Ok(Some(secret)),
+        Err(e @ (keyring::Error::PlatformFailure(_) | keyring::Error::NoStorageAccess(_))) => {
+            Err(anyhow::anyhow!(
+                "No keyring available to read {} from ({}). Without a keyring, set {}",
+                name,
+                e,
+                var
+            ))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Get refresh_token from EGNYTE_REFRESH_TOKEN or the system keyring
+fn get_refresh_token(env: &EnvSettings) -> Result<Option<String>> {
+    get_secret(
+        "refresh_token",
+        REFRESH_TOKEN_VAR,
+        env.refresh_token.clone(),
+    )
+}
+
+/// Get client_secret from EGNYTE_CLIENT_SECRET or the system keyring
+fn get_client_secret(env: &EnvSettings) -> Result<Option<String>> {
+    get_secret(
+        "client_secret",
+        CLIENT_SECRET_VAR,
+        env.client_secret.clone(),
+    )
+}
+
+/// Characters left as-is in a path segment (RFC 3986 unreserved)
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// HTTP Range header value for `len` (non-zero) bytes at `offset`
+fn range_header(offset: u64, len: u64) -> String {
+    format!("bytes={}-{}", offset, offset.saturating_add(len - 1))
+}
+
+/// Normalize an Egnyte path and percent-encode each segment, keeping the slashes
+fn encode_path(path: &str) -> String {
+    egnyte_path::normalize(path)
+        .split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Egnyte API entry (file or folder)
+#[derive(Debug, Clone, Deserialize)]
+pub struct EgnyteEntry {
+    pub name: String,
+    pub path: String,
+    #[serde(rename = "isFolder", alias = "is_folder", default)]
+    pub is_folder: bool,
+    #[serde(rename = "size", default)]
+    pub size: u64,
+    #[serde(
+        rename = "lastModified",
+        default = "default_timestamp",
+        deserialize_with = "deserialize_timestamp"
+    )]
+    pub modified_time: SystemTime,
+    /// Identifies the current version of a file; changes with every upload
+    #[serde(default)]
+    pub entry_id: Option<String>,
+}
+
+/// Numbers below this are taken as seconds, above as milliseconds
+/// (1e11 seconds is in the year 5138, 1e11 milliseconds in 1973).
+const SECONDS_CUTOFF: u64 = 100_000_000_000;
+
+/// lastModified as Egnyte sends it: a number or a numeric string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawTimestamp {
+    Number(u64),
+    Float(f64),
+    Text(String),
+}
+
+/// Timestamp used when lastModified is missing or null (folders often omit it)
+fn default_timestamp() -> SystemTime {
+    SystemTime::UNIX_EPOCH
+}
+
+fn timestamp_from_number(value: u64) -> Option<SystemTime> {
+    let duration = if value < SECONDS_CUTOFF {
+        Duration::from_secs(value)
+    } else {
+        Duration::from_millis(value)
+    };
+    SystemTime::UNIX_EPOCH.checked_add(duration)
+}
+
+fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<SystemTime, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let raw: Option<RawTimestamp> = serde::Deserialize::deserialize(deserializer)?;
+    let value = match raw {
+        None => return Ok(default_timestamp()),
+        Some(RawTimestamp::Number(value)) => value,
+        Some(RawTimestamp::Float(value)) if value >= 0.0 => value as u64,
+        Some(RawTimestamp::Text(text)) => text
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| serde::de::Error::custom(format!("Invalid timestamp: {}", text)))?,
+        Some(RawTimestamp::Float(value)) => {
+            return Err(serde::de::Error::custom(format!(
+                "Invalid timestamp: {}",
+                value
+            )))
+        }
+    };
+    timestamp_from_number(value).ok_or_else(|| serde::de::Error::custom("Invalid timestamp"))
+}
+
+impl EgnyteEntry {
+    /// The root folder, which Egnyte describes with a listing rather than an
+    /// entry: no name or path of its own
+    fn root() -> Self {
+        Self {
+            name: "/".to_string(),
+            path: "/".to_string(),
+            is_folder: true,
+            size: 0,
+            modified_time: default_timestamp(),
+            entry_id: None,
+        }
+    }
+
+    fn into_entry(self, is_folder: bool) -> crate::fs::fuse_ops::EgnyteEntry {
+        crate::fs::fuse_ops::EgnyteEntry {
+            name: self.name,
+            path: self.path,
+            is_folder,
+            size: if is_folder { 0 } else { self.size },
+            modified_time: self.modified_time,
+            entry_id: self.entry_id,
+            permission: None,
+            validators: Validators::default(),
+        }
+    }
+}
+
+/// Whether a file info response describes a folder: flagged as one, or
+/// listing what is inside it
+fn is_folder_info(raw: &serde_json::Value) -> bool {
+    ["isFolder", "is_folder"]
+        .iter()
+        .any(|key| raw.get(key).and_then(|v| v.as_bool()) == Some(true))
+        || ["folders", "files"]
+            .iter()
+            .any(|key| raw.get(key).is_some_and(|v| v.is_array()))
+}
+
+/// Extensions of the placeholders Google Docs, Sheets and the like are
+/// stored as
+const ONLINE_DOC_EXTENSIONS: [&str; 6] = ["gdoc", "gsheet", "gslides", "gdraw", "gform", "gsite"];
+
+/// Whether a raw entry is an online document: one edited in a browser, with
+/// no content to download, so applications opening it would only find an
+/// empty file. Egnyte flags none as such; they are told by a Google Apps
+/// content type, or else by being empty with one of
+/// [`ONLINE_DOC_EXTENSIONS`].
+fn is_online_doc(raw: &serde_json::Value) -> bool {
+    if is_folder_info(raw) {
+        return false;
+    }
+    let content_type = ["mime_type", "content_type", "contentType"]
+        .iter()
+        .find_map(|key| raw.get(key).and_then(|v| v.as_str()));
+    if content_type.is_some_and(|t| t.starts_with("application/vnd.google-apps.")) {
+        return true;
+    }
+    let empty = raw.get("size").and_then(|v| v.as_u64()).unwrap_or(0) == 0;
+    let extension = describe_raw_entry(raw)
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase());
+    empty && extension.is_some_and(|extension| ONLINE_DOC_EXTENSIONS.contains(&extension.as_str()))
+}
+
+/// Drop the online documents from a listing page; returns how many
+fn drop_online_docs(page: &mut serde_json::Value) -> usize {
+    let Some(files) = page.get_mut("files").and_then(|v| v.as_array_mut()) else {
+        return 0;
+    };
+    let before = files.len();
+    files.retain(|raw| !is_online_doc(raw));
+    before - files.len()
+}
+
+/// Say once that online documents are hidden, and how to show them
+fn note_hidden_online_doc(path: &str) {
+    static NOTED: std::sync::Once = std::sync::Once::new();
+    NOTED.call_once(|| {
+        info!(
+            "Hiding online documents such as {}, which have no content to download; set \
+             show_online_docs in config.json to list them as empty files",
+            path
+        )
+    });
+}
+
+/// Name or path of a raw listing entry, for log messages
+fn describe_raw_entry(raw: &serde_json::Value) -> &str {
+    raw.get("path")
+        .or_else(|| raw.get("name"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("<unnamed>")
+}
+
+/// Best-effort entry from a listing item that failed to deserialize: keep
+/// whatever fields are usable and default the rest. Only an item with neither
+/// a name nor a path is dropped.
+fn lenient_entry(
+    raw: &serde_json::Value,
+    parent: &str,
+    is_folder: bool,
+) -> Option<crate::fs::fuse_ops::EgnyteEntry> {
+    let path_field = raw.get("path").and_then(|v| v.as_str());
+    let name = raw
+        .get("name")
+        .and_then(|v| v.as_str())
+        .or_else(|| path_field.and_then(|p| p.rsplit('/').find(|s| !s.is_empty())))?
+        .to_string();
+    let path = match path_field {
+        Some(path) => path.to_string(),
+        None => egnyte_path::normalize(&format!("{}/{}", parent, name)),
+    };
+    let size = raw.get("size").and_then(|v| v.as_u64()).unwrap_or(0);
+    let modified_time = raw
+        .get("lastModified")
+        .and_then(|v| deserialize_timestamp(v).ok())
+        .unwrap_or_else(default_timestamp);
+
+    Some(crate::fs::fuse_ops::EgnyteEntry {
+        name,
+        path,
+        is_folder,
+        size: if is_folder { 0 } else { size },
+        modified_time,
+        entry_id: raw
+            .get("entry_id")
+            .and_then(|v| v.as_str())
+            .map(str::to_string),
+        permission: None,
+        validators: Validators::default(),
+    })
+}
+
+/// Collect the "folders" and "files" arrays of a listing response. Items that
+/// fail to deserialize are logged and parsed leniently, or fail the whole
+/// listing when `strict` is set.
+fn parse_listing(
+    data: &serde_json::Value,
+    parent: &str,
+    strict: bool,
+) -> Result<Vec<crate::fs::fuse_ops::EgnyteEntry>> {
+    let mut entries = Vec::new();
+
+    for (key, is_folder) in [("folders", true), ("files", false)] {
+        let Some(items) = data.get(key).and_then(|v| v.as_array()) else {
+            continue;
+        };
+        for raw in items {
+            match EgnyteEntry::deserialize(raw) {
+                Ok(entry) => entries.push(entry.into_entry(is_folder)),
+                Err(e) if strict => {
+                    return Err(anyhow::anyhow!(
+                        "Failed to parse entry {} in {}: {}",
+                        describe_raw_entry(raw),
+                        parent,
+                        e
+                    ));
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to parse entry {} in {}: {}",
+                        describe_raw_entry(raw),
+                        parent,
+                        e
+                    );
+                    match lenient_entry(raw, parent, is_folder) {
+                        Some(entry) => entries.push(entry),
+                        None => warn!("Skipping unnamed entry in {}", parent),
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Default number of items requested per listing page
+pub const DEFAULT_PAGE_SIZE: usize = 1000;
+
+/// Default cap on the number of entries returned for one folder
+pub const DEFAULT_MAX_ENTRIES: usize = 50_000;
+
+/// Number of folders plus files in one listing page
+fn page_item_count(page: &serde_json::Value) -> usize {
+    ["folders", "files"]
+        .iter()
+        .filter_map(|key| page.get(*key).and_then(|v| v.as_array()))
+        .map(|items| items.len())
+        .sum()
+}
+
+/// Fetch listing pages with `fetch(offset, count)` until a short or empty page,
+/// until the reported total_count has been reached, or once more than
+/// `max_items` items have been seen (so the caller can tell it truncated)
+async fn fetch_all_pages<F, Fut>(
+    page_size: usize,
+    max_items: usize,
+    mut fetch: F,
+) -> Result<Vec<serde_json::Value>>
+where
+    F: FnMut(usize, usize) -> Fut,
+    Fut: std::future::Future<Output = Result<serde_json::Value>>,
+{
+    let mut pages = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let page = fetch(offset, page_size).await?;
+        let count = page_item_count(&page);
+        let total = page.get("total_count").and_then(|v| v.as_u64());
+        pages.push(page);
+        offset += count;
+
+        let reached_total = total.is_some_and(|total| offset as u64 >= total);
+        if count < page_size || count == 0 || reached_total || offset > max_items {
+            return Ok(pages);
+        }
+    }
+}
+
+/// Check the checksum Egnyte reports for an upload (SHA-512, hex) against the
+/// bytes that were sent. A response without a checksum is accepted.
+fn verify_upload_checksum(
+    path: &str,
+    local_checksum: &str,
+    response: &serde_json::Value,
+) -> Result<()> {
+    match response.get("checksum").and_then(|v| v.as_str()) {
+        Some(remote) if !remote.eq_ignore_ascii_case(local_checksum) => {
+            Err(ApiError::ChecksumMismatch {
+                path: path.to_string(),
+                local: local_checksum.to_string(),
+                remote: remote.to_string(),
+            }
+            .into())
+        }
+        Some(_) => Ok(()),
+        None => {
+            warn!("No checksum in upload response for {}; not verified", path);
+            Ok(())
+        }
+    }
+}
+
+/// Most events requested per poll
+const EVENTS_PAGE_SIZE: usize = 100;
+
+/// Paths touched by an events response, and the cursor after it
+fn parse_events(data: &serde_json::Value, cursor: u64) -> EventBatch {
+    let events = data
+        .get("events")
+        .and_then(|v| v.as_array())
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+
+    let mut paths = Vec::new();
+    let mut last_id = cursor;
+    for event in events {
+        if let Some(id) = event.get("id").and_then(|v| v.as_u64()) {
+            last_id = last_id.max(id);
+        }
+        for key in ["target_path", "source_path"] {
+            let path = event
+                .get("data")
+                .and_then(|d| d.get(key))
+                .and_then(|v| v.as_str());
+            if let Some(path) = path {
+                if !paths.iter().any(|p| p == path) {
+                    paths.push(path.to_string());
+                }
+            }
+        }
+    }
+
+    let latest = data
+        .get("latest_id")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(last_id);
+    EventBatch {
+        paths,
+        cursor: latest.max(cursor),
+    }
+}
+
+/// Size of each part of a chunked upload (Egnyte wants at least 10 MB per
+/// part except the last)
+const UPLOAD_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Attempts per part of a chunked upload before giving up
+const CHUNK_ATTEMPTS: u32 = 4;
+
+/// One part of a chunked upload
+struct Chunk<'a> {
+    /// 1-based part number
+    number: usize,
+    data: &'a [u8],
+    /// Upload id from the first part's response (None for the first part)
+    upload_id: Option<String>,
+    last: bool,
+}
+
+/// Server acknowledgement of one part
+struct ChunkAck {
+    upload_id: Option<String>,
+    /// JSON body of the response to the last part
+    body: serde_json::Value,
+}
+
+/// Send `data` in parts with `send`, retrying each part on transient errors
+/// so a failure only resends the part that was not acknowledged. Returns the
+/// body of the response to the last part.
+async fn upload_in_chunks<'a, F, Fut>(
+    data: &'a [u8],
+    chunk_size: usize,
+    retry_delay: Duration,
+    mut send: F,
+) -> Result<serde_json::Value>
+where
+    F: FnMut(Chunk<'a>) -> Fut,
+    Fut: std::future::Future<Output = Result<ChunkAck>>,
+{
+    let mut parts: Vec<&[u8]> = data.chunks(chunk_size).collect();
+    if parts.is_empty() {
+        parts.push(&[]);
+    }
+    let total = parts.len();
+    let mut upload_id: Option<String> = None;
+    let mut final_body = serde_json::Value::Null;
+
+    for (index, part) in parts.into_iter().enumerate() {
+        let number = index + 1;
+        let mut attempt = 1;
+        let mut backoff = retry_delay;
+        loop {
+            let chunk = Chunk {
+                number,
+                data: part,
+                upload_id: upload_id.clone(),
+                last: number == total,
+            };
+            match send(chunk).await {
+                Ok(ack) => {
+                    if upload_id.is_none() {
+                        upload_id = ack.upload_id;
+                        if upload_id.is_none() && total > 1 {
+                            return Err(anyhow::anyhow!("No upload id in chunked upload response"));
+                        }
+                    }
+                    final_body = ack.body;
+                    break;
+                }
+                Err(e)
+                    if attempt < CHUNK_ATTEMPTS
+                        && crate::fs::error::is_transient(&e)
+                        && budget::may_retry(backoff) =>
+                {
+                    warn!(
+                        "Chunk {} of {} failed (attempt {}): {}",
+                        number, total, attempt, e
+                    );
+                    attempt += 1;
+                    sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => return Err(e.context(format!("Chunk {} of {} failed", number, total))),
+            }
+        }
+    }
+
+    Ok(final_body)
+}
+
+/// Body of an API request
+enum RequestBody<'a> {
+    Json(&'a serde_json::Value),
+    /// File content, streamed in pieces
+    Bytes(&'a [u8]),
+    /// File content, reporting each piece to the channel as it goes out
+    Tracked(&'a [u8], &'a ProgressSender),
+}
+
+/// How far a transfer has got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes sent or received so far
+    pub transferred: u64,
+    /// Size of the whole transfer, when known
+    pub total: Option<u64>,
+}
+
+/// Where a transfer reports its progress
+pub type ProgressSender = tokio::sync::mpsc::UnboundedSender<Progress>;
+
+/// Size of the pieces an upload is streamed in
+const UPLOAD_STREAM_CHUNK: usize = 64 * 1024;
+
+/// Request body that streams `data`, recording each piece written in
+/// `activity` and reporting the bytes written so far to `progress` if
+/// given. The HTTP client asks for a piece only once the one before is
+/// written, so that is when the one before counts; the last one counts
+/// when the response arrives (see `upload_with_progress`).
+fn streamed_body(
+    data: &[u8],
+    progress: Option<ProgressSender>,
+    activity: Activity,
+) -> reqwest::Body {
+    let data: std::sync::Arc<[u8]> = data.into();
+    let total = data.len() as u64;
+    let pieces = futures_util::stream::unfold(0, move |sent| {
+        let data = std::sync::Arc::clone(&data);
+        let progress = progress.clone();
+        let activity = activity.clone();
+        async move {
+            if sent >= data.len() {
+                return None;
+            }
+            activity.touch();
+            if let Some(progress) = progress.filter(|_| sent > 0) {
+                // Nobody listening is fine
+                let _ = progress.send(Progress {
+                    transferred: sent as u64,
+                    total: Some(total),
+                });
+            }
+            let end = (sent + UPLOAD_STREAM_CHUNK).min(data.len());
+            Some((Ok::<_, std::io::Error>(data[sent..end].to_vec()), end))
+        }
+    });
+    reqwest::Body::wrap_stream(pieces)
+}
+
+/// What recipients of a shared link may do with a file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinkKind {
+    /// Download it (for a folder: browse and download its files)
+    #[default]
+    Download,
+    /// Only view it in the browser
+    Preview,
+}
+
+/// Settings for a new shared link
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkOptions {
+    pub kind: LinkKind,
+    /// Days until the link stops working (None: it never expires)
+    pub expiry_days: Option<u32>,
+    /// Protect the link with a password, which Egnyte generates
+    pub password: bool,
+}
+
+/// A shared link Egnyte created
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkInfo {
+    pub id: String,
+    pub url: String,
+    /// Password recipients need, for a password-protected link
+    pub password: Option<String>,
+    /// Last day the link works, as YYYY-MM-DD
+    pub expiry_date: Option<String>,
+}
+
+/// Year, month and day of the date `days` after 1970-01-01 in the proleptic
+/// Gregorian calendar
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    // Counting years from March so the leap day comes last
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// The calendar date (UTC) `days` after `now`, as YYYY-MM-DD
+fn date_after(now: SystemTime, days: u32) -> String {
+    let today = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86400;
+    let (year, month, day) = civil_date(today + u64::from(days));
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Body of a links API request for `path`
+fn link_request(
+    path: &str,
+    is_folder: bool,
+    options: &LinkOptions,
+    now: SystemTime,
+) -> Result<serde_json::Value> {
+    let mut body = serde_json::json!({
+        "path": egnyte_path::normalize(path),
+        "type": if is_folder { "folder" } else { "file" },
+        "accessibility": if options.password { "password" } else { "anyone" },
+        "sendEmail": false,
+        "notify": false,
+    });
+    match options.kind {
+        LinkKind::Download => {}
+        LinkKind::Preview if is_folder => {
+            anyhow::bail!(
+                "Preview-only links can only be made for files, and {} is a folder",
+                path
+            )
+        }
+        LinkKind::Preview => body["protection"] = "PREVIEW".into(),
+    }
+    if let Some(days) = options.expiry_days {
+        body["expiryDate"] = date_after(now, days).into();
+    }
+    Ok(body)
+}
+
+/// The link in a links API response
+fn parse_link(data: &serde_json::Value) -> Result<LinkInfo> {
+    let link = data
+        .get("links")
+        .and_then(|links| links.get(0))
+        .context("No link in response")?;
+    let field = |value: &serde_json::Value, name: &str| {
+        value.get(name).and_then(|v| v.as_str()).map(str::to_string)
+    };
+    Ok(LinkInfo {
+        id: field(link, "id").context("No id in link")?,
+        url: field(link, "url").context("No url in link")?,
+        password: field(data, "password"),
+        expiry_date: field(data, "expiryDate"),
+    })
+}
+
+/// The folder whose permission applies to `path`: the folder itself (at
+/// `folder_path`, as the API spells it) or the folder a file is in
+fn permission_folder(path: &str, folder_path: Option<&str>) -> String {
+    if let Some(folder) = folder_path {
+        return folder.to_string();
+    }
+    let normalized = egnyte_path::normalize(path);
+    match normalized.rsplit_once('/') {
+        Some(("", _)) | None => "/".to_string(),
+        Some((parent, _)) => parent.to_string(),
+    }
+}
+
+/// Real Egnyte API client implementation.
+///
+/// It starts no tasks or threads of its own: requests, and the blocking
+/// checksum work of uploads, run on whichever Tokio runtime drives the call
+/// (in the binary, the one the filesystem uses).
+pub struct EgnyteAPIClient {
+    client: Client,
+    base_url: String,
+    config_file: PathBuf,
+    /// Where tokens.json is: next to the config file
+    config_dir: PathBuf,
+    /// What the environment supplies, over config.json and the keyring
+    env: EnvSettings,
+    inner: tokio::sync::RwLock<ClientInner>,
+    rate_limiter: RateLimiter,
+    /// Caps the requests in flight at once
+    in_flight: Semaphore,
+    /// Caps the range downloads of one file in flight at once, within
+    /// `in_flight`
+    ranges_per_file: FileLimits,
+    /// Cap on the doubling wait between retries after a 429
+    max_backoff: Duration,
+    /// Deadline for a metadata request, body included
+    metadata_timeout: Duration,
+    /// Longest a content transfer may stall
+    content_idle_timeout: Duration,
+    strict_parsing: bool,
+    page_size: usize,
+    max_entries: usize,
+    verify_checksums: bool,
+    show_online_docs: bool,
+    /// Kernel cache TTLs from config.json, for the mount to apply
+    entry_ttl: Option<Duration>,
+    attr_ttl: Option<Duration>,
+    negative_ttl: Option<Duration>,
+    /// Kernel request sizes from config.json, for the mount to apply
+    max_readahead: Option<u32>,
+    max_write: Option<u32>,
+    /// Egnyte username of the token's owner, fetched on first use
+    username: tokio::sync::OnceCell<String>,
+    /// Effective permissions looked up, by folder, and when
+    permissions: DashMap<String, (Option<Permission>, Instant)>,
+    /// How long a folder's looked-up permission is reused
+    permission_ttl: Duration,
+    /// Where `download_file` and the uploads report their progress
+    progress: Option<ProgressSender>,
+}
+
+struct ClientInner {
+    access_token: Option<String>,
+    token_expires_at: Option<Instant>,
+}
+
+/// Requests in flight at once by default
+const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 8;
+
+/// Run `request` while holding a permit from `limit`, so at most as many
+/// requests as it has permits run at once. The permit covers retries and
+/// backoff, and is released once the response headers are in.
+async fn with_permit<T>(limit: &Semaphore, request: impl std::future::Future<Output = T>) -> T {
+    let _permit = limit
+        .acquire()
+        .await
+        .expect("request semaphore is never closed");
+    request.await
+}
+
+/// Range downloads of one file in flight at once by default, so readahead
+/// over one large file leaves room in the pool for everything else
+const DEFAULT_MAX_RANGES_PER_FILE: usize = 4;
+
+/// A cap on the requests in flight for each path: one semaphore per path
+/// with requests running or waiting, dropped when its last request ends
+struct FileLimits {
+    permits: usize,
+    files: DashMap<String, Arc<Semaphore>>,
+}
+
+impl FileLimits {
+    fn new(permits: usize) -> Self {
+        Self {
+            permits: permits.max(1),
+            files: DashMap::new(),
+        }
+    }
+
+    /// Run `request` once fewer than `permits` others for `path` are running
+    async fn run<T>(&self, path: &str, request: impl std::future::Future<Output = T>) -> T {
+        let limit = Arc::clone(
+            &self
+                .files
+                .entry(path.to_string())
+                .or_insert_with(|| Arc::new(Semaphore::new(self.permits))),
+        );
+        // Released on drop, so a cancelled request gives its slot back too
+        let slot = FileSlot {
+            files: &self.files,
+            path,
+            limit: Some(limit),
+        };
+        with_permit(slot.limit.as_ref().unwrap(), request).await
+    }
+}
+
+/// A request's hold on its path's semaphore in [`FileLimits`]
+struct FileSlot<'a> {
+    files: &'a DashMap<String, Arc<Semaphore>>,
+    path: &'a str,
+    limit: Option<Arc<Semaphore>>,
+}
+
+impl Drop for FileSlot<'_> {
+    fn drop(&mut self) {
+        drop(self.limit.take());
+        // Only the map holds it now: nothing for this path is running
+        self.files
+            .remove_if(self.path, |_, limit| Arc::strong_count(limit) == 1);
+    }
+}
+
+/// Longest wait between retries of a rate-limited request by default
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How long to wait before retrying a 429: what the server asked for in
+/// `Retry-After` (seconds or an HTTP date), or else our own `backoff`
+fn rate_limit_delay(
+    retry_after: Option<&reqwest::header::HeaderValue>,
+    backoff: Duration,
+    now: SystemTime,
+) -> Duration {
+    retry_after
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_retry_after(value.trim(), now))
+        .unwrap_or(backoff)
+}
+
+/// Parse a `Retry-After` value: delta-seconds, or an IMF-fixdate such as
+/// `Sun, 06 Nov 1994 08:49:37 GMT` (a date in the past means now)
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let at = parse_http_date(value)?;
+    Some(at.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+/// Parse an IMF-fixdate, the HTTP date format servers must send
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (_weekday, rest) = value.split_once(", ")?;
+    let parts: Vec<&str> = rest.split(' ').collect();
+    let [day, month, year, time, "GMT"] = parts[..] else {
+        return None;
+    };
+    let day: u64 = day.parse().ok().filter(|day| (1..=31).contains(day))?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u64 + 1;
+    let year: u64 = year.parse().ok().filter(|year| *year >= 1970)?;
+    let mut clock = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (Some(Some(hour)), Some(Some(minute)), Some(Some(second)), None) =
+        (clock.next(), clock.next(), clock.next(), clock.next())
+    else {
+        return None;
+    };
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // Days since 1970-01-01 in the proleptic Gregorian calendar
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let year_of_era = y % 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = (era * 146097 + day_of_era).checked_sub(719468)?;
+
+    let seconds = days * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// The validators a response carries, kept as sent to be quoted back
+fn validators(headers: &reqwest::header::HeaderMap) -> Validators {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    Validators {
+        etag: header(reqwest::header::ETAG),
+        last_modified: header(reqwest::header::LAST_MODIFIED),
+    }
+}
+
+/// Outcome of a request made with validators for what is already cached
+pub enum Conditional {
+    /// The cached copy is current (HTTP 304)
+    NotModified,
+    Modified(reqwest::Response),
+}
+
+/// Requests that may go out back to back before the rate limit applies
+const DEFAULT_RATE_LIMIT_BURST: usize = 10;
+
+/// Token bucket: tokens accrue at `qps` per second up to `capacity`, and
+/// each request takes one. A full bucket lets a burst through at once; after
+/// that requests are spaced to the average rate.
+struct RateLimiter {
+    qps: f64,
+    capacity: f64,
+    bucket: std::sync::Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Negative when requests are already waiting for tokens
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    fn new(qps: f64, capacity: usize) -> Self {
+        let capacity = capacity.max(1) as f64;
+        Self {
+            qps,
+            capacity,
+            bucket: std::sync::Mutex::new(Bucket {
+                tokens: capacity,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take a token at `now`, returning how long to wait until it is ours.
+    /// The token is reserved right away, so the lock is never held while
+    /// waiting and waiters are served in order.
+    fn reserve(&self, now: Instant) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let refill = now.saturating_duration_since(bucket.updated).as_secs_f64() * self.qps;
+        bucket.tokens = (bucket.tokens + refill).min(self.capacity);
+        bucket.updated = now;
+        bucket.tokens -= 1.0;
+        (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / self.qps))
+    }
+
+    async fn wait_if_needed(&self) {
+        if let Some(wait) = self.reserve(Instant::now()) {
+            sleep(wait).await;
+        }
+    }
+}
+
+impl EgnyteAPIClient {
+    /// Create a new API client, loading config and tokens from ~/.config/egnyte-desktop/
+    /// or the `EGNYTE_*` environment variables (see [`EnvSettings`])
+    pub async fn new() -> Result<Self> {
+        Self::with_config_file(None).await
+    }
+
+    /// Create a client like [`Self::new`], reading `config_file` (if given)
+    /// instead of config.json, and the tokens.json next to it
+    pub async fn with_config_file(config_file: Option<&Path>) -> Result<Self> {
+        Self::from_sources(
+            config_file_or_default(config_file)?,
+            EnvSettings::from_env(),
+        )
+        .await
+    }
+
+    async fn from_sources(config_file: PathBuf, env: EnvSettings) -> Result<Self> {
+        let config = load_settings(&config_file, &env).await?;
+        let config_dir = config_file
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .to_path_buf();
+
+        let base_url = configured_base_url(&config)?;
+
+        // Load tokens from tokens.json (Python format: access_token, expires_in, issued_at)
+        let token_file = config_dir.join("tokens.json");
+        let token_data: TokenFile = if let Some(access_token) = &env.access_token {
+            // Its expiry is unknown: trust it until Egnyte answers 401
+            TokenFile {
+                access_token: Some(access_token.clone()),
+                expires_in: Some(ENV_TOKEN_TRUST.as_secs()),
+                issued_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|now| now.as_secs() as i64),
+            }
+        } else if token_file.exists() {
+            let content = tokio::fs::read_to_string(&token_file)
+                .await
+                .context("Failed to read token file")?;
+            serde_json::from_str(&content).unwrap_or(TokenFile {
+                access_token: None,
+                expires_in: None,
+                issued_at: None,
+            })
+        } else if env.refresh_token.is_some() {
+            // A first refresh gets the access token
+            TokenFile {
+                access_token: None,
+                expires_in: None,
+                issued_at: None,
+            }
+        } else {
+            return Err(anyhow::anyhow!(
+                "Not authenticated. Please run 'egnyte-cli auth login'"
+            ));
+        };
+
+        // Refresh token is in keyring (Python stores it there) or the environment;
+        // an access token from the environment does without one, and without
+        // a keyring to look in
+        if env.access_token.is_none() && get_refresh_token(&env)?.is_none() {
+            return Err(anyhow::anyhow!(NO_REFRESH_TOKEN));
+        }
+
+        let access_token = token_data.access_token.clone();
+        let token_expires_at = token_data.issued_at.and_then(|issued| {
+            token_data.expires_in.map(|expires_in| {
+                let expires_at_secs = issued as u64 + expires_in;
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                if expires_at_secs > now {
+                    Instant::now() + Duration::from_secs(expires_at_secs - now)
+                } else {
+                    Instant::now() // Already expired
+                }
+            })
+        });
+
+        let client = http_client(
+            config.pool_size.unwrap_or(DEFAULT_POOL_SIZE),
+            &user_agent(config.user_agent_suffix.as_deref()),
+        )?;
+
+        Ok(Self {
+            client,
+            base_url,
+            config_file,
+            config_dir,
+            env,
+            inner: tokio::sync::RwLock::new(ClientInner {
+                access_token,
+                token_expires_at,
+            }),
+            rate_limiter: RateLimiter::new(
+                10.0, // 10 QPS default
+                config.rate_limit_burst.unwrap_or(DEFAULT_RATE_LIMIT_BURST),
+            ),
+            in_flight: Semaphore::new(
+                config
+                    .max_concurrent_requests
+                    .unwrap_or(DEFAULT_MAX_CONCURRENT_REQUESTS)
+                    .max(1),
+            ),
+            ranges_per_file: FileLimits::new(
+                config
+                    .max_ranges_per_file
+                    .unwrap_or(DEFAULT_MAX_RANGES_PER_FILE),
+            ),
+            max_backoff: config
+                .max_backoff
+                .map_or(DEFAULT_MAX_BACKOFF, |secs| Duration::from_secs(secs as u64)),
+            metadata_timeout: config
+                .metadata_timeout
+                .map_or(DEFAULT_METADATA_TIMEOUT, |secs| {
+                    Duration::from_secs(secs as u64)
+                }),
+            content_idle_timeout: CONTENT_IDLE_TIMEOUT,
+            strict_parsing: false,
+            page_size: DEFAULT_PAGE_SIZE,
+            max_entries: config.max_entries.unwrap_or(DEFAULT_MAX_ENTRIES),
+            verify_checksums: config.verify_checksums.unwrap_or(true),
+            show_online_docs: config.show_online_docs.unwrap_or(false),
+            entry_ttl: config
+                .entry_ttl
+                .map(|secs| Duration::from_secs(secs as u64)),
+            attr_ttl: config.attr_ttl.map(|secs| Duration::from_secs(secs as u64)),
+            negative_ttl: config
+                .negative_ttl
+                .map(|secs| Duration::from_secs(secs as u64)),
+            max_readahead: config.max_readahead.map(saturating_u32),
+            max_write: config.max_write.map(saturating_u32),
+            username: tokio::sync::OnceCell::new(),
+            permissions: DashMap::new(),
+            permission_ttl: DEFAULT_ATTR_TTL,
+            progress: None,
+        })
+    }
+
+    /// Download a file like `download_file`, reporting the bytes received
+    /// to `progress` if given as the body streams in
+    pub async fn download_with_progress(
+        &self,
+        path: &str,
+        progress: Option<&ProgressSender>,
+    ) -> Result<Vec<u8>> {
+        let endpoint = format!("/pubapi/v1/fs-content{}", encode_path(path));
+        let response = self.request(reqwest::Method::GET, &endpoint).await?;
+        read_content(path, response, progress, self.content_idle_timeout).await
+    }
+
+    /// Upload a file like `upload_file`, streaming the body and reporting
+    /// the bytes sent to `progress` if given
+    pub async fn upload_with_progress(
+        &self,
+        path: &str,
+        data: &[u8],
+        progress: Option<&ProgressSender>,
+    ) -> Result<Option<String>> {
+        let endpoint = format!("/pubapi/v1/fs-content{}", encode_path(path));
+        // Hash on a blocking thread while the request is in flight
+        let hash = self.verify_checksums.then(|| {
+            let data = data.to_vec();
+            tokio::task::spawn_blocking(move || hex::encode(Sha512::digest(&data)))
+        });
+        let response = self
+            .request_with_body(
+                reqwest::Method::POST,
+                &endpoint,
+                Some(match progress {
+                    Some(progress) => RequestBody::Tracked(data, progress),
+                    None => RequestBody::Bytes(data),
+                }),
+            )
+            .await?;
+        // The server answers once all of the body has arrived
+        if let Some(progress) = progress {
+            let _ = progress.send(Progress {
+                transferred: data.len() as u64,
+                total: Some(data.len() as u64),
+            });
+        }
+
+        let data: serde_json::Value = response
+            .json()
+            .await
+            .context("Failed to parse upload response")?;
+        if let Some(hash) = hash {
+            let local_checksum = hash.await.context("Failed to hash uploaded data")?;
+            verify_upload_checksum(path, &local_checksum, &data)?;
+        }
+        Ok(data
+            .get("entry_id")
+            .and_then(|v| v.as_str())
+            .map(str::to_string))
+    }
+
+    /// Create a shared link to `path`. Fails with [`ApiError::Forbidden`]
+    /// when the domain has link sharing turned off or the user may not
+    /// share the path.
+    pub async fn create_link(&self, path: &str, options: LinkOptions) -> Result<LinkInfo> {
+        // Links are typed; the API wants to be told which kind of entry
+        let endpoint = format!("/pubapi/v1/fs{}", encode_path(path));
+        let response = self.request(reqwest::Method::GET, &endpoint).await?;
+        let info: serde_json::Value = response.json().await.context("Failed to parse file info")?;
+
+        let body = link_request(path, is_folder_info(&info), &options, SystemTime::now())?;
+        let response = self
+            .request_with_body(
+                reqwest::Method::POST,
+                "/pubapi/v1/links",
+                Some(RequestBody::Json(&body)),
+            )
+            .await?;
+        let data: serde_json::Value = response.json().await.context("Failed to parse link")?;
+        parse_link(&data)
+    }
+
+    /// Everything the API says about `path`, as it sent it, plus the user's
+    /// effective `permission` on it when known. A folder's children are
+    /// left out.
+    pub async fn file_metadata(&self, path: &str) -> Result<serde_json::Value> {
+        let endpoint = format!("/pubapi/v1/fs{}", encode_path(path));
+        let response = self.request(reqwest::Method::GET, &endpoint).await?;
+        let mut data: serde_json::Value =
+            response.json().await.context("Failed to parse file info")?;
+        let is_folder = is_folder_info(&data);
+        let Some(fields) = data.as_object_mut() else {
+            anyhow::bail!("File info for {} is not an object", path);
+        };
+        fields.remove("files");
+        fields.remove("folders");
+
+        let folder_path = if is_folder {
+            fields.get("path").and_then(|v| v.as_str())
+        } else {
+            None
+        };
+        let folder = permission_folder(path, folder_path);
+        if let Some(permission) = self.effective_permission(&folder).await {
+            fields.insert("permission".to_string(), format!("{:?}", permission).into());
+        }
+        Ok(data)
+    }
+
+    /// Report the progress of `download_file`, `upload_file` and
+    /// `upload_chunked` to `progress`
+    pub fn with_progress(mut self, progress: ProgressSender) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Reuse a folder's permission lookup for `ttl` across the stats of
+    /// what is in it; the mount passes its attribute TTL, so a permission is
+    /// never older than the attributes it comes with. Zero looks up every
+    /// time.
+    pub fn with_permission_ttl(mut self, ttl: Duration) -> Self {
+        self.permission_ttl = ttl;
+        self
+    }
+
+    /// Fail listings that contain an entry which does not deserialize,
+    /// instead of logging it and filling in defaults
+    pub fn with_strict_parsing(mut self, strict: bool) -> Self {
+        self.strict_parsing = strict;
+        self
+    }
+
+    /// Number of items to request per page when listing a folder
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    /// Most entries returned for one folder; larger listings are truncated
+    /// with a warning. Overrides max_entries from config.json.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries.max(1);
+        self
+    }
+
+    /// entry_ttl from config.json, if set
+    pub fn configured_entry_ttl(&self) -> Option<Duration> {
+        self.entry_ttl
+    }
+
+    /// attr_ttl from config.json, if set
+    pub fn configured_attr_ttl(&self) -> Option<Duration> {
+        self.attr_ttl
+    }
+
+    /// negative_ttl from config.json, if set
+    pub fn configured_negative_ttl(&self) -> Option<Duration> {
+        self.negative_ttl
+    }
+
+    /// max_readahead from config.json, if set
+    pub fn configured_max_readahead(&self) -> Option<u32> {
+        self.max_readahead
+    }
+
+    /// max_write from config.json, if set
+    pub fn configured_max_write(&self) -> Option<u32> {
+        self.max_write
+    }
+
+    /// Get a valid access token, refreshing if necessary
+    async fn get_valid_token(&self) -> Result<String> {
+        // Check if token is expired or about to expire (within 60 seconds)
+        let needs_refresh = {
+            let inner = self.inner.read().await;
+            inner.token_expires_at.is_none_or(|exp| {
+                exp.saturating_duration_since(Instant::now()) < Duration::from_secs(60)
+            })
+        };
+
+        if needs_refresh {
+            self.refresh_token().await?;
+        }
+
+        let inner = self.inner.read().await;
+        inner
+            .access_token
+            .clone()
+            .context("No access token available")
+    }
+
+    /// Refresh the access token
+    async fn refresh_token(&self) -> Result<()> {
+        let refresh_token = get_refresh_token(&self.env)?.context(NO_REFRESH_TOKEN)?;
+
+        let client_secret = get_client_secret(&self.env)?.context(NO_CLIENT_SECRET)?;
+
+        let config = load_settings(&self.config_file, &self.env).await?;
+
+        let client_id = config.client_id.context("Client ID not configured")?;
+
+        let refresh_url = format!("{}/puboauth/token", self.base_url);
+
+        let params = [
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token.as_str()),
+            ("client_id", client_id.as_str()),
+            ("client_secret", client_secret.as_str()),
+        ];
+
+        let response = self
+            .client
+            .post(&refresh_url)
+            .form(&params)
+            .timeout(self.metadata_timeout)
+            .send()
+            .await
+            .context("Failed to refresh token")?;
+
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "Token refresh failed. Please run 'egnyte-cli auth login'"
+            ));
+        }
+
+        let token_data: serde_json::Value = response
+            .json()
+            .await
+            .context("Failed to parse token response")?;
+
+        let new_access_token = token_data
+            .get("access_token")
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string())
+            .context("No access_token in response")?;
+
+        let expires_in = token_data
+            .get("expires_in")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(3600);
+
+        let issued_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        // Save tokens in Python format (access_token, expires_in, issued_at)
+        #[derive(serde::Serialize)]
+        struct TokenFileOut {
+            access_token: String,
+            expires_in: u64,
+            #[serde(rename = "token_type")]
+            token_type: String,
+            issued_at: i64,
+        }
+        let new_tokens = TokenFileOut {
+            access_token: new_access_token.clone(),
+            expires_in,
+            token_type: "Bearer".to_string(),
+            issued_at,
+        };
+
+        // Set up from the environment, there may be no config directory to
+        // keep them in
+        if !self.env.replaces_config() {
+            let token_file = self.config_dir.join("tokens.json");
+            let token_json =
+                serde_json::to_string_pretty(&new_tokens).context("Failed to serialize tokens")?;
+            tokio::fs::write(&token_file, token_json)
+                .await
+                .context("Failed to write token file")?;
+        }
+
+        // Update inner state
+        {
+            let mut inner = self.inner.write().await;
+            inner.access_token = Some(new_access_token);
+            inner.token_expires_at = Some(Instant::now() + Duration::from_secs(expires_in));
+        }
+
+        Ok(())
+    }
+
+    /// Username of the authenticated user
+    async fn username(&self) -> Result<&str> {
+        let username = self
+            .username
+            .get_or_try_init(|| async {
+                let response = self
+                    .request(reqwest::Method::GET, "/pubapi/v1/userinfo")
+                    .await?;
+                let info: serde_json::Value =
+                    response.json().await.context("Failed to parse user info")?;
+                info.get("username")
+                    .and_then(|v| v.as_str())
+                    .map(str::to_string)
+                    .context("No username in user info")
+            })
+            .await?;
+        Ok(username)
+    }
+
+    /// Effective permission of the current user on a folder, or None if it
+    /// can't be determined (the entry then keeps the default mode bits). A
+    /// lookup is reused for the folder's entries for `permission_ttl`.
+    async fn effective_permission(&self, folder: &str) -> Option<Permission> {
+        if let Some(known) = self.permissions.get(folder) {
+            let (permission, seen) = *known.value();
+            if seen.elapsed() < self.permission_ttl {
+                return permission;
+            }
+        }
+        let result = async {
+            let username = self.username().await?;
+            let endpoint = format!(
+                "/pubapi/v1/perms/user/{}?folder={}",
+                utf8_percent_encode(username, PATH_SEGMENT),
+                encode_path(folder)
+            );
+            let response = self.request(reqwest::Method::GET, &endpoint).await?;
+            let data: serde_json::Value = response
+                .json()
+                .await
+                .context("Failed to parse permission")?;
+            Ok::<_, anyhow::Error>(
+                data.get("permission")
+                    .and_then(|v| v.as_str())
+                    .and_then(Permission::parse),
+            )
+        }
+        .await;
+
+        match result {
+            Ok(permission) => {
+                if !self.permission_ttl.is_zero() {
+                    self.permissions
+                        .insert(folder.to_string(), (permission, Instant::now()));
+                }
+                permission
+            }
+            Err(e) => {
+                warn!("Could not get permission for {}: {}", folder, e);
+                None
+            }
+        }
+    }
+
+    /// Make an authenticated API request
+    async fn request(&self, method: reqwest::Method, endpoint: &str) -> Result<reqwest::Response> {
+        self.request_with_body(method, endpoint, None).await
+    }
+
+    /// Make an authenticated API request with an optional body
+    async fn request_with_body(
+        &self,
+        method: reqwest::Method,
+        endpoint: &str,
+        body: Option<RequestBody<'_>>,
+    ) -> Result<reqwest::Response> {
+        self.request_with_headers(method, endpoint, body, &[]).await
+    }
+
+    /// Make an authenticated API request with an optional body and extra headers
+    async fn request_with_headers(
+        &self,
+        method: reqwest::Method,
+        endpoint: &str,
+        body: Option<RequestBody<'_>>,
+        headers: &[(&'static str, String)],
+    ) -> Result<reqwest::Response> {
+        with_permit(&self.in_flight, async {
+            self.rate_limiter.wait_if_needed().await;
+            self.send_with_retries(method, endpoint, body, headers)
+                .await
+        })
+        .await
+    }
+
+    /// GET `endpoint` with `If-None-Match` and `If-Modified-Since` quoting
+    /// `known`, whichever it has
+    async fn request_conditional(&self, endpoint: &str, known: &Validators) -> Result<Conditional> {
+        let mut headers = Vec::new();
+        if let Some(etag) = &known.etag {
+            headers.push(("If-None-Match", etag.clone()));
+        }
+        if let Some(modified) = &known.last_modified {
+            headers.push(("If-Modified-Since", modified.clone()));
+        }
+        match self
+            .request_with_headers(reqwest::Method::GET, endpoint, None, &headers)
+            .await
+        {
+            Ok(response) => Ok(Conditional::Modified(response)),
+            Err(e) if matches!(e.downcast_ref(), Some(ApiError::Status { status: 304, .. })) => {
+                Ok(Conditional::NotModified)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Entry in a file info response for `path`, with its permission
+    async fn parse_file_info(
+        &self,
+        path: &str,
+        response: reqwest::Response,
+    ) -> Result<crate::fs::fuse_ops::EgnyteEntry> {
+        let validators = validators(response.headers());
+        let body: serde_json::Value = response.json().await.context("Failed to parse file info")?;
+        if !self.show_online_docs && is_online_doc(&body) {
+            note_hidden_online_doc(path);
+            return Err(ApiError::NotFound(path.to_string()).into());
+        }
+        // Folders answer with their listing, the root with nothing more
+        let entry = match EgnyteEntry::deserialize(&body) {
+            Ok(entry) => entry,
+            Err(_) if egnyte_path::normalize(path) == "/" => EgnyteEntry::root(),
+            Err(e) => return Err(e).context("Failed to parse file info"),
+        };
+
+        let is_folder = entry.is_folder || is_folder_info(&body);
+        let mut entry = entry.into_entry(is_folder);
+        let folder = permission_folder(path, is_folder.then_some(entry.path.as_str()));
+        entry.permission = self.effective_permission(&folder).await;
+        entry.validators = validators;
+        Ok(entry)
+    }
+
+    /// Send a request, refreshing the token once on 401 and backing off on 429
+    async fn send_with_retries(
+        &self,
+        method: reqwest::Method,
+        endpoint: &str,
+        body: Option<RequestBody<'_>>,
+        headers: &[(&'static str, String)],
+    ) -> Result<reqwest::Response> {
+        let token = self.get_valid_token().await?;
+        let url = format!("{}{}", self.base_url, endpoint);
+
+        let metadata = !is_content_endpoint(endpoint);
+        let idle = self.content_idle_timeout;
+        // Each attempt gets its own id, logged so it can be quoted to support
+        let send = |token: String| {
+            // A metadata request, body included, gets its own timeout cut to
+            // what is left of the deadline of the operation it serves, after
+            // any waits before this attempt; a content transfer only fails
+            // once it stalls (see `read_content` for download bodies)
+            let deadline = metadata.then(|| budget::request_timeout(self.metadata_timeout));
+            let request_id = request_id();
+            debug!("{} {} (request {})", method, endpoint, request_id);
+            let mut builder = self
+                .client
+                .request(method.clone(), &url)
+                .header("Authorization", format!("Bearer {}", token))
+                .header("X-Request-Id", &request_id);
+            for (name, value) in headers {
+                builder = builder.header(*name, value);
+            }
+            let activity = Activity::new();
+            let content = |builder: reqwest::RequestBuilder, data: &[u8], progress| {
+                builder
+                    .header("Content-Type", "application/octet-stream")
+                    .header("Content-Length", data.len())
+                    .body(streamed_body(data, progress, activity.clone()))
+            };
+            let builder = match &body {
+                Some(RequestBody::Json(value)) => builder.json(value),
+                Some(RequestBody::Bytes(data)) => content(builder, data, None),
+                Some(RequestBody::Tracked(data, progress)) => {
+                    content(builder, data, Some((*progress).clone()))
+                }
+                None => builder.header("Content-Type", "application/json"),
+            };
+            let sent = async move {
+                match deadline {
+                    Some(deadline) => Some(builder.timeout(deadline).send().await),
+                    None => tokio::select! {
+                        sent = builder.send() => Some(sent),
+                        () = activity.stalled(idle) => None,
+                    },
+                }
+            };
+            (request_id, sent)
+        };
+        // None: stalled
+        let received = |sent: Option<reqwest::Result<reqwest::Response>>| match sent {
+            Some(Err(e)) if e.is_timeout() => {
+                Err(anyhow::Error::new(ApiError::Timeout(endpoint.to_string())))
+            }
+            Some(response) => response.context("API request failed"),
+            None => Err(anyhow::Error::new(ApiError::Timeout(endpoint.to_string()))),
+        };
+
+        let mut retries = 5;
+        let mut backoff = Duration::from_millis(500);
+        let mut reconnected = false;
+
+        loop {
+            let (request_id, sent) = send(token.clone());
+            let response = match sent.await {
+                // Likely a stale pooled connection: retry once, at once, on a
+                // fresh one. Failing again means the network is down.
+                Some(Err(e))
+                    if is_connection_error(&e)
+                        && !reconnected
+                        && budget::may_retry(Duration::ZERO) =>
+                {
+                    debug!(
+                        "{} {} failed on its connection (request {}): {}; retrying",
+                        method, endpoint, request_id, e
+                    );
+                    reconnected = true;
+                    continue;
+                }
+                sent => received(sent)?,
+            };
+
+            if response.status() == 401 {
+                // Token might be invalid, try refreshing
+                self.refresh_token().await?;
+                let new_token = self.get_valid_token().await?;
+                let (_, sent) = send(new_token);
+                let response = received(sent.await).context("API request failed after refresh")?;
+
+                if response.status().is_success() {
+                    return Ok(response);
+                }
+            }
+
+            if response.status() == 429 {
+                // Rate limited: wait as long as the server says, or back off,
+                // unless that takes the operation past its budget
+                let delay = rate_limit_delay(
+                    response.headers().get(reqwest::header::RETRY_AFTER),
+                    backoff,
+                    SystemTime::now(),
+                );
+                if retries > 0 && budget::may_retry(delay) {
+                    retries -= 1;
+                    debug!("Rate limited on {}; retrying in {:?}", endpoint, delay);
+                    sleep(delay).await;
+                    backoff = (backoff * 2).min(self.max_backoff);
+                    continue;
+                }
+            }
+
+            if !response.status().is_success() {
+                let status = response.status().as_u16();
+                debug!(
+                    "{} {} failed with {} (request {})",
+                    method, endpoint, status, request_id
+                );
+                let body = response.text().await.unwrap_or_default();
+                return Err(ApiError::from_status(status, body).into());
+            }
+
+            return Ok(response);
+        }
+    }
+
+    /// One range download, once the file's cap allows it
+    async fn download_range_now(
+        &self,
+        path: &str,
+        version: Option<&str>,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>> {
+        let mut endpoint = format!("/pubapi/v1/fs-content{}", encode_path(path));
+        if let Some(version) = version {
+            endpoint.push_str("?entry_id=");
+            endpoint.extend(utf8_percent_encode(version, NON_ALPHANUMERIC));
+        }
+        let response = self
+            .request_with_headers(
+                reqwest::Method::GET,
+                &endpoint,
+                None,
+                // A range of gzipped bytes would be a range of the encoding,
+                // not of the file
+                &[
+                    ("Range", range_header(offset, len)),
+                    ("Accept-Encoding", "identity".to_string()),
+                ],
+            )
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            // The range starts at or past the end of the file
+            Err(e) if matches!(e.downcast_ref(), Some(ApiError::Status { status: 416, .. })) => {
+                return Ok(Vec::new())
+            }
+            Err(e) => match (version, e.downcast_ref()) {
+                // Not a missing path: the file may well have a newer version
+                (Some(version), Some(ApiError::NotFound(_))) => {
+                    return Err(ApiError::VersionGone {
+                        path: path.to_string(),
+                        version: version.to_string(),
+                    }
+                    .into())
+                }
+                _ => return Err(e),
+            },
+        };
+        let whole_file = response.status() != reqwest::StatusCode::PARTIAL_CONTENT;
+        let bytes = read_content(path, response, None, self.content_idle_timeout).await?;
+        if whole_file {
+            // Served without honoring the range
+            return Ok(slice_range(&bytes, offset, len).to_vec());
+        }
+        Ok(bytes)
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::fs::fuse_ops::EgnyteAPI for EgnyteAPIClient {
+    async fn list_folder(&self, path: &str) -> Result<Vec<crate::fs::fuse_ops::EgnyteEntry>> {
+        let base = format!("/pubapi/v1/fs{}", encode_path(path));
+        // Each page goes through request(), so the rate limiter spaces them out
+        let pages = fetch_all_pages(self.page_size, self.max_entries, |offset, count| {
+            let endpoint = format!(
+                "{}?list_content=true&count={}&offset={}",
+                base, count, offset
+            );
+            async move {
+                let response = self.request(reqwest::Method::GET, &endpoint).await?;
+                let data: serde_json::Value =
+                    response.json().await.context("Failed to parse response")?;
+                Ok(data)
+            }
+        })
+        .await?;
+
+        let mut entries = Vec::new();
+        for mut page in pages {
+            if !self.show_online_docs && drop_online_docs(&mut page) > 0 {
+                note_hidden_online_doc(path);
+            }
+            entries.extend(parse_listing(&page, path, self.strict_parsing)?);
+        }
+        // Permissions are set on folders and inherited by what is inside, so
+        // one lookup for the folder covers the listing, and the stats after it
+        let permission = self.effective_permission(path).await;
+        for entry in &mut entries {
+            entry.permission = permission;
+        }
+        if entries.len() > self.max_entries {
+            warn!(
+                "{} has more than {} entries; listing truncated (raise max_entries to see all)",
+                path, self.max_entries
+            );
+            entries.truncate(self.max_entries);
+        }
+        Ok(entries)
+    }
+
+    async fn get_file_info(&self, path: &str) -> Result<crate::fs::fuse_ops::EgnyteEntry> {
+        let endpoint = format!("/pubapi/v1/fs{}", encode_path(path));
+        let response = self.request(reqwest::Method::GET, &endpoint).await?;
+        self.parse_file_info(path, response).await
+    }
+
+    async fn download_file(&self, path: &str) -> Result<Vec<u8>> {
+        self.download_with_progress(path, self.progress.as_ref())
+            .await
+    }
+
+    async fn file_info_if_changed(
+        &self,
+        path: &str,
+        known: &crate::fs::fuse_ops::EgnyteEntry,
+    ) -> Result<Option<crate::fs::fuse_ops::EgnyteEntry>> {
+        let endpoint = format!("/pubapi/v1/fs{}", encode_path(path));
+        match self
+            .request_conditional(&endpoint, &known.validators)
+            .await?
+        {
+            Conditional::NotModified => Ok(None),
+            Conditional::Modified(response) => self.parse_file_info(path, response).await.map(Some),
+        }
+    }
+
+    async fn download_if_changed(
+        &self,
+        path: &str,
+        known: &Validators,
+    ) -> Result<Option<(Vec<u8>, Validators)>> {
+        let endpoint = format!("/pubapi/v1/fs-content{}", encode_path(path));
+        let Conditional::Modified(response) = self.request_conditional(&endpoint, known).await?
+        else {
+            return Ok(None);
+        };
+        let validators = validators(response.headers());
+        let data = read_content(
+            path,
+            response,
+            self.progress.as_ref(),
+            self.content_idle_timeout,
+        )
+        .await?;
+        Ok(Some((data, validators)))
+    }
+
+    async fn download_range(
+        &self,
+        path: &str,
+        version: Option<&str>,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        self.ranges_per_file
+            .run(path, self.download_range_now(path, version, offset, len))
+            .await
+    }
+
+    async fn copy_entry(&self, from: &str, to: &str) -> Result<()> {
+        // Egnyte copies folders recursively on the server side
+        let endpoint = format!("/pubapi/v1/fs{}", encode_path(from));
+        let body = serde_json::json!({
+            "action": "copy",
+            "destination": egnyte_path::normalize(to),
+        });
+        self.request_with_body(
+            reqwest::Method::POST,
+            &endpoint,
+            Some(RequestBody::Json(&body)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_entry(&self, path: &str) -> Result<()> {
+        let endpoint = format!("/pubapi/v1/fs{}", encode_path(path));
+        self.request(reqwest::Method::DELETE, &endpoint).await?;
+        Ok(())
+    }
+
+    async fn move_entry(&self, from: &str, to: &str) -> Result<()> {
+        let endpoint = format!("/pubapi/v1/fs{}", encode_path(from));
+        let body = serde_json::json!({
+            "action": "move",
+            "destination": egnyte_path::normalize(to),
+        });
+        self.request_with_body(
+            reqwest::Method::POST,
+            &endpoint,
+            Some(RequestBody::Json(&body)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn upload_file(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+        self.upload_with_progress(path, data, self.progress.as_ref())
+            .await
+    }
+
+    async fn latest_event_cursor(&self) -> Result<u64> {
+        let response = self
+            .request(reqwest::Method::GET, "/pubapi/v1/events/cursor")
+            .await?;
+        let data: serde_json::Value = response
+            .json()
+            .await
+            .context("Failed to parse events cursor")?;
+        data.get("latest_event_id")
+            .and_then(|v| v.as_u64())
+            .context("No latest_event_id in events cursor")
+    }
+
+    async fn events_since(&self, cursor: u64) -> Result<EventBatch> {
+        let endpoint = format!("/pubapi/v1/events?id={}&count={}", cursor, EVENTS_PAGE_SIZE);
+        let response = self.request(reqwest::Method::GET, &endpoint).await?;
+        // No content: nothing happened since the cursor
+        if response.status() == reqwest::StatusCode::NO_CONTENT {
+            return Ok(EventBatch {
+                paths: Vec::new(),
+                cursor,
+            });
+        }
+        let data: serde_json::Value = response.json().await.context("Failed to parse events")?;
+        Ok(parse_events(&data, cursor))
+    }
+
+    async fn upload_chunked(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+        let endpoint = format!("/pubapi/v1/fs-content-chunked{}", encode_path(path));
+        let hash = self.verify_checksums.then(|| {
+            let data = data.to_vec();
+            tokio::task::spawn_blocking(move || hex::encode(Sha512::digest(&data)))
+        });
+
+        let body = upload_in_chunks(
+            data,
+            UPLOAD_CHUNK_SIZE,
+            Duration::from_millis(500),
+            |chunk| {
+                let endpoint = &endpoint;
+                async move {
+                    let mut headers = vec![
+                        ("X-Egnyte-Chunk-Num", chunk.number.to_string()),
+                        (
+                            "X-Egnyte-Chunk-Sha512-Checksum",
+                            hex::encode(Sha512::digest(chunk.data)),
+                        ),
+                    ];
+                    if let Some(upload_id) = chunk.upload_id {
+                        headers.push(("X-Egnyte-Upload-Id", upload_id));
+                    }
+                    if chunk.last {
+                        headers.push(("X-Egnyte-Last-Chunk", "true".to_string()));
+                    }
+
+                    let response = self
+                        .request_with_headers(
+                            reqwest::Method::POST,
+                            endpoint,
+                            Some(RequestBody::Bytes(chunk.data)),
+                            &headers,
+                        )
+                        .await?;
+                    if let Some(progress) = &self.progress {
+                        let _ = progress.send(Progress {
+                            transferred: ((chunk.number - 1) * UPLOAD_CHUNK_SIZE + chunk.data.len())
+                                as u64,
+                            total: Some(data.len() as u64),
+                        });
+                    }
+                    let upload_id = response
+                        .headers()
+                        .get("X-Egnyte-Upload-Id")
+                        .and_then(|v| v.to_str().ok())
+                        .map(str::to_string);
+                    let body = if chunk.last {
+                        response
+                            .json()
+                            .await
+                            .context("Failed to parse upload response")?
+                    } else {
+                        serde_json::Value::Null
+                    };
+                    Ok(ChunkAck { upload_id, body })
                 }
+            },
+        )
+        .await?;
+
+        if let Some(hash) = hash {
+            let local_checksum = hash.await.context("Failed to hash uploaded data")?;
+            verify_upload_checksum(path, &local_checksum, &body)?;
+        }
+        Ok(body
+            .get("entry_id")
+            .and_then(|v| v.as_str())
+            .map(str::to_string))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use percent_encoding::percent_decode_str;
+
+    /// Client for `base_url` holding a token valid for an hour
+    fn test_client(base_url: String) -> EgnyteAPIClient {
+        EgnyteAPIClient {
+            client: http_client(DEFAULT_POOL_SIZE, USER_AGENT).unwrap(),
+            base_url,
+            config_file: std::env::temp_dir().join("config.json"),
+            config_dir: std::env::temp_dir(),
+            env: EnvSettings::default(),
+            inner: tokio::sync::RwLock::new(ClientInner {
+                access_token: Some("token".to_string()),
+                token_expires_at: Some(Instant::now() + Duration::from_secs(3600)),
+            }),
+            rate_limiter: RateLimiter::new(10.0, DEFAULT_RATE_LIMIT_BURST),
+            in_flight: Semaphore::new(DEFAULT_MAX_CONCURRENT_REQUESTS),
+            ranges_per_file: FileLimits::new(DEFAULT_MAX_RANGES_PER_FILE),
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            metadata_timeout: DEFAULT_METADATA_TIMEOUT,
+            content_idle_timeout: CONTENT_IDLE_TIMEOUT,
+            strict_parsing: false,
+            page_size: DEFAULT_PAGE_SIZE,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            verify_checksums: true,
+            show_online_docs: false,
+            entry_ttl: None,
+            attr_ttl: None,
+            negative_ttl: None,
+            max_readahead: None,
+            max_write: None,
+            username: tokio::sync::OnceCell::new(),
+            permissions: DashMap::new(),
+            permission_ttl: DEFAULT_ATTR_TTL,
+            progress: None,
+        }
+    }
+
+    /// Serve `responses` (raw HTTP, one per connection, in order) on a local
+    /// port, answering each request once its body is in; returns the base URL
+    async fn serve(responses: Vec<&'static str>) -> String {
+        serve_bytes(
+            responses
+                .into_iter()
+                .map(|r| r.as_bytes().to_vec())
+                .collect(),
+        )
+        .await
+    }
+
+    /// Like `serve`, for responses that are not text
+    async fn serve_bytes(responses: Vec<Vec<u8>>) -> String {
+        let count = responses.len();
+        let mut responses = responses.into_iter();
+        serve_answering(count, move |_| responses.next().unwrap()).await
+    }
+
+    /// Like `serve`, answering each of `count` requests with what `answer`
+    /// makes of it (head and body, header names in lower case)
+    async fn serve_answering(
+        count: usize,
+        mut answer: impl FnMut(&str) -> Vec<u8> + Send + 'static,
+    ) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            for _ in 0..count {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                let body_len = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .filter_map(|line| line.split_once(':'))
+                            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                            .map_or(0, |(_, value)| value.trim().parse().unwrap());
+                        break length - body.len().min(length);
+                    }
+                    if n == 0 {
+                        break 0;
+                    }
+                };
+                let mut body = vec![0; body_len];
+                stream.read_exact(&mut body).await.unwrap();
+                request.extend_from_slice(&body);
+                let response = answer(&String::from_utf8_lossy(&request));
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    /// Serve every request on a local port, keeping connections open for
+    /// more, with a listing, a user name or a permission as the path asks;
+    /// returns the base URL and the count of connections accepted
+    async fn serve_keep_alive() -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let accepted = Arc::clone(&connections);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    loop {
+                        let mut request_line = String::new();
+                        if stream.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        // Only GETs, so the head ends at the first empty line
+                        let mut line = String::new();
+                        while stream.read_line(&mut line).await.unwrap_or(0) > 2 {
+                            line.clear();
+                        }
+                        let body = if request_line.contains("/userinfo") {
+                            r#"{"username":"jo"}"#
+                        } else if request_line.contains("/perms/user/") {
+                            r#"{"permission":"Editor"}"#
+                        } else {
+                            r#"{"files":[{"name":"a.txt","path":"/a.txt","isFolder":false}]}"#
+                        };
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                            body.len(),
+                            body
+                        );
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        (format!("http://{}", addr), connections)
+    }
+
+    #[tokio::test]
+    async fn test_walk_reuses_one_connection() {
+        use crate::fs::fuse_ops::EgnyteAPI;
+
+        // A find over 20 folders: a listing and a permission lookup each,
+        // and the user name once
+        for (pool_size, expected) in [(DEFAULT_POOL_SIZE, 1), (0, 41)] {
+            let (base_url, connections) = serve_keep_alive().await;
+            let mut client = test_client(base_url);
+            client.client = http_client(pool_size, USER_AGENT).unwrap();
+            client.rate_limiter = RateLimiter::new(1000.0, DEFAULT_RATE_LIMIT_BURST);
+            for n in 0..20 {
+                client.list_folder(&format!("/Shared/{}", n)).await.unwrap();
             }
+            assert_eq!(
+                connections.load(std::sync::atomic::Ordering::SeqCst),
+                expected,
+                "pool_size {}",
+                pool_size
+            );
         }
+    }
 
-        // Process files
-        if let Some(files) = data.get("files").and_then(|v| v.as_array()) {
-            for file in files {
-                if let Ok(entry) = serde_json::from_value::<EgnyteEntry>(file.clone()) {
-                    entries.push(crate::fs::fuse_ops::EgnyteEntry {
-                        name: entry.name,
-                        path: entry.path,
-                        is_folder: false,
-                        size: entry.size,
-                        modified_time: entry.modified_time,
-                    });
-                }
+    #[test]
+    fn test_link_request() {
+        // 2024-02-28, so the expiry crosses the leap day
+        let now = UNIX_EPOCH + Duration::from_secs(19781 * 86400 + 3600);
+        let options = LinkOptions {
+            kind: LinkKind::Preview,
+            expiry_days: Some(2),
+            password: true,
+        };
+        let body = link_request("/Shared/a.pdf", false, &options, now).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "path": "/Shared/a.pdf",
+                "type": "file",
+                "accessibility": "password",
+                "sendEmail": false,
+                "notify": false,
+                "protection": "PREVIEW",
+                "expiryDate": "2024-03-01",
+            })
+        );
+
+        let body = link_request("/Shared/Docs", true, &LinkOptions::default(), now).unwrap();
+        assert_eq!(body["type"], "folder");
+        assert_eq!(body["accessibility"], "anyone");
+        assert!(body.get("expiryDate").is_none());
+        assert!(link_request("/Shared/Docs", true, &options, now).is_err());
+        assert_eq!(date_after(UNIX_EPOCH, 365), "1971-01-01");
+    }
+
+    #[tokio::test]
+    async fn test_create_link() {
+        let base_url = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 56\r\nConnection: close\r\n\r\n{\"name\":\"a.pdf\",\"path\":\"/Shared/a.pdf\",\"isFolder\":false}",
+            "HTTP/1.1 200 OK\r\nContent-Length: 79\r\nConnection: close\r\n\r\n{\"links\":[{\"id\":\"abc\",\"url\":\"https://test.egnyte.com/dl/abc\"}],\"password\":\"pw\"}",
+            "HTTP/1.1 200 OK\r\nContent-Length: 56\r\nConnection: close\r\n\r\n{\"name\":\"a.pdf\",\"path\":\"/Shared/a.pdf\",\"isFolder\":false}",
+            "HTTP/1.1 403 Forbidden\r\nContent-Length: 26\r\nConnection: close\r\n\r\nLink sharing is disabled.\n",
+        ])
+        .await;
+        let client = test_client(base_url);
+        let options = LinkOptions {
+            password: true,
+            ..Default::default()
+        };
+
+        let link = client
+            .create_link("/Shared/a.pdf", options.clone())
+            .await
+            .unwrap();
+        assert_eq!(link.url, "https://test.egnyte.com/dl/abc");
+        assert_eq!(link.password.as_deref(), Some("pw"));
+
+        // Sharing turned off for the domain
+        let err = client
+            .create_link("/Shared/a.pdf", options)
+            .await
+            .unwrap_err();
+        assert_eq!(crate::fs::error::errno(&err), libc::EACCES);
+    }
+
+    #[tokio::test]
+    async fn test_root_info_from_a_listing() {
+        use crate::fs::fuse_ops::EgnyteAPI;
+
+        // What getattr on the mount root gets: the root's listing; the
+        // permission lookup fails and is left out
+        let base_url = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 64\r\nConnection: close\r\n\r\n{\"folders\":[{\"name\":\"Shared\",\"path\":\"/Shared\",\"isFolder\":true}]}",
+        ])
+        .await;
+        let root = test_client(base_url).get_file_info("/").await.unwrap();
+        assert!(root.is_folder);
+        assert_eq!(root.path, "/");
+    }
+
+    #[tokio::test]
+    async fn test_permission_lookups_are_shared_by_a_folder() {
+        use crate::fs::fuse_ops::EgnyteAPI;
+
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = Arc::clone(&requests);
+        let base_url = serve_answering(5, move |request| {
+            let line = request.lines().next().unwrap().to_string();
+            let body = if line.contains("/userinfo") {
+                r#"{"username":"jo"}"#.to_string()
+            } else if line.contains("/perms/user/") {
+                r#"{"permission":"Viewer"}"#.to_string()
+            } else if line.contains("list_content") {
+                r#"{"files":[{"name":"c.txt","path":"/Shared/c.txt","isFolder":false}]}"#
+                    .to_string()
+            } else {
+                let name = line.split('/').nth(5).unwrap().split(' ').next().unwrap();
+                format!(
+                    r#"{{"name":"{0}","path":"/Shared/{0}","isFolder":false}}"#,
+                    name
+                )
+            };
+            seen.lock().unwrap().push(line);
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .into_bytes()
+        })
+        .await;
+        let client = test_client(base_url).with_permission_ttl(Duration::from_secs(60));
+
+        let a = client.get_file_info("/Shared/a.txt").await.unwrap();
+        let b = client.get_file_info("/Shared/b.txt").await.unwrap();
+        let listed = client.list_folder("/Shared").await.unwrap();
+        assert_eq!(a.permission, Some(Permission::Viewer));
+        assert_eq!(b.permission, Some(Permission::Viewer));
+        assert_eq!(listed[0].permission, Some(Permission::Viewer));
+        // One user info and one permission lookup for the three
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 5);
+        assert_eq!(
+            requests
+                .iter()
+                .filter(|line| line.contains("/perms/user/"))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_is_folder_info() {
+        let info = |json: &str| is_folder_info(&serde_json::from_str(json).unwrap());
+        assert!(info(r#"{"name":"Docs","path":"/Docs","isFolder":true}"#));
+        assert!(info(r#"{"name":"Docs","path":"/Docs","is_folder":true}"#));
+        assert!(info(
+            r#"{"name":"Docs","path":"/Docs","folders":[],"files":[]}"#
+        ));
+        assert!(!info(
+            r#"{"name":"a.txt","path":"/a.txt","isFolder":false,"size":1}"#
+        ));
+        assert!(!info(r#"{"name":"a.txt","path":"/a.txt","size":1}"#));
+    }
+
+    #[tokio::test]
+    async fn test_folder_info_from_a_listing() {
+        use crate::fs::fuse_ops::EgnyteAPI;
+
+        let base_url = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 90\r\nConnection: close\r\n\r\n{\"name\":\"Docs\",\"path\":\"/Shared/Docs\",\"is_folder\":true,\"folders\":[],\"files\":[{\"name\":\"a\"}]}",
+        ])
+        .await;
+        let docs = test_client(base_url)
+            .get_file_info("/Shared/Docs")
+            .await
+            .unwrap();
+        assert!(docs.is_folder);
+        assert_eq!(docs.path, "/Shared/Docs");
+        assert_eq!(docs.size, 0);
+    }
+
+    #[tokio::test]
+    async fn test_file_metadata_keeps_fields_but_not_children() {
+        // Only the entry is served; the permission lookup fails and is left out
+        let base_url = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 93\r\nConnection: close\r\n\r\n{\"name\":\"Docs\",\"path\":\"/Shared/Docs\",\"isFolder\":true,\"folder_id\":\"f1\",\"files\":[{\"name\":\"a\"}]}",
+        ])
+        .await;
+        let metadata = test_client(base_url)
+            .file_metadata("/Shared/Docs")
+            .await
+            .unwrap();
+        assert_eq!(metadata["folder_id"], "f1");
+        assert!(metadata.get("files").is_none());
+        assert!(metadata.get("permission").is_none());
+    }
+
+    #[test]
+    fn test_encode_path_segments() {
+        assert_eq!(encode_path("/"), "/");
+        assert_eq!(encode_path("/Shared/Documents"), "/Shared/Documents");
+        assert_eq!(
+            encode_path("/My Folder/report #2.pdf"),
+            "/My%20Folder/report%20%232.pdf"
+        );
+        assert_eq!(encode_path("/a?b/100%"), "/a%3Fb/100%25");
+    }
+
+    fn parse_modified(json: &str) -> SystemTime {
+        serde_json::from_str::<EgnyteEntry>(json)
+            .unwrap()
+            .modified_time
+    }
+
+    #[test]
+    fn test_timestamp_milliseconds() {
+        let t = parse_modified(
+            r#"{"name":"a","path":"/a","isFolder":false,"lastModified":1700000000123}"#,
+        );
+        assert_eq!(t, UNIX_EPOCH + Duration::from_millis(1_700_000_000_123));
+    }
+
+    #[test]
+    fn test_timestamp_seconds() {
+        let t = parse_modified(
+            r#"{"name":"a","path":"/a","isFolder":false,"lastModified":1700000000}"#,
+        );
+        assert_eq!(t, UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    }
+
+    #[test]
+    fn test_timestamp_numeric_string() {
+        let t = parse_modified(
+            r#"{"name":"a","path":"/a","isFolder":false,"lastModified":"1700000000123"}"#,
+        );
+        assert_eq!(t, UNIX_EPOCH + Duration::from_millis(1_700_000_000_123));
+    }
+
+    #[test]
+    fn test_timestamp_null_or_missing() {
+        let t = parse_modified(r#"{"name":"a","path":"/a","isFolder":true,"lastModified":null}"#);
+        assert_eq!(t, UNIX_EPOCH);
+        let t = parse_modified(r#"{"name":"a","path":"/a","isFolder":true}"#);
+        assert_eq!(t, UNIX_EPOCH);
+    }
+
+    #[test]
+    fn test_timestamp_invalid_string() {
+        let json = r#"{"name":"a","path":"/a","isFolder":false,"lastModified":"yesterday"}"#;
+        assert!(serde_json::from_str::<EgnyteEntry>(json).is_err());
+    }
+
+    #[test]
+    fn test_listing_keeps_malformed_entries() {
+        let data = serde_json::json!({
+            "folders": [{"name": "Docs", "path": "/Shared/Docs", "isFolder": true}],
+            "files": [
+                {"name": "big.bin", "path": "/Shared/big.bin", "isFolder": false, "size": "huge"},
+                {"path": "/Shared/noname.txt", "size": 3},
+                {"size": 1}
+            ]
+        });
+        let entries = parse_listing(&data, "/Shared", false).unwrap();
+        let names: Vec<_> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["Docs", "big.bin", "noname.txt"]);
+        assert!(entries[0].is_folder);
+        assert_eq!(entries[1].size, 0);
+        assert_eq!(entries[1].path, "/Shared/big.bin");
+        assert_eq!(entries[2].size, 3);
+    }
+
+    #[test]
+    fn test_online_docs() {
+        let mut page = serde_json::json!({
+            "folders": [{"name": "Plans.gdoc", "path": "/Shared/Plans.gdoc", "isFolder": true}],
+            "files": [
+                {"name": "Budget.gsheet", "path": "/Shared/Budget.gsheet", "size": 0},
+                {"name": "Deck", "path": "/Shared/Deck", "size": 0,
+                 "mime_type": "application/vnd.google-apps.presentation"},
+                {"name": "empty.txt", "path": "/Shared/empty.txt", "size": 0},
+                {"name": "real.GDOC", "path": "/Shared/real.GDOC", "size": 120},
+                {"name": "notes.GDOC", "path": "/Shared/notes.GDOC"}
+            ]
+        });
+        assert_eq!(drop_online_docs(&mut page), 3);
+        let entries = parse_listing(&page, "/Shared", true).unwrap();
+        let names: Vec<_> = entries.iter().map(|e| e.name.as_str()).collect();
+        // A folder, a file with content and an empty text file are kept
+        assert_eq!(names, vec!["Plans.gdoc", "empty.txt", "real.GDOC"]);
+    }
+
+    #[tokio::test]
+    async fn test_online_doc_info_is_not_found() {
+        let body =
+            r#"{"name":"Budget.gsheet","path":"/Shared/Budget.gsheet","isFolder":false,"size":0}"#;
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let base_url = serve_bytes(vec![response.into_bytes()]).await;
+        let err = test_client(base_url)
+            .get_file_info("/Shared/Budget.gsheet")
+            .await
+            .unwrap_err();
+        assert_eq!(crate::fs::error::errno(&err), libc::ENOENT);
+    }
+
+    #[test]
+    fn test_listing_strict_rejects_malformed_entries() {
+        let data = serde_json::json!({
+            "files": [{"name": "big.bin", "path": "/Shared/big.bin", "isFolder": false, "size": "huge"}]
+        });
+        let err = parse_listing(&data, "/Shared", true).unwrap_err();
+        assert!(err.to_string().contains("/Shared/big.bin"));
+    }
+
+    fn file_items(range: std::ops::Range<usize>) -> Vec<serde_json::Value> {
+        range
+            .map(|i| {
+                serde_json::json!({
+                    "name": format!("f{}", i),
+                    "path": format!("/Big/f{}", i),
+                    "isFolder": false,
+                    "size": i
+                })
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_listing_follows_pages() {
+        let requests = std::sync::Mutex::new(Vec::new());
+        let pages = fetch_all_pages(3, DEFAULT_MAX_ENTRIES, |offset, count| {
+            requests.lock().unwrap().push((offset, count));
+            let page = match offset {
+                0 => serde_json::json!({
+                    "folders": [{"name": "Sub", "path": "/Big/Sub", "isFolder": true}],
+                    "files": file_items(0..2),
+                    "total_count": 5
+                }),
+                3 => serde_json::json!({"files": file_items(2..4), "total_count": 5}),
+                _ => panic!("unexpected offset {}", offset),
+            };
+            async move { Ok(page) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(*requests.lock().unwrap(), vec![(0, 3), (3, 3)]);
+        let entries: Vec<_> = pages
+            .iter()
+            .flat_map(|page| parse_listing(page, "/Big", true).unwrap())
+            .collect();
+        let names: Vec<_> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["Sub", "f0", "f1", "f2", "f3"]);
+    }
+
+    #[tokio::test]
+    async fn test_listing_stops_on_full_final_page() {
+        let mut calls = 0;
+        let pages = fetch_all_pages(2, DEFAULT_MAX_ENTRIES, |offset, _| {
+            calls += 1;
+            let page = match offset {
+                0 => serde_json::json!({"files": file_items(0..2)}),
+                _ => serde_json::json!({"files": []}),
+            };
+            async move { Ok(page) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(calls, 2);
+        assert_eq!(pages.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_listing_stops_past_max_entries() {
+        let mut offsets = Vec::new();
+        let pages = fetch_all_pages(2, 3, |offset, _| {
+            offsets.push(offset);
+            let page = serde_json::json!({"files": file_items(offset..offset + 2)});
+            async move { Ok(page) }
+        })
+        .await
+        .unwrap();
+        // Stops once more than 3 items are in hand, well before the folder ends
+        assert_eq!(offsets, vec![0, 2]);
+        assert_eq!(pages.iter().map(page_item_count).sum::<usize>(), 4);
+    }
+
+    #[test]
+    fn test_config_max_entries() {
+        let config: Config = serde_json::from_str(r#"{"max_entries": "500"}"#).unwrap();
+        assert_eq!(config.max_entries, Some(500));
+        let config: Config = serde_json::from_str(r#"{"max_entries": 20}"#).unwrap();
+        assert_eq!(config.max_entries, Some(20));
+        let config: Config = serde_json::from_str(r#"{"domain": "acme"}"#).unwrap();
+        assert_eq!(config.max_entries, None);
+    }
+
+    #[test]
+    fn test_normalize_domain() {
+        for domain in [
+            "acme",
+            " Acme ",
+            "acme.egnyte.com",
+            "https://acme.egnyte.com",
+            "HTTPS://ACME.EGNYTE.COM/",
+            "http://acme.egnyte.com",
+        ] {
+            assert_eq!(normalize_domain(domain).unwrap(), "acme", "{:?}", domain);
+        }
+        assert_eq!(normalize_domain("acme-eu").unwrap(), "acme-eu");
+        for domain in [
+            "",
+            "https://",
+            "acme corp",
+            "acme.example.com",
+            "-acme",
+            "acme/Shared",
+        ] {
+            let err = normalize_domain(domain).unwrap_err();
+            assert!(err.to_string().contains("Invalid domain"), "{:?}", domain);
+        }
+    }
+
+    #[test]
+    fn test_configured_base_url() {
+        let config: Config = serde_json::from_str(r#"{"domain": "acme.egnyte.com"}"#).unwrap();
+        assert_eq!(
+            configured_base_url(&config).unwrap(),
+            "https://acme.egnyte.com"
+        );
+        let config: Config =
+            serde_json::from_str(r#"{"domain": "acme", "base_url": "https://acme.egnyte.eu/"}"#)
+                .unwrap();
+        assert_eq!(
+            configured_base_url(&config).unwrap(),
+            "https://acme.egnyte.eu"
+        );
+        // Plain HTTP only to this machine
+        for base_url in [
+            "http://127.0.0.1:8080",
+            "http://localhost/",
+            "http://[::1]:1",
+        ] {
+            assert!(configured_base_url(&Config {
+                base_url: Some(base_url.to_string()),
+                ..Config::default()
+            })
+            .is_ok());
+        }
+        for base_url in [
+            "http://acme.egnyte.eu",
+            "http://127.0.0.1.example.com",
+            "acme.egnyte.eu",
+            "https://",
+            "https://acme.egnyte.eu/?x=1",
+            "https://user:pw@acme.egnyte.eu",
+        ] {
+            let err = validate_base_url(base_url).unwrap_err();
+            assert!(
+                err.to_string().contains("Invalid base_url"),
+                "{:?}",
+                base_url
+            );
+        }
+    }
+
+    #[test]
+    fn test_upload_checksum_mismatch() {
+        let local = hex::encode(Sha512::digest(b"hello"));
+        let wrong = serde_json::json!({"checksum": hex::encode(Sha512::digest(b"hullo"))});
+        let err = verify_upload_checksum("/a.txt", &local, &wrong).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ApiError>(),
+            Some(ApiError::ChecksumMismatch { .. })
+        ));
+        assert_eq!(crate::fs::error::errno(&err), libc::EIO);
+
+        let right = serde_json::json!({"checksum": local.to_uppercase()});
+        assert!(verify_upload_checksum("/a.txt", &local, &right).is_ok());
+        assert!(verify_upload_checksum("/a.txt", &local, &serde_json::json!({})).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_load_config() {
+        let dir = std::env::temp_dir().join(format!("egnyte-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("config.json");
+        let err = load_config(&dir.join("missing.json")).await.unwrap_err();
+        assert!(err.to_string().contains("missing.json not found"));
+
+        std::fs::write(&file, "{not json").unwrap();
+        assert!(load_config(&file).await.is_err());
+
+        std::fs::write(&file, r#"{"domain": "acme"}"#).unwrap();
+        let config = load_config(&file).await.unwrap();
+        let err = describe_config(&config).unwrap_err();
+        assert!(err.to_string().contains("Client ID not configured"));
+
+        std::fs::write(&file, r#"{"domain": "acme", "client_id": "abc"}"#).unwrap();
+        let config = load_config(&file).await.unwrap();
+        assert_eq!(describe_config(&config).unwrap(), "domain acme");
+    }
+
+    #[tokio::test]
+    async fn test_config_file_elsewhere() {
+        let dir = std::env::temp_dir().join(format!("egnyte-service-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("service.json");
+        std::fs::write(&file, r#"{"domain": "acme", "client_id": "abc"}"#).unwrap();
+        std::fs::write(
+            dir.join("tokens.json"),
+            r#"{"access_token": "stored", "expires_in": 3600, "issued_at": 4102444800}"#,
+        )
+        .unwrap();
+        let env = EnvSettings {
+            refresh_token: Some("refresh".to_string()),
+            ..EnvSettings::default()
+        };
+
+        let client = EgnyteAPIClient::from_sources(file, env).await.unwrap();
+        assert_eq!(client.base_url, "https://acme.egnyte.com");
+        assert_eq!(client.config_dir, dir);
+        assert_eq!(
+            client.inner.read().await.access_token.as_deref(),
+            Some("stored")
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_allows_burst() {
+        let limiter = RateLimiter::new(10.0, 5);
+        let start = Instant::now();
+        for _ in 0..5 {
+            assert_eq!(limiter.reserve(start), None);
+        }
+        let wait = limiter.reserve(start).unwrap();
+        assert!((wait.as_secs_f64() - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_rate_limiter_steady_rate() {
+        let limiter = RateLimiter::new(10.0, 1);
+        let start = Instant::now();
+        assert_eq!(limiter.reserve(start), None);
+        // Twenty requests at once are spread over two seconds
+        let waits: Vec<f64> = (0..20)
+            .map(|_| limiter.reserve(start).unwrap().as_secs_f64())
+            .collect();
+        assert!((waits[0] - 0.1).abs() < 1e-9);
+        assert!((waits[19] - 2.0).abs() < 1e-9);
+
+        // Once that backlog has drained, a pause refills the bucket
+        let later = start + Duration::from_secs(5);
+        assert_eq!(limiter.reserve(later), None);
+        assert!(limiter.reserve(later).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_concurrent_callers_wait_in_parallel() {
+        use std::sync::Arc;
+
+        // 20 callers at 100 QPS with no burst: the last slot is 190ms out.
+        // Waiting happens outside the lock, so the callers' sleeps overlap
+        // instead of adding up behind one another.
+        let limiter = Arc::new(RateLimiter::new(100.0, 1));
+        let start = std::time::Instant::now();
+        let tasks: Vec<_> = (0..20)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move { limiter.wait_if_needed().await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(180), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(800), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_requests_never_exceed_cap() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let limit = Arc::new(Semaphore::new(3));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..50)
+            .map(|_| {
+                let (limit, in_flight, peak) = (limit.clone(), in_flight.clone(), peak.clone());
+                tokio::spawn(async move {
+                    with_permit(&limit, async {
+                        let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        sleep(Duration::from_millis(2)).await;
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                    })
+                    .await
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+    }
+
+    /// Answer every request on a local port with a 206 of four bytes after
+    /// `gap`, each on its own connection and all at once; returns the base
+    /// URL and the requests in flight and most ever in flight, by path
+    async fn serve_ranges(
+        gap: Duration,
+    ) -> (
+        String,
+        Arc<std::sync::Mutex<std::collections::HashMap<String, (usize, usize)>>>,
+    ) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let paths = Arc::new(std::sync::Mutex::new(std::collections::HashMap::new()));
+        let seen = Arc::clone(&paths);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let paths = Arc::clone(&seen);
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let mut request_line = String::new();
+                    stream.read_line(&mut request_line).await.unwrap();
+                    let path = request_line.split(' ').nth(1).unwrap().to_string();
+                    loop {
+                        let mut line = String::new();
+                        stream.read_line(&mut line).await.unwrap();
+                        if line == "\r\n" {
+                            break;
+                        }
+                    }
+                    {
+                        let mut paths = paths.lock().unwrap();
+                        let (now, peak): &mut (usize, usize) =
+                            paths.entry(path.clone()).or_default();
+                        *now += 1;
+                        *peak = (*peak).max(*now);
+                    }
+                    sleep(gap).await;
+                    paths.lock().unwrap().get_mut(&path).unwrap().0 -= 1;
+                    let response = "HTTP/1.1 206 Partial Content\r\n\
+                        Content-Range: bytes 0-3/100\r\n\
+                        Content-Length: 4\r\nConnection: close\r\n\r\nabcd";
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                });
             }
+        });
+        (format!("http://{}", addr), paths)
+    }
+
+    #[tokio::test]
+    async fn test_range_of_a_version_names_its_entry_id() {
+        use crate::fs::fuse_ops::EgnyteAPI;
+
+        let (base_url, paths) = serve_ranges(Duration::ZERO).await;
+        let client = test_client(base_url);
+        let data = client
+            .download_range("/Shared/big.bin", Some("v 7"), 0, 4)
+            .await;
+        assert_eq!(data.unwrap(), b"abcd");
+        let pinned = "/pubapi/v1/fs-content/Shared/big.bin?entry_id=v%207";
+        assert!(paths.lock().unwrap().contains_key(pinned));
+    }
+
+    #[tokio::test]
+    async fn test_range_downloads_per_file_never_exceed_cap() {
+        use crate::fs::fuse_ops::EgnyteAPI;
+
+        let (base_url, paths) = serve_ranges(Duration::from_millis(20)).await;
+        let mut client = test_client(base_url);
+        client.rate_limiter = RateLimiter::new(1000.0, DEFAULT_RATE_LIMIT_BURST);
+        client.ranges_per_file = FileLimits::new(2);
+        // As readahead over two large files asks for their blocks
+        let reads = (0..12u64).map(|n| {
+            let path = format!("/Shared/big{}.bin", n % 2);
+            let client = &client;
+            async move { client.download_range(&path, None, n << 20, 4).await }
+        });
+        for data in futures_util::future::join_all(reads).await {
+            assert_eq!(data.unwrap(), b"abcd");
         }
+        // Each file got its own two slots, and none are left behind
+        let paths = paths.lock().unwrap().clone();
+        assert_eq!(paths["/pubapi/v1/fs-content/Shared/big0.bin"], (0, 2));
+        assert_eq!(paths["/pubapi/v1/fs-content/Shared/big1.bin"], (0, 2));
+        assert!(client.ranges_per_file.files.is_empty());
 
-        Ok(entries)
+        // A request given up on, running or still waiting, leaves none either
+        let limits = FileLimits::new(1);
+        let running = limits.run("/big.bin", std::future::pending::<()>());
+        let waiting = limits.run("/big.bin", std::future::pending::<()>());
+        let both = async { tokio::join!(running, waiting) };
+        assert!(tokio::time::timeout(Duration::from_millis(10), both)
+            .await
+            .is_err());
+        assert!(limits.files.is_empty());
     }
 
-    async fn get_file_info(&self, path: &str) -> Result<crate::fs::fuse_ops::EgnyteEntry> {
-        let endpoint = format!("/pubapi/v1/fs{}", path);
-        let response = self.request(reqwest::Method::GET, &endpoint).await?;
-        let entry: EgnyteEntry = response
-            .json()
+    #[test]
+    fn test_config_pool_size() {
+        let config: Config = serde_json::from_str(r#"{"pool_size": "4"}"#).unwrap();
+        assert_eq!(config.pool_size, Some(4));
+        assert!(http_client(config.pool_size.unwrap(), USER_AGENT).is_ok());
+        // No pooling at all is allowed too
+        assert!(http_client(0, USER_AGENT).is_ok());
+    }
+
+    #[test]
+    fn test_secret_sources() {
+        let unavailable = || -> keyring::Result<String> {
+            Err(keyring::Error::PlatformFailure("no D-Bus session".into()))
+        };
+        let env = Some("from-env".to_string());
+        let secret = pick_secret("refresh_token", REFRESH_TOKEN_VAR, env, unavailable);
+        assert_eq!(secret.unwrap().as_deref(), Some("from-env"));
+        let secret = pick_secret("refresh_token", REFRESH_TOKEN_VAR, None, || {
+            Ok("stored".to_string())
+        });
+        assert_eq!(secret.unwrap().as_deref(), Some("stored"));
+
+        // No keyring and no variable: say which variable to set
+        let err = pick_secret(
+            "client_secret",
+            CLIENT_SECRET_VAR,
+            Some(String::new()),
+            unavailable,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains(CLIENT_SECRET_VAR));
+        let secret = pick_secret("client_secret", CLIENT_SECRET_VAR, None, || {
+            Err(keyring::Error::NoEntry)
+        });
+        assert_eq!(secret.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_client_from_environment_alone() {
+        use crate::fs::fuse_ops::EgnyteAPI;
+
+        let base_url = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 42\r\nConnection: close\r\n\r\n{\"access_token\":\"fresh\",\"expires_in\":3600}",
+            "HTTP/1.1 200 OK\r\nContent-Length: 53\r\nConnection: close\r\n\r\n{\"files\":[{\"name\":\"a.txt\",\"path\":\"/a.txt\",\"size\":1}]}",
+        ])
+        .await;
+        let vars = std::collections::HashMap::from([
+            ("EGNYTE_BASE_URL", base_url),
+            ("EGNYTE_CLIENT_ID", "id".to_string()),
+            ("EGNYTE_CLIENT_SECRET", "secret".to_string()),
+            ("EGNYTE_REFRESH_TOKEN", "refresh".to_string()),
+            ("EGNYTE_DOMAIN", String::new()),
+        ]);
+        let env = EnvSettings::from_vars(|name| vars.get(name).cloned());
+        assert_eq!(env.domain, None);
+
+        // No config.json, tokens.json or keyring needed
+        let empty = std::env::temp_dir().join(format!("egnyte-env-{}", std::process::id()));
+        let client = EgnyteAPIClient::from_sources(empty.join("config.json"), env)
             .await
-            .context("Failed to parse file info")?;
-
-        Ok(crate::fs::fuse_ops::EgnyteEntry {
-            name: entry.name,
-            path: entry.path,
-            is_folder: entry.is_folder,
-            size: entry.size,
-            modified_time: entry.modified_time,
+            .unwrap();
+        let entries = client.list_folder("/").await.unwrap();
+        assert_eq!(entries[0].name, "a.txt");
+        assert_eq!(
+            client.inner.read().await.access_token.as_deref(),
+            Some("fresh")
+        );
+        assert!(!empty.exists());
+
+        // Without a refresh token or access token, there is nothing to log in with
+        let env =
+            EnvSettings::from_vars(|name| (name == "EGNYTE_DOMAIN").then(|| "acme".to_string()));
+        assert!(
+            EgnyteAPIClient::from_sources(empty.join("config.json"), env)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_from_access_token_alone() {
+        use crate::fs::fuse_ops::EgnyteAPI;
+
+        let base_url = serve_answering(1, |request| {
+            assert!(request
+                .to_ascii_lowercase()
+                .contains("authorization: bearer given\r\n"));
+            b"HTTP/1.1 200 OK\r\nContent-Length: 53\r\nConnection: close\r\n\r\n{\"files\":[{\"name\":\"a.txt\",\"path\":\"/a.txt\",\"size\":1}]}".to_vec()
         })
+        .await;
+        let vars = std::collections::HashMap::from([
+            ("EGNYTE_BASE_URL", base_url),
+            ("EGNYTE_ACCESS_TOKEN", "given".to_string()),
+        ]);
+        let env = EnvSettings::from_vars(|name| vars.get(name).cloned());
+
+        // Neither a refresh token nor a keyring to look one up in
+        let empty = std::env::temp_dir().join(format!("egnyte-token-{}", std::process::id()));
+        let client = EgnyteAPIClient::from_sources(empty.join("config.json"), env)
+            .await
+            .unwrap();
+        let entries = client.list_folder("/").await.unwrap();
+        assert_eq!(entries[0].name, "a.txt");
+        assert!(!empty.exists());
     }
 
-    async fn download_file(&self, path: &str) -> Result<Vec<u8>> {
-        let endpoint = format!("/pubapi/v1/fs-content{}", path);
-        let response = self.request(reqwest::Method::GET, &endpoint).await?;
-        let bytes = response
-            .bytes()
+    #[test]
+    fn test_user_agent_and_request_ids() {
+        assert!(USER_AGENT.starts_with("egnyte-client-linux/"));
+        assert_eq!(user_agent(None), USER_AGENT);
+        assert_eq!(user_agent(Some(" ")), USER_AGENT);
+        let config: Config =
+            serde_json::from_str(r#"{"user_agent_suffix": "build-host-7"}"#).unwrap();
+        assert_eq!(
+            user_agent(config.user_agent_suffix.as_deref()),
+            format!("{} build-host-7", USER_AGENT)
+        );
+
+        let (a, b) = (request_id(), request_id());
+        assert_ne!(a, b);
+        assert_eq!(a.split('-').next(), b.split('-').next());
+        assert!(reqwest::header::HeaderValue::from_str(&a).is_ok());
+    }
+
+    #[test]
+    fn test_config_ttls() {
+        let config: Config =
+            serde_json::from_str(r#"{"entry_ttl": 600, "attr_ttl": "300", "negative_ttl": 0}"#)
+                .unwrap();
+        assert_eq!(config.entry_ttl, Some(600));
+        assert_eq!(config.attr_ttl, Some(300));
+        assert_eq!(config.negative_ttl, Some(0));
+    }
+
+    #[test]
+    fn test_config_verify_checksums() {
+        let config: Config = serde_json::from_str(r#"{"verify_checksums": "false"}"#).unwrap();
+        assert_eq!(config.verify_checksums, Some(false));
+        let config: Config = serde_json::from_str(r#"{"verify_checksums": true}"#).unwrap();
+        assert_eq!(config.verify_checksums, Some(true));
+        assert!(serde_json::from_str::<Config>(r#"{"verify_checksums": "maybe"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_chunked_upload_retries_failed_part() {
+        let sent = std::sync::Mutex::new(Vec::new());
+        let mut failures = 1;
+        let body = upload_in_chunks(b"0123456789", 4, Duration::ZERO, |chunk| {
+            sent.lock().unwrap().push((
+                chunk.number,
+                chunk.data.to_vec(),
+                chunk.upload_id.clone(),
+                chunk.last,
+            ));
+            let fail = chunk.number == 2 && failures > 0;
+            if fail {
+                failures -= 1;
+            }
+            async move {
+                if fail {
+                    return Err(anyhow::anyhow!("connection reset"));
+                }
+                Ok(ChunkAck {
+                    upload_id: Some("up-1".to_string()),
+                    body: serde_json::json!({"part": chunk.number}),
+                })
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(body, serde_json::json!({"part": 3}));
+        let sent = sent.lock().unwrap();
+        let numbers: Vec<_> = sent.iter().map(|(n, ..)| *n).collect();
+        assert_eq!(numbers, vec![1, 2, 2, 3]);
+        assert_eq!(sent[0].2, None);
+        assert!(sent[1..]
+            .iter()
+            .all(|(.., id, _)| id.as_deref() == Some("up-1")));
+        assert_eq!(sent[3].1, b"89");
+        assert!(sent.iter().all(|(n, .., last)| *last == (*n == 3)));
+    }
+
+    #[tokio::test]
+    async fn test_chunked_upload_stops_on_permanent_error() {
+        let mut calls = 0;
+        let result = upload_in_chunks(b"0123", 2, Duration::ZERO, |_| {
+            calls += 1;
+            async { Err::<ChunkAck, _>(ApiError::from_status(403, String::new()).into()) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_parse_events() {
+        let data = serde_json::json!({
+            "count": 3,
+            "latest_id": 1205,
+            "events": [
+                {"id": 1201, "action": "create", "data": {"target_path": "/Shared/a.txt"}},
+                {"id": 1202, "action": "move",
+                 "data": {"source_path": "/Shared/b.txt", "target_path": "/Shared/c.txt"}},
+                {"id": 1203, "action": "update", "data": {"target_path": "/Shared/a.txt"}}
+            ]
+        });
+        let batch = parse_events(&data, 1200);
+        assert_eq!(
+            batch.paths,
+            vec!["/Shared/a.txt", "/Shared/c.txt", "/Shared/b.txt"]
+        );
+        assert_eq!(batch.cursor, 1205);
+
+        let batch = parse_events(&serde_json::json!({}), 1200);
+        assert!(batch.paths.is_empty());
+        assert_eq!(batch.cursor, 1200);
+    }
+
+    #[test]
+    fn test_range_header() {
+        assert_eq!(range_header(0, 1), "bytes=0-0");
+        assert_eq!(range_header(1024, 4096), "bytes=1024-5119");
+        assert_eq!(
+            range_header(u64::MAX - 1, 10),
+            format!("bytes={}-{}", u64::MAX - 1, u64::MAX)
+        );
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = UNIX_EPOCH + Duration::from_secs(784111777); // 06 Nov 1994 08:49:37
+        assert_eq!(parse_retry_after("2", now), Some(Duration::from_secs(2)));
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:47 GMT", now),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            parse_http_date("Thu, 29 Feb 2024 00:00:00 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(1709164800))
+        );
+        assert_eq!(parse_retry_after("-1", now), None);
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 25:00:00 GMT", now),
+            None
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Foo 1994 08:49:37 GMT", now),
+            None
+        );
+    }
+
+    /// Answer a GET with 304 when its validators are the ones given out
+    /// with the 200 (`etag` and `Last-Modified` as sent), with `modified`
+    /// otherwise
+    fn answer_conditional(request: &str, modified: &str) -> Vec<u8> {
+        let unchanged = request.contains("\r\nif-none-match: \"v1\"\r\n")
+            && request.contains("\r\nif-modified-since: Sun, 06 Nov 1994 08:49:37 GMT\r\n");
+        if unchanged {
+            b"HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_vec()
+        } else {
+            modified.as_bytes().to_vec()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conditional_download() {
+        let base_url = serve_answering(3, |request| {
+            answer_conditional(
+                request,
+                "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nLast-Modified: Sun, 06 Nov 1994 08:49:37 GMT\r\nContent-Length: 3\r\nConnection: close\r\n\r\nnew",
+            )
+        })
+        .await;
+        let client = test_client(base_url);
+
+        // Nothing to quote: a plain GET, and the validators come back as sent
+        let (data, validators) = client
+            .download_if_changed("/Shared/a.txt", &Validators::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(data, b"new");
+        assert_eq!(validators.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(
+            validators.last_modified.as_deref(),
+            Some("Sun, 06 Nov 1994 08:49:37 GMT")
+        );
+        // Quoting them back gets a 304
+        let unchanged = client
+            .download_if_changed("/Shared/a.txt", &validators)
+            .await
+            .unwrap();
+        assert!(unchanged.is_none());
+        // Other validators get the content
+        let stale = Validators {
+            etag: Some("\"v0\"".to_string()),
+            last_modified: None,
+        };
+        let changed = client
+            .download_if_changed("/Shared/a.txt", &stale)
+            .await
+            .unwrap();
+        assert_eq!(changed.unwrap().0, b"new");
+    }
+
+    #[tokio::test]
+    async fn test_conditional_file_info() {
+        // The permission lookup after the 200 gets no permissions out of its
+        // answer and is left out
+        let base_url = serve_answering(3, |request| {
+            answer_conditional(
+                request,
+                "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nLast-Modified: Sun, 06 Nov 1994 08:49:37 GMT\r\nContent-Length: 81\r\nConnection: close\r\n\r\n{\"name\":\"a.txt\",\"path\":\"/Shared/a.txt\",\"isFolder\":false,\"size\":3,\"entry_id\":\"e2\"}",
+            )
+        })
+        .await;
+        let client = test_client(base_url);
+        let entry = client.get_file_info("/Shared/a.txt").await.unwrap();
+        assert_eq!(entry.validators.etag.as_deref(), Some("\"v1\""));
+
+        // The entry's validators are quoted, not its entry id
+        let unchanged = client
+            .file_info_if_changed("/Shared/a.txt", &entry)
+            .await
+            .unwrap();
+        assert!(unchanged.is_none());
+    }
+
+    #[test]
+    fn test_rate_limit_delay() {
+        let backoff = Duration::from_millis(500);
+        let now = SystemTime::now();
+        let header = reqwest::header::HeaderValue::from_static("2");
+        assert_eq!(
+            rate_limit_delay(Some(&header), backoff, now),
+            Duration::from_secs(2)
+        );
+        let garbled = reqwest::header::HeaderValue::from_static("later");
+        assert_eq!(rate_limit_delay(Some(&garbled), backoff, now), backoff);
+        assert_eq!(rate_limit_delay(None, backoff, now), backoff);
+    }
+
+    #[tokio::test]
+    async fn test_429_waits_for_retry_after() {
+        let base_url = serve(vec![
+            "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 2\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+        ])
+        .await;
+        let client = test_client(base_url);
+
+        let start = std::time::Instant::now();
+        let response = client
+            .request(reqwest::Method::GET, "/pubapi/v1/userinfo")
             .await
-            .context("Failed to read file content")?;
-        Ok(bytes.to_vec())
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+        // The 500ms default backoff would have been much shorter
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(2), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_429_retry_needs_budget() {
+        let base_url = serve(vec![
+            "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ])
+        .await;
+        let client = test_client(base_url);
+
+        // The operation has no retries left, so the 429 is final
+        let budget = budget::RetryBudget::new(Duration::from_secs(60), 0);
+        let err = budget
+            .scope(client.request(reqwest::Method::GET, "/pubapi/v1/userinfo"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ApiError::Status { status: 429, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retry_timeout_is_cut_to_what_the_wait_left() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // A 429 asking for a second's wait, then no answer at all
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf).await.unwrap();
+            let limited = "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\n\
+                Content-Length: 0\r\nConnection: close\r\n\r\n";
+            stream.write_all(limited.as_bytes()).await.unwrap();
+            let (_silent, _) = listener.accept().await.unwrap();
+            sleep(Duration::from_secs(30)).await;
+        });
+        let mut client = test_client(base_url);
+        client.metadata_timeout = Duration::from_secs(10);
+
+        let start = std::time::Instant::now();
+        let budget = budget::RetryBudget::new(Duration::from_secs(2), 5);
+        let err = budget
+            .scope(client.request(reqwest::Method::GET, "/pubapi/v1/userinfo"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(ApiError::Timeout(_))));
+        // The retry gets the second left, not the two there were at first
+        let elapsed = start.elapsed();
+        assert!(elapsed < Duration::from_millis(2500), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_download_reports_progress() {
+        let base_url = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello world",
+        ])
+        .await;
+        let client = test_client(base_url);
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let data = client
+            .download_with_progress("/Shared/a.txt", Some(&sender))
+            .await
+            .unwrap();
+        assert_eq!(data, b"hello world");
+        drop(sender);
+        let mut last = None;
+        while let Some(progress) = receiver.recv().await {
+            last = Some(progress);
+        }
+        assert_eq!(
+            last,
+            Some(Progress {
+                transferred: 11,
+                total: Some(11)
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_progress_covers_download_file() {
+        use crate::fs::fuse_ops::EgnyteAPI;
+
+        let base_url = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+        ])
+        .await;
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let client = test_client(base_url).with_progress(sender);
+
+        assert_eq!(
+            client.download_file("/Shared/a.txt").await.unwrap(),
+            b"hello"
+        );
+        drop(client);
+        let mut last = None;
+        while let Some(progress) = receiver.recv().await {
+            last = Some(progress.transferred);
+        }
+        assert_eq!(last, Some(5));
+    }
+
+    #[tokio::test]
+    async fn test_upload_streams_and_reports_progress() {
+        let base_url = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 17\r\nConnection: close\r\n\r\n{\"entry_id\":\"e1\"}",
+        ])
+        .await;
+        let mut client = test_client(base_url);
+        client.verify_checksums = false;
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        // The server only answers once the whole body has arrived
+        let data = vec![7; 3 * UPLOAD_STREAM_CHUNK + 100];
+        let entry_id = client
+            .upload_with_progress("/Shared/big.bin", &data, Some(&sender))
+            .await
+            .unwrap();
+        assert_eq!(entry_id.as_deref(), Some("e1"));
+        drop(sender);
+        let mut reported = Vec::new();
+        while let Some(progress) = receiver.recv().await {
+            reported.push(progress.transferred);
+            assert_eq!(progress.total, Some(data.len() as u64));
+        }
+        assert_eq!(reported, vec![65536, 131072, 196608, data.len() as u64]);
+    }
+
+    #[tokio::test]
+    async fn test_download_encodings_and_truncation() {
+        use crate::fs::fuse_ops::EgnyteAPI;
+
+        // gzip of "hello, compressed world\n" four times
+        const GZIPPED: [u8; 47] = [
+            31, 139, 8, 0, 0, 0, 0, 0, 2, 3, 203, 72, 205, 201, 201, 215, 81, 72, 206, 207, 45, 40,
+            74, 45, 46, 78, 77, 81, 40, 207, 47, 202, 73, 225, 202, 160, 146, 56, 0, 67, 191, 74,
+            136, 96, 0, 0, 0,
+        ];
+        let mut gzipped = format!(
+            "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            GZIPPED.len()
+        )
+        .into_bytes();
+        gzipped.extend_from_slice(&GZIPPED);
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let short = b"HTTP/1.1 200 OK\r\nContent-Length: 20\r\nConnection: close\r\n\r\nonly ten b";
+        let base_url = serve_bytes(vec![gzipped, chunked.to_vec(), short.to_vec()]).await;
+        let client = test_client(base_url);
+
+        let data = client.download_file("/Shared/a.txt").await.unwrap();
+        assert_eq!(data, b"hello, compressed world\n".repeat(4));
+        let data = client.download_file("/Shared/b.txt").await.unwrap();
+        assert_eq!(data, b"hello world");
+        // The connection closes ten bytes into twenty
+        let err = client.download_file("/Shared/c.txt").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ApiError::Truncated {
+                expected: Some(20),
+                received: 10,
+                ..
+            })
+        ));
+        assert_eq!(crate::fs::error::errno(&err), libc::EIO);
+        assert!(crate::fs::error::is_transient(&err));
+
+        assert!(check_length("/a", Some(3), 3).is_ok());
+        assert!(check_length("/a", None, 3).is_ok());
+        assert!(check_length("/a", Some(20), 10).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_write_test() {
+        use crate::fs::testutil::ScriptedMockAPI;
+
+        let api = ScriptedMockAPI::new().with_folder("/Shared/tmp");
+        let checks = write_test(&api, "/Shared/tmp/").await;
+        let names: Vec<&str> = checks.iter().map(|check| check.name).collect();
+        assert_eq!(names, WRITE_TEST_STEPS);
+        assert!(checks.iter().all(Check::passed), "{:?}", checks);
+        assert_eq!(api.calls("delete_entry"), 1);
+        assert_eq!(api.list_folder("/Shared/tmp").await.unwrap().len(), 0);
+
+        // Nothing to read back or clean up when the upload fails
+        api.set_offline(true);
+        let checks = write_test(&api, "/Shared/tmp").await;
+        assert!(!checks[0].passed());
+        assert_eq!(
+            checks[2].result,
+            Err("skipped, fix the failures above first".to_string())
+        );
+        assert_eq!(api.calls("download_file"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_a_dropped_connection() {
+        use crate::fs::fuse_ops::EgnyteAPI;
+
+        // The first connection closes without answering, as one the server
+        // dropped while idle; the retry gets a new one
+        let base_url = serve(vec![
+            "",
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+        ])
+        .await;
+        let client = test_client(base_url);
+        let start = std::time::Instant::now();
+        assert_eq!(
+            client.download_file("/Shared/a.txt").await.unwrap(),
+            b"hello"
+        );
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // Twice in a row is an error
+        let base_url = serve(vec!["", ""]).await;
+        let client = test_client(base_url);
+        assert!(client.download_file("/Shared/a.txt").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upload_over_quota() {
+        use crate::fs::fuse_ops::EgnyteAPI;
+
+        let base_url = serve(vec![
+            "HTTP/1.1 403 Forbidden\r\nContent-Length: 78\r\nConnection: close\r\n\r\n{\"errorCode\":\"STORAGE_QUOTA_EXCEEDED\",\"errorMessage\":\"Storage quota exceeded\"}",
+            "HTTP/1.1 507 Insufficient Storage\r\nContent-Length: 20\r\nConnection: close\r\n\r\nInsufficient Storage",
+        ])
+        .await;
+        let mut client = test_client(base_url);
+        client.verify_checksums = false;
+
+        // Either answer tells applications the disk is full
+        for _ in 0..2 {
+            let err = client
+                .upload_file("/Shared/a.bin", b"data")
+                .await
+                .unwrap_err();
+            assert_eq!(crate::fs::error::errno(&err), libc::ENOSPC);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metadata_request_times_out() {
+        use crate::fs::fuse_ops::EgnyteAPI;
+
+        // Accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        let mut client = test_client(base_url);
+        client.metadata_timeout = Duration::from_millis(200);
+
+        let start = std::time::Instant::now();
+        let err = client.get_file_info("/Shared/a.txt").await.unwrap_err();
+        assert_eq!(crate::fs::error::errno(&err), libc::EAGAIN);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        assert!(is_content_endpoint("/pubapi/v1/fs-content/Shared/a.txt"));
+        assert!(is_content_endpoint("/pubapi/v1/fs-content-chunked/a.bin"));
+        assert!(!is_content_endpoint("/pubapi/v1/fs/Shared"));
+    }
+
+    /// Answer one request on a local port with `head`, then `pieces` of
+    /// the body `gap` apart, then hold the connection open without a word
+    async fn serve_slowly(head: &'static str, pieces: Vec<&'static str>, gap: Duration) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4096];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream.write_all(head.as_bytes()).await.unwrap();
+            for piece in pieces {
+                sleep(gap).await;
+                stream.write_all(piece.as_bytes()).await.unwrap();
+            }
+            sleep(Duration::from_secs(30)).await;
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_content_transfers_only_time_out_when_stalled() {
+        use crate::fs::fuse_ops::EgnyteAPI;
+
+        let head = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\n";
+        // A body trickling in for well past the metadata deadline
+        let base_url = serve_slowly(
+            head,
+            vec!["h", "e", "l", "l", "o"],
+            Duration::from_millis(150),
+        )
+        .await;
+        let mut client = test_client(base_url);
+        client.metadata_timeout = Duration::from_millis(200);
+        client.content_idle_timeout = Duration::from_millis(500);
+        let data = client.download_file("/Shared/a.txt").await.unwrap();
+        assert_eq!(data, b"hello");
+
+        // One that stops halfway
+        let base_url = serve_slowly(head, vec!["he"], Duration::ZERO).await;
+        let mut client = test_client(base_url);
+        client.content_idle_timeout = Duration::from_millis(300);
+        let start = std::time::Instant::now();
+        let err = client.download_file("/Shared/a.txt").await.unwrap_err();
+        assert_eq!(crate::fs::error::errno(&err), libc::EAGAIN);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_config_max_backoff() {
+        let config: Config =
+            serde_json::from_str(r#"{"max_backoff": "5", "metadata_timeout": 3}"#).unwrap();
+        assert_eq!(config.max_backoff, Some(5));
+        assert_eq!(config.metadata_timeout, Some(3));
+    }
+
+    #[test]
+    fn test_encode_path_round_trip() {
+        let path = "/My Folder/report #2.pdf";
+        let encoded = encode_path(path);
+        let decoded = percent_decode_str(&encoded).decode_utf8().unwrap();
+        assert_eq!(decoded, path);
     }
 }
@@ -0,0 +1,118 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Default time one FUSE operation may spend on the API, retries included
+pub const DEFAULT_OP_DEADLINE: Duration = Duration::from_secs(30);
+
+/// Default retries one FUSE operation may make across all its requests
+pub const DEFAULT_OP_RETRIES: u32 = 5;
+
+tokio::task_local! {
+    static CURRENT: Arc<RetryBudget>;
+}
+
+/// Bounds the work of one FUSE operation however many requests it makes: a
+/// deadline, and a number of retries shared by all of them.
+///
+/// The filesystem runs each operation's API calls inside [`Self::scope`];
+/// the client finds the budget with [`Self::current`], so it needs no
+/// parameter on every API method. Calls outside a scope (background tasks,
+/// CLI commands) are not bounded.
+#[derive(Debug)]
+pub struct RetryBudget {
+    deadline: Instant,
+    retries: AtomicU32,
+}
+
+impl RetryBudget {
+    /// Budget of `retries` retries, ending `deadline` from now
+    pub fn new(deadline: Duration, retries: u32) -> Arc<Self> {
+        Arc::new(Self {
+            deadline: Instant::now() + deadline,
+            retries: AtomicU32::new(retries),
+        })
+    }
+
+    /// Run `future` with this as the current budget
+    pub async fn scope<F: Future>(self: Arc<Self>, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+
+    /// Budget of the operation the calling task works for, if any
+    pub fn current() -> Option<Arc<Self>> {
+        CURRENT.try_with(Arc::clone).ok()
+    }
+
+    /// Time left before the deadline
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    /// Take a retry that would start after `delay`, if one is left and it
+    /// would start before the deadline
+    pub fn take_retry(&self, delay: Duration) -> bool {
+        if delay >= self.remaining() {
+            return false;
+        }
+        self.retries
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok()
+    }
+}
+
+/// Whether the current operation may retry after `delay`, taking the retry
+/// from its budget; always true outside an operation
+pub fn may_retry(delay: Duration) -> bool {
+    RetryBudget::current().is_none_or(|budget| budget.take_retry(delay))
+}
+
+/// How long the next request may take: `timeout`, cut to what is left of
+/// the current operation's deadline
+pub fn request_timeout(timeout: Duration) -> Duration {
+    RetryBudget::current().map_or(timeout, |budget| timeout.min(budget.remaining()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_retries_are_shared_within_a_scope() {
+        assert!(may_retry(Duration::ZERO));
+        let budget = RetryBudget::new(Duration::from_secs(60), 2);
+        let taken = budget
+            .clone()
+            .scope(async {
+                // However many requests ask, only two retries are granted
+                (0..4).filter(|_| may_retry(Duration::ZERO)).count()
+            })
+            .await;
+        assert_eq!(taken, 2);
+        assert_eq!(budget.retries.load(Ordering::SeqCst), 0);
+        // Outside the scope nothing is bounded
+        assert!(may_retry(Duration::from_secs(3600)));
+    }
+
+    #[tokio::test]
+    async fn test_deadline_caps_delays_and_timeouts() {
+        let budget = RetryBudget::new(Duration::from_secs(5), 10);
+        budget
+            .scope(async {
+                assert!(!may_retry(Duration::from_secs(10)));
+                assert!(request_timeout(Duration::from_secs(60)) <= Duration::from_secs(5));
+                assert_eq!(
+                    request_timeout(Duration::from_secs(1)),
+                    Duration::from_secs(1)
+                );
+            })
+            .await;
+        assert_eq!(
+            request_timeout(Duration::from_secs(60)),
+            Duration::from_secs(60)
+        );
+    }
+}
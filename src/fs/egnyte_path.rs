@@ -0,0 +1,91 @@
+use std::path::{Path, PathBuf};
+
+/// Normalize an Egnyte path: always absolute, no duplicate or trailing
+/// slashes, and "/" for the root
+pub fn normalize(path: &str) -> String {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    format!("/{}", segments.join("/"))
+}
+
+/// Convert a path in the mount to the Egnyte path it stands for
+pub fn from_fs_path(path: &Path) -> String {
+    normalize(&path.to_string_lossy())
+}
+
+/// Convert a path in a mount of the Egnyte folder `root` to the Egnyte path
+/// it stands for
+pub fn under_root(root: &str, path: &Path) -> String {
+    normalize(&format!("{}/{}", root, path.to_string_lossy()))
+}
+
+/// Convert an Egnyte path to its path in a mount of `root`, or None if it is
+/// outside the mounted folder
+pub fn to_fs_path(root: &str, path: &str) -> Option<PathBuf> {
+    let root = normalize(root);
+    let path = normalize(path);
+    if root == "/" {
+        return Some(PathBuf::from(path));
+    }
+    if path == root {
+        return Some(PathBuf::from("/"));
+    }
+    path.strip_prefix(&root)
+        .filter(|rest| rest.starts_with('/'))
+        .map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("/"), "/");
+        assert_eq!(normalize(""), "/");
+        assert_eq!(normalize("//"), "/");
+        assert_eq!(normalize("/a"), "/a");
+        assert_eq!(normalize("/a/"), "/a");
+        assert_eq!(normalize("/a//b"), "/a/b");
+        assert_eq!(normalize("a/b/"), "/a/b");
+        assert_eq!(normalize("/Shared/My Docs/"), "/Shared/My Docs");
+    }
+
+    #[test]
+    fn test_under_root() {
+        assert_eq!(under_root("/", Path::new("/")), "/");
+        assert_eq!(
+            under_root("/Shared/Projects", Path::new("/")),
+            "/Shared/Projects"
+        );
+        assert_eq!(
+            under_root("/Shared/Projects/", Path::new("/a/b.txt")),
+            "/Shared/Projects/a/b.txt"
+        );
+    }
+
+    #[test]
+    fn test_to_fs_path() {
+        assert_eq!(
+            to_fs_path("/", "/Shared/a"),
+            Some(PathBuf::from("/Shared/a"))
+        );
+        let root = "/Shared/Projects";
+        assert_eq!(
+            to_fs_path(root, "/Shared/Projects"),
+            Some(PathBuf::from("/"))
+        );
+        assert_eq!(
+            to_fs_path(root, "/Shared/Projects/a/"),
+            Some(PathBuf::from("/a"))
+        );
+        assert_eq!(to_fs_path(root, "/Shared/ProjectsOld/a"), None);
+        assert_eq!(to_fs_path(root, "/Private/a"), None);
+    }
+
+    #[test]
+    fn test_from_fs_path() {
+        assert_eq!(from_fs_path(Path::new("/")), "/");
+        assert_eq!(from_fs_path(&Path::new("/").join("a")), "/a");
+        assert_eq!(from_fs_path(Path::new("/a/b/")), "/a/b");
+    }
+}
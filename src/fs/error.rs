@@ -0,0 +1,270 @@
+use std::fmt;
+
+/// Errors from the Egnyte API that callers need to tell apart.
+///
+/// They travel inside `anyhow::Error`; use [`errno`] to map one to the
+/// errno a FUSE reply or CLI exit should carry.
+#[derive(Debug, Clone)]
+pub enum ApiError {
+    /// The user's Egnyte permission does not allow this (HTTP 403)
+    Forbidden(String),
+    /// The path does not exist (HTTP 404)
+    NotFound(String),
+    /// The destination already exists (HTTP 409)
+    AlreadyExists(String),
+    /// The checksum Egnyte computed for an upload differs from ours
+    ChecksumMismatch {
+        path: String,
+        local: String,
+        remote: String,
+    },
+    /// A download stopped before all of the file arrived (`expected` is
+    /// the announced length, if any)
+    Truncated {
+        path: String,
+        expected: Option<u64>,
+        received: u64,
+    },
+    /// The file changed on the server since it was opened
+    Conflict {
+        path: String,
+        opened: String,
+        remote: String,
+    },
+    /// The version of a file asked for is no longer on the server
+    VersionGone { path: String, version: String },
+    /// No response arrived before the request's deadline
+    Timeout(String),
+    /// The mount is offline and this needs the service
+    Offline(String),
+    /// Egnyte is down (maintenance) and this is not cached; try again later
+    Unavailable(String),
+    /// An upload would take the domain over its storage quota (HTTP 507, or
+    /// 403 with a quota error code)
+    QuotaExceeded { code: Option<String>, body: String },
+    /// Any other unsuccessful response
+    Status { status: u16, body: String },
+}
+
+/// Egnyte's error code in an error response body: `errorCode` or `code`,
+/// at the top level or in the first of `errors`/`formErrors`
+pub fn error_code(body: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(body).ok()?;
+    let code_of = |v: &serde_json::Value| {
+        ["errorCode", "code"]
+            .iter()
+            .find_map(|key| v.get(key)?.as_str().map(str::to_string))
+    };
+    code_of(&value).or_else(|| {
+        ["errors", "formErrors"]
+            .iter()
+            .find_map(|key| code_of(value.get(key)?.get(0)?))
+    })
+}
+
+/// Whether an Egnyte error code says the storage quota is used up
+fn is_quota_code(code: &str) -> bool {
+    code.to_ascii_lowercase().contains("quota")
+}
+
+impl ApiError {
+    /// Classify an unsuccessful HTTP response
+    pub fn from_status(status: u16, body: String) -> Self {
+        let code = error_code(&body);
+        match status {
+            507 => ApiError::QuotaExceeded { code, body },
+            403 if code.as_deref().is_some_and(is_quota_code) => {
+                ApiError::QuotaExceeded { code, body }
+            }
+            403 => ApiError::Forbidden(body),
+            404 => ApiError::NotFound(body),
+            409 => ApiError::AlreadyExists(body),
+            _ => ApiError::Status { status, body },
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Forbidden(body) => write!(f, "API request failed: 403 Forbidden {}", body),
+            ApiError::NotFound(body) => write!(f, "API request failed: 404 Not Found {}", body),
+            ApiError::AlreadyExists(body) => {
+                write!(
+                    f,
+                    "API request failed: 409 Conflict (already exists) {}",
+                    body
+                )
+            }
+            ApiError::ChecksumMismatch {
+                path,
+                local,
+                remote,
+            } => write!(
+                f,
+                "Checksum mismatch after uploading {}: sent {}, server has {}",
+                path, local, remote
+            ),
+            ApiError::Truncated {
+                path,
+                expected: Some(expected),
+                received,
+            } => write!(
+                f,
+                "Download of {} was cut short: got {} of {} bytes",
+                path, received, expected
+            ),
+            ApiError::Truncated {
+                path,
+                expected: None,
+                received,
+            } => write!(
+                f,
+                "Download of {} was cut short after {} bytes",
+                path, received
+            ),
+            ApiError::Conflict {
+                path,
+                opened,
+                remote,
+            } => write!(
+                f,
+                "{} changed on the server (opened version {}, now {})",
+                path, opened, remote
+            ),
+            ApiError::VersionGone { path, version } => {
+                write!(
+                    f,
+                    "Version {} of {} is no longer on the server",
+                    version, path
+                )
+            }
+            ApiError::Timeout(endpoint) => write!(f, "API request timed out: {}", endpoint),
+            ApiError::Offline(what) => write!(f, "Not available offline: {}", what),
+            ApiError::Unavailable(what) => {
+                write!(f, "Egnyte is unavailable, try again later: {}", what)
+            }
+            ApiError::QuotaExceeded { code, body } => match code {
+                Some(code) => write!(f, "Egnyte storage quota exceeded ({}): {}", code, body),
+                None => write!(f, "Egnyte storage quota exceeded: {}", body),
+            },
+            ApiError::Status { status, body } => {
+                write!(f, "API request failed: {} {}", status, body)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// errno for an API error: EACCES, ENOENT, EEXIST, EAGAIN for a timeout or
+/// while Egnyte is unavailable, ENOSPC over quota, or EIO for anything else
+/// (including a checksum mismatch, a truncated download, a write conflict or
+/// a version that is gone)
+pub fn errno(err: &anyhow::Error) -> libc::c_int {
+    match err.downcast_ref::<ApiError>() {
+        Some(ApiError::Forbidden(_)) => libc::EACCES,
+        Some(ApiError::NotFound(_)) => libc::ENOENT,
+        Some(ApiError::AlreadyExists(_)) => libc::EEXIST,
+        Some(ApiError::Timeout(_) | ApiError::Unavailable(_)) => libc::EAGAIN,
+        Some(ApiError::QuotaExceeded { .. }) => libc::ENOSPC,
+        _ => libc::EIO,
+    }
+}
+
+/// Whether retrying might help: network errors, timeouts, truncated
+/// downloads and server-side (5xx) failures
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<ApiError>() {
+        Some(ApiError::Status { status, .. }) => *status >= 500,
+        Some(ApiError::Timeout(_) | ApiError::Unavailable(_) | ApiError::Truncated { .. }) => true,
+        Some(_) => false,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_errno_mapping() {
+        let exists = anyhow::Error::new(ApiError::from_status(409, String::new()));
+        assert_eq!(errno(&exists), libc::EEXIST);
+        let missing = anyhow::Error::new(ApiError::from_status(404, String::new()));
+        assert_eq!(errno(&missing), libc::ENOENT);
+        let denied = anyhow::Error::new(ApiError::from_status(403, String::new()));
+        assert_eq!(errno(&denied), libc::EACCES);
+        let server = anyhow::Error::new(ApiError::from_status(500, String::new()));
+        assert_eq!(errno(&server), libc::EIO);
+        let slow = anyhow::Error::new(ApiError::Timeout("/pubapi/v1/fs/".to_string()));
+        assert_eq!(errno(&slow), libc::EAGAIN);
+        assert!(is_transient(&slow));
+        let offline = anyhow::Error::new(ApiError::Offline("/Shared/a.txt".to_string()));
+        assert_eq!(errno(&offline), libc::EIO);
+        assert!(!is_transient(&offline));
+        let unavailable = anyhow::Error::new(ApiError::Unavailable("/Shared/a.txt".to_string()));
+        assert_eq!(errno(&unavailable), libc::EAGAIN);
+        assert!(is_transient(&unavailable));
+        let short = anyhow::Error::new(ApiError::Truncated {
+            path: "/Shared/a.txt".to_string(),
+            expected: Some(20),
+            received: 10,
+        });
+        assert_eq!(errno(&short), libc::EIO);
+        assert!(is_transient(&short));
+        assert_eq!(errno(&anyhow::anyhow!("network down")), libc::EIO);
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&anyhow::anyhow!("connection reset")));
+        assert!(is_transient(
+            &ApiError::from_status(502, String::new()).into()
+        ));
+        assert!(!is_transient(
+            &ApiError::from_status(404, String::new()).into()
+        ));
+        assert!(!is_transient(
+            &ApiError::from_status(400, String::new()).into()
+        ));
+    }
+
+    #[test]
+    fn test_quota_exceeded() {
+        let full = ApiError::from_status(507, "Insufficient Storage".to_string());
+        assert!(matches!(full, ApiError::QuotaExceeded { code: None, .. }));
+        let over = ApiError::from_status(
+            403,
+            r#"{"errorCode":"STORAGE_QUOTA_EXCEEDED","errorMessage":"Over quota"}"#.to_string(),
+        );
+        let ApiError::QuotaExceeded { code, .. } = &over else {
+            panic!("not a quota error: {:?}", over);
+        };
+        assert_eq!(code.as_deref(), Some("STORAGE_QUOTA_EXCEEDED"));
+        assert_eq!(errno(&over.into()), libc::ENOSPC);
+        assert!(!is_transient(
+            &ApiError::from_status(507, String::new()).into()
+        ));
+
+        // Other 403s are still permission errors
+        let denied = ApiError::from_status(403, r#"{"errorCode":"ACCESS_DENIED"}"#.to_string());
+        assert_eq!(errno(&denied.into()), libc::EACCES);
+        assert_eq!(
+            error_code(r#"{"formErrors":[{"code":"quota_reached","msg":"x"}]}"#).as_deref(),
+            Some("quota_reached")
+        );
+        assert_eq!(error_code("<h1>Developer Over Qps</h1>"), None);
+    }
+
+    #[test]
+    fn test_errno_through_context() {
+        let err: anyhow::Result<()> = Err(anyhow::Error::new(ApiError::from_status(
+            409,
+            String::new(),
+        )));
+        let err = err.context("Failed to copy").unwrap_err();
+        assert_eq!(errno(&err), libc::EEXIST);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::fs::error::ApiError;
+use crate::fs::fuse_ops::{EgnyteAPI, EgnyteEntry, EventBatch, Validators};
+use anyhow::Result;
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Default time a path's last hard failure is answered without asking again
+pub const DEFAULT_ERROR_TTL: Duration = Duration::from_secs(5);
+
+/// Whether an error says something about the path that asking again right
+/// away will not change: forbidden or not found. Rate limiting, server
+/// errors, timeouts and the like are worth retrying and never cached.
+fn is_cacheable(err: &ApiError) -> bool {
+    matches!(err, ApiError::Forbidden(_) | ApiError::NotFound(_))
+}
+
+/// Whether `a` is `b`, or one is inside the other
+fn related(a: &str, b: &str) -> bool {
+    let within = |inner: &str, outer: &str| {
+        inner
+            .strip_prefix(outer.trim_end_matches('/'))
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    };
+    within(a, b) || within(b, a)
+}
+
+/// An [`EgnyteAPI`] that remembers hard failures of reads (403, 404) by path
+/// for `ttl`, answering further reads of that path with the same error
+/// without a request, so a folder that keeps being listed does not flood
+/// Egnyte and the log.
+///
+/// A successful change to a path forgets the failures of it, of what is
+/// inside it and of the folders it is in, since those may now exist.
+pub struct ErrorCachingAPI {
+    inner: Arc<dyn EgnyteAPI>,
+    ttl: Duration,
+    failures: DashMap<String, (ApiError, Instant)>,
+}
+
+impl ErrorCachingAPI {
+    pub fn new(inner: Arc<dyn EgnyteAPI>, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            failures: DashMap::new(),
+        }
+    }
+
+    /// The failure of `path` still remembered, if any
+    fn cached(&self, path: &str) -> Option<anyhow::Error> {
+        let failure = self.failures.get(path)?;
+        let (err, seen) = failure.value();
+        if seen.elapsed() >= self.ttl {
+            drop(failure);
+            self.failures.remove(path);
+            return None;
+        }
+        debug!("Answering {} with the recent failure: {}", path, err);
+        Some(err.clone().into())
+    }
+
+    /// A read of `path`, answered from a recent failure if there is one
+    async fn read<T>(
+        &self,
+        path: &str,
+        call: impl std::future::Future<Output = Result<T>>,
+    ) -> Result<T> {
+        if let Some(err) = self.cached(path) {
+            return Err(err);
+        }
+        let result = call.await;
+        if let Some(err) = result
+            .as_ref()
+            .err()
+            .and_then(|e| e.downcast_ref::<ApiError>())
+            .filter(|err| is_cacheable(err))
+        {
+            self.failures
+                .insert(path.to_string(), (err.clone(), Instant::now()));
+        }
+        result
+    }
+
+    /// A change to `paths`: on success their failures may no longer hold
+    fn changed<T>(&self, result: Result<T>, paths: &[&str]) -> Result<T> {
+        if result.is_ok() && !self.failures.is_empty() {
+            self.failures
+                .retain(|failed, _| !paths.iter().any(|path| related(failed, path)));
+        }
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl EgnyteAPI for ErrorCachingAPI {
+    async fn list_folder(&self, path: &str) -> Result<Vec<EgnyteEntry>> {
+        self.read(path, self.inner.list_folder(path)).await
+    }
+
+    async fn get_file_info(&self, path: &str) -> Result<EgnyteEntry> {
+        self.read(path, self.inner.get_file_info(path)).await
+    }
+
+    async fn download_file(&self, path: &str) -> Result<Vec<u8>> {
+        self.read(path, self.inner.download_file(path)).await
+    }
+
+    async fn download_range(
+        &self,
+        path: &str,
+        version: Option<&str>,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>> {
+        self.read(path, self.inner.download_range(path, version, offset, len))
+            .await
+    }
+
+    async fn file_info_if_changed(
+        &self,
+        path: &str,
+        known: &EgnyteEntry,
+    ) -> Result<Option<EgnyteEntry>> {
+        self.read(path, self.inner.file_info_if_changed(path, known))
+            .await
+    }
+
+    async fn download_if_changed(
+        &self,
+        path: &str,
+        known: &Validators,
+    ) -> Result<Option<(Vec<u8>, Validators)>> {
+        self.read(path, self.inner.download_if_changed(path, known))
+            .await
+    }
+
+    async fn copy_entry(&self, from: &str, to: &str) -> Result<()> {
+        let result = self.inner.copy_entry(from, to).await;
+        self.changed(result, &[to])
+    }
+
+    async fn delete_entry(&self, path: &str) -> Result<()> {
+        let result = self.inner.delete_entry(path).await;
+        self.changed(result, &[path])
+    }
+
+    async fn move_entry(&self, from: &str, to: &str) -> Result<()> {
+        let result = self.inner.move_entry(from, to).await;
+        self.changed(result, &[from, to])
+    }
+
+    async fn upload_file(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+        let result = self.inner.upload_file(path, data).await;
+        self.changed(result, &[path])
+    }
+
+    async fn upload_chunked(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+        let result = self.inner.upload_chunked(path, data).await;
+        self.changed(result, &[path])
+    }
+
+    async fn latest_event_cursor(&self) -> Result<u64> {
+        self.inner.latest_event_cursor().await
+    }
+
+    async fn events_since(&self, cursor: u64) -> Result<EventBatch> {
+        self.inner.events_since(cursor).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::error;
+    use crate::fs::testutil::ScriptedMockAPI;
+
+    #[tokio::test]
+    async fn test_hard_failures_are_remembered() {
+        let egnyte = Arc::new(ScriptedMockAPI::new().with_folder("/Private"));
+        egnyte.fail_reads("/Private", Some(403));
+        let api = ErrorCachingAPI::new(egnyte.clone(), Duration::from_secs(60));
+
+        for _ in 0..3 {
+            let err = api.list_folder("/Private").await.unwrap_err();
+            assert_eq!(error::errno(&err), libc::EACCES);
+        }
+        assert_eq!(egnyte.calls("list_folder"), 1);
+        // Same path, other read: still forbidden
+        assert!(api.get_file_info("/Private").await.is_err());
+        assert_eq!(egnyte.calls("get_file_info"), 0);
+
+        for _ in 0..3 {
+            let err = api.get_file_info("/Private/a.txt").await.unwrap_err();
+            assert_eq!(error::errno(&err), libc::ENOENT);
+        }
+        assert_eq!(egnyte.calls("get_file_info"), 1);
+
+        // Creating the file forgets it was missing, and that its folder was
+        // forbidden
+        api.upload_file("/Private/a.txt", b"a").await.unwrap();
+        egnyte.fail_reads("/Private", None);
+        assert_eq!(api.get_file_info("/Private/a.txt").await.unwrap().size, 1);
+        assert_eq!(api.list_folder("/Private").await.unwrap().len(), 1);
+
+        // Once the TTL is over, Egnyte is asked again
+        let api = ErrorCachingAPI::new(egnyte.clone(), Duration::ZERO);
+        let calls = egnyte.calls("get_file_info");
+        assert!(api.get_file_info("/missing").await.is_err());
+        assert!(api.get_file_info("/missing").await.is_err());
+        assert_eq!(egnyte.calls("get_file_info"), calls + 2);
+    }
+
+    #[tokio::test]
+    async fn test_transient_failures_are_not_remembered() {
+        let egnyte = Arc::new(ScriptedMockAPI::new().with_file("/a.txt", b"a"));
+        let api = ErrorCachingAPI::new(egnyte.clone(), Duration::from_secs(60));
+        for status in [429, 500, 503] {
+            egnyte.fail_reads("/a.txt", Some(status));
+            assert!(api.download_file("/a.txt").await.is_err());
+            assert!(api.download_file("/a.txt").await.is_err());
+        }
+        assert_eq!(egnyte.calls("download_file"), 6);
+
+        egnyte.fail_reads("/a.txt", None);
+        assert_eq!(api.download_file("/a.txt").await.unwrap(), b"a");
+    }
+
+    #[test]
+    fn test_related() {
+        assert!(related("/a/b", "/a"));
+        assert!(related("/a", "/a/b"));
+        assert!(related("/a", "/a"));
+        assert!(related("/a/b", "/"));
+        assert!(!related("/ab", "/a"));
+    }
+}
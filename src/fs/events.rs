@@ -0,0 +1,190 @@
+use crate::fs::egnyte_path;
+use crate::fs::fuse_ops::EgnyteAPI;
+use crate::fs::notify::Invalidator;
+use anyhow::Result;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Polls the Egnyte events API and invalidates kernel caches for paths that
+/// changed remotely, so the mount picks up other users' edits
+pub struct ChangePoller {
+    api_client: Arc<dyn EgnyteAPI>,
+    invalidator: Invalidator,
+    /// Egnyte folder mounted as `/`; events outside it are ignored
+    root: String,
+    interval: Duration,
+}
+
+impl ChangePoller {
+    pub fn new(
+        api_client: Arc<dyn EgnyteAPI>,
+        invalidator: Invalidator,
+        root: &str,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            api_client,
+            invalidator,
+            root: egnyte_path::normalize(root),
+            interval,
+        }
+    }
+
+    /// Poll forever. Starts from the latest event at the time of the first
+    /// poll; errors are logged and retried on the next tick.
+    pub async fn run(self) {
+        let mut cursor = None;
+        loop {
+            cursor = match cursor {
+                None => match self.api_client.latest_event_cursor().await {
+                    Ok(latest) => Some(latest),
+                    Err(e) => {
+                        warn!("Could not get events cursor: {}", e);
+                        None
+                    }
+                },
+                Some(current) => match self.poll_once(current).await {
+                    Ok(next) => Some(next),
+                    Err(e) => {
+                        warn!("Polling events failed: {}", e);
+                        Some(current)
+                    }
+                },
+            };
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+
+    /// Fetch events after `cursor`, invalidate the paths they touched, and
+    /// return the cursor to continue from
+    pub async fn poll_once(&self, cursor: u64) -> Result<u64> {
+        let batch = self.api_client.events_since(cursor).await?;
+        for path in &batch.paths {
+            if let Some(fs_path) = egnyte_path::to_fs_path(&self.root, path) {
+                debug!("Remote change: {}", path);
+                self.invalidator.invalidate_path(&fs_path);
+            }
+        }
+        Ok(batch.cursor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::fuse_ops::{EgnyteEntry, EventBatch};
+    use crate::fs::inode_table::InodeTable;
+    use crate::fs::notify::tests::RecordingSink;
+    use crate::fs::notify::Invalidation;
+    use std::path::Path;
+
+    /// API stub whose only content is one batch of events after cursor 7
+    struct EventsAPI;
+
+    #[async_trait::async_trait]
+    impl EgnyteAPI for EventsAPI {
+        async fn list_folder(&self, _path: &str) -> Result<Vec<EgnyteEntry>> {
+            Ok(Vec::new())
+        }
+
+        async fn get_file_info(&self, path: &str) -> Result<EgnyteEntry> {
+            Err(anyhow::anyhow!("{} not found", path))
+        }
+
+        async fn download_file(&self, path: &str) -> Result<Vec<u8>> {
+            Err(anyhow::anyhow!("{} not found", path))
+        }
+
+        async fn copy_entry(&self, from: &str, _to: &str) -> Result<()> {
+            Err(anyhow::anyhow!("{} not found", from))
+        }
+
+        async fn delete_entry(&self, path: &str) -> Result<()> {
+            Err(anyhow::anyhow!("{} not found", path))
+        }
+
+        async fn move_entry(&self, from: &str, _to: &str) -> Result<()> {
+            Err(anyhow::anyhow!("{} not found", from))
+        }
+
+        async fn upload_file(&self, path: &str, _data: &[u8]) -> Result<Option<String>> {
+            Err(anyhow::anyhow!("{} not found", path))
+        }
+
+        async fn upload_chunked(&self, path: &str, _data: &[u8]) -> Result<Option<String>> {
+            Err(anyhow::anyhow!("{} not found", path))
+        }
+
+        async fn latest_event_cursor(&self) -> Result<u64> {
+            Ok(7)
+        }
+
+        async fn events_since(&self, cursor: u64) -> Result<EventBatch> {
+            assert_eq!(cursor, 7);
+            Ok(EventBatch {
+                paths: vec![
+                    "/Shared/a.txt".to_string(),
+                    "/Private/b.txt".to_string(),
+                    "/Shared/new/".to_string(),
+                ],
+                cursor: 9,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_poll_invalidates_changed_paths() {
+        let table = Arc::new(InodeTable::new());
+        let shared = table.get_or_create_inode(Path::new("/Shared"));
+        let file = table.get_or_create_inode(Path::new("/Shared/a.txt"));
+        let invalidator = Invalidator::new(table);
+        let sink = RecordingSink::default();
+        invalidator.attach(Box::new(sink.clone())).unwrap();
+
+        let poller = ChangePoller::new(
+            Arc::new(EventsAPI),
+            invalidator,
+            "/",
+            Duration::from_secs(60),
+        );
+        assert_eq!(poller.poll_once(7).await.unwrap(), 9);
+
+        // The file, its dentry and folder, then the new folder's dentry and
+        // the folder again: the new folder was never looked up, but its
+        // parent's listing is stale
+        let sent = sink.wait_for(5);
+        assert!(sent.contains(&Invalidation::Inode(file)));
+        assert!(sent.contains(&Invalidation::Entry(shared, "a.txt".into())));
+        assert!(sent.contains(&Invalidation::Entry(shared, "new".into())));
+    }
+
+    #[tokio::test]
+    async fn test_poll_maps_paths_under_root() {
+        let table = Arc::new(InodeTable::new());
+        let file = table.get_or_create_inode(Path::new("/a.txt"));
+        let invalidator = Invalidator::new(table);
+        let sink = RecordingSink::default();
+        invalidator.attach(Box::new(sink.clone())).unwrap();
+
+        let poller = ChangePoller::new(
+            Arc::new(EventsAPI),
+            invalidator,
+            "/Shared",
+            Duration::from_secs(60),
+        );
+        poller.poll_once(7).await.unwrap();
+
+        // /Private/b.txt is outside the mount and sends nothing
+        assert_eq!(
+            sink.wait_for(5),
+            vec![
+                Invalidation::Inode(file),
+                Invalidation::Entry(1, "a.txt".into()),
+                Invalidation::Inode(1),
+                Invalidation::Entry(1, "new".into()),
+                Invalidation::Inode(1),
+            ]
+        );
+    }
+}
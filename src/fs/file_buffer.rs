@@ -0,0 +1,214 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number used to name the next buffer file
+static NEXT_BUFFER: AtomicU64 = AtomicU64::new(0);
+
+/// Default directory for buffer files: ~/.cache/egnyte-fuse/buffers, or the
+/// system temp directory without a cache directory
+pub fn default_buffer_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("egnyte-fuse")
+        .join("buffers")
+}
+
+/// Content of an open file, kept in an unlinked temporary file instead of
+/// memory.
+///
+/// Writes go straight to their offset, so a file written out of order stays
+/// sparse on disk (unwritten ranges are holes that read back as zeros). The
+/// file is removed from its directory as soon as it is created, so nothing is
+/// left behind if the process dies.
+#[derive(Debug)]
+pub struct FileBuffer {
+    file: File,
+    len: u64,
+}
+
+impl FileBuffer {
+    /// Create an empty buffer in `dir`
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        loop {
+            let name = format!(
+                "{}-{}",
+                std::process::id(),
+                NEXT_BUFFER.fetch_add(1, Ordering::Relaxed)
+            );
+            let path = dir.join(name);
+            match OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => {
+                    std::fs::remove_file(&path)?;
+                    return Ok(Self { file, len: 0 });
+                }
+                // Left over from an earlier process with the same pid
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Create a buffer in `dir` holding `data`
+    pub fn with_contents(dir: &Path, data: &[u8]) -> io::Result<Self> {
+        let mut buffer = Self::new(dir)?;
+        buffer.write_at(0, data)?;
+        Ok(buffer)
+    }
+
+    /// Length of the content in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Write `data` at `offset`, growing the content if needed; a gap left
+    /// before `offset` reads back as zeros. EFBIG if the end would not fit
+    /// in a file offset.
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|end| i64::try_from(*end).is_ok())
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EFBIG))?;
+        self.file.write_all_at(data, offset)?;
+        self.len = self.len.max(end);
+        Ok(())
+    }
+
+    /// Read up to `size` bytes at `offset`; empty past the end
+    pub fn read_at(&self, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        if offset >= self.len {
+            return Ok(Vec::new());
+        }
+        let count = (self.len - offset).min(size as u64) as usize;
+        let mut data = vec![0; count];
+        self.file.read_exact_at(&mut data, offset)?;
+        Ok(data)
+    }
+
+    /// Truncate or extend the content; an extension reads back as zeros.
+    /// EFBIG if `len` does not fit in a file offset.
+    pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+        if i64::try_from(len).is_err() {
+            return Err(io::Error::from_raw_os_error(libc::EFBIG));
+        }
+        self.file.set_len(len)?;
+        self.len = len;
+        Ok(())
+    }
+
+    /// Reserve disk space for `len` bytes at `offset`. Unless `keep_size`,
+    /// the content grows to cover the range, reading back as zeros; with it,
+    /// only space is reserved and the length stays the same.
+    pub fn allocate(&mut self, offset: u64, len: u64, keep_size: bool) -> io::Result<()> {
+        let end = offset
+            .checked_add(len)
+            .filter(|end| i64::try_from(*end).is_ok())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let mode = if keep_size {
+            libc::FALLOC_FL_KEEP_SIZE
+        } else {
+            0
+        };
+        // SAFETY: the descriptor stays open for as long as `self.file`; the
+        // range was checked to fit in off_t above
+        let ret =
+            unsafe { libc::fallocate(self.file.as_raw_fd(), mode, offset as i64, len as i64) };
+        if ret != 0 {
+            let err = io::Error::last_os_error();
+            // Reserving is best effort on a buffer directory without support
+            if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+                return Err(err);
+            }
+            if !keep_size && end > self.len {
+                self.file.set_len(end)?;
+            }
+        }
+        if !keep_size {
+            self.len = self.len.max(end);
+        }
+        Ok(())
+    }
+
+    /// The whole content
+    pub fn contents(&self) -> io::Result<Vec<u8>> {
+        self.read_at(0, self.len as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("egnyte-fuse-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let mut buffer = FileBuffer::with_contents(&test_dir("rw"), b"hello").unwrap();
+        buffer.write_at(5, b" world").unwrap();
+        assert_eq!(buffer.len(), 11);
+        assert_eq!(buffer.read_at(6, 100).unwrap(), b"world");
+        assert!(buffer.read_at(11, 10).unwrap().is_empty());
+        assert_eq!(buffer.contents().unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn test_sparse_write_reads_zeros() {
+        let mut buffer = FileBuffer::new(&test_dir("sparse")).unwrap();
+        buffer.write_at(1 << 20, b"end").unwrap();
+        assert_eq!(buffer.len(), (1 << 20) + 3);
+        assert_eq!(buffer.read_at(1000, 4).unwrap(), vec![0; 4]);
+        assert_eq!(buffer.read_at(1 << 20, 3).unwrap(), b"end");
+
+        let err = buffer.write_at(u64::MAX - 1, b"xyz").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EFBIG));
+        assert_eq!(buffer.len(), (1 << 20) + 3);
+    }
+
+    #[test]
+    fn test_set_len() {
+        let mut buffer = FileBuffer::with_contents(&test_dir("len"), b"hello").unwrap();
+        buffer.set_len(2).unwrap();
+        assert_eq!(buffer.contents().unwrap(), b"he");
+        buffer.set_len(4).unwrap();
+        assert_eq!(buffer.contents().unwrap(), b"he\0\0");
+
+        let err = buffer.set_len(u64::MAX).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EFBIG));
+        assert_eq!(buffer.len(), 4);
+    }
+
+    #[test]
+    fn test_allocate() {
+        let mut buffer = FileBuffer::with_contents(&test_dir("alloc"), b"hi").unwrap();
+        buffer.allocate(0, 1 << 20, true).unwrap();
+        assert_eq!(buffer.len(), 2);
+        buffer.allocate(2, 3, false).unwrap();
+        assert_eq!(buffer.contents().unwrap(), b"hi\0\0\0");
+        // A range inside the content changes nothing
+        buffer.allocate(0, 1, false).unwrap();
+        assert_eq!(buffer.len(), 5);
+        assert!(buffer.allocate(u64::MAX, 1, false).is_err());
+    }
+
+    #[test]
+    fn test_leaves_no_files() {
+        let dir = test_dir("unlinked");
+        let _buffer = FileBuffer::with_contents(&dir, b"data").unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    }
+}
@@ -1,15 +1,31 @@
+use crate::fs::budget::{RetryBudget, DEFAULT_OP_DEADLINE, DEFAULT_OP_RETRIES};
+use crate::fs::egnyte_path;
+use crate::fs::error;
+use crate::fs::events::ChangePoller;
+use crate::fs::file_buffer::{self, FileBuffer};
 use crate::fs::inode_table::InodeTable;
+use crate::fs::locks::{Lock, LockTable};
+use crate::fs::notify::Invalidator;
+use crate::fs::spool::Spool;
 use anyhow::{Context, Result};
+use dashmap::DashMap;
+use fuser::consts::{
+    FOPEN_DIRECT_IO, FUSE_DO_READDIRPLUS, FUSE_HANDLE_KILLPRIV, FUSE_PARALLEL_DIROPS,
+    FUSE_POSIX_LOCKS, FUSE_READDIRPLUS_AUTO, FUSE_WRITEBACK_CACHE,
+};
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    ReplyOpen, Request,
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyIoctl, ReplyLock, ReplyLseek, ReplyOpen,
+    ReplyPoll, ReplyWrite, ReplyXattr, Request,
 };
 use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::runtime::Runtime;
-use tokio::sync::RwLock;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
 
 /// Egnyte API client interface (async)
 #[async_trait::async_trait]
@@ -17,502 +33,4775 @@ pub trait EgnyteAPI: Send + Sync {
     async fn list_folder(&self, path: &str) -> Result<Vec<EgnyteEntry>>;
     async fn get_file_info(&self, path: &str) -> Result<EgnyteEntry>;
     async fn download_file(&self, path: &str) -> Result<Vec<u8>>;
+    /// Up to `len` bytes of a file from `offset`; shorter at the end of the
+    /// file and empty past it. With a `version` (an entry_id), the bytes are
+    /// those of that version, and the download fails with `VersionGone` if
+    /// the server no longer has it. Downloads the whole file unless
+    /// overridden, and then only the current version.
+    async fn download_range(
+        &self,
+        path: &str,
+        _version: Option<&str>,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>> {
+        let data = self.download_file(path).await?;
+        Ok(slice_range(&data, offset, len).to_vec())
+    }
+    /// Copy a file or folder (recursively) to `to` on the server
+    async fn copy_entry(&self, from: &str, to: &str) -> Result<()>;
+    /// Delete a file or folder (recursively) on the server
+    async fn delete_entry(&self, path: &str) -> Result<()>;
+    /// Move a file or folder to `to`; fails with `AlreadyExists` if `to` exists
+    async fn move_entry(&self, from: &str, to: &str) -> Result<()>;
+    /// Create or replace a file with `data`, returning the new version's entry_id
+    async fn upload_file(&self, path: &str, data: &[u8]) -> Result<Option<String>>;
+    /// Like `upload_file`, but sent in bounded-size parts that are retried
+    /// individually; meant for large files
+    async fn upload_chunked(&self, path: &str, data: &[u8]) -> Result<Option<String>>;
+    /// `get_file_info`, or None when the entry known (with the validators
+    /// it came with) is still current. Asks again in full unless overridden.
+    async fn file_info_if_changed(
+        &self,
+        path: &str,
+        _known: &EgnyteEntry,
+    ) -> Result<Option<EgnyteEntry>> {
+        self.get_file_info(path).await.map(Some)
+    }
+    /// `download_file` with the validators of the content, or None when
+    /// the copy the known validators describe is still current. Downloads
+    /// in full unless overridden.
+    async fn download_if_changed(
+        &self,
+        path: &str,
+        _known: &Validators,
+    ) -> Result<Option<(Vec<u8>, Validators)>> {
+        let data = self.download_file(path).await?;
+        Ok(Some((data, Validators::default())))
+    }
+    /// Id of the most recent event in the domain, to start polling from
+    async fn latest_event_cursor(&self) -> Result<u64>;
+    /// Paths changed by events after `cursor`, and the cursor to poll from next
+    async fn events_since(&self, cursor: u64) -> Result<EventBatch>;
 }
 
-/// Entry from Egnyte API
-#[derive(Debug, Clone)]
-pub struct EgnyteEntry {
-    pub name: String,
-    pub path: String,
-    pub is_folder: bool,
-    pub size: u64,
-    pub modified_time: SystemTime,
+/// The part of `data` that a read of `len` bytes at `offset` sees
+pub(crate) fn slice_range(data: &[u8], offset: u64, len: u64) -> &[u8] {
+    let start = usize::try_from(offset)
+        .unwrap_or(usize::MAX)
+        .min(data.len());
+    let end = usize::try_from(len)
+        .unwrap_or(usize::MAX)
+        .saturating_add(start)
+        .min(data.len());
+    &data[start..end]
 }
 
-/// FUSE filesystem implementation for Egnyte
-pub struct EgnyteFuse {
-    /// Inode table for path <-> inode mapping
-    inode_table: Arc<InodeTable>,
-    /// Tokio runtime for async operations
-    rt: Arc<Runtime>,
-    /// API client
-    api_client: Arc<dyn EgnyteAPI>,
-    /// Open file handles (inode -> file data)
-    open_files: Arc<RwLock<std::collections::HashMap<u64, Vec<u8>>>>,
+/// Result of polling the events API
+#[derive(Debug, Clone, Default)]
+pub struct EventBatch {
+    /// Egnyte paths created, changed, deleted, or moved from or to
+    pub paths: Vec<String>,
+    /// Cursor to pass to the next poll
+    pub cursor: u64,
 }
 
-impl EgnyteFuse {
-    /// Create a new EgnyteFuse filesystem
-    pub fn new(api_client: Arc<dyn EgnyteAPI>) -> Result<Self> {
-        let rt = Runtime::new().context("Failed to create Tokio runtime")?;
-        
-        Ok(Self {
-            inode_table: Arc::new(InodeTable::new()),
-            rt: Arc::new(rt),
-            api_client,
-            open_files: Arc::new(RwLock::new(std::collections::HashMap::new())),
+/// Default time the kernel may cache a name's inode
+pub const DEFAULT_ENTRY_TTL: Duration = Duration::from_secs(1);
+
+/// Default time the kernel may cache attributes
+pub const DEFAULT_ATTR_TTL: Duration = Duration::from_secs(1);
+
+/// Default time the kernel may cache a lookup that found nothing
+pub const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(1);
+
+/// Default kernel readahead per file, in bytes
+pub const DEFAULT_MAX_READAHEAD: u32 = 256 * 1024;
+
+/// Default largest write request from the kernel, in bytes
+pub const DEFAULT_MAX_WRITE: u32 = 1024 * 1024;
+
+/// Default block size reported in attributes, in bytes
+pub const DEFAULT_BLOCK_SIZE: u32 = 512;
+
+/// Names desktops and autorun handlers look for in every mount, which
+/// Egnyte never has: looked up, they fail at once without an API call. A
+/// trailing `*` matches any rest of the name.
+pub const DEFAULT_IGNORED_NAMES: [&str; 5] = [
+    ".Trash",
+    ".Trash-*",
+    ".xdg-volume-info",
+    ".hidden",
+    "autorun.inf",
+];
+
+/// Extended attribute holding a file's Egnyte entry id, for scripts that
+/// call the REST API on a file found through the mount:
+/// `getfattr -n user.egnyte.entry_id FILE`
+pub const ENTRY_ID_XATTR: &str = "user.egnyte.entry_id";
+
+/// Lookups in one folder that miss the metadata cache within
+/// [`LOOKUP_BURST_WINDOW`] before the folder is listed once to answer the rest
+const LOOKUP_BURST: u32 = 2;
+const LOOKUP_BURST_WINDOW: Duration = Duration::from_secs(1);
+
+/// Whether `name` matches one of `patterns` (exact, or a prefix ending in `*`)
+fn is_ignored_name(name: &OsStr, patterns: &[String]) -> bool {
+    let Some(name) = name.to_str() else {
+        return false;
+    };
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
         })
+}
+
+/// Apply a kernel setting, falling back to the nearest value the kernel
+/// accepts; returns the value in effect
+fn negotiate(requested: u32, mut set: impl FnMut(u32) -> Result<u32, u32>) -> u32 {
+    match set(requested) {
+        Ok(_) => requested,
+        Err(nearest) => {
+            let _ = set(nearest);
+            nearest
+        }
     }
+}
 
-    /// Convert path to Egnyte API path
-    fn to_egnyte_path(&self, path: &Path) -> String {
-        let path_str = path.to_string_lossy();
-        if path_str == "/" {
-            "/".to_string()
-        } else {
-            path_str.to_string()
+/// Request each capability on its own, so one the kernel lacks doesn't take
+/// the others down with it; returns the names granted and refused
+fn negotiate_capabilities(
+    wanted: &[(&'static str, u32)],
+    mut add: impl FnMut(u32) -> Result<(), u32>,
+) -> (Vec<&'static str>, Vec<&'static str>) {
+    let mut granted = Vec::new();
+    let mut refused = Vec::new();
+    for &(name, flag) in wanted {
+        match add(flag) {
+            Ok(()) => granted.push(name),
+            Err(_) => refused.push(name),
         }
     }
+    (granted, refused)
+}
 
-    /// Get file attributes for a path
-    fn get_attr_internal(&self, path: &Path) -> Result<FileAttr> {
-        let egnyte_path = self.to_egnyte_path(path);
-        let entry = self
-            .rt
-            .block_on(self.api_client.get_file_info(&egnyte_path))
-            .context("Failed to get file info")?;
+/// Files larger than this are uploaded in parts
+pub const CHUNKED_UPLOAD_THRESHOLD: usize = 100 * 1024 * 1024;
 
-        let inode = self.inode_table.get_or_create_inode(path);
-        let file_type = if entry.is_folder {
-            FileType::Directory
-        } else {
-            FileType::RegularFile
-        };
+/// Files at least this large are opened without downloading them by
+/// default; reads fetch the blocks they touch by range
+pub const DEFAULT_RANGED_READ_THRESHOLD: u64 = 8 * 1024 * 1024;
 
-        let attr = FileAttr {
-            ino: inode,
-            size: entry.size,
-            blocks: (entry.size + 511) / 512, // 512-byte blocks
-            atime: entry.modified_time,
-            mtime: entry.modified_time,
-            ctime: entry.modified_time,
-            crtime: entry.modified_time,
-            kind: file_type,
-            perm: if entry.is_folder { 0o755 } else { 0o644 },
-            nlink: 1,
-            uid: unsafe { libc::getuid() },
-            gid: unsafe { libc::getgid() },
-            rdev: 0,
-            flags: 0,
-            blksize: 512,
-        };
+/// Size of the blocks a ranged read downloads
+pub const READ_BLOCK_SIZE: u64 = 1024 * 1024;
 
-        Ok(attr)
-    }
+/// Reads in a row, each starting where the last ended, after which a ranged
+/// file is taken to be read sequentially and the blocks ahead are prefetched
+const SEQUENTIAL_READS: u32 = 2;
 
-    /// Read directory entries
-    fn readdir_internal(&self, path: &Path) -> Result<Vec<(u64, FileType, String)>> {
-        let egnyte_path = self.to_egnyte_path(path);
-        let entries = self
-            .rt
-            .block_on(self.api_client.list_folder(&egnyte_path))
-            .context("Failed to list folder")?;
+/// An open file.
+///
+/// Flush policy: `write` only lands in the disk-backed buffer and marks it
+/// dirty, so any number of small writes coalesce. The buffer is uploaded as a
+/// whole when the kernel sends `flush` (on close) or `fsync`, and only if it
+/// is dirty. `release` tries once more and keeps the buffer if that fails
+/// too. With autoflush on, a buffer left dirty and idle also gets uploaded in
+/// the background.
+///
+/// A large file is opened without its content (see [`Ranged`]). It is
+/// downloaded in full before the first change to it, since an upload sends
+/// the whole buffer.
+#[derive(Debug)]
+struct OpenFile {
+    buffer: FileBuffer,
+    /// Written since the last successful upload
+    dirty: bool,
+    /// When the buffer was last changed
+    modified: Instant,
+    /// entry_id of the version this buffer is based on, to detect conflicting
+    /// uploads by others
+    base_entry_id: Option<String>,
+    /// Where the buffer is uploaded instead of the file, once a conflict
+    /// made the two diverge
+    conflict_copy: Option<PathBuf>,
+    /// Blocks still to download, while the buffer holds only what was read
+    ranged: Option<Arc<Ranged>>,
+    /// Handles open on the file; the buffer is dropped after the last
+    /// release, unless it holds changes that could not be saved
+    handles: u32,
+}
 
-        let mut result = Vec::new();
-        
-        // Add "." and ".." entries
-        let current_inode = self.inode_table.get_or_create_inode(path);
-        result.push((current_inode, FileType::Directory, ".".to_string()));
-        
-        if path != Path::new("/") {
-            if let Some(parent) = path.parent() {
-                let parent_inode = self.inode_table.get_or_create_inode(parent);
-                result.push((parent_inode, FileType::Directory, "..".to_string()));
-            }
-        } else {
-            result.push((current_inode, FileType::Directory, "..".to_string()));
+impl OpenFile {
+    fn new(buffer: FileBuffer, base_entry_id: Option<String>) -> Self {
+        Self {
+            buffer,
+            dirty: false,
+            modified: Instant::now(),
+            base_entry_id,
+            conflict_copy: None,
+            ranged: None,
+            handles: 1,
         }
+    }
 
-        // Add directory entries
-        for entry in entries {
-            let entry_path = if path == Path::new("/") {
-                Path::new("/").join(&entry.name)
-            } else {
-                path.join(&entry.name)
-            };
-            
-            let inode = self.inode_table.get_or_create_inode(&entry_path);
-            let file_type = if entry.is_folder {
-                FileType::Directory
-            } else {
-                FileType::RegularFile
-            };
-            
-            result.push((inode, file_type, entry.name));
+    /// A file of `size` bytes whose content is downloaded block by block as
+    /// it is read, into a `buffer` that already has that size; every block
+    /// comes from version `base_entry_id`
+    fn ranged(buffer: FileBuffer, base_entry_id: Option<String>, size: u64) -> Self {
+        Self {
+            ranged: Some(Arc::new(Ranged::new(size, base_entry_id.clone()))),
+            ..Self::new(buffer, base_entry_id)
         }
-
-        Ok(result)
     }
 
-    /// Read file content
-    fn read_file_internal(&self, path: &Path) -> Result<Vec<u8>> {
-        let egnyte_path = self.to_egnyte_path(path);
-        self.rt
-            .block_on(self.api_client.download_file(&egnyte_path))
-            .context("Failed to download file")
+    /// Record a change that still has to be uploaded
+    fn mark_dirty(&mut self) {
+        self.dirty = true;
+        self.modified = Instant::now();
     }
 }
 
-impl Filesystem for EgnyteFuse {
-    fn init(
-        &mut self,
-        _req: &Request<'_>,
-        config: &mut fuser::KernelConfig,
-    ) -> Result<(), libc::c_int> {
-        // Configure FUSE: writeback cache, parallel dirops, max_readahead 256KB, max_write 1MB
-        let _ = config.set_max_readahead(256 * 1024);
-        let _ = config.set_max_write(1024 * 1024);
-        Ok(())
+/// The blocks of a file opened without downloading it. Its buffer has the
+/// remote size from the start, and each [`READ_BLOCK_SIZE`] block of it is
+/// downloaded by range the first time a read needs it; a block nothing has
+/// read yet is a hole in the buffer.
+///
+/// Every block is downloaded from the version the file was opened at, so a
+/// change on the server meanwhile cannot splice two versions together; if
+/// that version is gone, reads of blocks not downloaded yet fail with EIO.
+///
+/// Once reads go through the file in order, the blocks just past the last
+/// read are downloaded in the background ahead of the reads for them (see
+/// [`ReadPattern`]).
+#[derive(Debug)]
+struct Ranged {
+    /// One per block, set once the block is in the buffer
+    blocks: Vec<tokio::sync::OnceCell<()>>,
+    /// entry_id of the version the blocks come from, if Egnyte gave one
+    version: Option<String>,
+    reads: std::sync::Mutex<ReadPattern>,
+}
+
+impl Ranged {
+    fn new(size: u64, version: Option<String>) -> Self {
+        let count = size.div_ceil(READ_BLOCK_SIZE) as usize;
+        Self {
+            blocks: (0..count).map(|_| tokio::sync::OnceCell::new()).collect(),
+            version,
+            reads: std::sync::Mutex::new(ReadPattern::default()),
+        }
     }
 
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        // Bridge to Tokio runtime
-        let inode_table = Arc::clone(&self.inode_table);
-        let rt = Arc::clone(&self.rt);
-        let api_client = Arc::clone(&self.api_client);
-        let name_str = name.to_string_lossy().to_string();
+    /// Record a read of `len` bytes at `offset`, and return the blocks to
+    /// prefetch for the `window` bytes after it: none unless the reads look
+    /// sequential, and none asked for already
+    fn read_ahead(&self, offset: u64, len: u64, window: u64) -> std::ops::Range<usize> {
+        let mut reads = self.reads.lock().unwrap();
+        let end = offset.saturating_add(len);
+        if !reads.record(offset, end) {
+            return 0..0;
+        }
+        let ahead = self.covering(end, window);
+        let start = ahead.start.max(reads.prefetched);
+        reads.prefetched = reads.prefetched.max(ahead.end);
+        start..ahead.end
+    }
 
-        // Get parent path
-        let parent_path = match inode_table.get_path(parent) {
-            Some(p) => p,
-            None => {
-                reply.error(libc::ENOENT);
-                return;
-            }
-        };
+    /// Indexes of the blocks holding `len` bytes at `offset`
+    fn covering(&self, offset: u64, len: u64) -> std::ops::Range<usize> {
+        let count = self.blocks.len() as u64;
+        let start = (offset / READ_BLOCK_SIZE).min(count);
+        let end = offset
+            .saturating_add(len)
+            .div_ceil(READ_BLOCK_SIZE)
+            .min(count);
+        start as usize..end as usize
+    }
+}
+
+/// Where the reads of a ranged file have been going, to tell sequential
+/// reading, worth prefetching for, from random access, which would only
+/// download blocks nobody reads
+#[derive(Debug, Default)]
+struct ReadPattern {
+    /// Where the last read ended
+    next: u64,
+    /// Reads in a row that started where the one before ended
+    sequential: u32,
+    /// Blocks before this one have been prefetched or read
+    prefetched: usize,
+}
 
-        // Build child path
-        let child_path = if parent_path == PathBuf::from("/") {
-            PathBuf::from("/").join(&name_str)
+impl ReadPattern {
+    /// Record a read from `offset` to `end`; true if reads are sequential
+    fn record(&mut self, offset: u64, end: u64) -> bool {
+        if offset == self.next {
+            self.sequential = self.sequential.saturating_add(1);
         } else {
-            parent_path.join(&name_str)
-        };
+            // A jump: whatever was prefetched around the old position is
+            // not what comes next
+            self.sequential = 0;
+            self.prefetched = 0;
+        }
+        self.next = end;
+        self.sequential >= SEQUENTIAL_READS
+    }
+}
 
-        // Spawn onto Tokio runtime
-        let handle = rt.spawn(async move {
-            let egnyte_path = if child_path == PathBuf::from("/") {
-                "/".to_string()
-            } else {
-                child_path.to_string_lossy().to_string()
-            };
+/// Download block `index` of `file`, opened as `ranged`, into its buffer
+/// unless it is there already. A block another read is downloading is
+/// waited for rather than asked for again.
+async fn fetch_block(
+    api: &dyn EgnyteAPI,
+    remote_path: &str,
+    file: &Mutex<OpenFile>,
+    ranged: &Arc<Ranged>,
+    index: usize,
+) -> Result<()> {
+    ranged.blocks[index]
+        .get_or_try_init(|| async {
+            let offset = index as u64 * READ_BLOCK_SIZE;
+            let version = ranged.version.as_deref();
+            let data = api
+                .download_range(remote_path, version, offset, READ_BLOCK_SIZE)
+                .await?;
+            let mut file = file.lock().await;
+            // Not if the buffer stopped waiting for remote content meanwhile
+            // (loaded in full, or cut off below this block)
+            if file
+                .ranged
+                .as_ref()
+                .is_some_and(|open| Arc::ptr_eq(open, ranged))
+            {
+                let size = file.buffer.len();
+                let data = slice_range(&data, 0, size.saturating_sub(offset));
+                file.buffer.write_at(offset, data)?;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await?;
+    Ok(())
+}
 
-            // Get file info from API
-            let entry = match api_client.get_file_info(&egnyte_path).await {
-                Ok(e) => e,
-                Err(_) => return Err(libc::ENOENT),
-            };
+/// Open files by inode. Each file has a lock of its own, so I/O on one never
+/// waits for another; the map is only touched to find, add or drop a file.
+type OpenFiles = DashMap<u64, Arc<Mutex<OpenFile>>>;
 
-            // Get or create inode
-            let inode = inode_table.get_or_create_inode(&child_path);
+/// An open file, ready to be locked
+fn shared(file: OpenFile) -> Arc<Mutex<OpenFile>> {
+    Arc::new(Mutex::new(file))
+}
 
-            // Build file attributes
-            let file_type = if entry.is_folder {
-                FileType::Directory
-            } else {
-                FileType::RegularFile
-            };
+/// What uploading an open file needs, separate from the filesystem so the
+/// autoflush task can hold a copy
+#[derive(Clone)]
+struct Uploader {
+    api_client: Arc<dyn EgnyteAPI>,
+    invalidator: Invalidator,
+    /// Egnyte folder shown as the root of the mount
+    root: String,
+}
 
-            let attr = FileAttr {
-                ino: inode,
-                size: entry.size,
-                blocks: (entry.size + 511) / 512,
-                atime: entry.modified_time,
-                mtime: entry.modified_time,
-                ctime: entry.modified_time,
-                crtime: entry.modified_time,
-                kind: file_type,
-                perm: if entry.is_folder { 0o755 } else { 0o644 },
-                nlink: 1,
-                uid: unsafe { libc::getuid() },
-                gid: unsafe { libc::getgid() },
-                rdev: 0,
-                flags: 0,
-                blksize: 512,
+impl Uploader {
+    /// Upload an open file if it has unsaved writes. On failure the data
+    /// stays dirty so a later flush can retry.
+    ///
+    /// If someone else uploaded a new version since the file was opened,
+    /// their version is left alone: our data goes to a `.conflict-<time>`
+    /// copy next to it and the flush fails with EIO. So do later flushes of
+    /// the file while it stays open, to the same copy. If the version cannot
+    /// be checked (a timeout, say), nothing is uploaded and the flush fails
+    /// with that error's errno.
+    async fn flush(&self, inode: u64, path: &Path, file: &mut OpenFile) -> Result<(), libc::c_int> {
+        if !file.dirty {
+            return Ok(());
+        }
+        let remote_path = egnyte_path::under_root(&self.root, path);
+        let data = file.buffer.contents().map_err(|_| libc::EIO)?;
+        if let Some(copy) = file.conflict_copy.clone() {
+            return self.save_conflict_copy(&copy, file, &data).await;
+        }
+
+        let checked = self
+            .check_conflict(&remote_path, file.base_entry_id.as_deref())
+            .await;
+        if let Err(e) = checked {
+            // A version check that could not be made is not a conflict
+            let Some(conflict @ error::ApiError::Conflict { .. }) = e.downcast_ref() else {
+                return Err(error::errno(&e));
             };
+            let copy = PathBuf::from(format!("{}.conflict-{}", path.display(), unix_time()));
+            warn!(
+                "{}; saving local changes to {}",
+                conflict,
+                egnyte_path::under_root(&self.root, &copy)
+            );
+            file.conflict_copy = Some(copy.clone());
+            return self.save_conflict_copy(&copy, file, &data).await;
+        }
 
-            Ok((inode, attr, Duration::from_secs(1)))
-        });
+        let entry_id = self
+            .upload(&remote_path, &data)
+            .await
+            .map_err(|e| error::errno(&e))?;
+        file.dirty = false;
+        file.base_entry_id = entry_id;
+        self.invalidator.invalidate_inode(inode);
+        Ok(())
+    }
 
-        // Block on the result
-        match rt.block_on(handle) {
-            Ok(Ok((_inode, attr, ttl))) => {
-                reply.entry(&ttl, &attr, 0);
-            }
-            _ => {
-                reply.error(libc::ENOENT);
-            }
-        }
+    /// Upload the changes to a file that diverged from it to its conflict
+    /// copy; EIO once saved there, since the file itself did not get them
+    async fn save_conflict_copy(
+        &self,
+        copy: &Path,
+        file: &mut OpenFile,
+        data: &[u8],
+    ) -> Result<(), libc::c_int> {
+        let copy_path = egnyte_path::under_root(&self.root, copy);
+        self.upload(&copy_path, data)
+            .await
+            .map_err(|e| error::errno(&e))?;
+        file.dirty = false;
+        self.invalidator.invalidate_path(copy);
+        Err(libc::EIO)
     }
 
-    fn getattr(&mut self, _req: &Request<'_>, inode: u64, reply: ReplyAttr) {
-        let inode_table = Arc::clone(&self.inode_table);
-        let rt = Arc::clone(&self.rt);
-        let api_client = Arc::clone(&self.api_client);
+    /// Upload a whole file, in parts once it is past the chunking threshold
+    async fn upload(&self, remote_path: &str, data: &[u8]) -> Result<Option<String>> {
+        upload_whole(self.api_client.as_ref(), remote_path, data).await
+    }
 
-        let path = match inode_table.get_path(inode) {
-            Some(p) => p,
-            None => {
-                reply.error(libc::ENOENT);
-                return;
-            }
+    async fn check_conflict(&self, remote_path: &str, base_entry_id: Option<&str>) -> Result<()> {
+        check_conflict(self.api_client.as_ref(), remote_path, base_entry_id).await
+    }
+}
+
+/// Upload a whole file, in parts once it is past the chunking threshold
+pub(crate) async fn upload_whole(
+    api: &dyn EgnyteAPI,
+    remote_path: &str,
+    data: &[u8],
+) -> Result<Option<String>> {
+    if data.len() > CHUNKED_UPLOAD_THRESHOLD {
+        api.upload_chunked(remote_path, data).await
+    } else {
+        api.upload_file(remote_path, data).await
+    }
+}
+
+/// Fail with a conflict if the remote file is no longer the version the
+/// buffer was based on. A file deleted remotely, or one whose version is
+/// unknown, is not a conflict.
+pub(crate) async fn check_conflict(
+    api: &dyn EgnyteAPI,
+    remote_path: &str,
+    base_entry_id: Option<&str>,
+) -> Result<()> {
+    let Some(opened) = base_entry_id else {
+        return Ok(());
+    };
+    let remote = match api.get_file_info(remote_path).await {
+        Ok(entry) => entry.entry_id,
+        Err(e) if error::errno(&e) == libc::ENOENT => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    match remote {
+        Some(remote) if remote != opened => Err(error::ApiError::Conflict {
+            path: remote_path.to_string(),
+            opened: opened.to_string(),
+            remote,
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Upload every dirty buffer that has not changed for `idle`. Each file
+/// stays locked while it uploads, so no write lands in it mid-upload.
+async fn flush_idle(
+    uploader: &Uploader,
+    open_files: &OpenFiles,
+    inode_table: &InodeTable,
+    idle: Duration,
+) {
+    let files: Vec<(u64, Arc<Mutex<OpenFile>>)> = open_files
+        .iter()
+        .map(|file| (*file.key(), Arc::clone(file.value())))
+        .collect();
+    for (inode, file) in files {
+        let mut file = file.lock().await;
+        if !file.dirty || file.modified.elapsed() < idle {
+            continue;
+        }
+        let Some(path) = inode_table.get_path(inode) else {
+            continue;
         };
+        if let Err(errno) = uploader.flush(inode, &path, &mut file).await {
+            warn!("Autoflush of {} failed (errno {})", path.display(), errno);
+        }
+    }
+}
 
-        let handle = rt.spawn(async move {
-            let egnyte_path = if path == PathBuf::from("/") {
-                "/".to_string()
-            } else {
-                path.to_string_lossy().to_string()
-            };
+/// Entry of a directory listing snapshot
+#[derive(Debug, Clone)]
+struct DirEntry {
+    ino: u64,
+    kind: FileType,
+    name: String,
+    /// Attributes from the listing (None for "." and "..")
+    attr: Option<FileAttr>,
+}
 
-            let entry = match api_client.get_file_info(&egnyte_path).await {
-                Ok(e) => e,
-                Err(_) => return Err(libc::ENOENT),
-            };
+/// Directory listing, including "." and ".."
+type DirEntries = Vec<DirEntry>;
 
-            let file_type = if entry.is_folder {
-                FileType::Directory
-            } else {
-                FileType::RegularFile
-            };
+/// Entries of a directory listing that follow `offset`, paired with their cookie.
+///
+/// The cookie of an entry is its position in the listing plus one, so passing the
+/// last cookie back as `offset` resumes right after that entry.
+fn dir_page<T>(entries: &[T], offset: i64) -> impl Iterator<Item = (i64, &T)> {
+    entries
+        .iter()
+        .enumerate()
+        .skip(offset.max(0) as usize)
+        .map(|(index, entry)| (index as i64 + 1, entry))
+}
 
-            let attr = FileAttr {
-                ino: inode,
-                size: entry.size,
-                blocks: (entry.size + 511) / 512,
-                atime: entry.modified_time,
-                mtime: entry.modified_time,
-                ctime: entry.modified_time,
-                crtime: entry.modified_time,
-                kind: file_type,
-                perm: if entry.is_folder { 0o755 } else { 0o644 },
-                nlink: 1,
-                uid: unsafe { libc::getuid() },
-                gid: unsafe { libc::getgid() },
-                rdev: 0,
-                flags: 0,
-                blksize: 512,
-            };
+/// Join a kernel-supplied name onto a directory path.
+///
+/// The name's bytes are kept as-is rather than lossily converted, and names
+/// that aren't valid UTF-8 are refused with EINVAL: Egnyte names are UTF-8,
+/// so a lossy conversion would silently address a different entry.
+fn child_path(parent_path: &Path, name: &OsStr) -> Result<PathBuf, libc::c_int> {
+    if name.to_str().is_none() {
+        return Err(libc::EINVAL);
+    }
+    Ok(parent_path.join(name))
+}
 
-            Ok((attr, Duration::from_secs(1)))
-        });
+/// Name to list an entry under: the last component of a name Egnyte gave
+/// with slashes in it, or None for a name no directory entry can have
+/// (empty, `.`, `..`, or with a NUL)
+fn listing_name(name: &str) -> Option<&str> {
+    let name = name.trim_end_matches('/').rsplit('/').next()?;
+    (!name.is_empty() && name != "." && name != ".." && !name.contains('\0')).then_some(name)
+}
 
-        match rt.block_on(handle) {
-            Ok(Ok((attr, ttl))) => {
-                reply.attr(&ttl, &attr);
-            }
-            _ => {
-                reply.error(libc::ENOENT);
-            }
-        }
+/// Seconds since the epoch, for naming conflict copies
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Entry for a file just written locally, before Egnyte has listed it
+fn local_entry(path: &Path, remote_path: String, size: u64) -> EgnyteEntry {
+    EgnyteEntry {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        path: remote_path,
+        is_folder: false,
+        size,
+        modified_time: SystemTime::now(),
+        entry_id: None,
+        permission: None,
+        validators: Validators::default(),
     }
+}
 
-    fn readdir(
-        &mut self,
-        _req: &Request<'_>,
-        inode: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        let inode_table = Arc::clone(&self.inode_table);
-        let rt = Arc::clone(&self.rt);
-        let api_client = Arc::clone(&self.api_client);
+/// The current user's effective Egnyte permission on an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Viewer,
+    Editor,
+    Full,
+    Owner,
+}
 
-        let path = match inode_table.get_path(inode) {
-            Some(p) => p,
-            None => {
-                reply.error(libc::ENOENT);
-                return;
-            }
-        };
+impl Permission {
+    /// Parse a permission level as Egnyte names it ("Viewer", "Editor", ...)
+    pub fn parse(level: &str) -> Option<Self> {
+        match level.to_ascii_lowercase().as_str() {
+            "viewer" => Some(Permission::Viewer),
+            "editor" => Some(Permission::Editor),
+            "full" => Some(Permission::Full),
+            "owner" => Some(Permission::Owner),
+            _ => None,
+        }
+    }
 
-        let handle = rt.spawn(async move {
-            let egnyte_path = if path == PathBuf::from("/") {
-                "/".to_string()
-            } else {
-                path.to_string_lossy().to_string()
-            };
+    pub fn can_write(self) -> bool {
+        self != Permission::Viewer
+    }
+}
 
-            let entries = match api_client.list_folder(&egnyte_path).await {
-                Ok(e) => e,
-                Err(_) => return Err(libc::ENOENT),
-            };
+/// Ownership and mode overrides for the attributes the mount reports
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AttrOptions {
+    /// Owner of every entry (default: the mounting user)
+    pub uid: Option<u32>,
+    /// Group of every entry (default: the mounting user's group)
+    pub gid: Option<u32>,
+    /// Bits cleared from 0o666 for files (default: 0o644)
+    pub file_mask: Option<u16>,
+    /// Bits cleared from 0o777 for folders (default: 0o755)
+    pub dir_mask: Option<u16>,
+}
 
-            let mut dir_entries = Vec::new();
+/// Mode bits for an entry: 0o755/0o644 unless a mask is set, with the write
+/// bits dropped for viewers (an unknown permission counts as writable).
+///
+/// Never setuid or setgid: with `FUSE_HANDLE_KILLPRIV` clearing those on a
+/// write, truncate or chown is left to us, and this is what does it. A
+/// mapping that can set them must drop them on those changes.
+fn perm_bits(entry: &EgnyteEntry, options: &AttrOptions) -> u16 {
+    let base = if entry.is_folder {
+        options.dir_mask.map_or(0o755, |mask| 0o777 & !mask)
+    } else {
+        options.file_mask.map_or(0o644, |mask| 0o666 & !mask)
+    };
+    if entry.permission.is_none_or(Permission::can_write) {
+        base
+    } else {
+        base & !0o222
+    }
+}
 
-            // Add "." and ".."
-            dir_entries.push((inode, FileType::Directory, ".".to_string()));
-            if path == PathBuf::from("/") {
-                dir_entries.push((inode, FileType::Directory, "..".to_string()));
-            } else if let Some(parent) = path.parent() {
-                let parent_inode = inode_table.get_or_create_inode(parent);
-                dir_entries.push((parent_inode, FileType::Directory, "..".to_string()));
-            }
+/// Entry from Egnyte API
+#[derive(Debug, Clone)]
+pub struct EgnyteEntry {
+    pub name: String,
+    pub path: String,
+    pub is_folder: bool,
+    pub size: u64,
+    pub modified_time: SystemTime,
+    pub entry_id: Option<String>,
+    /// Effective permission of the current user (None when unknown)
+    pub permission: Option<Permission>,
+    /// Validators of the metadata response the entry came from
+    pub validators: Validators,
+}
 
-            // Add directory entries
-            for entry in entries {
-                let entry_path = if path == PathBuf::from("/") {
-                    PathBuf::from("/").join(&entry.name)
-                } else {
-                    path.join(&entry.name)
-                };
-
-                let entry_inode = inode_table.get_or_create_inode(&entry_path);
-                let file_type = if entry.is_folder {
-                    FileType::Directory
-                } else {
-                    FileType::RegularFile
-                };
-
-                dir_entries.push((entry_inode, file_type, entry.name.clone()));
-            }
+/// What a response said to quote when asking whether it changed: the
+/// `ETag` and `Last-Modified` headers, as sent
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
 
-            Ok(dir_entries)
-        });
+impl Validators {
+    /// Whether there is nothing to revalidate with
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
 
-        match rt.block_on(handle) {
-            Ok(Ok(dir_entries)) => {
-                let mut offset = offset as usize;
-                for (ino, kind, name) in dir_entries {
-                    offset += 1;
-                    if reply.add(ino, offset as i64, kind, name.as_str()) {
-                        break;
-                    }
-                }
-                reply.ok();
-            }
-            _ => {
-                reply.error(libc::ENOENT);
-            }
+/// Settings of a mount. The default is what `EgnyteFuse::new` uses: the
+/// whole domain, writable, with the default cache times and request sizes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuseOptions {
+    /// Reject every mutating operation with EROFS
+    pub read_only: bool,
+    /// Egnyte folder shown as the root of the mount
+    pub root: String,
+    /// How long the kernel may cache a name's inode (and the attributes
+    /// that came with it)
+    pub entry_ttl: Duration,
+    /// How long the kernel may cache attributes from getattr and setattr
+    pub attr_ttl: Duration,
+    /// How long the kernel may cache a name as nonexistent (zero: not at all)
+    pub negative_ttl: Duration,
+    /// Ownership and mode overrides
+    pub attr_options: AttrOptions,
+    /// Kernel readahead per file, in bytes, and how far ahead of sequential
+    /// reads the blocks of a ranged file are prefetched
+    pub max_readahead: u32,
+    /// Largest write request to ask the kernel for, in bytes
+    pub max_write: u32,
+    /// Open files with FOPEN_DIRECT_IO
+    pub direct_io: bool,
+    /// Block size reported in attributes (a power of two, at least 512)
+    pub block_size: u32,
+    /// Time one operation may spend on metadata requests and retries
+    pub op_deadline: Duration,
+    /// Names that are never looked up on Egnyte (see [`DEFAULT_IGNORED_NAMES`])
+    pub ignored_names: Vec<String>,
+    /// Map names differing only in case to one inode, the way Egnyte
+    /// resolves them; entries keep the case they were first seen with
+    pub case_insensitive: bool,
+    /// Directory for the temporary files behind open-file buffers
+    pub buffer_dir: PathBuf,
+    /// Directory to spool uploads that fail on close (None: keep them in
+    /// memory until the file is opened again)
+    pub spool_dir: Option<PathBuf>,
+    /// Cache at all; false overrides the TTLs and `direct_io` so nothing is
+    /// cached, here or in the kernel (see [`EgnyteFuse::with_caching_enabled`])
+    pub caching_enabled: bool,
+    /// Size from which files are opened without downloading them, and read
+    /// by range instead
+    pub ranged_read_threshold: u64,
+}
+
+impl Default for FuseOptions {
+    fn default() -> Self {
+        Self {
+            read_only: false,
+            root: "/".to_string(),
+            entry_ttl: DEFAULT_ENTRY_TTL,
+            attr_ttl: DEFAULT_ATTR_TTL,
+            negative_ttl: DEFAULT_NEGATIVE_TTL,
+            attr_options: AttrOptions::default(),
+            max_readahead: DEFAULT_MAX_READAHEAD,
+            max_write: DEFAULT_MAX_WRITE,
+            direct_io: false,
+            block_size: DEFAULT_BLOCK_SIZE,
+            op_deadline: DEFAULT_OP_DEADLINE,
+            ignored_names: DEFAULT_IGNORED_NAMES.map(String::from).to_vec(),
+            case_insensitive: false,
+            buffer_dir: file_buffer::default_buffer_dir(),
+            spool_dir: None,
+            caching_enabled: true,
+            ranged_read_threshold: DEFAULT_RANGED_READ_THRESHOLD,
         }
     }
+}
 
-    fn open(&mut self, _req: &Request<'_>, inode: u64, _flags: i32, reply: ReplyOpen) {
-        let inode_table = Arc::clone(&self.inode_table);
-        let rt = Arc::clone(&self.rt);
-        let api_client = Arc::clone(&self.api_client);
-        let open_files = Arc::clone(&self.open_files);
+/// Builds an [`EgnyteFuse`] one option at a time. Anything not set keeps the
+/// [`FuseOptions`] default.
+///
+/// A read-only mount whose kernel cache lasts a minute:
+///
+/// ```
+/// use egnyte_fuse::fs::fuse_ops::{EgnyteAPI, EgnyteFuse, EgnyteFuseBuilder};
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// fn read_only_mount(api: Arc<dyn EgnyteAPI>) -> anyhow::Result<EgnyteFuse> {
+///     EgnyteFuseBuilder::new()
+///         .read_only(true)
+///         .attr_ttl(Duration::from_secs(60))
+///         .build(api)
+/// }
+/// ```
+#[derive(Default)]
+pub struct EgnyteFuseBuilder {
+    options: FuseOptions,
+    runtime: Option<Arc<Runtime>>,
+}
 
-        let path = match inode_table.get_path(inode) {
-            Some(p) => p,
-            None => {
-                reply.error(libc::ENOENT);
-                return;
-            }
-        };
+impl EgnyteFuseBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        // Check if it's a directory
-        let egnyte_path = if path == PathBuf::from("/") {
-            "/".to_string()
-        } else {
-            path.to_string_lossy().to_string()
-        };
+    /// Start from a complete set of options
+    pub fn options(mut self, options: FuseOptions) -> Self {
+        self.options = options;
+        self
+    }
 
-        let handle = rt.spawn(async move {
-            let entry = match api_client.get_file_info(&egnyte_path).await {
-                Ok(e) => e,
-                Err(_) => return Err(libc::ENOENT),
-            };
+    /// Run API calls on `rt` instead of a runtime of the filesystem's own
+    pub fn runtime(mut self, rt: Arc<Runtime>) -> Self {
+        self.runtime = Some(rt);
+        self
+    }
 
-            if entry.is_folder {
-                // Directories don't need file handles
-                return Ok(0);
-            }
+    /// Fail every mutating operation with EROFS
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
 
-            // Download file content
-            let content = match api_client.download_file(&egnyte_path).await {
-                Ok(c) => c,
-                Err(_) => return Err(libc::EIO),
-            };
+    /// Mount the Egnyte folder `root` instead of the whole domain
+    pub fn root(mut self, root: &str) -> Self {
+        self.options.root = root.to_string();
+        self
+    }
 
-            // Store in open_files
-            {
-                let mut files = open_files.write().await;
-                files.insert(inode, content);
-            }
+    /// How long the kernel may cache a name's inode
+    pub fn entry_ttl(mut self, ttl: Duration) -> Self {
+        self.options.entry_ttl = ttl;
+        self
+    }
 
-            Ok(0)
-        });
+    /// How long the kernel may cache attributes
+    pub fn attr_ttl(mut self, ttl: Duration) -> Self {
+        self.options.attr_ttl = ttl;
+        self
+    }
 
-        match rt.block_on(handle) {
-            Ok(Ok(_)) => {
-                reply.opened(inode, 0);
-            }
-            _ => {
-                reply.error(libc::ENOENT);
-            }
+    /// How long the kernel may cache a name as nonexistent (zero: not at all)
+    pub fn negative_ttl(mut self, ttl: Duration) -> Self {
+        self.options.negative_ttl = ttl;
+        self
+    }
+
+    /// Owner, group and mode masks reported for every entry
+    pub fn attr_options(mut self, options: AttrOptions) -> Self {
+        self.options.attr_options = options;
+        self
+    }
+
+    /// Kernel readahead per file, in bytes
+    pub fn max_readahead(mut self, bytes: u32) -> Self {
+        self.options.max_readahead = bytes;
+        self
+    }
+
+    /// Largest write request to ask the kernel for, in bytes
+    pub fn max_write(mut self, bytes: u32) -> Self {
+        self.options.max_write = bytes;
+        self
+    }
+
+    /// Bypass the kernel page cache for file data
+    pub fn direct_io(mut self, direct_io: bool) -> Self {
+        self.options.direct_io = direct_io;
+        self
+    }
+
+    /// Block size reported in attributes, in bytes
+    pub fn block_size(mut self, bytes: u32) -> Self {
+        self.options.block_size = bytes;
+        self
+    }
+
+    /// Time one operation may spend on metadata requests and retries
+    pub fn op_deadline(mut self, deadline: Duration) -> Self {
+        self.options.op_deadline = deadline;
+        self
+    }
+
+    /// Names to answer ENOENT for without asking Egnyte
+    pub fn ignored_names(mut self, names: Vec<String>) -> Self {
+        self.options.ignored_names = names;
+        self
+    }
+
+    /// Resolve paths regardless of case
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.options.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Directory for the temporary files behind open-file buffers
+    pub fn buffer_dir(mut self, dir: PathBuf) -> Self {
+        self.options.buffer_dir = dir;
+        self
+    }
+
+    /// Directory to spool uploads that fail on close
+    pub fn spool_dir(mut self, dir: PathBuf) -> Self {
+        self.options.spool_dir = Some(dir);
+        self
+    }
+
+    /// Turn off every cache, for debugging or when nothing may be stale
+    pub fn caching_enabled(mut self, enabled: bool) -> Self {
+        self.options.caching_enabled = enabled;
+        self
+    }
+
+    /// Size from which files are read by range rather than downloaded on
+    /// open (`u64::MAX`: always download)
+    pub fn ranged_read_threshold(mut self, bytes: u64) -> Self {
+        self.options.ranged_read_threshold = bytes;
+        self
+    }
+
+    /// Create the filesystem, and its runtime unless one was given
+    pub fn build(self, api_client: Arc<dyn EgnyteAPI>) -> Result<EgnyteFuse> {
+        let rt = match self.runtime {
+            Some(rt) => rt,
+            None => Arc::new(Runtime::new().context("Failed to create Tokio runtime")?),
+        };
+        Ok(EgnyteFuse::with_runtime(api_client, rt, self.options))
+    }
+}
+
+/// FUSE filesystem implementation for Egnyte.
+///
+/// Threading: fuser calls the handlers on its session thread, and each one
+/// blocks on the Tokio runtime for its API calls. Background work
+/// (autoflush, change polling, upload retries) runs as tasks on that same
+/// runtime, which the binary also hands to the API client checks, the
+/// metrics server and the degraded-mode prober. There is one runtime per
+/// process; pass it in with [`EgnyteFuseBuilder::runtime`].
+pub struct EgnyteFuse {
+    /// Inode table for path <-> inode mapping
+    inode_table: Arc<InodeTable>,
+    /// Tokio runtime for async operations
+    rt: Arc<Runtime>,
+    /// API client
+    api_client: Arc<dyn EgnyteAPI>,
+    /// Open file handles (inode -> file data)
+    open_files: Arc<OpenFiles>,
+    /// Advisory POSIX locks, held in this process only
+    locks: LockTable,
+    /// Open directory handles (fh -> listing snapshot taken at opendir)
+    dir_handles: DashMap<u64, Arc<DirEntries>>,
+    /// Next directory handle number
+    next_dir_fh: AtomicU64,
+    /// Reject every mutating operation with EROFS
+    read_only: bool,
+    /// Ownership and mode overrides
+    attr_options: AttrOptions,
+    /// Directory for the temporary files behind open-file buffers
+    buffer_dir: PathBuf,
+    /// Where uploads that fail on close wait for a retry
+    spool: Option<Spool>,
+    /// Kernel cache invalidation, attached after mount
+    invalidator: Invalidator,
+    /// Egnyte folder shown as the root of the mount
+    root: String,
+    /// How long the kernel may cache names, and attributes from lookups
+    entry_ttl: Duration,
+    /// How long the kernel may cache attributes from getattr and setattr
+    attr_ttl: Duration,
+    /// How long the kernel may cache a name as nonexistent (zero: not at all)
+    negative_ttl: Duration,
+    /// Readahead and write sizes to ask the kernel for in `init`
+    max_readahead: u32,
+    max_write: u32,
+    /// Open files with FOPEN_DIRECT_IO so reads and writes bypass the page
+    /// cache
+    direct_io: bool,
+    /// Block size reported in attributes; sizes are counted in whole blocks
+    block_size: u32,
+    /// Time each operation may spend on metadata requests and retries
+    op_deadline: Duration,
+    /// Whether the metadata cache is read and written at all
+    caching_enabled: bool,
+    /// Files this large or larger are opened without their content
+    ranged_read_threshold: u64,
+    /// Budget of the operation being served (None before the first)
+    op_budget: std::sync::Mutex<Option<Arc<RetryBudget>>>,
+    /// Names looked up as missing without an API call
+    ignored_names: Vec<String>,
+    /// Owner reported for every entry (the override or the mounting user)
+    uid: u32,
+    /// Group reported for every entry (the override or the mounting user's group)
+    gid: u32,
+}
+
+impl EgnyteFuse {
+    /// Create a new EgnyteFuse filesystem with default options and a
+    /// runtime of its own
+    pub fn new(api_client: Arc<dyn EgnyteAPI>) -> Result<Self> {
+        EgnyteFuseBuilder::new().build(api_client)
+    }
+
+    /// Create a filesystem that runs its API calls on `rt`. FUSE handlers
+    /// block on the runtime, so they must not be called from one of its
+    /// tasks.
+    pub fn with_runtime(
+        api_client: Arc<dyn EgnyteAPI>,
+        rt: Arc<Runtime>,
+        options: FuseOptions,
+    ) -> Self {
+        // SAFETY: getuid/getgid have no preconditions and cannot fail
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+
+        let inode_table = Arc::new(InodeTable::with_case_insensitive(options.case_insensitive));
+
+        let fs = Self {
+            invalidator: Invalidator::new(Arc::clone(&inode_table)),
+            inode_table,
+            rt,
+            api_client,
+            open_files: Arc::new(DashMap::new()),
+            locks: LockTable::new(),
+            dir_handles: DashMap::new(),
+            next_dir_fh: AtomicU64::new(1),
+            read_only: options.read_only,
+            attr_options: options.attr_options,
+            buffer_dir: options.buffer_dir,
+            spool: options.spool_dir.map(Spool::new),
+            root: egnyte_path::normalize(&options.root),
+            entry_ttl: options.entry_ttl,
+            attr_ttl: options.attr_ttl,
+            negative_ttl: options.negative_ttl,
+            max_readahead: options.max_readahead,
+            max_write: options.max_write,
+            direct_io: options.direct_io,
+            block_size: options.block_size,
+            op_deadline: options.op_deadline,
+            caching_enabled: true,
+            ranged_read_threshold: options.ranged_read_threshold,
+            op_budget: std::sync::Mutex::new(None),
+            ignored_names: options.ignored_names,
+            uid: options.attr_options.uid.unwrap_or(uid),
+            gid: options.attr_options.gid.unwrap_or(gid),
+        };
+        fs.with_caching_enabled(options.caching_enabled)
+    }
+
+    /// Make the filesystem read-only: mutating operations fail with EROFS
+    /// without reaching the API.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Mount the Egnyte folder `root` instead of the whole domain. The kernel
+    /// still sees `/` as the top; nothing outside `root` is reachable.
+    pub fn with_root(mut self, root: &str) -> Self {
+        self.root = egnyte_path::normalize(root);
+        self
+    }
+
+    /// The Egnyte path a path in the mount stands for
+    fn to_egnyte_path(&self, path: &Path) -> String {
+        egnyte_path::under_root(&self.root, path)
+    }
+
+    /// Let the kernel remember for `ttl` which inode a name stands for, so
+    /// path walks skip lookups. fuser sends one TTL per entry reply, so the
+    /// attributes that come with a lookup, create or readdirplus are valid as
+    /// long; attributes fetched by getattr follow [`Self::with_attr_ttl`].
+    pub fn with_entry_ttl(mut self, ttl: Duration) -> Self {
+        self.entry_ttl = ttl;
+        self
+    }
+
+    /// Let the kernel cache attributes from getattr and setattr for `ttl`.
+    /// Longer cuts API calls on mostly static shares; shorter shows others'
+    /// changes sooner. Zero also turns off the metadata cache, so every
+    /// lookup, stat and open asks Egnyte.
+    pub fn with_attr_ttl(mut self, ttl: Duration) -> Self {
+        self.attr_ttl = ttl;
+        self
+    }
+
+    /// Let the kernel remember for `ttl` that a name does not exist, so
+    /// repeated stats of a missing path don't each reach the API. Zero
+    /// disables negative caching.
+    pub fn with_negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    /// Ask the kernel to read ahead up to `bytes` per file. Bigger helps on
+    /// high-latency links; the kernel may cap it. Files read by range are
+    /// also prefetched this far ahead of sequential reads.
+    pub fn with_max_readahead(mut self, bytes: u32) -> Self {
+        self.max_readahead = bytes;
+        self
+    }
+
+    /// Ask the kernel for write requests of up to `bytes`; the kernel may
+    /// cap it.
+    pub fn with_max_write(mut self, bytes: u32) -> Self {
+        self.max_write = bytes;
+        self
+    }
+
+    /// Open every file in direct-IO mode: the kernel neither caches file data
+    /// nor reads ahead, so each read reaches the filesystem. Saves memory on
+    /// huge files read once, at the cost of slower small or repeated reads.
+    pub fn with_direct_io(mut self, direct_io: bool) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    /// The master switch for caching. Off, every lookup, stat, listing and
+    /// open asks Egnyte: the metadata cache is neither read nor written, the
+    /// kernel TTLs are zero, and files are opened in direct-IO mode so the
+    /// page cache holds no content. Slow, but never stale, which helps when
+    /// reproducing bugs. Setting TTLs afterwards brings kernel caching back.
+    pub fn with_caching_enabled(mut self, enabled: bool) -> Self {
+        self.caching_enabled = enabled;
+        if !enabled {
+            self.entry_ttl = Duration::ZERO;
+            self.attr_ttl = Duration::ZERO;
+            self.negative_ttl = Duration::ZERO;
+            self.direct_io = true;
         }
+        self
     }
 
-    fn read(
-        &mut self,
-        _req: &Request<'_>,
-        inode: u64,
-        _fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData,
-    ) {
-        let open_files = Arc::clone(&self.open_files);
-        let rt = Arc::clone(&self.rt);
+    /// Whether API entries may be kept in, and answered from, the metadata
+    /// cache
+    fn caches_metadata(&self) -> bool {
+        self.caching_enabled && !self.attr_ttl.is_zero()
+    }
 
-        let handle = rt.spawn(async move {
-            let files = open_files.read().await;
-            let content = match files.get(&inode) {
-                Some(c) => c,
-                None => return Err(libc::EBADF),
-            };
+    /// Entry of `inode` from the metadata cache, if caching and still fresh
+    fn cached_entry(&self, inode: u64) -> Option<EgnyteEntry> {
+        if !self.caches_metadata() {
+            return None;
+        }
+        self.invalidator.metadata().get(inode, self.attr_ttl)
+    }
+
+    /// Start serving a new operation: its API calls share a fresh budget of
+    /// retries and time, whatever earlier operations used up
+    fn begin_op(&self) {
+        let budget = RetryBudget::new(self.op_deadline, DEFAULT_OP_RETRIES);
+        *self.op_budget.lock().unwrap() = Some(budget);
+    }
+
+    /// Run an API call within the current operation's budget
+    fn call<F: std::future::Future>(&self, future: F) -> F::Output {
+        let budget = self.op_budget.lock().unwrap().clone();
+        match budget {
+            Some(budget) => self.rt.block_on(budget.scope(future)),
+            None => self.rt.block_on(future),
+        }
+    }
+
+    /// Flags for replies to `open` and `create`
+    fn open_flags(&self) -> u32 {
+        if self.direct_io {
+            FOPEN_DIRECT_IO
+        } else {
+            0
+        }
+    }
 
-            let offset = offset as usize;
-            let size = size as usize;
-            let end = std::cmp::min(offset + size, content.len());
+    /// Handle for invalidating kernel caches. Take it before mounting and
+    /// attach the session's notifier to it once mounted.
+    pub fn invalidator(&self) -> Invalidator {
+        self.invalidator.clone()
+    }
+
+    /// Uploader sharing this filesystem's client, invalidator and root
+    fn uploader(&self) -> Uploader {
+        Uploader {
+            api_client: Arc::clone(&self.api_client),
+            invalidator: self.invalidator.clone(),
+            root: self.root.clone(),
+        }
+    }
 
-            if offset >= content.len() {
-                return Ok(Vec::new());
+    /// Every `interval`, upload open files that are dirty and have not been
+    /// written for at least `interval`, so long-held files don't lose edits
+    /// in a crash
+    pub fn start_autoflush(&self, interval: Duration) {
+        let uploader = self.uploader();
+        let open_files = Arc::clone(&self.open_files);
+        let inode_table = Arc::clone(&self.inode_table);
+        self.rt.spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                flush_idle(&uploader, &open_files, &inode_table, interval).await;
             }
+        });
+    }
+
+    /// Retry spooled uploads in the background until they go through; does
+    /// nothing without a spool directory
+    pub fn start_upload_retrier(&self) {
+        let Some(spool) = self.spool.clone() else {
+            return;
+        };
+        let invalidator = self.invalidator.clone();
+        let root = self.root.clone();
+        self.rt.spawn(
+            spool.run_retrier(Arc::clone(&self.api_client), move |remote_path| {
+                if let Some(path) = egnyte_path::to_fs_path(&root, remote_path) {
+                    invalidator.invalidate_path(&path);
+                }
+            }),
+        );
+    }
+
+    /// Poll Egnyte for remote changes every `interval` in the background,
+    /// invalidating kernel caches for changed paths
+    pub fn start_change_poller(&self, interval: Duration) {
+        let poller = ChangePoller::new(
+            Arc::clone(&self.api_client),
+            self.invalidator.clone(),
+            &self.root,
+            interval,
+        );
+        self.rt.spawn(poller.run());
+    }
+
+    /// Keep open-file buffers in `dir` instead of the default cache directory
+    pub fn with_buffer_dir(mut self, dir: PathBuf) -> Self {
+        self.buffer_dir = dir;
+        self
+    }
 
-            Ok(content[offset..end].to_vec())
+    /// Open-file buffer holding `data`
+    fn new_buffer(&self, data: &[u8]) -> Result<FileBuffer, libc::c_int> {
+        FileBuffer::with_contents(&self.buffer_dir, data).map_err(|e| {
+            warn!(
+                "Could not create file buffer in {}: {}",
+                self.buffer_dir.display(),
+                e
+            );
+            libc::EIO
+        })
+    }
+
+    /// Report entries with the given owner and mode masks instead of the
+    /// mounting user and the default modes
+    pub fn with_attr_options(mut self, options: AttrOptions) -> Self {
+        self.attr_options = options;
+        if let Some(uid) = options.uid {
+            self.uid = uid;
+        }
+        if let Some(gid) = options.gid {
+            self.gid = gid;
+        }
+        self
+    }
+
+    /// The open file of `inode`, if it is open
+    fn open_file(&self, inode: u64) -> Option<Arc<Mutex<OpenFile>>> {
+        self.open_files.get(&inode).map(|file| Arc::clone(&file))
+    }
+
+    /// Get file attributes for a path. A file with unflushed writes reports
+    /// its local size, modified now, without asking the API.
+    fn get_attr_internal(&self, path: &Path) -> Result<FileAttr> {
+        let egnyte_path = self.to_egnyte_path(path);
+        let inode = self.inode_table.get_or_create_inode(path);
+        let unsaved_size = self.open_file(inode).and_then(|file| {
+            let file = self.rt.block_on(file.lock());
+            file.dirty.then(|| file.buffer.len())
         });
+        if let Some(size) = unsaved_size {
+            return Ok(self.entry_to_attr(inode, &local_entry(path, egnyte_path, size)));
+        }
+
+        let entry = self
+            .fetch_entry(inode, &egnyte_path)
+            .context("Failed to get file info")?;
+        Ok(self.entry_to_attr(inode, &entry))
+    }
+
+    /// Current entry of `inode` from the API, remembered. An entry cached
+    /// before, even past its TTL, is revalidated when it came with
+    /// validators, so an unchanged entry costs a 304 rather than a full
+    /// answer.
+    fn fetch_entry(&self, inode: u64, remote_path: &str) -> Result<EgnyteEntry> {
+        let known = self
+            .caches_metadata()
+            .then(|| self.invalidator.metadata().get_stale(inode))
+            .flatten()
+            .filter(|entry| !entry.validators.is_empty());
+        let entry = match known {
+            Some(known) => self
+                .call(self.api_client.file_info_if_changed(remote_path, &known))?
+                .unwrap_or(known),
+            None => self.call(self.api_client.get_file_info(remote_path))?,
+        };
+        self.remember(inode, &entry);
+        Ok(entry)
+    }
 
-        match rt.block_on(handle) {
-            Ok(Ok(data)) => {
-                reply.data(&data);
+    /// Kernel capabilities to ask for in `init`. Without any of them the
+    /// mount still works, only slower.
+    fn wanted_capabilities(&self) -> Vec<(&'static str, u32)> {
+        let mut wanted = vec![
+            // Use readdirplus when the kernel wants attributes too
+            ("readdirplus", FUSE_DO_READDIRPLUS),
+            ("readdirplus_auto", FUSE_READDIRPLUS_AUTO),
+            // Lookups and readdirs in one folder need not wait on each other
+            ("parallel_dirops", FUSE_PARALLEL_DIROPS),
+            // Send fcntl locks here, to the lock table
+            ("posix_locks", FUSE_POSIX_LOCKS),
+        ];
+        if !self.read_only {
+            // Buffer writes in the page cache and send them in large chunks
+            wanted.push(("writeback_cache", FUSE_WRITEBACK_CACHE));
+            // Clearing setuid/setgid on writes is ours (see perm_bits), so
+            // the kernel sends no setattr for it before each write. Kernels
+            // without it send that setattr; see kill_privileges_internal.
+            wanted.push(("handle_killpriv", FUSE_HANDLE_KILLPRIV));
+        }
+        wanted
+    }
+
+    /// Look up `name` in a folder. ENOENT means the API says it does not
+    /// exist, or the name is ignored; other failures map to their own errno
+    /// so they aren't cached as missing.
+    fn lookup_internal(&self, parent: u64, name: &OsStr) -> Result<FileAttr, libc::c_int> {
+        if is_ignored_name(name, &self.ignored_names) {
+            return Err(libc::ENOENT);
+        }
+        let parent_path = self.inode_table.get_path(parent).ok_or(libc::ENOENT)?;
+        // A name that isn't UTF-8 cannot exist on Egnyte
+        let path = child_path(&parent_path, name).map_err(|_| libc::ENOENT)?;
+        if let Some(attr) = self.cached_attr(&path) {
+            return Ok(attr);
+        }
+        // Siblings looked up one after another (a file manager filling in a
+        // view) are answered from one listing of the folder, if it is cached
+        let burst = self.caches_metadata()
+            && self.invalidator.metadata().burst_of_misses(
+                parent,
+                LOOKUP_BURST,
+                LOOKUP_BURST_WINDOW,
+                self.attr_ttl,
+            );
+        if burst && self.readdir_internal(&parent_path).is_ok() {
+            if let Some(attr) = self.cached_attr(&path) {
+                return Ok(attr);
             }
-            _ => {
-                reply.error(libc::EBADF);
+        }
+
+        let entry = self
+            .call(self.api_client.get_file_info(&self.to_egnyte_path(&path)))
+            .map_err(|e| error::errno(&e))?;
+        let inode = self.inode_table.get_or_create_inode(&path);
+        self.remember(inode, &entry);
+        Ok(self.entry_to_attr(inode, &entry))
+    }
+
+    /// Keep `entry` in the metadata cache, unless attributes are not to be
+    /// cached at all (caching off, or a zero attribute TTL: every stat asks
+    /// Egnyte)
+    fn remember(&self, inode: u64, entry: &EgnyteEntry) {
+        if self.caches_metadata() {
+            self.invalidator.metadata().insert(inode, entry);
+        }
+    }
+
+    /// Attributes of `path` from the metadata cache, if still fresh
+    fn cached_attr(&self, path: &Path) -> Option<FileAttr> {
+        let inode = self.inode_table.get_inode(path)?;
+        let entry = self.cached_entry(inode)?;
+        Some(self.entry_to_attr(inode, &entry))
+    }
+
+    /// Attributes for a negative entry; only the zero inode matters
+    fn negative_attr(&self) -> FileAttr {
+        let placeholder = local_entry(Path::new("/"), String::new(), 0);
+        self.entry_to_attr(0, &placeholder)
+    }
+
+    /// Current attributes of an inode, as getattr reports them: the local
+    /// size of unflushed writes, else the entry revalidated against the
+    /// metadata cache
+    fn stat_internal(&self, inode: u64) -> Result<FileAttr, libc::c_int> {
+        let path = self.inode_table.get_path(inode).ok_or(libc::ENOENT)?;
+        self.get_attr_internal(&path).map_err(|e| error::errno(&e))
+    }
+
+    /// Build file attributes from an API entry
+    fn entry_to_attr(&self, inode: u64, entry: &EgnyteEntry) -> FileAttr {
+        let file_type = if entry.is_folder {
+            FileType::Directory
+        } else {
+            FileType::RegularFile
+        };
+
+        FileAttr {
+            ino: inode,
+            size: entry.size,
+            blocks: blocks(entry.size, self.block_size),
+            atime: entry.modified_time,
+            mtime: entry.modified_time,
+            ctime: entry.modified_time,
+            crtime: entry.modified_time,
+            kind: file_type,
+            perm: perm_bits(entry, &self.attr_options),
+            nlink: 1,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: self.block_size,
+        }
+    }
+
+    /// Read directory entries
+    fn readdir_internal(&self, path: &Path) -> Result<DirEntries> {
+        let egnyte_path = self.to_egnyte_path(path);
+        let entries = self
+            .call(self.api_client.list_folder(&egnyte_path))
+            .context("Failed to list folder")?;
+
+        let mut result = Vec::new();
+
+        // Add "." and ".." entries
+        let current_inode = self.inode_table.get_or_create_inode(path);
+        result.push(DirEntry {
+            ino: current_inode,
+            kind: FileType::Directory,
+            name: ".".to_string(),
+            attr: None,
+        });
+
+        let parent_inode = match path.parent() {
+            Some(parent) => self.inode_table.get_or_create_inode(parent),
+            None => current_inode,
+        };
+        result.push(DirEntry {
+            ino: parent_inode,
+            kind: FileType::Directory,
+            name: "..".to_string(),
+            attr: None,
+        });
+
+        // Add directory entries; one with an unusable name must not take the
+        // rest of the folder with it
+        for mut entry in entries {
+            let Some(name) = listing_name(&entry.name) else {
+                warn!(
+                    "Skipping entry named {:?} in {}: not a valid file name",
+                    entry.name, egnyte_path
+                );
+                continue;
+            };
+            if name != entry.name {
+                warn!(
+                    "Listing {} as {:?} in {}: names cannot contain '/'",
+                    entry.name, name, egnyte_path
+                );
+                entry.name = name.to_string();
             }
+            let entry_path = path.join(&entry.name);
+            let inode = self.inode_table.get_or_create_inode(&entry_path);
+            self.remember(inode, &entry);
+            let attr = self.entry_to_attr(inode, &entry);
+
+            result.push(DirEntry {
+                ino: inode,
+                kind: attr.kind,
+                name: entry.name,
+                attr: Some(attr),
+            });
         }
+
+        Ok(result)
     }
 
-    fn release(
-        &mut self,
-        _req: &Request<'_>,
-        inode: u64,
-        _fh: u64,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        _flush: bool,
-        reply: fuser::ReplyEmpty,
-    ) {
-        let open_files = Arc::clone(&self.open_files);
-        let rt = Arc::clone(&self.rt);
+    /// Listing snapshot for a directory handle, or a fresh listing without one
+    fn dir_snapshot(&self, inode: u64, fh: u64) -> Result<Arc<DirEntries>, libc::c_int> {
+        if let Some(entries) = self.dir_handles.get(&fh) {
+            return Ok(Arc::clone(&entries));
+        }
+
+        // No handle from opendir: list directly
+        let path = self.inode_table.get_path(inode).ok_or(libc::ENOENT)?;
+        self.readdir_internal(&path)
+            .map(Arc::new)
+            .map_err(|e| error::errno(&e))
+    }
+
+    /// Answer an access(2) probe from what is already known about the inode,
+    /// without asking the API: W_OK fails on a read-only mount, and with
+    /// EACCES when the cached entry says the user may only view it
+    fn access_internal(&self, inode: u64, mask: i32) -> Result<(), libc::c_int> {
+        self.inode_table.get_path(inode).ok_or(libc::ENOENT)?;
+        if mask & libc::W_OK != 0 {
+            self.check_writable()?;
+            let permission = self.cached_entry(inode).and_then(|entry| entry.permission);
+            if permission.is_some_and(|p| !p.can_write()) {
+                return Err(libc::EACCES);
+            }
+        }
+        Ok(())
+    }
 
-        let handle: tokio::task::JoinHandle<Result<(), libc::c_int>> = rt.spawn(async move {
-            let mut files = open_files.write().await;
-            files.remove(&inode);
+    /// Fail with EROFS on a read-only mount
+    fn check_writable(&self) -> Result<(), libc::c_int> {
+        if self.read_only {
+            Err(libc::EROFS)
+        } else {
             Ok(())
-        });
+        }
+    }
+
+    // Mutating operations. Each is rejected up front on a read-only mount;
+    // those without write support yet answer ENOSYS like the fuser defaults.
+    // After a change the kernel is told to drop what it cached about the
+    // paths involved, rather than serving it until the TTL runs out.
+
+    /// A setattr changing only the mode, which Egnyte has none of. Accepted
+    /// when it leaves the mode as it is without setuid/setgid, as the kernel
+    /// asks before a write when it is not leaving that to us; anything else
+    /// is ENOSYS.
+    fn kill_privileges_internal(&self, inode: u64, mode: u32) -> Result<FileAttr, libc::c_int> {
+        let attr = self.stat_internal(inode)?;
+        let privileges = libc::S_ISUID | libc::S_ISGID;
+        if mode & 0o7777 == u32::from(attr.perm) & !privileges {
+            Ok(attr)
+        } else {
+            Err(libc::ENOSYS)
+        }
+    }
+
+    /// Write data into an open file's buffer; it is uploaded on flush
+    fn write_internal(&self, inode: u64, offset: i64, data: &[u8]) -> Result<u32, libc::c_int> {
+        self.check_writable()?;
+        let offset = u64::try_from(offset).map_err(|_| libc::EINVAL)?;
+
+        let file = self.open_file(inode).ok_or(libc::EBADF)?;
+        self.load_content(inode, &file, u64::MAX)?;
+        let mut file = self.rt.block_on(file.lock());
+        file.buffer
+            .write_at(offset, data)
+            .map_err(|e| buffer_errno(&e))?;
+        file.mark_dirty();
+        Ok(data.len() as u32)
+    }
+
+    /// Upload an open file if it has unsaved writes; see [`Uploader::flush`]
+    fn flush_internal(&self, inode: u64) -> Result<(), libc::c_int> {
+        let path = self.inode_table.get_path(inode).ok_or(libc::ENOENT)?;
+        let Some(file) = self.open_file(inode) else {
+            return Ok(());
+        };
+        let mut file = self.rt.block_on(file.lock());
+        self.call(self.uploader().flush(inode, &path, &mut file))
+    }
+
+    /// Upload a whole file, in parts once it is past the chunking threshold
+    fn upload(&self, remote_path: &str, data: &[u8]) -> Result<Option<String>> {
+        self.call(self.uploader().upload(remote_path, data))
+    }
+
+    /// Close a handle of an open file, uploading it first if it has unsaved
+    /// writes. The buffer goes with the last handle.
+    ///
+    /// A failed upload fails with its errno and hands the data to the spool, for
+    /// the background retrier. Without a spool, or if spooling fails too,
+    /// the buffer stays in place, still dirty, for the next open, fsync or
+    /// autoflush to retry; dirty data is never dropped on close.
+    fn release_internal(&self, inode: u64) -> Result<(), libc::c_int> {
+        let Some(shared) = self.open_file(inode) else {
+            return Ok(());
+        };
+        let mut file = self.rt.block_on(shared.lock());
+        file.handles = file.handles.saturating_sub(1);
+        let result = match self.inode_table.get_path(inode) {
+            Some(path) => self.call(self.uploader().flush(inode, &path, &mut file)),
+            None => Err(libc::EIO),
+        };
+        if let Err(errno) = result {
+            if file.dirty {
+                warn!(
+                    "Upload of inode {} failed on close (errno {}); keeping its changes",
+                    inode, errno
+                );
+                if self.spool_file(inode, &file) {
+                    file.dirty = false;
+                }
+            }
+        }
+        if file.handles == 0 && !file.dirty {
+            // Only if it is still this file, not one created over it meanwhile
+            self.open_files
+                .remove_if(&inode, |_, open| Arc::ptr_eq(open, &shared));
+        }
+        result
+    }
 
-        match rt.block_on(handle) {
-            Ok(Ok(_)) => {
-                reply.ok();
+    /// Save an open file's content to the spool; false if there is no spool
+    /// or it could not be written. A file that diverged is spooled for its
+    /// conflict copy.
+    fn spool_file(&self, inode: u64, file: &OpenFile) -> bool {
+        let (Some(spool), Some(path)) = (&self.spool, self.inode_table.get_path(inode)) else {
+            return false;
+        };
+        let (remote_path, base_entry_id) = match &file.conflict_copy {
+            Some(copy) => (self.to_egnyte_path(copy), None),
+            None => (self.to_egnyte_path(&path), file.base_entry_id.as_deref()),
+        };
+        let saved = file
+            .buffer
+            .contents()
+            .and_then(|data| spool.save(&remote_path, base_entry_id, &data));
+        match saved {
+            Ok(_) => {
+                info!("Spooled {} for a later upload", remote_path);
+                true
             }
-            _ => {
-                reply.error(libc::EBADF);
+            Err(e) => {
+                warn!(
+                    "Cannot spool {} in {}: {}",
+                    remote_path,
+                    spool.dir().display(),
+                    e
+                );
+                false
             }
         }
     }
-}
 
+    /// Take the spooled changes to `remote_path` back into an open-file
+    /// buffer, so reopening a file shows what was written rather than the
+    /// older remote content
+    fn unspool(&self, remote_path: &str) -> Result<Option<OpenFile>, libc::c_int> {
+        let Some(spool) = &self.spool else {
+            return Ok(None);
+        };
+        let Some(pending) = spool.find(remote_path).map_err(|_| libc::EIO)? else {
+            return Ok(None);
+        };
+        let data = spool.data(&pending).map_err(|_| libc::EIO)?;
+        let mut file = OpenFile::new(self.new_buffer(&data)?, pending.base_entry_id.clone());
+        file.mark_dirty();
+        spool.remove(&pending).map_err(|_| libc::EIO)?;
+        Ok(Some(file))
+    }
+
+    /// Create an empty file on Egnyte; returns its inode, its path in the
+    /// mount and its version
+    fn create_empty(
+        &self,
+        parent: u64,
+        name: &OsStr,
+    ) -> Result<(u64, PathBuf, Option<String>), libc::c_int> {
+        self.check_writable()?;
+        let parent_path = self.inode_table.get_path(parent).ok_or(libc::ENOENT)?;
+        let path = child_path(&parent_path, name)?;
+        let entry_id = self
+            .call(
+                self.api_client
+                    .upload_file(&self.to_egnyte_path(&path), &[]),
+            )
+            .map_err(|e| error::errno(&e))?;
+        let inode = self.inode_table.get_or_create_inode(&path);
+        Ok((inode, path, entry_id))
+    }
+
+    /// Create a node with `mode`: an empty regular file, like `create`
+    /// without opening it. FIFOs, sockets and devices have no Egnyte
+    /// equivalent.
+    fn mknod_internal(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+    ) -> Result<FileAttr, libc::c_int> {
+        // A type of zero means a regular file, as in mknod(2)
+        let kind = mode & libc::S_IFMT;
+        if kind != 0 && kind != libc::S_IFREG {
+            return Err(unsupported("mknod", libc::EPERM));
+        }
+        let (inode, path, _) = self.create_empty(parent, name)?;
+        let remote_path = self.to_egnyte_path(&path);
+        Ok(self.entry_to_attr(inode, &local_entry(&path, remote_path, 0)))
+    }
+
+    /// Create and open a new, empty file
+    fn create_internal(&self, parent: u64, name: &OsStr) -> Result<FileAttr, libc::c_int> {
+        let (inode, path, entry_id) = self.create_empty(parent, name)?;
+        let remote_path = self.to_egnyte_path(&path);
+        let buffer = self.new_buffer(&[])?;
+        self.open_files
+            .insert(inode, shared(OpenFile::new(buffer, entry_id)));
+        Ok(self.entry_to_attr(inode, &local_entry(&path, remote_path, 0)))
+    }
+
+    /// Hard link `inode` as `newname`. Egnyte has no hard links, so this is
+    /// a server-side copy with an inode of its own: tools that only need
+    /// link(2) to succeed (archivers, mail spools) carry on, but a change
+    /// through one name does not show through the other.
+    fn link_internal(
+        &self,
+        inode: u64,
+        newparent: u64,
+        newname: &OsStr,
+    ) -> Result<FileAttr, libc::c_int> {
+        static WARNED: std::sync::Once = std::sync::Once::new();
+
+        self.check_writable()?;
+        let from = self.inode_table.get_path(inode).ok_or(libc::ENOENT)?;
+        let newparent_path = self.inode_table.get_path(newparent).ok_or(libc::ENOENT)?;
+        let to = child_path(&newparent_path, newname)?;
+        let attr = self
+            .get_attr_internal(&from)
+            .map_err(|e| error::errno(&e))?;
+        if attr.kind == FileType::Directory {
+            return Err(libc::EPERM);
+        }
+        // What is copied is what Egnyte has
+        self.flush_internal(inode)?;
+
+        self.call(
+            self.api_client
+                .copy_entry(&self.to_egnyte_path(&from), &self.to_egnyte_path(&to)),
+        )
+        .map_err(|e| error::errno(&e))?;
+        WARNED.call_once(|| {
+            warn!(
+                "Egnyte has no hard links: link({}, {}) made a copy, and so will later links",
+                from.display(),
+                to.display()
+            )
+        });
+        let linked = self.inode_table.get_or_create_inode(&to);
+        Ok(FileAttr {
+            ino: linked,
+            ..attr
+        })
+    }
+
+    /// Copy up to `len` bytes between open files, like copy_file_range(2).
+    /// A whole file copied into an empty one is copied by Egnyte, so the
+    /// bytes are not uploaded again; see [`Self::copy_whole_file`]. Any
+    /// other range is read from one buffer and written to the other, and
+    /// uploaded on flush.
+    fn copy_file_range_internal(
+        &self,
+        inode_in: u64,
+        offset_in: i64,
+        inode_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+    ) -> Result<u32, libc::c_int> {
+        self.check_writable()?;
+        if flags != 0 {
+            return Err(libc::EINVAL);
+        }
+        let offset_in = u64::try_from(offset_in).map_err(|_| libc::EINVAL)?;
+        let source = self.open_file(inode_in).ok_or(libc::EBADF)?;
+        self.open_file(inode_out).ok_or(libc::EBADF)?;
+        // The reply counts at most u32::MAX bytes
+        let len = len.min(u64::from(u32::MAX));
+
+        if inode_in != inode_out && offset_in == 0 && offset_out == 0 {
+            if let Some(copied) = self.copy_whole_file(inode_in, inode_out, len)? {
+                return Ok(copied);
+            }
+        }
+        self.fetch_range(inode_in, &source, offset_in, len)?;
+        let data = self
+            .rt
+            .block_on(source.lock())
+            .buffer
+            .read_at(offset_in, len as usize)
+            .map_err(|_| libc::EIO)?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        self.write_internal(inode_out, offset_out, &data)
+    }
+
+    /// Have Egnyte copy open file `inode_in` over open file `inode_out`, if
+    /// `len` covers all of the first and the second is empty; None if not.
+    /// Neither file is downloaded: the destination's buffer is opened over
+    /// the copy like a large file, its blocks fetched as they are read.
+    fn copy_whole_file(
+        &self,
+        inode_in: u64,
+        inode_out: u64,
+        len: u64,
+    ) -> Result<Option<u32>, libc::c_int> {
+        let source = self.open_file(inode_in).ok_or(libc::EBADF)?;
+        let dest = self.open_file(inode_out).ok_or(libc::EBADF)?;
+        let size = self.rt.block_on(source.lock()).buffer.len();
+        if size == 0 || len < size || !self.rt.block_on(dest.lock()).buffer.is_empty() {
+            return Ok(None);
+        }
+        let from = self.inode_table.get_path(inode_in).ok_or(libc::ENOENT)?;
+        let to = self.inode_table.get_path(inode_out).ok_or(libc::ENOENT)?;
+        let (remote_from, remote_to) = (self.to_egnyte_path(&from), self.to_egnyte_path(&to));
+        // What is copied is what Egnyte has
+        self.flush_internal(inode_in)?;
+
+        // The empty destination is on Egnyte already (created on open), and
+        // Egnyte does not copy over an entry
+        match self.call(self.api_client.delete_entry(&remote_to)) {
+            Err(e) if error::errno(&e) != libc::ENOENT => return Err(error::errno(&e)),
+            _ => {}
+        }
+        let copied = self.call(self.api_client.copy_entry(&remote_from, &remote_to));
+        let mut dest = self.rt.block_on(dest.lock());
+        if let Err(e) = copied {
+            // Closing the destination puts back the empty file just deleted
+            dest.mark_dirty();
+            return Err(error::errno(&e));
+        }
+        // The version blocks are read from, and conflicts checked against on
+        // the next upload
+        let entry_id = self
+            .call(self.api_client.get_file_info(&remote_to))
+            .ok()
+            .and_then(|entry| entry.entry_id);
+        dest.buffer.set_len(size).map_err(|e| buffer_errno(&e))?;
+        dest.ranged = Some(Arc::new(Ranged::new(size, entry_id.clone())));
+        dest.base_entry_id = entry_id;
+        dest.dirty = false;
+        self.invalidator.invalidate_path(&to);
+        Ok(Some(size as u32))
+    }
+
+    /// Egnyte entry id of a file, fresh from the metadata cache or else
+    /// from the API; ENODATA for folders and files Egnyte has no id for
+    fn entry_id_internal(&self, inode: u64) -> Result<String, libc::c_int> {
+        let entry = match self.cached_entry(inode) {
+            Some(entry) => entry,
+            None => {
+                let path = self.inode_table.get_path(inode).ok_or(libc::ENOENT)?;
+                let entry = self
+                    .call(self.api_client.get_file_info(&self.to_egnyte_path(&path)))
+                    .map_err(|e| error::errno(&e))?;
+                self.remember(inode, &entry);
+                entry
+            }
+        };
+        entry
+            .entry_id
+            .filter(|_| !entry.is_folder)
+            .ok_or(libc::ENODATA)
+    }
+
+    /// Value of extended attribute `name`; only [`ENTRY_ID_XATTR`] has one
+    fn getxattr_internal(&self, inode: u64, name: &OsStr) -> Result<Vec<u8>, libc::c_int> {
+        if name != ENTRY_ID_XATTR {
+            return Err(libc::ENODATA);
+        }
+        self.entry_id_internal(inode).map(String::into_bytes)
+    }
+
+    /// Names of the extended attributes `inode` has, each NUL-terminated
+    fn listxattr_internal(&self, inode: u64) -> Result<Vec<u8>, libc::c_int> {
+        match self.entry_id_internal(inode) {
+            Ok(_) => Ok(format!("{}\0", ENTRY_ID_XATTR).into_bytes()),
+            Err(libc::ENODATA) => Ok(Vec::new()),
+            Err(errno) => Err(errno),
+        }
+    }
+
+    /// Create a folder: not supported yet, so ENOSYS (EROFS on a read-only
+    /// mount, like every other change)
+    fn mkdir_internal(&self, _parent: u64, _name: &OsStr) -> Result<FileAttr, libc::c_int> {
+        self.check_writable()?;
+        Err(unsupported("mkdir", libc::ENOSYS))
+    }
+
+    /// Delete a file
+    fn unlink_internal(&self, parent: u64, name: &OsStr) -> Result<(), libc::c_int> {
+        self.check_writable()?;
+        let parent_path = self.inode_table.get_path(parent).ok_or(libc::ENOENT)?;
+        let path = child_path(&parent_path, name)?;
+
+        self.call(self.api_client.delete_entry(&self.to_egnyte_path(&path)))
+            .map_err(|e| error::errno(&e))?;
+        self.invalidator.invalidate_path(&path);
+        Ok(())
+    }
+
+    /// Delete an empty folder. Egnyte deletes folders recursively, so
+    /// emptiness is checked first.
+    fn rmdir_internal(&self, parent: u64, name: &OsStr) -> Result<(), libc::c_int> {
+        self.check_writable()?;
+        let parent_path = self.inode_table.get_path(parent).ok_or(libc::ENOENT)?;
+        let path = child_path(&parent_path, name)?;
+        let remote_path = self.to_egnyte_path(&path);
+
+        let children = self
+            .call(self.api_client.list_folder(&remote_path))
+            .map_err(|e| error::errno(&e))?;
+        if !children.is_empty() {
+            return Err(libc::ENOTEMPTY);
+        }
+        self.call(self.api_client.delete_entry(&remote_path))
+            .map_err(|e| error::errno(&e))?;
+        self.invalidator.invalidate_path(&path);
+        Ok(())
+    }
+
+    /// Move or rename an entry. Egnyte refuses to move onto an existing
+    /// entry; with RENAME_NOREPLACE that refusal is the answer (EEXIST, with
+    /// no window between checking and moving), and otherwise the destination
+    /// is replaced as rename(2) would, see [`Self::replace_entry`].
+    /// RENAME_EXCHANGE has no Egnyte equivalent (two moves would not be
+    /// atomic) and, like unknown flags, fails with EINVAL.
+    fn rename_internal(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<(), libc::c_int> {
+        self.check_writable()?;
+        if flags & !libc::RENAME_NOREPLACE != 0 {
+            return Err(libc::EINVAL);
+        }
+        let parent_path = self.inode_table.get_path(parent).ok_or(libc::ENOENT)?;
+        let newparent_path = self.inode_table.get_path(newparent).ok_or(libc::ENOENT)?;
+        let from = child_path(&parent_path, name)?;
+        let to = child_path(&newparent_path, newname)?;
+        let remote_from = self.to_egnyte_path(&from);
+        let remote_to = self.to_egnyte_path(&to);
+
+        let moved = self.call(self.api_client.move_entry(&remote_from, &remote_to));
+        match moved {
+            Err(e) if error::errno(&e) == libc::EEXIST && flags & libc::RENAME_NOREPLACE == 0 => {
+                self.replace_entry(&remote_from, &remote_to)?
+            }
+            result => result.map_err(|e| error::errno(&e))?,
+        }
+
+        // Both folders' listings changed. The destination is invalidated
+        // while it still resolves to the inode it replaced.
+        self.invalidator.invalidate_path(&to);
+        self.inode_table.rename(&from, &to);
+        self.invalidator.invalidate_path(&from);
+        // The moved inode's cached entry still has the old path
+        if let Some(moved) = self.inode_table.get_inode(&to) {
+            self.invalidator.metadata().remove(moved);
+        }
+        Ok(())
+    }
+
+    /// Move `remote_from` over the existing `remote_to`, as rename(2) does:
+    /// a file may replace a file and a folder an empty folder; otherwise
+    /// EISDIR, ENOTDIR or ENOTEMPTY. The destination is moved aside first
+    /// and only deleted once the move succeeded, so a failed move leaves
+    /// both where they were.
+    fn replace_entry(&self, remote_from: &str, remote_to: &str) -> Result<(), libc::c_int> {
+        let source = self
+            .call(self.api_client.get_file_info(remote_from))
+            .map_err(|e| error::errno(&e))?;
+        let dest = self
+            .call(self.api_client.get_file_info(remote_to))
+            .map_err(|e| error::errno(&e))?;
+        match (source.is_folder, dest.is_folder) {
+            (false, true) => return Err(libc::EISDIR),
+            (true, false) => return Err(libc::ENOTDIR),
+            (true, true) => {
+                let children = self
+                    .call(self.api_client.list_folder(remote_to))
+                    .map_err(|e| error::errno(&e))?;
+                if !children.is_empty() {
+                    return Err(libc::ENOTEMPTY);
+                }
+            }
+            (false, false) => {}
+        }
+
+        let aside = format!(
+            "{}.egnyte-fuse-replaced-{}-{}",
+            remote_to,
+            std::process::id(),
+            unix_time()
+        );
+        self.call(self.api_client.move_entry(remote_to, &aside))
+            .map_err(|e| error::errno(&e))?;
+        if let Err(e) = self.call(self.api_client.move_entry(remote_from, remote_to)) {
+            if let Err(restore) = self.call(self.api_client.move_entry(&aside, remote_to)) {
+                warn!(
+                    "Failed to put {} back after a failed rename onto it; it is at {}: {:#}",
+                    remote_to, aside, restore
+                );
+            }
+            return Err(error::errno(&e));
+        }
+        if let Err(e) = self.call(self.api_client.delete_entry(&aside)) {
+            warn!(
+                "Replaced {}, but failed to delete the old one at {}: {:#}",
+                remote_to, aside, e
+            );
+        }
+        Ok(())
+    }
+
+    /// Reserve space in an open file, like posix_fallocate. The buffer grows
+    /// to cover the range and the new size is uploaded on flush; with
+    /// FALLOC_FL_KEEP_SIZE only local space is reserved. Modes that punch or
+    /// collapse ranges have no Egnyte equivalent and fail with EOPNOTSUPP.
+    fn fallocate_internal(
+        &self,
+        inode: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+    ) -> Result<(), libc::c_int> {
+        self.check_writable()?;
+        if mode & !libc::FALLOC_FL_KEEP_SIZE != 0 {
+            return Err(libc::EOPNOTSUPP);
+        }
+        let offset = u64::try_from(offset).map_err(|_| libc::EINVAL)?;
+        let length = u64::try_from(length)
+            .ok()
+            .filter(|length| *length > 0)
+            .ok_or(libc::EINVAL)?;
+        let keep_size = mode & libc::FALLOC_FL_KEEP_SIZE != 0;
+
+        let file = self.open_file(inode).ok_or(libc::EBADF)?;
+        self.load_content(inode, &file, u64::MAX)?;
+        let mut file = self.rt.block_on(file.lock());
+        let old_len = file.buffer.len();
+        file.buffer
+            .allocate(offset, length, keep_size)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        if file.buffer.len() != old_len {
+            file.mark_dirty();
+        }
+        Ok(())
+    }
+
+    /// Answer SEEK_DATA and SEEK_HOLE probes. Files are never reported as
+    /// sparse: data runs from any offset to the end, where the only hole
+    /// starts. As in lseek(2), an offset at or past the end fails with ENXIO.
+    fn lseek_internal(&self, inode: u64, offset: i64, whence: i32) -> Result<i64, libc::c_int> {
+        if whence != libc::SEEK_DATA && whence != libc::SEEK_HOLE {
+            return Err(libc::EINVAL);
+        }
+        let offset = u64::try_from(offset).map_err(|_| libc::EINVAL)?;
+        let size = match self.open_file(inode) {
+            Some(file) => self.rt.block_on(file.lock()).buffer.len(),
+            None => self.stat_internal(inode)?.size,
+        };
+        if offset >= size {
+            return Err(libc::ENXIO);
+        }
+        let found = if whence == libc::SEEK_DATA {
+            offset
+        } else {
+            size
+        };
+        i64::try_from(found).map_err(|_| libc::EINVAL)
+    }
+
+    /// Change the size of a file. An open file is resized in its buffer and
+    /// uploaded on flush; a closed one is rewritten right away.
+    fn truncate_internal(&self, inode: u64, size: u64) -> Result<FileAttr, libc::c_int> {
+        self.check_writable()?;
+        let path = self.inode_table.get_path(inode).ok_or(libc::ENOENT)?;
+        let remote_path = self.to_egnyte_path(&path);
+        let new_len = usize::try_from(size).map_err(|_| libc::EFBIG)?;
+
+        if let Some(file) = self.open_file(inode) {
+            // Only what is kept needs downloading
+            self.load_content(inode, &file, size)?;
+            let mut file = self.rt.block_on(file.lock());
+            file.buffer.set_len(size).map_err(|e| buffer_errno(&e))?;
+            file.mark_dirty();
+        } else {
+            let mut data = if size == 0 {
+                Vec::new()
+            } else {
+                self.call(self.api_client.download_file(&remote_path))
+                    .map_err(|e| error::errno(&e))?
+            };
+            data.resize(new_len, 0);
+            self.upload(&remote_path, &data)
+                .map_err(|e| error::errno(&e))?;
+        }
+        self.invalidator.invalidate_inode(inode);
+        Ok(self.entry_to_attr(inode, &local_entry(&path, remote_path, size)))
+    }
+
+    /// Open a file, downloading its content into an open-file buffer, and
+    /// return its handle. Folders get handle 0 and no buffer. Whether the
+    /// inode is a folder comes from the metadata cache when a recent lookup
+    /// or listing left it there. A file of at least the ranged-read threshold
+    /// is not downloaded here; reads fetch the blocks they need.
+    fn open_internal(&self, inode: u64) -> Result<u64, libc::c_int> {
+        let path = self.inode_table.get_path(inode).ok_or(libc::ENOENT)?;
+
+        let remote_path = self.to_egnyte_path(&path);
+        let (entry, revalidated) = match self.cached_entry(inode) {
+            Some(entry) => (entry, false),
+            None => (
+                self.fetch_entry(inode, &remote_path)
+                    .map_err(|e| error::errno(&e))?,
+                true,
+            ),
+        };
+        if entry.is_folder {
+            return Ok(0);
+        }
+        // Another handle's buffer is shared, and so are changes a failed
+        // close kept; either wins over the remote content
+        if let Some(file) = self.open_file(inode) {
+            self.rt.block_on(file.lock()).handles += 1;
+            return Ok(inode);
+        }
+        if let Some(file) = self.unspool(&remote_path)? {
+            self.open_files.insert(inode, shared(file));
+            return Ok(inode);
+        }
+        // The content is downloaded now, and its version is what a flush
+        // checks for conflicts; a cached entry may be older than both
+        let entry = if revalidated {
+            entry
+        } else {
+            self.fetch_entry(inode, &remote_path)
+                .map_err(|e| error::errno(&e))?
+        };
+        if entry.size >= self.ranged_read_threshold {
+            let mut buffer = self.new_buffer(&[])?;
+            buffer.set_len(entry.size).map_err(|e| buffer_errno(&e))?;
+            let file = OpenFile::ranged(buffer, entry.entry_id, entry.size);
+            self.open_files.insert(inode, shared(file));
+            return Ok(inode);
+        }
+        // Download file content and store it in open_files
+        let content = self
+            .read_file_internal(&path)
+            .map_err(|e| error::errno(&e))?;
+        let buffer = self.new_buffer(&content)?;
+        self.open_files
+            .insert(inode, shared(OpenFile::new(buffer, entry.entry_id)));
+        Ok(inode)
+    }
+
+    /// Read from an open file's buffer, first downloading the blocks the
+    /// read needs if the file was opened without its content
+    fn read_internal(&self, inode: u64, offset: i64, size: u32) -> Result<Vec<u8>, libc::c_int> {
+        let file = self.open_file(inode).ok_or(libc::EBADF)?;
+        let offset = offset.max(0) as u64;
+        self.fetch_range(inode, &file, offset, u64::from(size))?;
+        self.read_ahead(inode, &file, offset, u64::from(size));
+        let file = self.rt.block_on(file.lock());
+        file.buffer
+            .read_at(offset, size as usize)
+            .map_err(|_| libc::EIO)
+    }
+
+    /// After a read of a ranged file, prefetch the blocks of the readahead
+    /// window past it in the background if reads are sequential. Up to
+    /// `max_readahead` bytes, and at least one block, are fetched ahead; a
+    /// failed prefetch is only logged, and the read that needs the block
+    /// asks again.
+    fn read_ahead(&self, inode: u64, file: &Arc<Mutex<OpenFile>>, offset: u64, len: u64) {
+        let Some(ranged) = self.rt.block_on(file.lock()).ranged.clone() else {
+            return;
+        };
+        let window = u64::from(self.max_readahead).max(READ_BLOCK_SIZE);
+        let ahead = ranged.read_ahead(offset, len, window);
+        let Some(path) = self
+            .inode_table
+            .get_path(inode)
+            .filter(|_| !ahead.is_empty())
+        else {
+            return;
+        };
+        let remote_path = self.to_egnyte_path(&path);
+        for index in ahead.filter(|index| !ranged.blocks[*index].initialized()) {
+            let api = Arc::clone(&self.api_client);
+            let (remote_path, file, ranged) =
+                (remote_path.clone(), Arc::clone(file), Arc::clone(&ranged));
+            self.rt.spawn(async move {
+                let fetched = fetch_block(api.as_ref(), &remote_path, &file, &ranged, index).await;
+                if let Err(e) = fetched {
+                    debug!(
+                        "Readahead of block {} of {} failed: {:#}",
+                        index, remote_path, e
+                    );
+                }
+            });
+        }
+    }
+
+    /// Download the blocks holding `len` bytes at `offset` of an open file
+    /// that are not in its buffer yet; nothing for a file opened whole
+    fn fetch_range(
+        &self,
+        inode: u64,
+        file: &Mutex<OpenFile>,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), libc::c_int> {
+        let Some(ranged) = self.rt.block_on(file.lock()).ranged.clone() else {
+            return Ok(());
+        };
+        let path = self.inode_table.get_path(inode).ok_or(libc::ENOENT)?;
+        let remote_path = self.to_egnyte_path(&path);
+        let api = self.api_client.as_ref();
+        let fetches = ranged
+            .covering(offset, len)
+            .map(|index| fetch_block(api, &remote_path, file, &ranged, index));
+        self.call(futures_util::future::try_join_all(fetches))
+            .map_err(|e| error::errno(&e))?;
+        Ok(())
+    }
+
+    /// Download what is still missing below `end` of an open file opened
+    /// without its content. From then on the buffer stands on its own, as
+    /// the file's whole content, ready to be changed and uploaded.
+    fn load_content(
+        &self,
+        inode: u64,
+        file: &Mutex<OpenFile>,
+        end: u64,
+    ) -> Result<(), libc::c_int> {
+        self.fetch_range(inode, file, 0, end)?;
+        self.rt.block_on(file.lock()).ranged = None;
+        Ok(())
+    }
+
+    /// Read file content
+    fn read_file_internal(&self, path: &Path) -> Result<Vec<u8>> {
+        let egnyte_path = self.to_egnyte_path(path);
+        self.call(self.api_client.download_file(&egnyte_path))
+            .context("Failed to download file")
+    }
+}
+
+/// `st_blocks` for a file of `size` bytes: whole blocks of `block_size`,
+/// counted in the 512-byte units stat(2) uses whatever the block size
+fn blocks(size: u64, block_size: u32) -> u64 {
+    let block_size = u64::from(block_size);
+    size.div_ceil(block_size) * (block_size / 512)
+}
+
+/// errno for a failed write to an open-file buffer: EFBIG and ENOSPC (the
+/// buffer directory's disk is full) as they are, EIO for anything else
+fn buffer_errno(err: &std::io::Error) -> libc::c_int {
+    match err.raw_os_error() {
+        Some(errno @ (libc::EFBIG | libc::ENOSPC)) => errno,
+        _ => libc::EIO,
+    }
+}
+
+/// Answer getxattr or listxattr: the size of `value` when asked for it
+/// (`size` 0), ERANGE when it does not fit in `size`, else `value` itself
+fn reply_xattr(value: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+/// Log a request for an operation Egnyte has no equivalent of, and return
+/// the errno to fail it with
+fn unsupported(op: &str, errno: libc::c_int) -> libc::c_int {
+    debug!("Unsupported operation {} (errno {})", op, errno);
+    errno
+}
+
+/// Supported operations:
+///
+/// - Lookup and attributes: `lookup`, `getattr`, `access`, `setattr` (size;
+///   times are accepted but not stored, and so is a mode that only clears
+///   setuid/setgid, which files never have)
+/// - Folders: `opendir`, `readdir`, `readdirplus`, `releasedir`, `fsyncdir`
+///   (nothing to sync), `rmdir`
+/// - Files: `create`, `mknod` (regular files only), `open`, `read`, `write`,
+///   `flush`, `fsync`, `release`, `fallocate` (growing only), `lseek`
+///   (SEEK_DATA/SEEK_HOLE), `copy_file_range` (a server-side copy for a
+///   whole file)
+/// - Names: `unlink`, `rename` (RENAME_NOREPLACE too, but not
+///   RENAME_EXCHANGE), `link` (a server-side copy with an inode of its own,
+///   not a shared inode)
+/// - Locks: `getlk`, `setlk` (local to this host; see [`LockTable`])
+/// - `getxattr`, `listxattr`: only [`ENTRY_ID_XATTR`], on files Egnyte has
+///   an entry id for; any other name is ENODATA
+/// - `statfs`: the fuser default, with no capacity figures
+///
+/// Unsupported, with explicit errors instead of the trait defaults:
+///
+/// - `mkdir`: ENOSYS, folders cannot be created yet
+/// - `readlink`: EINVAL, nothing is a symlink
+/// - `symlink`: EPERM, Egnyte only stores files and folders
+/// - `ioctl`: ENOTTY
+/// - `setxattr`, `removexattr`, `bmap`, `poll`: ENOSYS, which the kernel
+///   remembers and stops sending them, falling back to its generic behaviour
+impl Filesystem for EgnyteFuse {
+    fn init(
+        &mut self,
+        _req: &Request<'_>,
+        config: &mut fuser::KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        // The kernel may accept less than asked; use what it allows
+        let max_readahead = negotiate(self.max_readahead, |v| config.set_max_readahead(v));
+        let max_write = negotiate(self.max_write, |v| config.set_max_write(v));
+        if max_readahead != self.max_readahead || max_write != self.max_write {
+            warn!(
+                "Kernel limits max_readahead to {} and max_write to {} (asked for {} and {})",
+                max_readahead, max_write, self.max_readahead, self.max_write
+            );
+        }
+        info!(
+            "max_readahead {} bytes, max_write {} bytes",
+            max_readahead, max_write
+        );
+        let (granted, refused) =
+            negotiate_capabilities(&self.wanted_capabilities(), |c| config.add_capabilities(c));
+        info!("Kernel capabilities granted: {}", granted.join(", "));
+        if !refused.is_empty() {
+            info!("Kernel capabilities not supported: {}", refused.join(", "));
+        }
+        Ok(())
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.begin_op();
+        match self.lookup_internal(parent, name) {
+            Ok(attr) => reply.entry(&self.entry_ttl, &attr, 0),
+            // Inode 0 tells the kernel to cache the name as nonexistent
+            Err(libc::ENOENT) if !self.negative_ttl.is_zero() => {
+                reply.entry(&self.negative_ttl, &self.negative_attr(), 0)
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, inode: u64, reply: ReplyAttr) {
+        self.begin_op();
+        let path = match self.inode_table.get_path(inode) {
+            Some(p) => p,
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+
+        match self.get_attr_internal(&path) {
+            Ok(attr) => {
+                reply.attr(&self.attr_ttl, &attr);
+            }
+            Err(e) => {
+                reply.error(error::errno(&e));
+            }
+        }
+    }
+
+    fn access(&mut self, _req: &Request<'_>, inode: u64, mask: i32, reply: ReplyEmpty) {
+        match self.access_internal(inode, mask) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, inode: u64, _flags: i32, reply: ReplyOpen) {
+        self.begin_op();
+        let path = match self.inode_table.get_path(inode) {
+            Some(p) => p,
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+
+        // Snapshot the listing once so every readdir on this handle pages through
+        // the same entries, even if the remote folder changes in between.
+        match self.readdir_internal(&path) {
+            Ok(entries) => {
+                let fh = self.next_dir_fh.fetch_add(1, Ordering::Relaxed);
+                self.dir_handles.insert(fh, Arc::new(entries));
+                reply.opened(fh, 0);
+            }
+            Err(e) => {
+                reply.error(error::errno(&e));
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        inode: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        self.begin_op();
+        let snapshot = match self.dir_snapshot(inode, fh) {
+            Ok(entries) => entries,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        for (cookie, entry) in dir_page(&snapshot, offset) {
+            if reply.add(entry.ino, cookie, entry.kind, entry.name.as_str()) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn readdirplus(
+        &mut self,
+        _req: &Request<'_>,
+        inode: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        self.begin_op();
+        let snapshot = match self.dir_snapshot(inode, fh) {
+            Ok(entries) => entries,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let ttl = self.entry_ttl;
+        for (cookie, entry) in dir_page(&snapshot, offset) {
+            let added = match &entry.attr {
+                Some(attr) => reply.add(entry.ino, cookie, entry.name.as_str(), &ttl, attr, 0),
+                None => {
+                    // "." and "..": the kernel does not cache attributes for these
+                    let placeholder = EgnyteEntry {
+                        name: entry.name.clone(),
+                        path: String::new(),
+                        is_folder: true,
+                        size: 0,
+                        modified_time: SystemTime::UNIX_EPOCH,
+                        entry_id: None,
+                        permission: None,
+                        validators: Validators::default(),
+                    };
+                    let attr = self.entry_to_attr(entry.ino, &placeholder);
+                    reply.add(
+                        entry.ino,
+                        cookie,
+                        entry.name.as_str(),
+                        &Duration::ZERO,
+                        &attr,
+                        0,
+                    )
+                }
+            };
+            if added {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _inode: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.dir_handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn open(&mut self, _req: &Request<'_>, inode: u64, _flags: i32, reply: ReplyOpen) {
+        self.begin_op();
+        match self.open_internal(inode) {
+            Ok(fh) => reply.opened(fh, self.open_flags()),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        inode: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        self.begin_op();
+        match self.read_internal(inode, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        inode: u64,
+        _fh: u64,
+        _flags: i32,
+        lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.begin_op();
+        if let Some(owner) = lock_owner {
+            self.locks.release_owner(inode, owner);
+        }
+        match self.release_internal(inode) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        inode: u64,
+        _fh: u64,
+        lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        self.begin_op();
+        // Closing a file drops the closing process's locks on it
+        self.locks.release_owner(inode, lock_owner);
+        match self.flush_internal(inode) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        inode: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.begin_op();
+        match self.flush_internal(inode) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        inode: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<fuser::TimeOrNow>,
+        _mtime: Option<fuser::TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        self.begin_op();
+        let result = match size {
+            Some(size) => self.truncate_internal(inode, size),
+            // Only timestamps, which the kernel sends on its own with the
+            // writeback cache; Egnyte keeps its own, so report those
+            None if mode.is_none() && uid.is_none() && gid.is_none() => self.stat_internal(inode),
+            None => match mode {
+                Some(mode) if uid.is_none() && gid.is_none() => {
+                    self.kill_privileges_internal(inode, mode)
+                }
+                _ => Err(libc::ENOSYS),
+            },
+        };
+        match result {
+            Ok(attr) => reply.attr(&self.attr_ttl, &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        inode: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        self.begin_op();
+        match self.write_internal(inode, offset, data) {
+            Ok(written) => reply.written(written),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn fallocate(
+        &mut self,
+        _req: &Request<'_>,
+        inode: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.begin_op();
+        match self.fallocate_internal(inode, offset, length, mode) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn lseek(
+        &mut self,
+        _req: &Request<'_>,
+        inode: u64,
+        _fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        self.begin_op();
+        match self.lseek_internal(inode, offset, whence) {
+            Ok(offset) => reply.offset(offset),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        self.begin_op();
+        match self.create_internal(parent, name) {
+            Ok(attr) => reply.created(&self.entry_ttl, &attr, 0, attr.ino, self.open_flags()),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        self.begin_op();
+        match self.mkdir_internal(parent, name) {
+            Ok(attr) => reply.entry(&self.entry_ttl, &attr, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.begin_op();
+        match self.unlink_internal(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.begin_op();
+        match self.rmdir_internal(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.begin_op();
+        match self.rename_internal(parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn fsyncdir(
+        &mut self,
+        _req: &Request<'_>,
+        _inode: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        // Folder changes go to the API as they happen
+        reply.ok();
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, _inode: u64, reply: ReplyData) {
+        reply.error(unsupported("readlink", libc::EINVAL));
+    }
+
+    fn mknod(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        _umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        self.begin_op();
+        match self.mknod_internal(parent, name, mode) {
+            Ok(attr) => reply.entry(&self.entry_ttl, &attr, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request<'_>,
+        _parent: u64,
+        _link_name: &OsStr,
+        _target: &Path,
+        reply: ReplyEntry,
+    ) {
+        reply.error(unsupported("symlink", libc::EPERM));
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request<'_>,
+        inode: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        self.begin_op();
+        match self.link_internal(inode, newparent, newname) {
+            Ok(attr) => reply.entry(&self.entry_ttl, &attr, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        inode: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        self.begin_op();
+        match self.getxattr_internal(inode, name) {
+            Ok(value) => reply_xattr(&value, size, reply),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, inode: u64, size: u32, reply: ReplyXattr) {
+        self.begin_op();
+        match self.listxattr_internal(inode) {
+            Ok(names) => reply_xattr(&names, size, reply),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request<'_>,
+        _inode: u64,
+        _name: &OsStr,
+        _value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(unsupported("setxattr", libc::ENOSYS));
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, _inode: u64, _name: &OsStr, reply: ReplyEmpty) {
+        reply.error(unsupported("removexattr", libc::ENOSYS));
+    }
+
+    fn getlk(
+        &mut self,
+        _req: &Request<'_>,
+        inode: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        _pid: u32,
+        reply: ReplyLock,
+    ) {
+        match self.locks.conflict(inode, lock_owner, start, end, typ) {
+            Some(lock) => reply.locked(lock.start, lock.end, lock.typ, lock.pid),
+            None => reply.locked(start, end, libc::F_UNLCK, 0),
+        }
+    }
+
+    fn setlk(
+        &mut self,
+        _req: &Request<'_>,
+        inode: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        _sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        // Waiting for a blocking lock (F_SETLKW) would stall every other
+        // request behind this one, including the unlock it waits for, so a
+        // held lock fails with EAGAIN either way
+        let lock = Lock {
+            owner: lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+        };
+        match self.locks.set(inode, lock) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn bmap(
+        &mut self,
+        _req: &Request<'_>,
+        _inode: u64,
+        _blocksize: u32,
+        _idx: u64,
+        reply: ReplyBmap,
+    ) {
+        // There is no block device behind the files
+        reply.error(unsupported("bmap", libc::ENOSYS));
+    }
+
+    fn ioctl(
+        &mut self,
+        _req: &Request<'_>,
+        _inode: u64,
+        _fh: u64,
+        _flags: u32,
+        _cmd: u32,
+        _in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        reply.error(unsupported("ioctl", libc::ENOTTY));
+    }
+
+    fn poll(
+        &mut self,
+        _req: &Request<'_>,
+        _inode: u64,
+        _fh: u64,
+        _kh: u64,
+        _events: u32,
+        _flags: u32,
+        reply: ReplyPoll,
+    ) {
+        reply.error(unsupported("poll", libc::ENOSYS));
+    }
+
+    fn copy_file_range(
+        &mut self,
+        _req: &Request<'_>,
+        inode_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        inode_out: u64,
+        _fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        self.begin_op();
+        match self.copy_file_range_internal(inode_in, offset_in, inode_out, offset_out, len, flags)
+        {
+            Ok(copied) => reply.written(copied),
+            Err(errno) => reply.error(errno),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::notify::tests::RecordingSink;
+    use crate::fs::notify::Invalidation;
+    use crate::fs::testutil::{pattern, ScriptedMockAPI};
+    use std::sync::atomic::AtomicUsize;
+
+    /// API stub that counts calls and has no entries
+    #[derive(Default)]
+    struct CountingAPI {
+        calls: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl EgnyteAPI for CountingAPI {
+        async fn list_folder(&self, _path: &str) -> Result<Vec<EgnyteEntry>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(Vec::new())
+        }
+
+        async fn get_file_info(&self, path: &str) -> Result<EgnyteEntry> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Err(error::ApiError::NotFound(path.to_string()).into())
+        }
+
+        async fn download_file(&self, path: &str) -> Result<Vec<u8>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Err(anyhow::anyhow!("{} not found", path))
+        }
+
+        async fn copy_entry(&self, from: &str, _to: &str) -> Result<()> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Err(anyhow::anyhow!("{} not found", from))
+        }
+
+        async fn delete_entry(&self, path: &str) -> Result<()> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Err(anyhow::anyhow!("{} not found", path))
+        }
+
+        async fn move_entry(&self, from: &str, _to: &str) -> Result<()> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Err(anyhow::anyhow!("{} not found", from))
+        }
+
+        async fn upload_file(&self, path: &str, _data: &[u8]) -> Result<Option<String>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Err(anyhow::anyhow!("{} not found", path))
+        }
+
+        async fn upload_chunked(&self, path: &str, _data: &[u8]) -> Result<Option<String>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Err(anyhow::anyhow!("{} not found", path))
+        }
+
+        async fn latest_event_cursor(&self) -> Result<u64> {
+            Ok(0)
+        }
+
+        async fn events_since(&self, cursor: u64) -> Result<EventBatch> {
+            Ok(EventBatch {
+                paths: Vec::new(),
+                cursor,
+            })
+        }
+    }
+
+    /// Filesystem over `api` with its file buffers in the temp directory
+    fn test_fs(api: Arc<dyn EgnyteAPI>) -> EgnyteFuse {
+        EgnyteFuse::new(api)
+            .unwrap()
+            .with_buffer_dir(std::env::temp_dir().join("egnyte-fuse-tests"))
+    }
+
+    /// API stub holding a single file, /doc.txt, that records listings,
+    /// uploads, deletes and moves
+    #[derive(Default)]
+    struct UploadAPI {
+        uploads: std::sync::Mutex<Vec<(String, Vec<u8>)>>,
+        listed: std::sync::Mutex<Vec<String>>,
+        deletes: std::sync::Mutex<Vec<String>>,
+        moves: std::sync::Mutex<Vec<(String, String)>>,
+        /// Paths a move cannot overwrite until they are deleted
+        occupied: std::sync::Mutex<Vec<String>>,
+        /// Answer every upload with a checksum mismatch
+        corrupt: bool,
+        /// Current entry_id of /doc.txt
+        version: std::sync::Mutex<Option<String>>,
+    }
+
+    #[async_trait::async_trait]
+    impl EgnyteAPI for UploadAPI {
+        async fn list_folder(&self, path: &str) -> Result<Vec<EgnyteEntry>> {
+            self.listed.lock().unwrap().push(path.to_string());
+            Ok(Vec::new())
+        }
+
+        async fn get_file_info(&self, path: &str) -> Result<EgnyteEntry> {
+            let mut entry = local_entry(Path::new(path), path.to_string(), 5);
+            entry.entry_id = self.version.lock().unwrap().clone();
+            Ok(entry)
+        }
+
+        async fn download_file(&self, _path: &str) -> Result<Vec<u8>> {
+            Ok(b"hello".to_vec())
+        }
+
+        async fn copy_entry(&self, _from: &str, _to: &str) -> Result<()> {
+            Ok(())
+        }
+
+        async fn delete_entry(&self, path: &str) -> Result<()> {
+            self.occupied.lock().unwrap().retain(|p| p != path);
+            self.deletes.lock().unwrap().push(path.to_string());
+            Ok(())
+        }
+
+        async fn move_entry(&self, from: &str, to: &str) -> Result<()> {
+            if self.occupied.lock().unwrap().iter().any(|p| p == to) {
+                return Err(error::ApiError::AlreadyExists(to.to_string()).into());
+            }
+            self.occupied.lock().unwrap().retain(|p| p != from);
+            self.moves
+                .lock()
+                .unwrap()
+                .push((from.to_string(), to.to_string()));
+            Ok(())
+        }
+
+        async fn upload_file(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+            let mut uploads = self.uploads.lock().unwrap();
+            uploads.push((path.to_string(), data.to_vec()));
+            if self.corrupt {
+                return Err(error::ApiError::ChecksumMismatch {
+                    path: path.to_string(),
+                    local: "aa".to_string(),
+                    remote: "bb".to_string(),
+                }
+                .into());
+            }
+            let entry_id = format!("v{}", uploads.len());
+            if path == "/doc.txt" {
+                *self.version.lock().unwrap() = Some(entry_id.clone());
+            }
+            Ok(Some(entry_id))
+        }
+
+        async fn upload_chunked(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+            self.upload_file(path, data).await
+        }
+
+        async fn latest_event_cursor(&self) -> Result<u64> {
+            Ok(0)
+        }
+
+        async fn events_since(&self, cursor: u64) -> Result<EventBatch> {
+            Ok(EventBatch {
+                paths: Vec::new(),
+                cursor,
+            })
+        }
+    }
+
+    #[test]
+    fn test_write_uploads_on_flush() {
+        let api = Arc::new(UploadAPI::default());
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+
+        fs.open_internal(inode).unwrap();
+        assert_eq!(fs.write_internal(inode, 5, b" world"), Ok(6));
+        assert!(api.uploads.lock().unwrap().is_empty());
+
+        fs.flush_internal(inode).unwrap();
+        fs.flush_internal(inode).unwrap(); // clean: nothing more to upload
+        let uploads = api.uploads.lock().unwrap();
+        assert_eq!(
+            *uploads,
+            vec![("/doc.txt".to_string(), b"hello world".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_autoflush_uploads_idle_dirty_files() {
+        let api = Arc::new(UploadAPI::default());
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+        fs.open_internal(inode).unwrap();
+        fs.write_internal(inode, 5, b"!").unwrap();
+
+        // Written just now: not idle for an hour yet
+        let uploader = fs.uploader();
+        let idle = Duration::from_secs(3600);
+        fs.rt
+            .block_on(flush_idle(&uploader, &fs.open_files, &fs.inode_table, idle));
+        assert!(api.uploads.lock().unwrap().is_empty());
+
+        fs.rt.block_on(flush_idle(
+            &uploader,
+            &fs.open_files,
+            &fs.inode_table,
+            Duration::ZERO,
+        ));
+        fs.rt.block_on(flush_idle(
+            &uploader,
+            &fs.open_files,
+            &fs.inode_table,
+            Duration::ZERO,
+        ));
+        assert_eq!(
+            *api.uploads.lock().unwrap(),
+            vec![("/doc.txt".to_string(), b"hello!".to_vec())]
+        );
+        // Clean after the background upload, so closing uploads nothing
+        fs.flush_internal(inode).unwrap();
+        assert_eq!(api.uploads.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_small_writes_coalesce_into_one_upload() {
+        let api = Arc::new(UploadAPI::default());
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/log.txt"));
+        fs.open_files.insert(
+            inode,
+            shared(OpenFile::new(fs.new_buffer(&[]).unwrap(), None)),
+        );
+
+        let block = [b'x'; 4096];
+        for i in 0..1000 {
+            assert_eq!(fs.write_internal(inode, i * 4096, &block), Ok(4096));
+        }
+        assert!(api.uploads.lock().unwrap().is_empty());
+
+        fs.flush_internal(inode).unwrap();
+        let uploads = api.uploads.lock().unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].1.len(), 1000 * 4096);
+        assert!(uploads[0].1.iter().all(|b| *b == b'x'));
+    }
+
+    #[test]
+    fn test_sparse_write_fills_zeros() {
+        let api = Arc::new(UploadAPI::default());
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+
+        fs.open_internal(inode).unwrap();
+        fs.write_internal(inode, 10, b"!").unwrap();
+        fs.flush_internal(inode).unwrap();
+        let uploads = api.uploads.lock().unwrap();
+        assert_eq!(uploads[0].1, b"hello\0\0\0\0\0!");
+    }
+
+    #[test]
+    fn test_writes_at_start_end_and_past_eof() {
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/a.txt", b"hello"));
+        let fs = test_fs(api.clone());
+        let inode = fs.lookup_internal(1, OsStr::new("a.txt")).unwrap().ino;
+        fs.open_internal(inode).unwrap();
+        let size = |fs: &EgnyteFuse| fs.get_attr_internal(Path::new("/a.txt")).unwrap().size;
+
+        fs.write_internal(inode, 0, b"J").unwrap();
+        assert_eq!(fs.read_internal(inode, 0, 100).unwrap(), b"Jello");
+        assert_eq!(size(&fs), 5);
+
+        // Appending at the end
+        fs.write_internal(inode, 5, b"!").unwrap();
+        assert_eq!(fs.read_internal(inode, 0, 100).unwrap(), b"Jello!");
+        assert_eq!(size(&fs), 6);
+
+        // A megabyte past the end: the hole reads back as zeros
+        let past = 6 + (1 << 20);
+        fs.write_internal(inode, past, b"end").unwrap();
+        assert_eq!(size(&fs), past as u64 + 3);
+        assert_eq!(fs.read_internal(inode, 6, 4096).unwrap(), vec![0; 4096]);
+        assert_eq!(fs.read_internal(inode, past, 10).unwrap(), b"end");
+
+        assert_eq!(fs.write_internal(inode, i64::MAX, b"x"), Err(libc::EFBIG));
+        fs.flush_internal(inode).unwrap();
+        let content = api.content("/a.txt").unwrap();
+        assert_eq!(content.len(), past as usize + 3);
+        assert_eq!(&content[..6], b"Jello!");
+        assert!(content[6..past as usize].iter().all(|b| *b == 0));
+        assert_eq!(&content[past as usize..], b"end");
+    }
+
+    #[test]
+    fn test_checksum_mismatch_fails_flush() {
+        let api = Arc::new(UploadAPI {
+            corrupt: true,
+            ..Default::default()
+        });
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+
+        fs.open_internal(inode).unwrap();
+        fs.write_internal(inode, 0, b"J").unwrap();
+        assert_eq!(fs.flush_internal(inode), Err(libc::EIO));
+        // Still dirty, so the next flush tries again
+        assert_eq!(fs.flush_internal(inode), Err(libc::EIO));
+        assert_eq!(api.uploads.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_release_uploads_and_closes() {
+        let api = Arc::new(UploadAPI::default());
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+
+        fs.open_internal(inode).unwrap();
+        fs.write_internal(inode, 5, b"!").unwrap();
+        fs.release_internal(inode).unwrap();
+        assert_eq!(
+            *api.uploads.lock().unwrap(),
+            vec![("/doc.txt".to_string(), b"hello!".to_vec())]
+        );
+        assert!(fs.open_files.is_empty());
+    }
+
+    #[test]
+    fn test_mknod_regular_file() {
+        let api = Arc::new(ScriptedMockAPI::new());
+        let fs = test_fs(api.clone());
+        let attr = fs
+            .mknod_internal(1, OsStr::new("a.txt"), libc::S_IFREG | 0o644)
+            .unwrap();
+        assert_eq!((attr.kind, attr.size), (FileType::RegularFile, 0));
+        assert_eq!(api.content("/a.txt").unwrap(), b"");
+        // Made, not opened
+        assert!(fs.open_files.is_empty());
+
+        assert_eq!(
+            fs.mknod_internal(1, OsStr::new("fifo"), libc::S_IFIFO | 0o644),
+            Err(libc::EPERM)
+        );
+        assert!(!api.exists("/fifo"));
+    }
+
+    #[test]
+    fn test_mkdir_is_unsupported() {
+        let api = Arc::new(ScriptedMockAPI::new());
+        let fs = test_fs(api.clone());
+        assert_eq!(
+            fs.mkdir_internal(1, OsStr::new("dir")).unwrap_err(),
+            libc::ENOSYS
+        );
+        assert_eq!(api.total_calls(), 0);
+    }
+
+    #[test]
+    fn test_copy_file_range() {
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/a.txt", b"some data"));
+        let fs = test_fs(api.clone());
+        let a = fs.lookup_internal(1, OsStr::new("a.txt")).unwrap().ino;
+        fs.open_internal(a).unwrap();
+        let b = fs.create_internal(1, OsStr::new("b.txt")).unwrap().ino;
+        let uploads = api.calls("upload_file");
+
+        // cp asks for more than there is; the whole file is copied by Egnyte
+        assert_eq!(fs.copy_file_range_internal(a, 0, b, 0, 1 << 30, 0), Ok(9));
+        assert_eq!(api.calls("copy_entry"), 1);
+        assert_eq!(api.content("/b.txt").unwrap(), b"some data");
+        assert_eq!(fs.read_internal(b, 0, 100).unwrap(), b"some data");
+        // Nothing is left to upload
+        fs.release_internal(b).unwrap();
+        assert_eq!(api.calls("upload_file"), uploads);
+
+        // Part of a file is copied through the buffers
+        let c = fs.create_internal(1, OsStr::new("c.txt")).unwrap().ino;
+        assert_eq!(fs.copy_file_range_internal(a, 5, c, 0, 4, 0), Ok(4));
+        assert_eq!(fs.copy_file_range_internal(a, 20, c, 4, 4, 0), Ok(0));
+        fs.release_internal(c).unwrap();
+        assert_eq!(api.content("/c.txt").unwrap(), b"data");
+        assert_eq!(api.calls("copy_entry"), 1);
+
+        assert_eq!(
+            fs.copy_file_range_internal(a, 0, c, 0, 4, 0),
+            Err(libc::EBADF)
+        );
+    }
+
+    #[test]
+    fn test_copying_a_large_file_downloads_nothing() {
+        let api = Arc::new(ScriptedMockAPI::new().with_large_file("/big.bin", 10 << 20));
+        let fs = test_fs(api.clone());
+        let big = fs.lookup_internal(1, OsStr::new("big.bin")).unwrap().ino;
+        fs.open_internal(big).unwrap();
+        let copy = fs.create_internal(1, OsStr::new("copy.bin")).unwrap().ino;
+
+        let copied = fs.copy_file_range_internal(big, 0, copy, 0, 1 << 30, 0);
+        assert_eq!(copied, Ok(10 << 20));
+        assert_eq!(api.content("/copy.bin").unwrap(), pattern(0, 10 << 20));
+        assert!(api.ranges().is_empty());
+        assert_eq!(api.calls("download_file"), 0);
+
+        // The copy reads like any large file, from its own version
+        let data = fs.read_internal(copy, 3 << 20, 4096).unwrap();
+        assert_eq!(data, pattern(3 << 20, 4096));
+        assert_eq!(api.ranges(), [("/copy.bin".to_string(), 3 << 20, 1 << 20)]);
+        fs.release_internal(copy).unwrap();
+        assert_eq!(api.calls("upload_file"), 1);
+    }
+
+    #[test]
+    fn test_link_copies() {
+        let api = Arc::new(
+            ScriptedMockAPI::new()
+                .with_file("/a.txt", b"data")
+                .with_folder("/Docs"),
+        );
+        let fs = test_fs(api.clone());
+        let a = fs.lookup_internal(1, OsStr::new("a.txt")).unwrap().ino;
+        let docs = fs.lookup_internal(1, OsStr::new("Docs")).unwrap().ino;
+
+        let linked = fs.link_internal(a, docs, OsStr::new("b.txt")).unwrap();
+        assert_ne!(linked.ino, a);
+        assert_eq!((linked.size, linked.nlink), (4, 1));
+        assert_eq!(api.content("/Docs/b.txt").unwrap(), b"data");
+        assert_eq!(api.content("/a.txt").unwrap(), b"data");
+        assert_eq!(
+            fs.lookup_internal(docs, OsStr::new("b.txt")).unwrap().ino,
+            linked.ino
+        );
+
+        // As with link(2): no folders, and no replacing
+        assert_eq!(
+            fs.link_internal(docs, 1, OsStr::new("Docs2")).unwrap_err(),
+            libc::EPERM
+        );
+        assert_eq!(
+            fs.link_internal(a, docs, OsStr::new("b.txt")).unwrap_err(),
+            libc::EEXIST
+        );
+    }
+
+    #[test]
+    fn test_entry_id_xattr() {
+        let api = Arc::new(
+            ScriptedMockAPI::new()
+                .with_file("/a.txt", b"data")
+                .with_folder("/Docs"),
+        );
+        let fs = test_fs(api.clone());
+        let a = fs.lookup_internal(1, OsStr::new("a.txt")).unwrap().ino;
+        let docs = fs.lookup_internal(1, OsStr::new("Docs")).unwrap().ino;
+        let entry_id = OsStr::new(ENTRY_ID_XATTR);
+        let calls = api.total_calls();
+
+        assert_eq!(fs.getxattr_internal(a, entry_id).unwrap(), b"v1");
+        assert_eq!(fs.listxattr_internal(a).unwrap(), b"user.egnyte.entry_id\0");
+        // Answered from what the lookups found
+        assert_eq!(api.total_calls(), calls);
+
+        // Folders have no id, and no other attribute exists
+        assert_eq!(fs.getxattr_internal(docs, entry_id), Err(libc::ENODATA));
+        assert!(fs.listxattr_internal(docs).unwrap().is_empty());
+        assert_eq!(
+            fs.getxattr_internal(a, OsStr::new("security.capability")),
+            Err(libc::ENODATA)
+        );
+        assert_eq!(fs.getxattr_internal(99, entry_id), Err(libc::ENOENT));
+    }
+
+    #[test]
+    fn test_readdir_skips_bad_names() {
+        let api = Arc::new(
+            ScriptedMockAPI::new()
+                .with_file("/Docs/a.txt", b"a")
+                .with_file("/Docs/nul", b"")
+                .with_file("/Docs/dots", b"")
+                .with_file("/Docs/empty", b"")
+                .with_file("/Docs/slashed", b"")
+                .with_file("/Docs/z.txt", b"z")
+                .list_as("/Docs/nul", "bad\0name")
+                .list_as("/Docs/dots", "..")
+                .list_as("/Docs/empty", "")
+                .list_as("/Docs/slashed", "Shared/Docs/deep.txt"),
+        );
+        let fs = test_fs(api);
+        let entries = fs.readdir_internal(Path::new("/Docs")).unwrap();
+        let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, vec![".", "..", "a.txt", "deep.txt", "z.txt"]);
+        assert!(fs
+            .inode_table
+            .get_inode(Path::new("/Docs/deep.txt"))
+            .is_some());
+
+        assert_eq!(listing_name("a/"), Some("a"));
+        assert_eq!(listing_name("/"), None);
+    }
+
+    #[test]
+    fn test_read() {
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/a.txt", b"hello world"));
+        let fs = test_fs(api);
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/a.txt"));
+        assert_eq!(fs.read_internal(inode, 0, 5), Err(libc::EBADF));
+        fs.open_internal(inode).unwrap();
+        assert_eq!(fs.read_internal(inode, 6, 100).unwrap(), b"world");
+        assert_eq!(fs.read_internal(inode, 20, 5).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_large_files_are_read_by_range() {
+        let api = Arc::new(ScriptedMockAPI::new().with_large_file("/big.bin", 10 << 20));
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/big.bin"));
+        fs.open_internal(inode).unwrap();
+        assert_eq!(api.calls("download_file"), 0);
+
+        let offset = (5 << 20) + 10;
+        let data = fs.read_internal(inode, offset as i64, 4096).unwrap();
+        assert_eq!(data, pattern(offset, 4096));
+        // Read again, the block is in the buffer already
+        fs.read_internal(inode, offset as i64, 100).unwrap();
+        assert_eq!(api.ranges(), [("/big.bin".to_string(), 5 << 20, 1 << 20)]);
+
+        // A read across a block boundary needs both blocks
+        let data = fs.read_internal(inode, (2 << 20) - 10, 20).unwrap();
+        assert_eq!(data, pattern((2 << 20) - 10, 20));
+        assert_eq!(api.ranges().len(), 3);
+        assert!(fs.read_internal(inode, 11 << 20, 10).unwrap().is_empty());
+        assert_eq!(api.ranges().len(), 3);
+    }
+
+    #[test]
+    fn test_writes_download_within_their_own_budget() {
+        let api = Arc::new(
+            ScriptedMockAPI::new()
+                .with_large_file("/big.bin", 3 << 20)
+                .retry_ranges(),
+        );
+        let fs = EgnyteFuseBuilder::new()
+            .buffer_dir(std::env::temp_dir().join("egnyte-fuse-tests"))
+            .ranged_read_threshold(1 << 20)
+            .op_deadline(Duration::from_millis(200))
+            .build(api.clone())
+            .unwrap();
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/big.bin"));
+        fs.begin_op();
+        fs.open_internal(inode).unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+
+        // Past the open's deadline its budget grants no retry, so the
+        // download before the first write needs a budget of its own
+        assert_eq!(fs.write_internal(inode, 0, b"JJ"), Err(libc::EIO));
+        fs.begin_op();
+        assert_eq!(fs.write_internal(inode, 0, b"JJ"), Ok(2));
+        fs.flush_internal(inode).unwrap();
+        assert_eq!(api.content("/big.bin").unwrap()[..4], *b"JJ\x02\x03");
+    }
+
+    #[test]
+    fn test_sequential_reads_prefetch_the_blocks_ahead() {
+        let api = Arc::new(ScriptedMockAPI::new().with_large_file("/big.bin", 10 << 20));
+        let fs = EgnyteFuseBuilder::new()
+            .buffer_dir(std::env::temp_dir().join("egnyte-fuse-tests"))
+            .max_readahead(2 << 20)
+            .build(api.clone())
+            .unwrap();
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/big.bin"));
+        let ranges_of = |blocks: &[u64]| -> Vec<(String, u64, u64)> {
+            let range = |block: &u64| ("/big.bin".to_string(), block << 20, 1 << 20);
+            blocks.iter().map(range).collect()
+        };
+        let fetched = |count: usize| {
+            let start = Instant::now();
+            while api.ranges().len() < count && start.elapsed() < Duration::from_secs(5) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            let mut ranges = api.ranges();
+            ranges.sort();
+            ranges
+        };
+
+        // Reads all over the file fetch only what they read
+        fs.open_internal(inode).unwrap();
+        for offset in [5 << 20, 1 << 20, 8 << 20] {
+            fs.read_internal(inode, offset, 4096).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(fetched(3), ranges_of(&[1, 5, 8]));
+
+        // Once two reads in a row start where the last one ended, the
+        // blocks of the 2 MiB after each read are prefetched, each once
+        for offset in (0..4).map(|n| n * (128 << 10)) {
+            fs.read_internal(inode, offset, 128 << 10).unwrap();
+        }
+        assert_eq!(fetched(5), ranges_of(&[0, 1, 2, 5, 8]));
+        let data = fs.read_internal(inode, 2 << 20, 4096).unwrap();
+        assert_eq!(data, pattern(2 << 20, 4096));
+        assert_eq!(fetched(5).len(), 5);
+    }
+
+    #[test]
+    fn test_changing_a_ranged_file_downloads_it_first() {
+        let api = Arc::new(
+            ScriptedMockAPI::new()
+                .with_large_file("/big.bin", 10 << 20)
+                .with_large_file("/cut.bin", 10 << 20),
+        );
+        let fs = test_fs(api.clone());
+        let big = fs.inode_table.get_or_create_inode(Path::new("/big.bin"));
+        fs.open_internal(big).unwrap();
+        fs.read_internal(big, 0, 10).unwrap();
+        fs.write_internal(big, 0, b"JJ").unwrap();
+        fs.flush_internal(big).unwrap();
+        let mut expected = pattern(0, 10 << 20);
+        expected[..2].copy_from_slice(b"JJ");
+        assert_eq!(api.content("/big.bin").unwrap(), expected);
+        // Every block once, the one read first included
+        assert_eq!(api.ranges().len(), 10);
+
+        // Cutting a file off downloads only what it keeps
+        let cut = fs.inode_table.get_or_create_inode(Path::new("/cut.bin"));
+        fs.open_internal(cut).unwrap();
+        fs.truncate_internal(cut, 100).unwrap();
+        fs.flush_internal(cut).unwrap();
+        assert_eq!(api.content("/cut.bin").unwrap(), pattern(0, 100));
+        assert_eq!(api.ranges().len(), 11);
+    }
+
+    #[test]
+    fn test_ranged_reads_stay_on_the_opened_version() {
+        let api = Arc::new(ScriptedMockAPI::new().with_large_file("/big.bin", 10 << 20));
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/big.bin"));
+        fs.open_internal(inode).unwrap();
+        fs.read_internal(inode, 0, 10).unwrap();
+
+        // Blocks read after a change still come from the version opened
+        api.change_remotely("/big.bin", &vec![b'x'; 12 << 20]);
+        let data = fs.read_internal(inode, 5 << 20, 4096).unwrap();
+        assert_eq!(data, pattern(5 << 20, 4096));
+        assert!(fs.read_internal(inode, 10 << 20, 10).unwrap().is_empty());
+
+        // With that version gone, a block not read yet cannot be had
+        api.drop_old_versions();
+        assert_eq!(fs.read_internal(inode, 7 << 20, 10), Err(libc::EIO));
+        assert_eq!(fs.read_internal(inode, 0, 10).unwrap(), pattern(0, 10));
+    }
+
+    #[test]
+    fn test_open_files_lock_independently() {
+        let api = Arc::new(
+            ScriptedMockAPI::new()
+                .with_file("/a.txt", b"a")
+                .with_file("/b.txt", b"b"),
+        );
+        let fs = test_fs(api);
+        let a = fs.inode_table.get_or_create_inode(Path::new("/a.txt"));
+        let b = fs.inode_table.get_or_create_inode(Path::new("/b.txt"));
+        fs.open_internal(a).unwrap();
+        fs.open_internal(b).unwrap();
+
+        // As if a slow upload of a held its lock, a write to b goes through
+        let held = fs.open_file(a).unwrap();
+        let guard = held.blocking_lock();
+        let (done, wait) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            scope.spawn(|| done.send(fs.write_internal(b, 1, b"!")).unwrap());
+            let written = wait.recv_timeout(Duration::from_secs(5));
+            drop(guard);
+            assert_eq!(written, Ok(Ok(1)));
+        });
+    }
+
+    #[test]
+    fn test_handles_share_a_buffer_until_the_last_release() {
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/a.txt", b"hello"));
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/a.txt"));
+
+        fs.open_internal(inode).unwrap();
+        let first = fs.open_file(inode).unwrap();
+        // A second open shares the buffer rather than downloading anew
+        fs.open_internal(inode).unwrap();
+        assert!(Arc::ptr_eq(&first, &fs.open_file(inode).unwrap()));
+        assert_eq!(api.calls("download_file"), 1);
+
+        // Closing one leaves the other readable and writable
+        fs.release_internal(inode).unwrap();
+        assert_eq!(fs.read_internal(inode, 0, 5).unwrap(), b"hello");
+        assert_eq!(fs.write_internal(inode, 0, b"J"), Ok(1));
+        fs.release_internal(inode).unwrap();
+        assert_eq!(fs.read_internal(inode, 0, 5), Err(libc::EBADF));
+        assert!(fs.open_files.is_empty());
+        assert_eq!(api.content("/a.txt").unwrap(), b"Jello");
+    }
+
+    #[test]
+    fn test_failed_release_keeps_changes() {
+        let api = Arc::new(UploadAPI {
+            corrupt: true,
+            ..Default::default()
+        });
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+
+        fs.open_internal(inode).unwrap();
+        fs.write_internal(inode, 0, b"J").unwrap();
+        assert_eq!(fs.release_internal(inode), Err(libc::EIO));
+        assert_eq!(api.uploads.lock().unwrap().len(), 1);
+
+        // Reopening finds the unsaved buffer rather than the remote content
+        fs.open_internal(inode).unwrap();
+        {
+            let file = fs.open_file(inode).unwrap();
+            let file = file.blocking_lock();
+            assert!(file.dirty);
+            assert_eq!(file.buffer.contents().unwrap(), b"Jello");
+        }
+        assert_eq!(fs.release_internal(inode), Err(libc::EIO));
+        assert_eq!(api.uploads.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_failed_release_spools_until_reopened() {
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/doc.txt", b"hello"));
+        let spool_dir =
+            std::env::temp_dir().join(format!("egnyte-fuse-release-spool-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&spool_dir);
+        let fs = EgnyteFuseBuilder::new()
+            .buffer_dir(std::env::temp_dir().join("egnyte-fuse-tests"))
+            .spool_dir(spool_dir.clone())
+            .build(api.clone())
+            .unwrap();
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+
+        fs.open_internal(inode).unwrap();
+        fs.write_internal(inode, 0, b"J").unwrap();
+        api.set_offline(true);
+        assert_eq!(fs.release_internal(inode), Err(libc::EIO));
+        // The changes left memory for the spool
+        assert!(fs.open_files.is_empty());
+        let spool = Spool::new(spool_dir);
+        assert_eq!(spool.list().unwrap().len(), 1);
+
+        // Reopening takes them back, and closing online uploads them
+        fs.open_internal(inode).unwrap();
+        assert!(spool.list().unwrap().is_empty());
+        api.set_offline(false);
+        fs.release_internal(inode).unwrap();
+        assert_eq!(api.content("/doc.txt").unwrap(), b"Jello");
+    }
+
+    #[test]
+    fn test_release_over_quota_fails_with_enospc() {
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/doc.txt", b"hello"));
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+
+        fs.open_internal(inode).unwrap();
+        fs.write_internal(inode, 0, b"J").unwrap();
+        api.fail_uploads_with(Some(error::ApiError::QuotaExceeded {
+            code: None,
+            body: "Insufficient Storage".to_string(),
+        }));
+        assert_eq!(fs.release_internal(inode), Err(libc::ENOSPC));
+        assert!(fs.open_file(inode).unwrap().blocking_lock().dirty);
+
+        // Once there is room, the kept changes go up on the next close
+        api.fail_uploads_with(None);
+        fs.open_internal(inode).unwrap();
+        fs.release_internal(inode).unwrap();
+        assert_eq!(api.content("/doc.txt").unwrap(), b"Jello");
+    }
+
+    #[test]
+    fn test_open_after_listing_skips_file_info() {
+        let api = Arc::new(
+            ScriptedMockAPI::new()
+                .with_etags()
+                .with_file("/Docs/doc.txt", b"hello")
+                .with_folder("/Docs/Sub"),
+        );
+        let fs = test_fs(api.clone());
+        fs.readdir_internal(Path::new("/Docs")).unwrap();
+        let file = fs
+            .inode_table
+            .get_inode(Path::new("/Docs/doc.txt"))
+            .unwrap();
+        let folder = fs.inode_table.get_inode(Path::new("/Docs/Sub")).unwrap();
+
+        assert_eq!(fs.open_internal(folder), Ok(0));
+        assert_eq!(api.total_calls(), 1);
+        // A file's version is checked before its content is downloaded,
+        // which costs a 304 when nothing changed
+        assert_eq!(fs.open_internal(file), Ok(file));
+        assert_eq!(api.calls("get_file_info"), 0);
+        assert_eq!(api.calls("not_modified"), 1);
+        let file = fs.open_file(file).unwrap();
+        assert_eq!(file.blocking_lock().buffer.contents().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_open_after_remote_change_is_no_conflict() {
+        let api = Arc::new(
+            ScriptedMockAPI::new()
+                .with_file("/Docs/doc.txt", b"hello")
+                .with_large_file("/Docs/big.bin", 10 << 20),
+        );
+        let fs = test_fs(api.clone());
+        fs.readdir_internal(Path::new("/Docs")).unwrap();
+        let doc = fs
+            .inode_table
+            .get_inode(Path::new("/Docs/doc.txt"))
+            .unwrap();
+        let big = fs
+            .inode_table
+            .get_inode(Path::new("/Docs/big.bin"))
+            .unwrap();
+
+        // Changed while the listing is still cached: the edit is of the new
+        // version, not a conflict with it
+        api.change_remotely("/Docs/doc.txt", b"hello, world");
+        fs.open_internal(doc).unwrap();
+        fs.write_internal(doc, 0, b"J").unwrap();
+        assert_eq!(fs.flush_internal(doc), Ok(()));
+        assert_eq!(fs.release_internal(doc), Ok(()));
+        assert_eq!(api.content("/Docs/doc.txt").unwrap(), b"Jello, world");
+        assert_eq!(api.calls("upload_file"), 1);
+
+        // A large file is read by range from the version now current
+        api.change_remotely("/Docs/big.bin", &pattern(0, 12 << 20));
+        api.drop_old_versions();
+        fs.open_internal(big).unwrap();
+        let data = fs.read_internal(big, 11 << 20, 4096).unwrap();
+        assert_eq!(data, pattern(11 << 20, 4096));
+    }
+
+    #[test]
+    fn test_stat_reports_unflushed_size() {
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/doc.txt", b"hello"));
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+        fs.open_internal(inode).unwrap();
+        let before = api.calls("get_file_info");
+
+        fs.write_internal(inode, 0, &[b'x'; 100]).unwrap();
+        let attr = fs.get_attr_internal(Path::new("/doc.txt")).unwrap();
+        assert_eq!((attr.ino, attr.size), (inode, 100));
+        assert_eq!(api.calls("get_file_info"), before);
+        assert_eq!(api.content("/doc.txt").unwrap(), b"hello");
+
+        // Once uploaded, the API's answer is current again
+        fs.flush_internal(inode).unwrap();
+        assert_eq!(
+            fs.get_attr_internal(Path::new("/doc.txt")).unwrap().size,
+            100
+        );
+    }
+
+    #[test]
+    fn test_conflicting_flush_keeps_both_versions() {
+        let api = Arc::new(UploadAPI {
+            version: std::sync::Mutex::new(Some("v0".to_string())),
+            ..Default::default()
+        });
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+
+        fs.open_internal(inode).unwrap();
+        fs.write_internal(inode, 0, b"mine!").unwrap();
+        // Someone else uploads a new version meanwhile
+        *api.version.lock().unwrap() = Some("theirs".to_string());
+
+        assert_eq!(fs.flush_internal(inode), Err(libc::EIO));
+        assert_eq!(*api.version.lock().unwrap(), Some("theirs".to_string()));
+        {
+            let uploads = api.uploads.lock().unwrap();
+            assert_eq!(uploads.len(), 1);
+            assert!(uploads[0].0.starts_with("/doc.txt.conflict-"));
+            assert_eq!(uploads[0].1, b"mine!");
+        }
+        // Local changes are saved in the copy, so there is nothing left to flush
+        assert_eq!(fs.flush_internal(inode), Ok(()));
+    }
+
+    #[test]
+    fn test_diverged_file_keeps_one_conflict_copy() {
+        let api = Arc::new(UploadAPI {
+            version: std::sync::Mutex::new(Some("v0".to_string())),
+            ..Default::default()
+        });
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+
+        fs.open_internal(inode).unwrap();
+        fs.write_internal(inode, 0, b"mine!").unwrap();
+        *api.version.lock().unwrap() = Some("theirs".to_string());
+        assert_eq!(fs.flush_internal(inode), Err(libc::EIO));
+
+        // A second later a new copy would get another name; the changes
+        // after the conflict go to the copy made for it
+        std::thread::sleep(Duration::from_millis(1100));
+        fs.write_internal(inode, 5, b" more").unwrap();
+        assert_eq!(fs.flush_internal(inode), Err(libc::EIO));
+        assert_eq!(fs.release_internal(inode), Ok(()));
+        let uploads = api.uploads.lock().unwrap();
+        assert_eq!(uploads.len(), 2);
+        assert!(uploads[0].0.starts_with("/doc.txt.conflict-"));
+        assert_eq!(uploads[1], (uploads[0].0.clone(), b"mine! more".to_vec()));
+        assert_eq!(*api.version.lock().unwrap(), Some("theirs".to_string()));
+    }
+
+    #[test]
+    fn test_flush_without_version_check_is_no_conflict() {
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/doc.txt", b"hello"));
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+
+        fs.open_internal(inode).unwrap();
+        fs.write_internal(inode, 0, b"Jello").unwrap();
+        // The version check before the upload times out
+        api.fail_reads_with("/doc.txt", error::ApiError::Timeout("/doc.txt".to_string()));
+        assert_eq!(fs.flush_internal(inode), Err(libc::EAGAIN));
+        assert_eq!(api.calls("upload_file"), 0);
+
+        // The changes are still there for the next flush
+        api.fail_reads("/doc.txt", None);
+        fs.flush_internal(inode).unwrap();
+        assert_eq!(api.content("/doc.txt").unwrap(), b"Jello");
+        assert_eq!(api.calls("upload_file"), 1);
+    }
+
+    #[test]
+    fn test_flush_tracks_own_versions() {
+        let api = Arc::new(UploadAPI {
+            version: std::sync::Mutex::new(Some("v0".to_string())),
+            ..Default::default()
+        });
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+
+        fs.open_internal(inode).unwrap();
+        fs.write_internal(inode, 0, b"one").unwrap();
+        fs.flush_internal(inode).unwrap();
+        // Our own upload moved the version; the next flush is not a conflict
+        fs.write_internal(inode, 0, b"two").unwrap();
+        fs.flush_internal(inode).unwrap();
+        let uploads = api.uploads.lock().unwrap();
+        assert!(uploads.iter().all(|(path, _)| path == "/doc.txt"));
+    }
+
+    #[test]
+    fn test_create_and_truncate() {
+        let api = Arc::new(UploadAPI::default());
+        let fs = test_fs(api.clone());
+
+        let attr = fs.create_internal(1, OsStr::new("new.txt")).unwrap();
+        assert_eq!(attr.size, 0);
+        assert_eq!(
+            fs.inode_table.get_path(attr.ino),
+            Some(PathBuf::from("/new.txt"))
+        );
+
+        // Closed file: rewritten right away
+        let doc = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+        assert_eq!(fs.truncate_internal(doc, 2).unwrap().size, 2);
+        let uploads = api.uploads.lock().unwrap();
+        assert_eq!(uploads[0], ("/new.txt".to_string(), Vec::new()));
+        assert_eq!(uploads[1], ("/doc.txt".to_string(), b"he".to_vec()));
+    }
+
+    /// Filesystem over `api` whose kernel invalidations are recorded
+    fn recording_fs(api: Arc<dyn EgnyteAPI>) -> (EgnyteFuse, RecordingSink) {
+        let fs = test_fs(api);
+        let sink = RecordingSink::default();
+        fs.invalidator().attach(Box::new(sink.clone())).unwrap();
+        (fs, sink)
+    }
+
+    #[test]
+    fn test_unlink_invalidates_entry() {
+        let api = Arc::new(UploadAPI::default());
+        let (fs, sink) = recording_fs(api.clone());
+        let dir = fs.inode_table.get_or_create_inode(Path::new("/Shared"));
+        let file = fs
+            .inode_table
+            .get_or_create_inode(Path::new("/Shared/a.txt"));
+
+        fs.unlink_internal(dir, OsStr::new("a.txt")).unwrap();
+        assert_eq!(*api.deletes.lock().unwrap(), vec!["/Shared/a.txt"]);
+        assert_eq!(
+            sink.wait_for(3),
+            vec![
+                Invalidation::Inode(file),
+                Invalidation::Entry(dir, "a.txt".into()),
+                Invalidation::Inode(dir)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rename_invalidates_both_parents() {
+        let api = Arc::new(UploadAPI::default());
+        let (fs, sink) = recording_fs(api.clone());
+        let old_dir = fs.inode_table.get_or_create_inode(Path::new("/Old"));
+        let new_dir = fs.inode_table.get_or_create_inode(Path::new("/New"));
+        let file = fs.inode_table.get_or_create_inode(Path::new("/Old/a.txt"));
+
+        fs.rename_internal(
+            old_dir,
+            OsStr::new("a.txt"),
+            new_dir,
+            OsStr::new("b.txt"),
+            0,
+        )
+        .unwrap();
+        assert_eq!(
+            *api.moves.lock().unwrap(),
+            vec![("/Old/a.txt".to_string(), "/New/b.txt".to_string())]
+        );
+        assert_eq!(
+            fs.inode_table.get_path(file),
+            Some(PathBuf::from("/New/b.txt"))
+        );
+
+        let sent = sink.wait_for(4);
+        assert!(sent.contains(&Invalidation::Entry(new_dir, "b.txt".into())));
+        assert!(sent.contains(&Invalidation::Inode(new_dir)));
+        assert!(sent.contains(&Invalidation::Entry(old_dir, "a.txt".into())));
+        assert!(sent.contains(&Invalidation::Inode(old_dir)));
+    }
+
+    #[test]
+    fn test_rename_replaces_destination() {
+        let api = Arc::new(UploadAPI {
+            occupied: std::sync::Mutex::new(vec!["/b.txt".to_string()]),
+            ..Default::default()
+        });
+        let fs = test_fs(api.clone());
+        fs.rename_internal(1, OsStr::new("a.txt"), 1, OsStr::new("b.txt"), 0)
+            .unwrap();
+        // The old b.txt is moved aside, and deleted only once a.txt is in place
+        let moves = api.moves.lock().unwrap().clone();
+        assert_eq!(moves.len(), 2);
+        let (old, aside) = &moves[0];
+        assert_eq!(old, "/b.txt");
+        assert!(aside.starts_with("/b.txt.egnyte-fuse-replaced-"));
+        assert_eq!(moves[1], ("/a.txt".to_string(), "/b.txt".to_string()));
+        assert_eq!(*api.deletes.lock().unwrap(), vec![aside.clone()]);
+    }
+
+    #[test]
+    fn test_rename_onto_existing_entries() {
+        let api = Arc::new(
+            ScriptedMockAPI::new()
+                .with_file("/a.txt", b"a")
+                .with_file("/b.txt", b"b")
+                .with_file("/full/x.txt", b"x")
+                .with_folder("/empty")
+                .with_folder("/src"),
+        );
+        let fs = test_fs(api.clone());
+        let rename =
+            |from: &str, to: &str| fs.rename_internal(1, OsStr::new(from), 1, OsStr::new(to), 0);
+
+        // What rename(2) refuses is refused, and nothing is touched
+        assert_eq!(rename("a.txt", "empty"), Err(libc::EISDIR));
+        assert_eq!(rename("src", "a.txt"), Err(libc::ENOTDIR));
+        assert_eq!(rename("src", "full"), Err(libc::ENOTEMPTY));
+        assert_eq!(api.content("/full/x.txt").unwrap(), b"x");
+        assert!(api.exists("/empty") && api.exists("/src"));
+        assert_eq!(api.calls("delete_entry"), 0);
+
+        // If moving the source in fails, the destination is put back
+        api.fail_moves_from("/a.txt", true);
+        assert_eq!(rename("a.txt", "b.txt"), Err(libc::EIO));
+        assert_eq!(api.content("/a.txt").unwrap(), b"a");
+        assert_eq!(api.content("/b.txt").unwrap(), b"b");
+        assert_eq!(api.calls("delete_entry"), 0);
+        api.fail_moves_from("/a.txt", false);
+
+        // A folder replaces an empty folder
+        rename("src", "empty").unwrap();
+        assert!(!api.exists("/src") && api.exists("/empty"));
+        assert_eq!(api.calls("delete_entry"), 1);
+    }
+
+    #[test]
+    fn test_create_write_rename_and_unlink_against_tree() {
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/b.txt", b"old"));
+        let fs = test_fs(api.clone());
+
+        let attr = fs.create_internal(1, OsStr::new("a.txt")).unwrap();
+        fs.write_internal(attr.ino, 0, b"draft").unwrap();
+        fs.flush_internal(attr.ino).unwrap();
+        assert_eq!(api.content("/a.txt").unwrap(), b"draft");
+
+        // Replacing b.txt takes a failed move, moving the old one aside,
+        // the move itself and deleting the old one
+        fs.rename_internal(1, OsStr::new("a.txt"), 1, OsStr::new("b.txt"), 0)
+            .unwrap();
+        assert!(!api.exists("/a.txt"));
+        assert_eq!(api.content("/b.txt").unwrap(), b"draft");
+        assert_eq!(api.calls("move_entry"), 3);
+
+        fs.unlink_internal(1, OsStr::new("b.txt")).unwrap();
+        assert!(!api.exists("/b.txt"));
+        assert_eq!(api.calls("delete_entry"), 2);
+    }
+
+    #[test]
+    fn test_rename_flags() {
+        let api = Arc::new(
+            ScriptedMockAPI::new()
+                .with_file("/a.txt", b"a")
+                .with_file("/b.txt", b"b")
+                .with_folder("/dir"),
+        );
+        let fs = test_fs(api.clone());
+        let rename = |from: &str, to: &str, flags| {
+            fs.rename_internal(1, OsStr::new(from), 1, OsStr::new(to), flags)
+        };
+
+        // NOREPLACE leaves an existing destination alone
+        assert_eq!(
+            rename("a.txt", "b.txt", libc::RENAME_NOREPLACE),
+            Err(libc::EEXIST)
+        );
+        assert_eq!(api.content("/a.txt").unwrap(), b"a");
+        assert_eq!(api.content("/b.txt").unwrap(), b"b");
+        // also when the destination is a folder, empty or not
+        assert_eq!(
+            rename("a.txt", "dir", libc::RENAME_NOREPLACE),
+            Err(libc::EEXIST)
+        );
+        assert!(api.exists("/dir"));
+        assert_eq!(api.calls("delete_entry"), 0);
+        assert_eq!(api.calls("get_file_info"), 0);
+        // and renames onto a free name as usual
+        rename("a.txt", "c.txt", libc::RENAME_NOREPLACE).unwrap();
+        assert_eq!(api.content("/c.txt").unwrap(), b"a");
+
+        // No exchange, and no guessing at flags we don't know
+        for flags in [libc::RENAME_EXCHANGE, libc::RENAME_WHITEOUT] {
+            assert_eq!(rename("b.txt", "c.txt", flags), Err(libc::EINVAL));
+        }
+        assert_eq!(api.content("/b.txt").unwrap(), b"b");
+        assert_eq!(api.content("/c.txt").unwrap(), b"a");
+        assert_eq!(api.calls("move_entry"), 3);
+    }
+
+    #[test]
+    fn test_flush_and_truncate_invalidate_inode() {
+        let api = Arc::new(UploadAPI::default());
+        let (fs, sink) = recording_fs(api);
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+
+        fs.open_internal(inode).unwrap();
+        fs.write_internal(inode, 0, b"HELLO").unwrap();
+        fs.flush_internal(inode).unwrap();
+        fs.truncate_internal(inode, 2).unwrap();
+        assert_eq!(
+            sink.wait_for(2),
+            vec![Invalidation::Inode(inode), Invalidation::Inode(inode)]
+        );
+    }
+
+    #[test]
+    fn test_truncate_past_a_file_offset() {
+        let api = Arc::new(UploadAPI::default());
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+        fs.open_internal(inode).unwrap();
+        fs.write_internal(inode, 0, b"HELLO").unwrap();
+
+        assert_eq!(
+            fs.truncate_internal(inode, u64::MAX).unwrap_err(),
+            libc::EFBIG
+        );
+        assert_eq!(fs.read_internal(inode, 0, 100).unwrap(), b"HELLO");
+    }
+
+    #[test]
+    fn test_fallocate() {
+        let api = Arc::new(UploadAPI::default());
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+        fs.open_internal(inode).unwrap();
+
+        // Reserving space only doesn't change the file
+        fs.fallocate_internal(inode, 0, 4096, libc::FALLOC_FL_KEEP_SIZE)
+            .unwrap();
+        fs.flush_internal(inode).unwrap();
+        assert!(api.uploads.lock().unwrap().is_empty());
+
+        fs.fallocate_internal(inode, 5, 3, 0).unwrap();
+        fs.flush_internal(inode).unwrap();
+        assert_eq!(api.uploads.lock().unwrap()[0].1, b"hello\0\0\0");
+
+        assert_eq!(
+            fs.fallocate_internal(
+                inode,
+                0,
+                2,
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE
+            ),
+            Err(libc::EOPNOTSUPP)
+        );
+        assert_eq!(fs.fallocate_internal(inode, 0, 0, 0), Err(libc::EINVAL));
+        assert_eq!(fs.fallocate_internal(99, 0, 1, 0), Err(libc::EBADF));
+    }
+
+    #[test]
+    fn test_root_maps_to_egnyte_folder() {
+        let api = Arc::new(UploadAPI::default());
+        let fs = test_fs(api.clone()).with_root("/Shared/Projects/");
+
+        fs.readdir_internal(Path::new("/")).unwrap();
+        assert_eq!(*api.listed.lock().unwrap(), vec!["/Shared/Projects"]);
+
+        let dir = fs.inode_table.get_or_create_inode(Path::new("/Plans"));
+        fs.unlink_internal(dir, OsStr::new("a.txt")).unwrap();
+        assert_eq!(
+            *api.deletes.lock().unwrap(),
+            vec!["/Shared/Projects/Plans/a.txt"]
+        );
+        // The kernel still sees / as the top
+        assert_eq!(fs.inode_table.get_path(1), Some(PathBuf::from("/")));
+    }
+
+    #[test]
+    fn test_read_only_rejects_mutations() {
+        let api = Arc::new(CountingAPI::default());
+        let fs = test_fs(api.clone()).with_read_only(true);
+        let name = OsStr::new("file.txt");
+
+        assert_eq!(fs.write_internal(2, 0, b"data"), Err(libc::EROFS));
+        assert_eq!(fs.create_internal(1, name).unwrap_err(), libc::EROFS);
+        assert_eq!(
+            fs.mknod_internal(1, name, libc::S_IFREG).unwrap_err(),
+            libc::EROFS
+        );
+        assert_eq!(fs.mkdir_internal(1, name).unwrap_err(), libc::EROFS);
+        assert_eq!(fs.unlink_internal(1, name), Err(libc::EROFS));
+        assert_eq!(fs.rmdir_internal(1, name), Err(libc::EROFS));
+        assert_eq!(
+            fs.rename_internal(1, name, 1, OsStr::new("b"), 0),
+            Err(libc::EROFS)
+        );
+        assert_eq!(
+            fs.link_internal(2, 1, OsStr::new("b")).unwrap_err(),
+            libc::EROFS
+        );
+        assert_eq!(fs.truncate_internal(2, 0).unwrap_err(), libc::EROFS);
+        assert_eq!(fs.fallocate_internal(2, 0, 1, 0), Err(libc::EROFS));
+        assert_eq!(api.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let fs = test_fs(Arc::new(UploadAPI::default()));
+        let attr = fs.lookup_internal(1, OsStr::new("doc.txt")).unwrap();
+        assert_eq!(attr.size, 5);
+        assert_eq!(
+            fs.inode_table.get_inode(Path::new("/doc.txt")),
+            Some(attr.ino)
+        );
+
+        let fs = test_fs(Arc::new(CountingAPI::default()));
+        assert_eq!(
+            fs.lookup_internal(1, OsStr::new("missing.txt")),
+            Err(libc::ENOENT)
+        );
+        assert_eq!(fs.inode_table.get_inode(Path::new("/missing.txt")), None);
+        assert_eq!(fs.lookup_internal(99, OsStr::new("a")), Err(libc::ENOENT));
+    }
+
+    #[test]
+    fn test_sibling_lookups_share_one_listing() {
+        let mut api = ScriptedMockAPI::new();
+        for i in 0..10 {
+            api = api.with_file(&format!("/Docs/{}.txt", i), b"x");
+        }
+        let api = Arc::new(api);
+        let fs = test_fs(api.clone()).with_attr_ttl(Duration::from_secs(60));
+        let docs = fs.lookup_internal(1, OsStr::new("Docs")).unwrap().ino;
+
+        for i in 0..10 {
+            let name = format!("{}.txt", i);
+            let attr = fs.lookup_internal(docs, OsStr::new(&name)).unwrap();
+            assert_eq!(attr.size, 1);
+        }
+        assert_eq!(api.calls("list_folder"), 1);
+        // Docs itself and the first file, before the burst showed
+        assert_eq!(api.calls("get_file_info"), 2);
+
+        // A name the listing lacks is asked about, without listing again
+        assert_eq!(
+            fs.lookup_internal(docs, OsStr::new("new.txt")),
+            Err(libc::ENOENT)
+        );
+        assert_eq!(api.calls("list_folder"), 1);
+    }
+
+    #[test]
+    fn test_case_insensitive_lookups_share_an_inode() {
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/Docs/Report.PDF", b"x"));
+        let fs = EgnyteFuseBuilder::new()
+            .case_insensitive(true)
+            .build(api.clone())
+            .unwrap()
+            .with_attr_ttl(Duration::from_secs(60));
+        let docs = fs.lookup_internal(1, OsStr::new("Docs")).unwrap().ino;
+        fs.readdir_internal(Path::new("/Docs")).unwrap();
+        let listed = fs
+            .inode_table
+            .get_inode(Path::new("/Docs/Report.PDF"))
+            .unwrap();
+
+        // Answered from the listing, under the name Egnyte has
+        let attr = fs.lookup_internal(docs, OsStr::new("report.pdf")).unwrap();
+        assert_eq!(attr.ino, listed);
+        assert_eq!(fs.lookup_internal(1, OsStr::new("DOCS")).unwrap().ino, docs);
+        assert_eq!(api.calls("get_file_info"), 1);
+        assert_eq!(
+            fs.inode_table.get_path(listed),
+            Some(PathBuf::from("/Docs/Report.PDF"))
+        );
+    }
+
+    #[test]
+    fn test_zero_ttl_asks_every_time() {
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/a.txt", b"x"));
+        let fs = test_fs(api.clone())
+            .with_entry_ttl(Duration::ZERO)
+            .with_attr_ttl(Duration::ZERO);
+        fs.readdir_internal(Path::new("/")).unwrap();
+
+        // Neither the listing nor earlier answers are reused
+        let a = fs.lookup_internal(1, OsStr::new("a.txt")).unwrap().ino;
+        for _ in 0..3 {
+            fs.get_attr_internal(Path::new("/a.txt")).unwrap();
+        }
+        fs.open_internal(a).unwrap();
+        assert_eq!(api.calls("get_file_info"), 5);
+        assert!(fs.invalidator.metadata().get(a, Duration::MAX).is_none());
+    }
+
+    #[test]
+    fn test_caching_disabled_asks_every_time() {
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/a.txt", b"x"));
+        let fs = EgnyteFuseBuilder::new()
+            .caching_enabled(false)
+            .build(api.clone())
+            .unwrap();
+        assert_eq!(fs.attr_ttl, Duration::ZERO);
+        assert_eq!(fs.open_flags(), FOPEN_DIRECT_IO);
+        fs.readdir_internal(Path::new("/")).unwrap();
+
+        let a = fs.lookup_internal(1, OsStr::new("a.txt")).unwrap().ino;
+        for _ in 0..5 {
+            fs.get_attr_internal(Path::new("/a.txt")).unwrap();
+        }
+        fs.getxattr_internal(a, OsStr::new(ENTRY_ID_XATTR)).unwrap();
+        fs.open_internal(a).unwrap();
+        assert_eq!(api.calls("get_file_info"), 8);
+
+        // Even TTLs set later do not bring the metadata cache back
+        let fs = fs.with_attr_ttl(Duration::from_secs(60));
+        fs.get_attr_internal(Path::new("/a.txt")).unwrap();
+        fs.get_attr_internal(Path::new("/a.txt")).unwrap();
+        assert_eq!(api.calls("get_file_info"), 10);
+    }
+
+    #[test]
+    fn test_ignored_names_skip_the_api() {
+        let api = Arc::new(CountingAPI::default());
+        let fs = test_fs(api.clone());
+        for name in [".Trash", ".Trash-1000", ".xdg-volume-info", "autorun.inf"] {
+            assert_eq!(fs.lookup_internal(1, OsStr::new(name)), Err(libc::ENOENT));
+        }
+        assert_eq!(api.calls.load(Ordering::SeqCst), 0);
+        assert!(!is_ignored_name(
+            OsStr::new(".Trashcan"),
+            &FuseOptions::default().ignored_names
+        ));
+
+        let fs = EgnyteFuseBuilder::new()
+            .ignored_names(vec!["desktop.ini".to_string()])
+            .build(api.clone())
+            .unwrap();
+        assert_eq!(
+            fs.lookup_internal(1, OsStr::new("desktop.ini")),
+            Err(libc::ENOENT)
+        );
+        assert_eq!(api.calls.load(Ordering::SeqCst), 0);
+        let _ = fs.lookup_internal(1, OsStr::new(".Trash"));
+        assert_eq!(api.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let mut applied = Vec::new();
+        let value = negotiate(4096, |v| {
+            applied.push(v);
+            Ok(0)
+        });
+        assert_eq!(value, 4096);
+        assert_eq!(applied, vec![4096]);
+
+        let mut applied = Vec::new();
+        let value = negotiate(64 << 20, |v| {
+            applied.push(v);
+            if v > 16 << 20 {
+                Err(16 << 20)
+            } else {
+                Ok(0)
+            }
+        });
+        assert_eq!(value, 16 << 20);
+        assert_eq!(applied, vec![64 << 20, 16 << 20]);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let fs = test_fs(Arc::new(CountingAPI::default()));
+        let wanted = fs.wanted_capabilities();
+        assert!(wanted.contains(&("writeback_cache", FUSE_WRITEBACK_CACHE)));
+        assert!(wanted.contains(&("handle_killpriv", FUSE_HANDLE_KILLPRIV)));
+
+        // A kernel without writeback cache still grants the rest
+        let (granted, refused) = negotiate_capabilities(&wanted, |flag| {
+            if flag == FUSE_WRITEBACK_CACHE || flag == FUSE_HANDLE_KILLPRIV {
+                Err(flag)
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(
+            granted,
+            vec![
+                "readdirplus",
+                "readdirplus_auto",
+                "parallel_dirops",
+                "posix_locks"
+            ]
+        );
+        assert_eq!(refused, vec!["writeback_cache", "handle_killpriv"]);
+
+        let fs = fs.with_read_only(true);
+        assert!(!fs
+            .wanted_capabilities()
+            .iter()
+            .any(|(name, _)| *name == "writeback_cache" || *name == "handle_killpriv"));
+    }
+
+    #[test]
+    fn test_setattr_mode_only_clears_privileges() {
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/a.txt", b"a"));
+        let fs = test_fs(api);
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/a.txt"));
+        // What the kernel sends before a write without HANDLE_KILLPRIV
+        let killed = libc::S_IFREG | 0o644;
+        assert_eq!(
+            fs.kill_privileges_internal(inode, killed).unwrap().perm,
+            0o644
+        );
+        // A real chmod, setuid included, is not supported
+        for mode in [0o600, killed | libc::S_ISUID] {
+            assert_eq!(
+                fs.kill_privileges_internal(inode, mode).unwrap_err(),
+                libc::ENOSYS
+            );
+        }
+    }
+
+    #[test]
+    fn test_setattr_reports_unflushed_size_from_cache() {
+        let api = Arc::new(
+            ScriptedMockAPI::new()
+                .with_etags()
+                .with_file("/a.txt", b"a"),
+        );
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/a.txt"));
+        assert_eq!(fs.stat_internal(inode).unwrap().size, 1);
+        assert_eq!(fs.stat_internal(inode).unwrap().size, 1);
+        assert_eq!(api.calls("get_file_info"), 1);
+        assert_eq!(api.calls("not_modified"), 1);
+
+        // A touch or chmod of an open file with unflushed writes, as cp -p
+        // sends before close, reports the local size without asking
+        fs.open_internal(inode).unwrap();
+        fs.write_internal(inode, 0, b"abcdef").unwrap();
+        let calls = api.total_calls();
+        assert_eq!(fs.stat_internal(inode).unwrap().size, 6);
+        let killed = libc::S_IFREG | 0o644;
+        assert_eq!(fs.kill_privileges_internal(inode, killed).unwrap().size, 6);
+        assert_eq!(api.total_calls(), calls);
+    }
+
+    #[test]
+    fn test_lseek() {
+        let fs = test_fs(Arc::new(UploadAPI::default()));
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+        // Closed: the size comes from the API (5 bytes)
+        assert_eq!(fs.lseek_internal(inode, 2, libc::SEEK_DATA), Ok(2));
+        assert_eq!(fs.lseek_internal(inode, 2, libc::SEEK_HOLE), Ok(5));
+        assert_eq!(
+            fs.lseek_internal(inode, 5, libc::SEEK_DATA),
+            Err(libc::ENXIO)
+        );
+        assert_eq!(
+            fs.lseek_internal(inode, -1, libc::SEEK_HOLE),
+            Err(libc::EINVAL)
+        );
+        assert_eq!(
+            fs.lseek_internal(inode, 0, libc::SEEK_END),
+            Err(libc::EINVAL)
+        );
+
+        // Open: unsaved writes count
+        fs.open_internal(inode).unwrap();
+        fs.write_internal(inode, 5, b" world").unwrap();
+        assert_eq!(fs.lseek_internal(inode, 5, libc::SEEK_DATA), Ok(5));
+        assert_eq!(fs.lseek_internal(inode, 0, libc::SEEK_HOLE), Ok(11));
+
+        // Other seeks are refused before anything is asked of Egnyte
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/a.txt", b"a"));
+        let fs = test_fs(api.clone());
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/a.txt"));
+        for whence in [libc::SEEK_SET, libc::SEEK_CUR, libc::SEEK_END] {
+            assert_eq!(fs.lseek_internal(inode, 0, whence), Err(libc::EINVAL));
+        }
+        assert_eq!(api.total_calls(), 0);
+    }
+
+    #[test]
+    fn test_slice_range() {
+        assert_eq!(slice_range(b"hello", 1, 3), b"ell");
+        assert_eq!(slice_range(b"hello", 3, 10), b"lo");
+        assert!(slice_range(b"hello", 5, 1).is_empty());
+        assert!(slice_range(b"hello", u64::MAX, u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let fs = test_fs(Arc::new(CountingAPI::default()));
+        assert_eq!(fs.open_flags(), 0);
+        let fs = fs.with_direct_io(true);
+        assert_eq!(fs.open_flags(), FOPEN_DIRECT_IO);
+    }
+
+    #[test]
+    fn test_ttls() {
+        let fs = test_fs(Arc::new(CountingAPI::default()));
+        assert_eq!(fs.attr_ttl, DEFAULT_ATTR_TTL);
+        assert_eq!(fs.negative_ttl, DEFAULT_NEGATIVE_TTL);
+
+        let fs = fs
+            .with_attr_ttl(Duration::from_secs(300))
+            .with_negative_ttl(Duration::ZERO);
+        assert_eq!(fs.attr_ttl, Duration::from_secs(300));
+        assert!(fs.negative_ttl.is_zero());
+        assert_eq!(fs.negative_attr().ino, 0);
+    }
+
+    #[test]
+    fn test_child_path_keeps_utf8_names() {
+        let path = child_path(Path::new("/"), OsStr::new("café.txt")).unwrap();
+        assert_eq!(path, PathBuf::from("/café.txt"));
+        let path = child_path(Path::new("/Shared"), OsStr::new("a b")).unwrap();
+        assert_eq!(path, PathBuf::from("/Shared/a b"));
+    }
+
+    #[test]
+    fn test_child_path_rejects_latin1_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        // "café.txt" encoded as Latin-1
+        let name = OsStr::from_bytes(b"caf\xe9.txt");
+        assert_eq!(child_path(Path::new("/Shared"), name), Err(libc::EINVAL));
+    }
+
+    #[test]
+    fn test_degraded_mode_asks_to_retry() {
+        let egnyte = Arc::new(
+            ScriptedMockAPI::new()
+                .with_file("/a.txt", b"a")
+                .with_file("/b.txt", b"b"),
+        );
+        let api = Arc::new(crate::fs::health::DegradableAPI::new(egnyte, None));
+        let fs = test_fs(api.clone());
+        let looked_up = fs.lookup_internal(1, OsStr::new("a.txt")).unwrap();
+        let unknown = fs.inode_table.get_or_create_inode(Path::new("/b.txt"));
+        for _ in 0..crate::fs::health::DEGRADED_AFTER {
+            api.health()
+                .record::<()>(&Err(error::ApiError::from_status(503, String::new()).into()));
+        }
+
+        // Nothing is cached for these: EAGAIN, not ENOENT or EIO
+        assert_eq!(fs.open_internal(unknown), Err(libc::EAGAIN));
+        assert_eq!(fs.open_internal(looked_up.ino), Err(libc::EAGAIN));
+        let err = fs.get_attr_internal(Path::new("/b.txt")).unwrap_err();
+        assert_eq!(error::errno(&err), libc::EAGAIN);
+        assert_eq!(fs.dir_snapshot(1, 0).unwrap_err(), libc::EAGAIN);
+    }
+
+    #[test]
+    fn test_timeouts_ask_to_retry() {
+        let api = Arc::new(
+            ScriptedMockAPI::new()
+                .with_file("/a.txt", b"a")
+                .with_folder("/dir"),
+        );
+        let fs = test_fs(api.clone());
+        let a = fs.inode_table.get_or_create_inode(Path::new("/a.txt"));
+        for path in ["/a.txt", "/dir"] {
+            api.fail_reads_with(path, error::ApiError::Timeout(path.to_string()));
+        }
+
+        assert_eq!(fs.open_internal(a), Err(libc::EAGAIN));
+        let err = fs.get_attr_internal(Path::new("/a.txt")).unwrap_err();
+        assert_eq!(error::errno(&err), libc::EAGAIN);
+        let dir = fs.inode_table.get_or_create_inode(Path::new("/dir"));
+        assert_eq!(fs.dir_snapshot(dir, 0).unwrap_err(), libc::EAGAIN);
+    }
+
+    #[test]
+    fn test_getattr_revalidates_cached_entry() {
+        let api = Arc::new(
+            ScriptedMockAPI::new()
+                .with_etags()
+                .with_file("/a.txt", b"a"),
+        );
+        let fs = test_fs(api.clone());
+        let path = Path::new("/a.txt");
+
+        assert_eq!(fs.get_attr_internal(path).unwrap().size, 1);
+        assert_eq!(fs.get_attr_internal(path).unwrap().size, 1);
+        assert_eq!(api.calls("get_file_info"), 1);
+        assert_eq!(api.calls("not_modified"), 1);
+
+        api.change_remotely("/a.txt", b"abc");
+        assert_eq!(fs.get_attr_internal(path).unwrap().size, 3);
+        assert_eq!(api.calls("file_info_if_changed"), 2);
+        assert_eq!(api.calls("not_modified"), 1);
+    }
+
+    #[test]
+    fn test_access() {
+        let api = Arc::new(CountingAPI::default());
+        let fs = test_fs(api.clone());
+        assert_eq!(fs.access_internal(1, libc::F_OK), Ok(()));
+        assert_eq!(fs.access_internal(1, libc::R_OK | libc::W_OK), Ok(()));
+        assert_eq!(fs.access_internal(42, libc::F_OK), Err(libc::ENOENT));
+
+        let fs = fs.with_read_only(true);
+        assert_eq!(fs.access_internal(1, libc::R_OK | libc::X_OK), Ok(()));
+        assert_eq!(fs.access_internal(1, libc::W_OK), Err(libc::EROFS));
+        assert_eq!(api.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_access_of_viewer_entries() {
+        let api = Arc::new(CountingAPI::default());
+        let fs = test_fs(api.clone());
+        let mut entry = local_entry(Path::new("/doc.txt"), "/doc.txt".to_string(), 1);
+        let inode = fs.inode_table.get_or_create_inode(Path::new("/doc.txt"));
+        entry.permission = Some(Permission::Viewer);
+        fs.remember(inode, &entry);
+        assert_eq!(fs.access_internal(inode, libc::R_OK), Ok(()));
+        assert_eq!(fs.access_internal(inode, libc::W_OK), Err(libc::EACCES));
+
+        entry.permission = Some(Permission::Editor);
+        fs.remember(inode, &entry);
+        assert_eq!(fs.access_internal(inode, libc::W_OK), Ok(()));
+        assert_eq!(api.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_permission_mode_bits() {
+        let defaults = AttrOptions::default();
+        let mut entry = local_entry(Path::new("/doc.txt"), "/doc.txt".to_string(), 1);
+        assert_eq!(perm_bits(&entry, &defaults), 0o644);
+        entry.permission = Permission::parse("Viewer");
+        assert_eq!(perm_bits(&entry, &defaults), 0o444);
+        entry.permission = Permission::parse("Editor");
+        assert_eq!(perm_bits(&entry, &defaults), 0o644);
+
+        entry.is_folder = true;
+        entry.permission = Permission::parse("owner");
+        assert_eq!(perm_bits(&entry, &defaults), 0o755);
+        entry.permission = Some(Permission::Viewer);
+        assert_eq!(perm_bits(&entry, &defaults), 0o555);
+        assert_eq!(Permission::parse("None"), None);
+    }
+
+    #[test]
+    fn test_entry_to_attr() {
+        let fs = EgnyteFuse::new(Arc::new(CountingAPI::default())).unwrap();
+        let mut entry = local_entry(Path::new("/doc.txt"), "/doc.txt".to_string(), 1025);
+        entry.modified_time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let attr = fs.entry_to_attr(7, &entry);
+        assert_eq!(
+            (attr.ino, attr.kind, attr.size),
+            (7, FileType::RegularFile, 1025)
+        );
+        // 512-byte blocks, rounded up
+        assert_eq!(attr.blocks, 3);
+        assert_eq!(
+            (attr.mtime, attr.ctime),
+            (entry.modified_time, entry.modified_time)
+        );
+        assert_eq!((attr.perm, attr.nlink), (0o644, 1));
+
+        entry.size = 0;
+        entry.is_folder = true;
+        let attr = fs.entry_to_attr(7, &entry);
+        assert_eq!(
+            (attr.kind, attr.blocks, attr.perm),
+            (FileType::Directory, 0, 0o755)
+        );
+    }
+
+    #[test]
+    fn test_blocks_follow_block_size() {
+        let entry = local_entry(Path::new("/doc.txt"), "/doc.txt".to_string(), 4097);
+        for (block_size, blocks) in [(512, 9), (4096, 16)] {
+            let fs = EgnyteFuseBuilder::new()
+                .block_size(block_size)
+                .build(Arc::new(CountingAPI::default()))
+                .unwrap();
+            let attr = fs.entry_to_attr(2, &entry);
+            assert_eq!((attr.blksize, attr.blocks), (block_size, blocks));
+        }
+    }
+
+    #[test]
+    fn test_owner_defaults_to_mounting_user() {
+        let fs = EgnyteFuse::new(Arc::new(CountingAPI::default())).unwrap();
+        let entry = local_entry(Path::new("/doc.txt"), "/doc.txt".to_string(), 1);
+        let attr = fs.entry_to_attr(2, &entry);
+        assert_eq!(attr.uid, unsafe { libc::getuid() });
+        assert_eq!(attr.gid, unsafe { libc::getgid() });
+
+        // Overriding one keeps the other
+        let fs = fs.with_attr_options(AttrOptions {
+            gid: Some(4242),
+            ..Default::default()
+        });
+        let attr = fs.entry_to_attr(2, &entry);
+        assert_eq!((attr.uid, attr.gid), (unsafe { libc::getuid() }, 4242));
+    }
+
+    #[test]
+    fn test_attr_options() {
+        let options = AttrOptions {
+            uid: Some(1234),
+            gid: Some(5678),
+            file_mask: Some(0o077),
+            dir_mask: Some(0o027),
+        };
+        let fs = EgnyteFuse::new(Arc::new(CountingAPI::default()))
+            .unwrap()
+            .with_attr_options(options);
+
+        let mut entry = local_entry(Path::new("/doc.txt"), "/doc.txt".to_string(), 1);
+        let attr = fs.entry_to_attr(2, &entry);
+        assert_eq!((attr.uid, attr.gid, attr.perm), (1234, 5678, 0o600));
+
+        entry.is_folder = true;
+        assert_eq!(fs.entry_to_attr(2, &entry).perm, 0o750);
+        entry.permission = Some(Permission::Viewer);
+        assert_eq!(fs.entry_to_attr(2, &entry).perm, 0o550);
+    }
+
+    #[test]
+    fn test_with_runtime_applies_options() {
+        let rt = Arc::new(Runtime::new().unwrap());
+        let options = FuseOptions {
+            read_only: true,
+            root: "/Shared/Projects/".to_string(),
+            entry_ttl: Duration::from_secs(300),
+            attr_ttl: Duration::from_secs(60),
+            attr_options: AttrOptions {
+                uid: Some(1234),
+                ..Default::default()
+            },
+            ..FuseOptions::default()
+        };
+        let api = Arc::new(CountingAPI::default());
+        let fs = EgnyteFuse::with_runtime(api.clone(), Arc::clone(&rt), options);
+        assert!(Arc::ptr_eq(&fs.rt, &rt));
+        assert_eq!(fs.check_writable(), Err(libc::EROFS));
+        assert_eq!(fs.to_egnyte_path(Path::new("/a")), "/Shared/Projects/a");
+        assert_eq!(fs.entry_ttl, Duration::from_secs(300));
+        assert_eq!(fs.attr_ttl, Duration::from_secs(60));
+        assert_eq!(fs.negative_ttl, DEFAULT_NEGATIVE_TTL);
+        assert_eq!((fs.uid, fs.gid), (1234, unsafe { libc::getgid() }));
+
+        // Several filesystems can share one runtime
+        let other = EgnyteFuse::with_runtime(api, Arc::clone(&rt), FuseOptions::default());
+        assert_eq!(other.check_writable(), Ok(()));
+        assert_eq!(Arc::strong_count(&rt), 3);
+    }
+
+    #[test]
+    fn test_operations_each_get_a_budget() {
+        let fs = EgnyteFuseBuilder::new()
+            .op_deadline(Duration::from_secs(5))
+            .build(Arc::new(CountingAPI::default()))
+            .unwrap();
+        let current = || fs.call(async { RetryBudget::current() });
+        // Outside an operation nothing is bounded
+        assert!(current().is_none());
+
+        fs.begin_op();
+        let first = current().unwrap();
+        assert!(Arc::ptr_eq(&first, &current().unwrap()));
+        assert!(first.remaining() <= Duration::from_secs(5));
+        fs.begin_op();
+        assert!(!Arc::ptr_eq(&first, &current().unwrap()));
+    }
+
+    #[test]
+    fn test_builder() {
+        let built = EgnyteFuseBuilder::new()
+            .build(Arc::new(CountingAPI::default()))
+            .unwrap();
+        let plain = EgnyteFuse::new(Arc::new(CountingAPI::default())).unwrap();
+        assert_eq!(built.check_writable(), plain.check_writable());
+        assert_eq!(built.root, plain.root);
+        assert_eq!(built.entry_ttl, plain.entry_ttl);
+        assert_eq!(built.attr_ttl, plain.attr_ttl);
+        assert_eq!(built.negative_ttl, plain.negative_ttl);
+        assert_eq!(built.open_flags(), plain.open_flags());
+        assert_eq!(built.buffer_dir, plain.buffer_dir);
+
+        let rt = Arc::new(Runtime::new().unwrap());
+        let fs = EgnyteFuseBuilder::new()
+            .runtime(Arc::clone(&rt))
+            .read_only(true)
+            .root("/Shared")
+            .attr_ttl(Duration::from_secs(60))
+            .negative_ttl(Duration::ZERO)
+            .max_readahead(4 << 20)
+            .max_write(128 << 10)
+            .direct_io(true)
+            .build(Arc::new(CountingAPI::default()))
+            .unwrap();
+        assert!(Arc::ptr_eq(&fs.rt, &rt));
+        assert_eq!(fs.check_writable(), Err(libc::EROFS));
+        assert_eq!(fs.root, "/Shared");
+        assert_eq!(fs.attr_ttl, Duration::from_secs(60));
+        assert!(fs.negative_ttl.is_zero());
+        assert_eq!((fs.max_readahead, fs.max_write), (4 << 20, 128 << 10));
+        assert_eq!(fs.open_flags(), FOPEN_DIRECT_IO);
+    }
+
+    #[test]
+    fn test_writable_by_default() {
+        let fs = EgnyteFuse::new(Arc::new(CountingAPI::default())).unwrap();
+        assert_eq!(fs.check_writable(), Ok(()));
+    }
+
+    fn listing() -> Vec<&'static str> {
+        vec![".", "..", "a.txt", "b"]
+    }
+
+    #[test]
+    fn test_dir_page_cookies() {
+        let entries = listing();
+        let cookies: Vec<i64> = dir_page(&entries, 0).map(|(cookie, _)| cookie).collect();
+        assert_eq!(cookies, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_dir_page_resume() {
+        let entries = listing();
+        let page: Vec<(i64, &str)> = dir_page(&entries, 2)
+            .map(|(cookie, name)| (cookie, *name))
+            .collect();
+        assert_eq!(page, vec![(3, "a.txt"), (4, "b")]);
+        assert_eq!(dir_page(&entries, 4).count(), 0);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::fs::error::{self, ApiError};
+use crate::fs::fuse_ops::{EgnyteAPI, EgnyteEntry, EventBatch, Validators};
+use anyhow::Result;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Consecutive 503 responses after which Egnyte counts as down
+pub const DEGRADED_AFTER: u32 = 3;
+
+/// How often to check whether Egnyte is back while degraded
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Whether a result is Egnyte saying it is unavailable (maintenance)
+fn is_unavailable<T>(result: &Result<T>) -> bool {
+    matches!(
+        result.as_ref().err().and_then(|e| e.downcast_ref()),
+        Some(ApiError::Status { status: 503, .. })
+    )
+}
+
+/// Availability of Egnyte as seen from API results: degraded after
+/// [`DEGRADED_AFTER`] 503s in a row, healthy again on the first answer that
+/// is not a 503. Each change is logged once.
+#[derive(Debug, Default)]
+pub struct ServiceHealth {
+    /// 503s since the last other answer
+    unavailable: Mutex<u32>,
+}
+
+impl ServiceHealth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        *self.unavailable.lock().unwrap() >= DEGRADED_AFTER
+    }
+
+    /// Count one API result. Errors other than 503 (not found, forbidden,
+    /// ...) still show the service answering.
+    pub fn record<T>(&self, result: &Result<T>) {
+        let mut unavailable = self.unavailable.lock().unwrap();
+        if is_unavailable(result) {
+            *unavailable += 1;
+            if *unavailable == DEGRADED_AFTER {
+                warn!(
+                    "Egnyte is unavailable (HTTP 503, likely maintenance); serving cached data and \
+                     answering EAGAIN for the rest until it is back"
+                );
+            }
+        } else if result
+            .as_ref()
+            .err()
+            .is_none_or(|e| !error::is_transient(e))
+        {
+            if *unavailable >= DEGRADED_AFTER {
+                info!("Egnyte is available again; leaving degraded mode");
+            }
+            *unavailable = 0;
+        }
+    }
+}
+
+/// An [`EgnyteAPI`] that stops hammering Egnyte while it is down.
+///
+/// Calls pass through to `inner` until [`ServiceHealth`] says Egnyte is
+/// degraded. From then on reads are answered by `fallback` (typically the
+/// offline copies), and whatever it cannot answer, as well as every change,
+/// fails with [`ApiError::Unavailable`] (EAGAIN, so applications retry)
+/// rather than EIO.
+/// [`Self::probe_while_degraded`] checks for recovery in the background.
+pub struct DegradableAPI {
+    inner: Arc<dyn EgnyteAPI>,
+    fallback: Option<Arc<dyn EgnyteAPI>>,
+    health: ServiceHealth,
+}
+
+impl DegradableAPI {
+    pub fn new(inner: Arc<dyn EgnyteAPI>, fallback: Option<Arc<dyn EgnyteAPI>>) -> Self {
+        Self {
+            inner,
+            fallback,
+            health: ServiceHealth::new(),
+        }
+    }
+
+    pub fn health(&self) -> &ServiceHealth {
+        &self.health
+    }
+
+    /// Run `call` against Egnyte while it is up; `None` once it is degraded
+    async fn try_inner<'a, T, F, Fut>(&'a self, call: &F) -> Option<Result<T>>
+    where
+        F: Fn(&'a dyn EgnyteAPI) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        if self.health.is_degraded() {
+            return None;
+        }
+        let result = call(self.inner.as_ref()).await;
+        self.health.record(&result);
+        (!self.health.is_degraded()).then_some(result)
+    }
+
+    /// A read: from Egnyte, or from the fallback while degraded
+    async fn read<'a, T, F, Fut>(&'a self, what: &str, call: F) -> Result<T>
+    where
+        F: Fn(&'a dyn EgnyteAPI) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        if let Some(result) = self.try_inner(&call).await {
+            return result;
+        }
+        let served = match &self.fallback {
+            Some(fallback) => call(fallback.as_ref()).await.ok(),
+            None => None,
+        };
+        served.ok_or_else(|| ApiError::Unavailable(what.to_string()).into())
+    }
+
+    /// A change: only Egnyte can make it, so it waits while degraded
+    async fn write<'a, T, F, Fut>(&'a self, what: &str, call: F) -> Result<T>
+    where
+        F: Fn(&'a dyn EgnyteAPI) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        match self.try_inner(&call).await {
+            Some(result) => result,
+            None => Err(ApiError::Unavailable(what.to_string()).into()),
+        }
+    }
+
+    /// Every [`PROBE_INTERVAL`] while degraded, ask Egnyte something cheap
+    /// to see whether it is back; runs until the runtime stops
+    pub async fn probe_while_degraded(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(PROBE_INTERVAL).await;
+            if self.health.is_degraded() {
+                let result = self.inner.latest_event_cursor().await;
+                self.health.record(&result);
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EgnyteAPI for DegradableAPI {
+    async fn list_folder(&self, path: &str) -> Result<Vec<EgnyteEntry>> {
+        self.read(path, |api| api.list_folder(path)).await
+    }
+
+    async fn get_file_info(&self, path: &str) -> Result<EgnyteEntry> {
+        self.read(path, |api| api.get_file_info(path)).await
+    }
+
+    async fn download_file(&self, path: &str) -> Result<Vec<u8>> {
+        self.read(path, |api| api.download_file(path)).await
+    }
+
+    async fn download_range(
+        &self,
+        path: &str,
+        version: Option<&str>,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>> {
+        self.read(path, |api| api.download_range(path, version, offset, len))
+            .await
+    }
+
+    async fn file_info_if_changed(
+        &self,
+        path: &str,
+        known: &EgnyteEntry,
+    ) -> Result<Option<EgnyteEntry>> {
+        self.read(path, |api| api.file_info_if_changed(path, known))
+            .await
+    }
+
+    async fn download_if_changed(
+        &self,
+        path: &str,
+        known: &Validators,
+    ) -> Result<Option<(Vec<u8>, Validators)>> {
+        self.read(path, |api| api.download_if_changed(path, known))
+            .await
+    }
+
+    async fn copy_entry(&self, from: &str, to: &str) -> Result<()> {
+        self.write(from, |api| api.copy_entry(from, to)).await
+    }
+
+    async fn delete_entry(&self, path: &str) -> Result<()> {
+        self.write(path, |api| api.delete_entry(path)).await
+    }
+
+    async fn move_entry(&self, from: &str, to: &str) -> Result<()> {
+        self.write(from, |api| api.move_entry(from, to)).await
+    }
+
+    async fn upload_file(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+        self.write(path, |api| api.upload_file(path, data)).await
+    }
+
+    async fn upload_chunked(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+        self.write(path, |api| api.upload_chunked(path, data)).await
+    }
+
+    async fn latest_event_cursor(&self) -> Result<u64> {
+        self.read("events", |api| api.latest_event_cursor()).await
+    }
+
+    async fn events_since(&self, cursor: u64) -> Result<EventBatch> {
+        self.read("events", |api| api.events_since(cursor)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::testutil::ScriptedMockAPI;
+
+    #[test]
+    fn test_degraded_after_sustained_503s() {
+        let health = ServiceHealth::new();
+        let down = || -> Result<()> {
+            Err(ApiError::from_status(503, "Service Unavailable".to_string()).into())
+        };
+        health.record(&down());
+        health.record(&down());
+        assert!(!health.is_degraded());
+        // A network error neither counts nor resets
+        health.record::<()>(&Err(anyhow::anyhow!("connection reset")));
+        health.record(&down());
+        assert!(health.is_degraded());
+
+        // Not found is still an answer
+        health.record::<()>(&Err(ApiError::NotFound("/a".to_string()).into()));
+        assert!(!health.is_degraded());
+    }
+
+    #[test]
+    fn test_degraded_mode_serves_fallback_and_eagain() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let egnyte = Arc::new(ScriptedMockAPI::new().with_file("/a.txt", b"remote"));
+        let fallback = Arc::new(ScriptedMockAPI::new().with_file("/cached.txt", b"cached"));
+        let api = DegradableAPI::new(egnyte.clone(), Some(fallback));
+
+        egnyte.set_offline(true);
+        for _ in 0..DEGRADED_AFTER {
+            assert!(rt.block_on(api.upload_file("/a.txt", b"x")).is_err());
+        }
+        assert!(api.health().is_degraded());
+        let calls = egnyte.total_calls();
+
+        // Cached data is served, the rest asks to retry, and Egnyte is left alone
+        let data = rt.block_on(api.download_file("/cached.txt")).unwrap();
+        assert_eq!(data, b"cached");
+        let err = rt.block_on(api.download_file("/a.txt")).unwrap_err();
+        assert_eq!(error::errno(&err), libc::EAGAIN);
+        assert_eq!(egnyte.total_calls(), calls);
+
+        // The first success ends it
+        egnyte.set_offline(false);
+        api.health().record(&Ok(()));
+        assert_eq!(rt.block_on(api.download_file("/a.txt")).unwrap(), b"remote");
+    }
+}
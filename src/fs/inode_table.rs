@@ -1,54 +1,104 @@
 use dashmap::DashMap;
+use std::borrow::Cow;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
 
-/// Inode table for mapping paths to inodes and vice versa
+/// Inode of the mount's root, "/"
+pub const ROOT_INODE: u64 = 1;
+
+/// Inodes kept for nodes the filesystem makes up rather than finds on
+/// Egnyte (such as virtual `.egnyte-versions` folders); paths are never
+/// given one
+pub const RESERVED_INODES: Range<u64> = 2..16;
+
+/// First inode handed out to a path, unless the table is told otherwise
+pub const DEFAULT_FIRST_INODE: u64 = RESERVED_INODES.end;
+
+/// Inode table for mapping paths to inodes and vice versa.
+///
+/// A case-insensitive table maps paths differing only in case to one inode,
+/// which keeps the path it was first seen with for display and API calls.
+///
+/// Inode numbers: [`ROOT_INODE`] is "/", [`RESERVED_INODES`] are never
+/// handed out, and paths get increasing numbers from
+/// [`DEFAULT_FIRST_INODE`] (or [`Self::with_first_inode`]) on.
 pub struct InodeTable {
-    /// Path -> Inode mapping
+    /// Path (lowercased if case-insensitive) -> Inode mapping
     path_to_inode: DashMap<PathBuf, u64>,
     /// Inode -> Path mapping
     inode_to_path: DashMap<u64, PathBuf>,
-    /// Next inode number (starts at 2, as 1 is root)
+    /// Next inode number to hand out
     next_inode: AtomicU64,
+    case_insensitive: bool,
 }
 
 impl InodeTable {
-    /// Create a new inode table with root inode (1) for "/"
+    /// Create a new inode table with [`ROOT_INODE`] for "/"
     pub fn new() -> Self {
+        Self::with_case_insensitive(false)
+    }
+
+    /// Create a table that matches paths case-insensitively if asked to
+    pub fn with_case_insensitive(case_insensitive: bool) -> Self {
         let table = Self {
             path_to_inode: DashMap::new(),
             inode_to_path: DashMap::new(),
-            next_inode: AtomicU64::new(2),
+            next_inode: AtomicU64::new(DEFAULT_FIRST_INODE),
+            case_insensitive,
         };
         
         // Initialize root inode
         let root_path = PathBuf::from("/");
-        table.path_to_inode.insert(root_path.clone(), 1);
-        table.inode_to_path.insert(1, root_path);
+        table.path_to_inode.insert(root_path.clone(), ROOT_INODE);
+        table.inode_to_path.insert(ROOT_INODE, root_path);
         
         table
     }
 
+    /// Hand out inodes from `first` on, so they do not overlap those of
+    /// another table (one being merged in, say). Numbers below
+    /// [`DEFAULT_FIRST_INODE`] are root's or reserved, so `first` is raised
+    /// to that if lower. Meant for a table no path has been added to yet.
+    pub fn with_first_inode(self, first: u64) -> Self {
+        self.next_inode
+            .store(first.max(DEFAULT_FIRST_INODE), Ordering::Relaxed);
+        self
+    }
+
+    /// Whether `inode` is one of [`RESERVED_INODES`]
+    pub fn is_reserved(inode: u64) -> bool {
+        RESERVED_INODES.contains(&inode)
+    }
+
+    /// Key of `path` in `path_to_inode`
+    fn key<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        if !self.case_insensitive {
+            return Cow::Borrowed(path);
+        }
+        Cow::Owned(PathBuf::from(path.to_string_lossy().to_lowercase()))
+    }
+
     /// Get or create an inode for a given path
     pub fn get_or_create_inode(&self, path: &Path) -> u64 {
-        let path_buf = path.to_path_buf();
+        let key = self.key(path).into_owned();
         
         // Check if path already has an inode
-        if let Some(inode) = self.path_to_inode.get(&path_buf) {
+        if let Some(inode) = self.path_to_inode.get(&key) {
             return *inode;
         }
         
         // Create new inode
         let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
-        self.path_to_inode.insert(path_buf.clone(), inode);
-        self.inode_to_path.insert(inode, path_buf);
+        self.path_to_inode.insert(key, inode);
+        self.inode_to_path.insert(inode, path.to_path_buf());
         
         inode
     }
 
     /// Get inode for a path, returning None if not found
     pub fn get_inode(&self, path: &Path) -> Option<u64> {
-        self.path_to_inode.get(path).map(|entry| *entry)
+        self.path_to_inode.get(&*self.key(path)).map(|entry| *entry)
     }
 
     /// Get path for an inode, returning None if not found
@@ -59,16 +109,60 @@ impl InodeTable {
     /// Remove an inode and its path mapping
     pub fn remove(&self, inode: u64) {
         if let Some((_, path)) = self.inode_to_path.remove(&inode) {
-            self.path_to_inode.remove(&path);
+            self.path_to_inode.remove(&*self.key(&path));
         }
     }
 
     /// Remove a path and its inode mapping
     pub fn remove_path(&self, path: &Path) {
-        if let Some((_, inode)) = self.path_to_inode.remove(path) {
+        if let Some((_, inode)) = self.path_to_inode.remove(&*self.key(path)) {
             self.inode_to_path.remove(&inode);
         }
     }
+
+    /// Move `from` and everything below it to `to`, keeping their inodes.
+    /// Whatever was at `to` before is forgotten.
+    pub fn rename(&self, from: &Path, to: &Path) {
+        let (from_key, to_key) = (self.key(from), self.key(to));
+        // Only a change of case: nothing there to replace
+        let replaced: Vec<PathBuf> = if from_key == to_key {
+            Vec::new()
+        } else {
+            self.path_to_inode
+                .iter()
+                .filter(|entry| entry.key().starts_with(&to_key))
+                .map(|entry| entry.key().clone())
+                .collect()
+        };
+        for key in replaced {
+            if let Some((_, inode)) = self.path_to_inode.remove(&key) {
+                self.inode_to_path.remove(&inode);
+            }
+        }
+
+        let moved: Vec<(PathBuf, u64)> = self
+            .path_to_inode
+            .iter()
+            .filter(|entry| entry.key().starts_with(&from_key))
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        let depth = from.components().count();
+        for (old_key, inode) in moved {
+            let Some(old_path) = self.get_path(inode) else {
+                continue;
+            };
+            let suffix: PathBuf = old_path.components().skip(depth).collect();
+            let new_path = if suffix.as_os_str().is_empty() {
+                to.to_path_buf()
+            } else {
+                to.join(suffix)
+            };
+            self.path_to_inode.remove(&old_key);
+            self.path_to_inode
+                .insert(self.key(&new_path).into_owned(), inode);
+            self.inode_to_path.insert(inode, new_path);
+        }
+    }
 }
 
 impl Default for InodeTable {
@@ -88,6 +182,32 @@ mod tests {
         assert_eq!(table.get_path(1), Some(PathBuf::from("/")));
     }
 
+    #[test]
+    fn test_reserved_inodes_are_never_handed_out() {
+        let table = InodeTable::new();
+        let inodes: Vec<u64> = (0..100)
+            .map(|n| table.get_or_create_inode(Path::new(&format!("/f{}", n))))
+            .collect();
+        assert_eq!(inodes[0], DEFAULT_FIRST_INODE);
+        assert!(inodes
+            .iter()
+            .all(|inode| *inode != ROOT_INODE && !InodeTable::is_reserved(*inode)));
+        assert!(InodeTable::is_reserved(2));
+        assert!(!InodeTable::is_reserved(ROOT_INODE));
+        assert!(!InodeTable::is_reserved(DEFAULT_FIRST_INODE));
+        assert_eq!(table.get_path(2), None);
+
+        // A chosen start is kept, but not one inside the reserved range
+        let table = InodeTable::new().with_first_inode(1000);
+        assert_eq!(table.get_or_create_inode(Path::new("/a")), 1000);
+        assert_eq!(table.get_inode(Path::new("/")), Some(ROOT_INODE));
+        let table = InodeTable::new().with_first_inode(3);
+        assert_eq!(
+            table.get_or_create_inode(Path::new("/a")),
+            DEFAULT_FIRST_INODE
+        );
+    }
+
     #[test]
     fn test_get_or_create() {
         let table = InodeTable::new();
@@ -97,4 +217,54 @@ mod tests {
         assert_eq!(inode1, inode2);
         assert_eq!(table.get_path(inode1), Some(PathBuf::from("/test")));
     }
+
+    #[test]
+    fn test_rename_moves_descendants() {
+        let table = InodeTable::new();
+        let dir = table.get_or_create_inode(Path::new("/a"));
+        let file = table.get_or_create_inode(Path::new("/a/f.txt"));
+        let sibling = table.get_or_create_inode(Path::new("/ab"));
+        let replaced = table.get_or_create_inode(Path::new("/b"));
+
+        table.rename(Path::new("/a"), Path::new("/b"));
+        assert_eq!(table.get_inode(Path::new("/b")), Some(dir));
+        assert_eq!(table.get_path(file), Some(PathBuf::from("/b/f.txt")));
+        assert_eq!(table.get_inode(Path::new("/a/f.txt")), None);
+        assert_eq!(table.get_path(sibling), Some(PathBuf::from("/ab")));
+        assert_eq!(table.get_path(replaced), None);
+    }
+
+    #[test]
+    fn test_case_insensitive_paths_share_an_inode() {
+        let table = InodeTable::with_case_insensitive(true);
+        let dir = table.get_or_create_inode(Path::new("/Docs"));
+        let file = table.get_or_create_inode(Path::new("/Docs/Report.PDF"));
+        assert_eq!(table.get_or_create_inode(Path::new("/docs")), dir);
+        assert_eq!(table.get_inode(Path::new("/DOCS/report.pdf")), Some(file));
+        // The first spelling is kept
+        assert_eq!(
+            table.get_path(file),
+            Some(PathBuf::from("/Docs/Report.PDF"))
+        );
+
+        table.rename(Path::new("/docs"), Path::new("/Archive"));
+        assert_eq!(
+            table.get_path(file),
+            Some(PathBuf::from("/Archive/Report.PDF"))
+        );
+        assert_eq!(
+            table.get_inode(Path::new("/archive/REPORT.pdf")),
+            Some(file)
+        );
+        // A rename that only changes case keeps the inode
+        table.rename(Path::new("/Archive"), Path::new("/ARCHIVE"));
+        assert_eq!(table.get_path(dir), Some(PathBuf::from("/ARCHIVE")));
+
+        table.remove_path(Path::new("/archive/report.pdf"));
+        assert_eq!(table.get_path(file), None);
+
+        let table = InodeTable::new();
+        let file = table.get_or_create_inode(Path::new("/Report.PDF"));
+        assert_ne!(table.get_or_create_inode(Path::new("/report.pdf")), file);
+    }
 }
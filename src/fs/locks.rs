@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// A POSIX record lock on a byte range of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lock {
+    /// Kernel's id for the lock owner (a process's file table)
+    pub owner: u64,
+    /// First and last byte covered, inclusive
+    pub start: u64,
+    pub end: u64,
+    /// `libc::F_RDLCK` or `libc::F_WRLCK`
+    pub typ: i32,
+    pub pid: u32,
+}
+
+impl Lock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    /// Whether this lock keeps `owner` from taking a `typ` lock over the range
+    fn blocks(&self, owner: u64, start: u64, end: u64, typ: i32) -> bool {
+        self.owner != owner
+            && self.overlaps(start, end)
+            && (self.typ == libc::F_WRLCK || typ == libc::F_WRLCK)
+    }
+}
+
+/// Advisory POSIX locks held through this mount.
+///
+/// Egnyte has no lock API, so locks only exist in this process: they keep
+/// programs on this host from stepping on each other (SQLite, LibreOffice),
+/// but other hosts and the web UI never see them.
+#[derive(Debug, Default)]
+pub struct LockTable {
+    locks: Mutex<HashMap<u64, Vec<Lock>>>,
+}
+
+impl LockTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The first lock held by someone else that conflicts with `owner`
+    /// taking a `typ` lock on the range, as F_GETLK reports it
+    pub fn conflict(&self, inode: u64, owner: u64, start: u64, end: u64, typ: i32) -> Option<Lock> {
+        let locks = self.locks.lock().unwrap();
+        locks
+            .get(&inode)?
+            .iter()
+            .find(|lock| lock.blocks(owner, start, end, typ))
+            .copied()
+    }
+
+    /// Take, change or (with `F_UNLCK`) drop `owner`'s lock on a range.
+    /// Like fcntl, a lock replaces whatever the owner held on that range
+    /// already. Fails with EAGAIN on a conflict and EINVAL on a bad type.
+    pub fn set(&self, inode: u64, lock: Lock) -> Result<(), libc::c_int> {
+        if ![libc::F_RDLCK, libc::F_WRLCK, libc::F_UNLCK].contains(&lock.typ)
+            || lock.start > lock.end
+        {
+            return Err(libc::EINVAL);
+        }
+        let mut locks = self.locks.lock().unwrap();
+        let held = locks.entry(inode).or_default();
+        if lock.typ != libc::F_UNLCK
+            && held
+                .iter()
+                .any(|other| other.blocks(lock.owner, lock.start, lock.end, lock.typ))
+        {
+            return Err(libc::EAGAIN);
+        }
+
+        // Cut the range out of the owner's locks, keeping what lies outside
+        let mut kept = Vec::with_capacity(held.len() + 1);
+        for other in held.drain(..) {
+            if other.owner != lock.owner || !other.overlaps(lock.start, lock.end) {
+                kept.push(other);
+                continue;
+            }
+            if other.start < lock.start {
+                kept.push(Lock {
+                    end: lock.start - 1,
+                    ..other
+                });
+            }
+            if other.end > lock.end {
+                kept.push(Lock {
+                    start: lock.end + 1,
+                    ..other
+                });
+            }
+        }
+        if lock.typ != libc::F_UNLCK {
+            kept.push(lock);
+        }
+        if kept.is_empty() {
+            locks.remove(&inode);
+        } else {
+            *held = kept;
+        }
+        Ok(())
+    }
+
+    /// Drop every lock `owner` holds on a file, as closing it does
+    pub fn release_owner(&self, inode: u64, owner: u64) {
+        let mut locks = self.locks.lock().unwrap();
+        if let Some(held) = locks.get_mut(&inode) {
+            held.retain(|lock| lock.owner != owner);
+            if held.is_empty() {
+                locks.remove(&inode);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(owner: u64, start: u64, end: u64, typ: i32) -> Lock {
+        Lock {
+            owner,
+            start,
+            end,
+            typ,
+            pid: owner as u32,
+        }
+    }
+
+    #[test]
+    fn test_shared_and_exclusive() {
+        let table = LockTable::new();
+        table.set(2, lock(1, 0, 99, libc::F_RDLCK)).unwrap();
+        // Readers share; a writer has to wait
+        table.set(2, lock(2, 50, 149, libc::F_RDLCK)).unwrap();
+        assert_eq!(
+            table.set(2, lock(3, 0, 0, libc::F_WRLCK)),
+            Err(libc::EAGAIN)
+        );
+        assert_eq!(
+            table.conflict(2, 3, 120, 200, libc::F_WRLCK),
+            Some(lock(2, 50, 149, libc::F_RDLCK))
+        );
+        // Past both ranges, or on another file, nothing is in the way
+        assert_eq!(table.conflict(2, 3, 150, 200, libc::F_WRLCK), None);
+        table.set(3, lock(3, 0, 0, libc::F_WRLCK)).unwrap();
+        // An owner never conflicts with itself
+        assert_eq!(table.conflict(2, 1, 0, 10, libc::F_WRLCK), None);
+    }
+
+    #[test]
+    fn test_unlock_splits_range() {
+        let table = LockTable::new();
+        table.set(2, lock(1, 0, 99, libc::F_WRLCK)).unwrap();
+        table.set(2, lock(1, 40, 59, libc::F_UNLCK)).unwrap();
+        assert!(table.conflict(2, 2, 40, 59, libc::F_WRLCK).is_none());
+        assert_eq!(
+            table.conflict(2, 2, 0, 39, libc::F_RDLCK),
+            Some(lock(1, 0, 39, libc::F_WRLCK))
+        );
+        assert_eq!(
+            table.conflict(2, 2, 60, 60, libc::F_RDLCK),
+            Some(lock(1, 60, 99, libc::F_WRLCK))
+        );
+
+        // Downgrading the rest to a read lock lets other readers in
+        table.set(2, lock(1, 0, u64::MAX, libc::F_RDLCK)).unwrap();
+        assert!(table.conflict(2, 2, 0, 99, libc::F_RDLCK).is_none());
+    }
+
+    #[test]
+    fn test_release_owner_and_bad_requests() {
+        let table = LockTable::new();
+        table.set(2, lock(1, 0, 9, libc::F_WRLCK)).unwrap();
+        table.set(2, lock(2, 10, 19, libc::F_WRLCK)).unwrap();
+        table.release_owner(2, 1);
+        assert!(table.conflict(2, 3, 0, 9, libc::F_WRLCK).is_none());
+        assert!(table.conflict(2, 3, 10, 19, libc::F_WRLCK).is_some());
+
+        assert_eq!(table.set(2, lock(1, 0, 9, 42)), Err(libc::EINVAL));
+        assert_eq!(
+            table.set(2, lock(1, 9, 0, libc::F_RDLCK)),
+            Err(libc::EINVAL)
+        );
+    }
+}
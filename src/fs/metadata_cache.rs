@@ -0,0 +1,184 @@
+use crate::fs::fuse_ops::EgnyteEntry;
+use dashmap::DashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::{Duration, Instant};
+
+/// Most an entry's lifetime is stretched or shortened from the one asked
+/// for, so entries cached together (a listing) do not all expire together
+const TTL_JITTER: f64 = 0.2;
+
+/// API entries seen in lookups, stats and listings, by inode, so operations
+/// that only need an entry's type or version can skip asking again.
+///
+/// Entries are dropped along with the kernel's caches (see
+/// [`Invalidator`](crate::fs::notify::Invalidator)) and otherwise trusted for
+/// as long as the kernel trusts attributes, give or take [`TTL_JITTER`].
+#[derive(Default)]
+pub struct MetadataCache {
+    entries: DashMap<u64, Cached>,
+    /// Recent lookups that missed, by the folder they were in
+    misses: DashMap<u64, Misses>,
+}
+
+struct Cached {
+    entry: EgnyteEntry,
+    seen: Instant,
+    /// Factor applied to the maximum age asked for this entry
+    jitter: f64,
+}
+
+/// A random factor within 1 ± [`TTL_JITTER`]
+fn jitter() -> f64 {
+    // Every RandomState is seeded anew, so hashing anything gives a fresh
+    // random number
+    let random = RandomState::new().hash_one(0u8) as f64 / u64::MAX as f64;
+    1.0 - TTL_JITTER + 2.0 * TTL_JITTER * random
+}
+
+/// Lookups in one folder that found nothing cached, to tell a burst (a file
+/// manager statting every file it shows) from a one-off lookup
+struct Misses {
+    since: Instant,
+    count: u32,
+    /// When the folder was last listed because of a burst
+    listed: Option<Instant>,
+}
+
+impl MetadataCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember `entry` as the current state of `inode`
+    pub fn insert(&self, inode: u64, entry: &EgnyteEntry) {
+        self.entries.insert(
+            inode,
+            Cached {
+                entry: entry.clone(),
+                seen: Instant::now(),
+                jitter: jitter(),
+            },
+        );
+    }
+
+    /// Entry of `inode` if it was seen less than `max_age` (jittered for
+    /// that entry) ago
+    pub fn get(&self, inode: u64, max_age: Duration) -> Option<EgnyteEntry> {
+        let cached = self.entries.get(&inode)?;
+        (cached.seen.elapsed() < max_age.mul_f64(cached.jitter)).then(|| cached.entry.clone())
+    }
+
+    /// Entry of `inode` however long ago it was seen, to revalidate
+    pub fn get_stale(&self, inode: u64) -> Option<EgnyteEntry> {
+        Some(self.entries.get(&inode)?.entry.clone())
+    }
+
+    /// Forget what is known about `inode`
+    pub fn remove(&self, inode: u64) {
+        self.entries.remove(&inode);
+        self.misses.remove(&inode);
+    }
+
+    /// Count a lookup in folder `parent` that missed the cache. True when it
+    /// is the `burst`th within `window`, so one listing of the folder would
+    /// answer it and the lookups to come; false again until `max_age` after
+    /// that listing, so names a listing lacks don't each list again.
+    pub fn burst_of_misses(
+        &self,
+        parent: u64,
+        burst: u32,
+        window: Duration,
+        max_age: Duration,
+    ) -> bool {
+        let now = Instant::now();
+        let mut misses = self.misses.entry(parent).or_insert(Misses {
+            since: now,
+            count: 0,
+            listed: None,
+        });
+        if misses
+            .listed
+            .is_some_and(|listed| listed.elapsed() < max_age)
+        {
+            return false;
+        }
+        if misses.since.elapsed() > window {
+            misses.since = now;
+            misses.count = 0;
+        }
+        misses.count += 1;
+        if misses.count < burst {
+            return false;
+        }
+        misses.count = 0;
+        misses.listed = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn entry(is_folder: bool) -> EgnyteEntry {
+        EgnyteEntry {
+            name: "a".to_string(),
+            path: "/a".to_string(),
+            is_folder,
+            size: 0,
+            modified_time: SystemTime::UNIX_EPOCH,
+            entry_id: None,
+            permission: None,
+            validators: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_entries_expire_and_are_removed() {
+        let cache = MetadataCache::new();
+        cache.insert(2, &entry(true));
+        assert!(cache.get(2, Duration::from_secs(60)).unwrap().is_folder);
+        assert!(cache.get(2, Duration::ZERO).is_none());
+        assert!(cache.get(3, Duration::from_secs(60)).is_none());
+
+        cache.remove(2);
+        assert!(cache.get(2, Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn test_expiry_is_jittered() {
+        let cache = MetadataCache::new();
+        for inode in 0..200 {
+            cache.insert(inode, &entry(false));
+        }
+        let factors: Vec<f64> = cache.entries.iter().map(|cached| cached.jitter).collect();
+        assert!(factors
+            .iter()
+            .all(|f| (1.0 - TTL_JITTER..=1.0 + TTL_JITTER).contains(f)));
+        // Spread across the band, not all the same
+        assert!(factors.iter().any(|f| *f < 0.95));
+        assert!(factors.iter().any(|f| *f > 1.05));
+        assert!(cache.get(0, Duration::ZERO).is_none());
+    }
+
+    #[test]
+    fn test_burst_of_misses() {
+        let cache = MetadataCache::new();
+        let window = Duration::from_secs(60);
+        assert!(!cache.burst_of_misses(1, 3, window, window));
+        assert!(!cache.burst_of_misses(1, 3, window, window));
+        assert!(!cache.burst_of_misses(2, 3, window, window));
+        assert!(cache.burst_of_misses(1, 3, window, window));
+        // Listed: later misses are names the listing did not have
+        assert!(!cache.burst_of_misses(1, 1, window, window));
+
+        // Misses further apart than the window are no burst
+        assert!(!cache.burst_of_misses(3, 2, Duration::ZERO, window));
+        assert!(!cache.burst_of_misses(3, 2, Duration::ZERO, window));
+        // Once the listing is older than max_age, bursts count again
+        assert!(cache.burst_of_misses(4, 1, window, Duration::ZERO));
+        assert!(cache.burst_of_misses(4, 1, window, Duration::ZERO));
+    }
+}
@@ -0,0 +1,524 @@
+use crate::fs::error;
+use crate::fs::fuse_ops::{EgnyteAPI, EgnyteEntry, EventBatch, Validators};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+use tracing::{debug, warn};
+
+/// Upper bounds of the API call duration histogram buckets, in seconds
+const DURATION_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Counters of API use, updated by [`MeteredAPI`]
+#[derive(Debug, Default)]
+pub struct Metrics {
+    calls: Mutex<BTreeMap<&'static str, u64>>,
+    /// API errors by the errno they are reported as
+    errors: Mutex<BTreeMap<libc::c_int, u64>>,
+    in_flight: AtomicI64,
+    bytes_downloaded: AtomicU64,
+    bytes_uploaded: AtomicU64,
+    /// Calls per duration bucket (the last one is +Inf), and their total
+    durations: Mutex<([u64; DURATION_BUCKETS.len() + 1], Duration)>,
+}
+
+/// A point-in-time copy of [`Metrics`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub calls: BTreeMap<&'static str, u64>,
+    pub errors: BTreeMap<libc::c_int, u64>,
+    pub in_flight: i64,
+    pub bytes_downloaded: u64,
+    pub bytes_uploaded: u64,
+    /// Calls that took at most each of [`DURATION_BUCKETS`], then all calls
+    /// (cumulative, as Prometheus counts them)
+    pub duration_buckets: Vec<u64>,
+    pub duration_sum: Duration,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count one call to `method` while `call` runs
+    async fn measure<T>(
+        &self,
+        method: &'static str,
+        call: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        *self.calls.lock().unwrap().entry(method).or_insert(0) += 1;
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let started = Instant::now();
+        let result = call.await;
+        let elapsed = started.elapsed();
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+
+        {
+            let mut durations = self.durations.lock().unwrap();
+            let bucket = DURATION_BUCKETS
+                .iter()
+                .position(|bound| elapsed.as_secs_f64() <= *bound)
+                .unwrap_or(DURATION_BUCKETS.len());
+            durations.0[bucket] += 1;
+            durations.1 += elapsed;
+        }
+        if let Err(e) = &result {
+            *self
+                .errors
+                .lock()
+                .unwrap()
+                .entry(error::errno(e))
+                .or_insert(0) += 1;
+        }
+        result
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let (counts, sum) = *self.durations.lock().unwrap();
+        let duration_buckets = counts
+            .iter()
+            .scan(0, |total, count| {
+                *total += count;
+                Some(*total)
+            })
+            .collect();
+        MetricsSnapshot {
+            calls: self.calls.lock().unwrap().clone(),
+            errors: self.errors.lock().unwrap().clone(),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            bytes_downloaded: self.bytes_downloaded.load(Ordering::Relaxed),
+            bytes_uploaded: self.bytes_uploaded.load(Ordering::Relaxed),
+            duration_buckets,
+            duration_sum: sum,
+        }
+    }
+}
+
+/// The HELP and TYPE lines that start a metric family
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP egnyte_fuse_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE egnyte_fuse_{} {}", name, kind);
+}
+
+/// Name of an errno the API layer reports, its number for any other
+fn errno_name(errno: libc::c_int) -> String {
+    match errno {
+        libc::EACCES => "EACCES".to_string(),
+        libc::ENOENT => "ENOENT".to_string(),
+        libc::EEXIST => "EEXIST".to_string(),
+        libc::EAGAIN => "EAGAIN".to_string(),
+        libc::ENOSPC => "ENOSPC".to_string(),
+        libc::EIO => "EIO".to_string(),
+        other => other.to_string(),
+    }
+}
+
+impl MetricsSnapshot {
+    /// The Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        family(
+            &mut out,
+            "api_calls_total",
+            "counter",
+            "Egnyte API calls by method.",
+        );
+        for (method, count) in &self.calls {
+            let _ = writeln!(
+                out,
+                "egnyte_fuse_api_calls_total{{method=\"{}\"}} {}",
+                method, count
+            );
+        }
+        family(
+            &mut out,
+            "api_errors_total",
+            "counter",
+            "Failed Egnyte API calls by the errno reported.",
+        );
+        for (errno, count) in &self.errors {
+            let _ = writeln!(
+                out,
+                "egnyte_fuse_api_errors_total{{errno=\"{}\"}} {}",
+                errno_name(*errno),
+                count
+            );
+        }
+        family(
+            &mut out,
+            "api_in_flight",
+            "gauge",
+            "Egnyte API calls in progress.",
+        );
+        let _ = writeln!(out, "egnyte_fuse_api_in_flight {}", self.in_flight);
+        family(
+            &mut out,
+            "bytes_downloaded_total",
+            "counter",
+            "File content downloaded, in bytes.",
+        );
+        let _ = writeln!(
+            out,
+            "egnyte_fuse_bytes_downloaded_total {}",
+            self.bytes_downloaded
+        );
+        family(
+            &mut out,
+            "bytes_uploaded_total",
+            "counter",
+            "File content uploaded, in bytes.",
+        );
+        let _ = writeln!(
+            out,
+            "egnyte_fuse_bytes_uploaded_total {}",
+            self.bytes_uploaded
+        );
+
+        family(
+            &mut out,
+            "api_call_duration_seconds",
+            "histogram",
+            "Time taken by Egnyte API calls, retries included.",
+        );
+        let bounds = DURATION_BUCKETS.iter().map(|bound| bound.to_string());
+        for (bound, count) in bounds
+            .chain(std::iter::once("+Inf".to_string()))
+            .zip(&self.duration_buckets)
+        {
+            let _ = writeln!(
+                out,
+                "egnyte_fuse_api_call_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "egnyte_fuse_api_call_duration_seconds_sum {}",
+            self.duration_sum.as_secs_f64()
+        );
+        let _ = writeln!(
+            out,
+            "egnyte_fuse_api_call_duration_seconds_count {}",
+            self.duration_buckets.last().copied().unwrap_or(0)
+        );
+        out
+    }
+}
+
+/// An [`EgnyteAPI`] that counts the calls it passes on to `inner` in a
+/// [`Metrics`]
+pub struct MeteredAPI {
+    inner: Arc<dyn EgnyteAPI>,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredAPI {
+    pub fn new(inner: Arc<dyn EgnyteAPI>, metrics: Arc<Metrics>) -> Self {
+        Self { inner, metrics }
+    }
+
+    fn downloaded(&self, data: &[u8]) {
+        self.metrics
+            .bytes_downloaded
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+    }
+
+    fn uploaded(&self, data: &[u8]) {
+        self.metrics
+            .bytes_uploaded
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+    }
+}
+
+#[async_trait::async_trait]
+impl EgnyteAPI for MeteredAPI {
+    async fn list_folder(&self, path: &str) -> Result<Vec<EgnyteEntry>> {
+        self.metrics
+            .measure("list_folder", self.inner.list_folder(path))
+            .await
+    }
+
+    async fn get_file_info(&self, path: &str) -> Result<EgnyteEntry> {
+        self.metrics
+            .measure("get_file_info", self.inner.get_file_info(path))
+            .await
+    }
+
+    async fn download_file(&self, path: &str) -> Result<Vec<u8>> {
+        let data = self
+            .metrics
+            .measure("download_file", self.inner.download_file(path))
+            .await?;
+        self.downloaded(&data);
+        Ok(data)
+    }
+
+    async fn download_range(
+        &self,
+        path: &str,
+        version: Option<&str>,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>> {
+        let data = self
+            .metrics
+            .measure(
+                "download_range",
+                self.inner.download_range(path, version, offset, len),
+            )
+            .await?;
+        self.downloaded(&data);
+        Ok(data)
+    }
+
+    async fn file_info_if_changed(
+        &self,
+        path: &str,
+        known: &EgnyteEntry,
+    ) -> Result<Option<EgnyteEntry>> {
+        self.metrics
+            .measure(
+                "file_info_if_changed",
+                self.inner.file_info_if_changed(path, known),
+            )
+            .await
+    }
+
+    async fn download_if_changed(
+        &self,
+        path: &str,
+        known: &Validators,
+    ) -> Result<Option<(Vec<u8>, Validators)>> {
+        let changed = self
+            .metrics
+            .measure(
+                "download_if_changed",
+                self.inner.download_if_changed(path, known),
+            )
+            .await?;
+        if let Some((data, _)) = &changed {
+            self.downloaded(data);
+        }
+        Ok(changed)
+    }
+
+    async fn copy_entry(&self, from: &str, to: &str) -> Result<()> {
+        self.metrics
+            .measure("copy_entry", self.inner.copy_entry(from, to))
+            .await
+    }
+
+    async fn delete_entry(&self, path: &str) -> Result<()> {
+        self.metrics
+            .measure("delete_entry", self.inner.delete_entry(path))
+            .await
+    }
+
+    async fn move_entry(&self, from: &str, to: &str) -> Result<()> {
+        self.metrics
+            .measure("move_entry", self.inner.move_entry(from, to))
+            .await
+    }
+
+    async fn upload_file(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+        let entry_id = self
+            .metrics
+            .measure("upload_file", self.inner.upload_file(path, data))
+            .await?;
+        self.uploaded(data);
+        Ok(entry_id)
+    }
+
+    async fn upload_chunked(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+        let entry_id = self
+            .metrics
+            .measure("upload_chunked", self.inner.upload_chunked(path, data))
+            .await?;
+        self.uploaded(data);
+        Ok(entry_id)
+    }
+
+    async fn latest_event_cursor(&self) -> Result<u64> {
+        self.metrics
+            .measure("latest_event_cursor", self.inner.latest_event_cursor())
+            .await
+    }
+
+    async fn events_since(&self, cursor: u64) -> Result<EventBatch> {
+        self.metrics
+            .measure("events_since", self.inner.events_since(cursor))
+            .await
+    }
+}
+
+/// The metrics HTTP server; stops when dropped
+pub struct MetricsServer {
+    addr: SocketAddr,
+    shutdown: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+impl MetricsServer {
+    /// Serve `metrics` at `http://addr/metrics` on `rt` until dropped
+    pub fn start(rt: &Runtime, addr: SocketAddr, metrics: Arc<Metrics>) -> std::io::Result<Self> {
+        let listener = rt.block_on(TcpListener::bind(addr))?;
+        let addr = listener.local_addr()?;
+        let (shutdown, mut stop) = tokio::sync::oneshot::channel();
+        rt.spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = &mut stop => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            tokio::spawn(respond(stream, Arc::clone(&metrics)));
+                        }
+                        Err(e) => warn!("Metrics server cannot accept: {}", e),
+                    },
+                }
+            }
+            debug!("Metrics server stopped");
+        });
+        Ok(Self {
+            addr,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    /// Address the server listens on (with the port chosen, for port 0)
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+/// Answer one HTTP request: the metrics for `GET /metrics`, 404 otherwise
+async fn respond(mut stream: tokio::net::TcpStream, metrics: Arc<Metrics>) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+    let line = String::from_utf8_lossy(&request);
+    let mut parts = line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics.snapshot().render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::testutil::ScriptedMockAPI;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_metered_calls_and_bytes() {
+        let rt = Runtime::new().unwrap();
+        let metrics = Arc::new(Metrics::new());
+        let api = MeteredAPI::new(
+            Arc::new(ScriptedMockAPI::new().with_file("/a.txt", b"hello")),
+            Arc::clone(&metrics),
+        );
+        rt.block_on(async {
+            api.download_file("/a.txt").await.unwrap();
+            api.upload_file("/b.txt", b"abc").await.unwrap();
+            api.get_file_info("/missing").await.unwrap_err();
+        });
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.calls["download_file"], 1);
+        assert_eq!(snapshot.calls["upload_file"], 1);
+        assert_eq!(snapshot.calls["get_file_info"], 1);
+        assert_eq!(snapshot.errors, BTreeMap::from([(libc::ENOENT, 1)]));
+        assert_eq!(snapshot.in_flight, 0);
+        assert_eq!(snapshot.bytes_downloaded, 5);
+        assert_eq!(snapshot.bytes_uploaded, 3);
+        assert_eq!(snapshot.duration_buckets.last(), Some(&3));
+    }
+
+    #[test]
+    fn test_render() {
+        let snapshot = MetricsSnapshot {
+            calls: BTreeMap::from([("list_folder", 2)]),
+            errors: BTreeMap::from([(libc::EACCES, 1), (libc::EXDEV, 4)]),
+            in_flight: 1,
+            bytes_downloaded: 10,
+            bytes_uploaded: 0,
+            duration_buckets: vec![1, 2, 2, 2, 2, 2, 2, 2, 2, 2],
+            duration_sum: Duration::from_millis(150),
+        };
+        let text = snapshot.render();
+        assert!(text.contains("# TYPE egnyte_fuse_api_calls_total counter\n"));
+        assert!(text.contains("egnyte_fuse_api_calls_total{method=\"list_folder\"} 2\n"));
+        assert!(text.contains("egnyte_fuse_api_errors_total{errno=\"EACCES\"} 1\n"));
+        assert!(text.contains(&format!(
+            "egnyte_fuse_api_errors_total{{errno=\"{}\"}} 4\n",
+            libc::EXDEV
+        )));
+        assert!(text.contains("egnyte_fuse_api_in_flight 1\n"));
+        assert!(text.contains("egnyte_fuse_bytes_downloaded_total 10\n"));
+        assert!(text.contains("egnyte_fuse_api_call_duration_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(text.contains("egnyte_fuse_api_call_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("egnyte_fuse_api_call_duration_seconds_sum 0.15\n"));
+        assert!(text.contains("egnyte_fuse_api_call_duration_seconds_count 2\n"));
+    }
+
+    #[test]
+    fn test_server_serves_and_stops() {
+        let rt = Runtime::new().unwrap();
+        let server = MetricsServer::start(
+            &rt,
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(Metrics::new()),
+        )
+        .unwrap();
+        let addr = server.local_addr();
+        let get = |path: &str| {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("egnyte_fuse_api_in_flight 0"));
+        assert!(get("/").starts_with("HTTP/1.1 404"));
+
+        drop(server);
+        // The listener closes once the server task sees the shutdown
+        let refused = (0..50).any(|_| {
+            std::thread::sleep(Duration::from_millis(20));
+            std::net::TcpStream::connect(addr).is_err()
+        });
+        assert!(refused);
+    }
+}
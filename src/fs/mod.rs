@@ -1,3 +1,22 @@
 pub mod api_client;
+pub mod budget;
+pub mod egnyte_path;
+pub mod error;
+pub mod error_cache;
+pub mod events;
+pub mod file_buffer;
 pub mod fuse_ops;
+pub mod health;
 pub mod inode_table;
+pub mod locks;
+pub mod metadata_cache;
+pub mod metrics;
+pub mod notify;
+pub mod offline;
+pub mod spool;
+pub mod usage;
+
+#[cfg(test)]
+mod mount_tests;
+#[cfg(test)]
+pub(crate) mod testutil;
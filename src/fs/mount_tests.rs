@@ -0,0 +1,140 @@
+//! End-to-end tests that mount an `EgnyteFuse` in-process and go through the
+//! kernel with ordinary filesystem calls.
+//!
+//! They need /dev/fuse and permission to mount, so they are ignored by
+//! default; run them with `cargo test -- --ignored`.
+
+use crate::fs::fuse_ops::{EgnyteFuse, EgnyteFuseBuilder};
+use crate::fs::testutil::{pattern, ScriptedMockAPI};
+use fuser::{BackgroundSession, MountOption};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A mounted filesystem; unmounted when dropped
+struct Mount {
+    dir: PathBuf,
+    _session: BackgroundSession,
+}
+
+impl Mount {
+    /// A read-only mount of `api`
+    fn new(name: &str, api: ScriptedMockAPI) -> Self {
+        let fs = EgnyteFuse::new(Arc::new(api))
+            .unwrap()
+            .with_buffer_dir(std::env::temp_dir().join("egnyte-fuse-tests"));
+        Self::of(name, fs, MountOption::RO)
+    }
+
+    /// A mount of `fs`, read-only or read-write as `access` says
+    fn of(name: &str, fs: EgnyteFuse, access: MountOption) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("egnyte-fuse-mount-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let options = [access, MountOption::FSName("egnyte".to_string())];
+        let session = fuser::spawn_mount2(fs, &dir, &options).unwrap();
+        Self {
+            dir,
+            _session: session,
+        }
+    }
+
+    fn path(&self, path: &str) -> PathBuf {
+        self.dir.join(path.trim_start_matches('/'))
+    }
+}
+
+fn names(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn test_list_stat_and_read() {
+    let api = ScriptedMockAPI::new()
+        .with_file("/Shared/a.txt", b"hello")
+        .with_file("/Shared/b.txt", b"");
+    let mount = Mount::new("read", api);
+
+    assert_eq!(names(&mount.path("/")), vec!["Shared"]);
+    assert_eq!(names(&mount.path("/Shared")), vec!["a.txt", "b.txt"]);
+    assert!(mount.path("/Shared").metadata().unwrap().is_dir());
+
+    let metadata = mount.path("/Shared/a.txt").metadata().unwrap();
+    assert!(metadata.is_file());
+    assert_eq!(metadata.len(), 5);
+
+    let mut content = String::new();
+    std::fs::File::open(mount.path("/Shared/a.txt"))
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, "hello");
+
+    let missing = mount.path("/Shared/missing.txt").metadata().unwrap_err();
+    assert_eq!(missing.kind(), std::io::ErrorKind::NotFound);
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn test_large_folder_lists_every_entry_once() {
+    // Far more than fit in one readdir reply, so the listing comes back in
+    // several pages that must continue where the last one stopped
+    let mut api = ScriptedMockAPI::new().with_folder("/Big");
+    let mut expected = Vec::new();
+    for i in 0..2000 {
+        let name = format!("file-{:04}.txt", i);
+        api = api.with_file(&format!("/Big/{}", name), b"x");
+        expected.push(name);
+    }
+    let mount = Mount::new("big", api);
+
+    assert_eq!(names(&mount.path("/Big")), expected);
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn test_read_only_mount_rejects_writes() {
+    let mount = Mount::new("ro", ScriptedMockAPI::new().with_file("/a.txt", b"hello"));
+
+    let err = std::fs::write(mount.path("/new.txt"), b"data").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+    assert_eq!(std::fs::read(mount.path("/a.txt")).unwrap(), b"hello");
+}
+
+#[test]
+#[ignore = "mounts a FUSE filesystem"]
+fn test_first_write_to_a_ranged_file_gets_its_own_budget() {
+    let api = Arc::new(
+        ScriptedMockAPI::new()
+            .with_large_file("/big.bin", 3 << 20)
+            .retry_ranges(),
+    );
+    let fs = EgnyteFuseBuilder::new()
+        .buffer_dir(std::env::temp_dir().join("egnyte-fuse-tests"))
+        .ranged_read_threshold(1 << 20)
+        .op_deadline(Duration::from_millis(200))
+        .build(api.clone())
+        .unwrap();
+    let mount = Mount::of("budget", fs, MountOption::RW);
+
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(mount.path("/big.bin"))
+        .unwrap();
+    // Long past the deadline of the open, the write downloads the rest of
+    // the file before changing it
+    std::thread::sleep(Duration::from_millis(500));
+    file.write_all(b"JJ").unwrap();
+    file.sync_all().unwrap();
+    let mut expected = pattern(0, 3 << 20);
+    expected[..2].copy_from_slice(b"JJ");
+    assert_eq!(api.content("/big.bin").unwrap(), expected);
+}
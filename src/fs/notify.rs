@@ -0,0 +1,218 @@
+use crate::fs::inode_table::InodeTable;
+use crate::fs::metadata_cache::MetadataCache;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::Path;
+use std::sync::{mpsc, Arc, OnceLock};
+use tracing::debug;
+
+/// Destination of kernel cache invalidations; `fuser::Notifier` once mounted
+pub trait InvalidationSink: Send + Sync {
+    /// Drop cached attributes and data of an inode
+    fn inval_inode(&self, ino: u64) -> io::Result<()>;
+    /// Drop the cached lookup of `name` in `parent`
+    fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()>;
+}
+
+impl InvalidationSink for fuser::Notifier {
+    fn inval_inode(&self, ino: u64) -> io::Result<()> {
+        // Offset 0 and length 0 cover the whole file
+        fuser::Notifier::inval_inode(self, ino, 0, 0)
+    }
+
+    fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        fuser::Notifier::inval_entry(self, parent, name)
+    }
+}
+
+/// One cache entry for the kernel to drop
+#[derive(Debug, Clone, PartialEq)]
+pub enum Invalidation {
+    Inode(u64),
+    Entry(u64, OsString),
+}
+
+/// Tells the kernel to forget what it cached about paths that changed.
+///
+/// A session only provides its notifier after the filesystem has been moved
+/// into it, so the sink is attached late; until then invalidations are
+/// dropped (nothing can be cached before the mount anyway). Clones share the
+/// sink.
+///
+/// Invalidations are queued and sent from a thread of their own: the kernel
+/// takes the parent directory's lock to drop a dentry, and that lock may be
+/// held by a request waiting on our reply, so sending from a request handler
+/// could deadlock the session.
+///
+/// Our own [`MetadataCache`] is dropped from right away, mounted or not, so
+/// local and remote changes reach it the same way they reach the kernel.
+#[derive(Clone)]
+pub struct Invalidator {
+    inode_table: Arc<InodeTable>,
+    metadata: Arc<MetadataCache>,
+    queue: Arc<OnceLock<mpsc::Sender<Invalidation>>>,
+}
+
+impl Invalidator {
+    pub fn new(inode_table: Arc<InodeTable>) -> Self {
+        Self {
+            inode_table,
+            metadata: Arc::new(MetadataCache::new()),
+            queue: Arc::new(OnceLock::new()),
+        }
+    }
+
+    /// Cache of API entries that this invalidator keeps current
+    pub fn metadata(&self) -> &MetadataCache {
+        &self.metadata
+    }
+
+    /// Start sending invalidations to `sink` from a background thread. Only
+    /// the first call has effect.
+    pub fn attach(&self, sink: Box<dyn InvalidationSink>) -> io::Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        if self.queue.set(sender).is_err() {
+            return Ok(());
+        }
+        std::thread::Builder::new()
+            .name("egnyte-invalidate".to_string())
+            .spawn(move || {
+                for invalidation in receiver {
+                    send(sink.as_ref(), &invalidation);
+                }
+            })?;
+        Ok(())
+    }
+
+    fn queue(&self, invalidation: Invalidation) {
+        if let Some(sender) = self.queue.get() {
+            // The sending thread only stops with the process
+            let _ = sender.send(invalidation);
+        }
+    }
+
+    /// Invalidate the attributes and data of an inode
+    pub fn invalidate_inode(&self, ino: u64) {
+        self.metadata.remove(ino);
+        self.queue(Invalidation::Inode(ino));
+    }
+
+    /// Invalidate the dentry for `name` in `parent`
+    pub fn invalidate_entry(&self, parent: u64, name: &OsStr) {
+        self.queue(Invalidation::Entry(parent, name.to_os_string()));
+    }
+
+    /// Invalidate everything the kernel may cache about a path in the mount:
+    /// the inode itself, its dentry, and the listing of its parent. Paths
+    /// the kernel has never been told about are skipped.
+    pub fn invalidate_path(&self, path: &Path) {
+        if let Some(ino) = self.inode_table.get_inode(path) {
+            self.invalidate_inode(ino);
+        }
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return;
+        };
+        if let Some(parent_ino) = self.inode_table.get_inode(parent) {
+            self.invalidate_entry(parent_ino, name);
+            self.invalidate_inode(parent_ino);
+        }
+    }
+}
+
+/// Pass one invalidation to the sink; failures are only logged
+fn send(sink: &dyn InvalidationSink, invalidation: &Invalidation) {
+    // ENOENT just means the kernel had nothing cached
+    let result = match invalidation {
+        Invalidation::Inode(ino) => sink.inval_inode(*ino),
+        Invalidation::Entry(parent, name) => sink.inval_entry(*parent, name),
+    };
+    if let Err(e) = result {
+        debug!("{:?} failed: {}", invalidation, e);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::{Condvar, Mutex};
+    use std::time::Duration;
+
+    /// Sink that records invalidations for tests
+    #[derive(Clone, Default)]
+    pub(crate) struct RecordingSink(Arc<(Mutex<Vec<Invalidation>>, Condvar)>);
+
+    impl RecordingSink {
+        /// Wait (briefly) until `count` invalidations have arrived, then
+        /// return everything recorded
+        pub(crate) fn wait_for(&self, count: usize) -> Vec<Invalidation> {
+            let (sent, arrived) = &*self.0;
+            let (sent, _) = arrived
+                .wait_timeout_while(sent.lock().unwrap(), Duration::from_secs(5), |sent| {
+                    sent.len() < count
+                })
+                .unwrap();
+            sent.clone()
+        }
+
+        fn record(&self, invalidation: Invalidation) {
+            let (sent, arrived) = &*self.0;
+            sent.lock().unwrap().push(invalidation);
+            arrived.notify_all();
+        }
+    }
+
+    impl InvalidationSink for RecordingSink {
+        fn inval_inode(&self, ino: u64) -> io::Result<()> {
+            self.record(Invalidation::Inode(ino));
+            Ok(())
+        }
+
+        fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+            self.record(Invalidation::Entry(parent, name.to_os_string()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_invalidate_path() {
+        let table = Arc::new(InodeTable::new());
+        let dir = table.get_or_create_inode(Path::new("/Shared"));
+        let file = table.get_or_create_inode(Path::new("/Shared/a.txt"));
+        let invalidator = Invalidator::new(table);
+        let sink = RecordingSink::default();
+        invalidator.attach(Box::new(sink.clone())).unwrap();
+
+        invalidator.invalidate_path(Path::new("/Shared/a.txt"));
+        assert_eq!(
+            sink.wait_for(3),
+            vec![
+                Invalidation::Inode(file),
+                Invalidation::Entry(dir, "a.txt".into()),
+                Invalidation::Inode(dir)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_paths_and_detached_sink() {
+        let table = Arc::new(InodeTable::new());
+        let invalidator = Invalidator::new(table.clone());
+        // Not attached yet: nothing to send to, nothing panics
+        invalidator.invalidate_path(Path::new("/a.txt"));
+
+        let sink = RecordingSink::default();
+        invalidator.attach(Box::new(sink.clone())).unwrap();
+        invalidator.invalidate_path(Path::new("/Unknown/b.txt"));
+
+        // A new file in a known folder still invalidates the folder. The
+        // queue is in order, so the unknown path above sent nothing.
+        invalidator.invalidate_path(Path::new("/c.txt"));
+        assert_eq!(
+            sink.wait_for(2),
+            vec![
+                Invalidation::Entry(1, "c.txt".into()),
+                Invalidation::Inode(1)
+            ]
+        );
+    }
+}
@@ -0,0 +1,670 @@
+use crate::fs::egnyte_path;
+use crate::fs::error::ApiError;
+use crate::fs::fuse_ops::{
+    slice_range, EgnyteAPI, EgnyteEntry, EventBatch, Permission, Validators,
+};
+use crate::fs::spool::Spool;
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Default directory for offline copies: ~/.cache/egnyte-fuse/offline, or
+/// the system temp directory without a cache directory
+pub fn default_offline_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("egnyte-fuse")
+        .join("offline")
+}
+
+/// An `EgnyteEntry` as stored on disk
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredEntry {
+    name: String,
+    path: String,
+    is_folder: bool,
+    size: u64,
+    /// Seconds since the Unix epoch
+    modified: u64,
+    entry_id: Option<String>,
+    permission: Option<String>,
+    #[serde(default)]
+    etag: Option<String>,
+    #[serde(default)]
+    last_modified: Option<String>,
+}
+
+/// Validators of a stored file's content, from the download that fetched it
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoredValidators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl From<&EgnyteEntry> for StoredEntry {
+    fn from(entry: &EgnyteEntry) -> Self {
+        Self {
+            name: entry.name.clone(),
+            path: entry.path.clone(),
+            is_folder: entry.is_folder,
+            size: entry.size,
+            modified: entry
+                .modified_time
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            entry_id: entry.entry_id.clone(),
+            permission: entry.permission.map(|p| format!("{:?}", p)),
+            etag: entry.validators.etag.clone(),
+            last_modified: entry.validators.last_modified.clone(),
+        }
+    }
+}
+
+impl From<StoredEntry> for EgnyteEntry {
+    fn from(stored: StoredEntry) -> Self {
+        Self {
+            name: stored.name,
+            path: stored.path,
+            is_folder: stored.is_folder,
+            size: stored.size,
+            modified_time: UNIX_EPOCH + Duration::from_secs(stored.modified),
+            entry_id: stored.entry_id,
+            permission: stored.permission.as_deref().and_then(Permission::parse),
+            validators: Validators {
+                etag: stored.etag,
+                last_modified: stored.last_modified,
+            },
+        }
+    }
+}
+
+/// Egnyte folder holding `path`, None for the root
+fn parent_of(path: &str) -> Option<String> {
+    let path = egnyte_path::normalize(path);
+    if path == "/" {
+        return None;
+    }
+    let (parent, _) = path.rsplit_once('/')?;
+    Some(egnyte_path::normalize(parent))
+}
+
+/// Listings, entries and file content kept on disk for an offline mount.
+///
+/// Each kind lives in its own subdirectory, one file per Egnyte path, named
+/// by the path's SHA-256. Files are replaced by rename, so a reader never
+/// sees half of one.
+#[derive(Debug, Clone)]
+pub struct OfflineCache {
+    dir: PathBuf,
+}
+
+impl OfflineCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn file(&self, kind: &str, path: &str) -> PathBuf {
+        let key = hex::encode(Sha256::digest(egnyte_path::normalize(path).as_bytes()));
+        self.dir.join(kind).join(key)
+    }
+
+    fn write(&self, kind: &str, path: &str, data: &[u8]) -> io::Result<()> {
+        let file = self.file(kind, path);
+        let dir = file.parent().unwrap_or(&self.dir);
+        std::fs::create_dir_all(dir)?;
+        let tmp = file.with_extension(format!("tmp-{}", std::process::id()));
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, &file)
+    }
+
+    fn read(&self, kind: &str, path: &str) -> io::Result<Option<Vec<u8>>> {
+        match std::fs::read(self.file(kind, path)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_json<T: Serialize + ?Sized>(
+        &self,
+        kind: &str,
+        path: &str,
+        value: &T,
+    ) -> io::Result<()> {
+        self.write(kind, path, &serde_json::to_vec(value)?)
+    }
+
+    fn read_json<T: DeserializeOwned>(&self, kind: &str, path: &str) -> io::Result<Option<T>> {
+        match self.read(kind, path)? {
+            Some(json) => Ok(Some(serde_json::from_slice(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn forget(&self, kind: &str, path: &str) -> io::Result<()> {
+        match std::fs::remove_file(self.file(kind, path)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn put_entry(&self, entry: &EgnyteEntry) -> io::Result<()> {
+        self.write_json("entries", &entry.path, &StoredEntry::from(entry))
+    }
+
+    pub fn entry(&self, path: &str) -> io::Result<Option<EgnyteEntry>> {
+        let stored: Option<StoredEntry> = self.read_json("entries", path)?;
+        Ok(stored.map(EgnyteEntry::from))
+    }
+
+    /// Store a folder's listing, and each child's entry with it
+    pub fn put_listing(&self, path: &str, entries: &[EgnyteEntry]) -> io::Result<()> {
+        for entry in entries {
+            self.put_entry(entry)?;
+        }
+        let stored: Vec<StoredEntry> = entries.iter().map(StoredEntry::from).collect();
+        self.write_json("listings", path, &stored)
+    }
+
+    pub fn listing(&self, path: &str) -> io::Result<Option<Vec<EgnyteEntry>>> {
+        let stored: Option<Vec<StoredEntry>> = self.read_json("listings", path)?;
+        Ok(stored.map(|entries| entries.into_iter().map(EgnyteEntry::from).collect()))
+    }
+
+    /// Store a file's content, and its entry updated to match. The entry
+    /// shows up in the parent's listing if that was stored. The content has
+    /// no validators; see [`Self::put_download`].
+    pub fn put_file(&self, entry: &EgnyteEntry, data: &[u8]) -> io::Result<()> {
+        self.write("content", &entry.path, data)?;
+        self.forget("content-validators", &entry.path)?;
+        let mut entry = entry.clone();
+        entry.size = data.len() as u64;
+        self.put_entry(&entry)?;
+
+        let Some(parent) = parent_of(&entry.path) else {
+            return Ok(());
+        };
+        if let Some(mut listing) = self.listing(&parent)? {
+            match listing.iter_mut().find(|child| child.path == entry.path) {
+                Some(child) => *child = entry,
+                None => listing.push(entry),
+            }
+            self.put_listing(&parent, &listing)?;
+        }
+        Ok(())
+    }
+
+    /// Store downloaded content, like [`Self::put_file`], with the
+    /// validators the download came with for revalidating it later
+    pub fn put_download(
+        &self,
+        entry: &EgnyteEntry,
+        data: &[u8],
+        validators: &Validators,
+    ) -> io::Result<()> {
+        self.put_file(entry, data)?;
+        if validators.is_empty() {
+            return Ok(());
+        }
+        let stored = StoredValidators {
+            etag: validators.etag.clone(),
+            last_modified: validators.last_modified.clone(),
+        };
+        self.write_json("content-validators", &entry.path, &stored)
+    }
+
+    pub fn content(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        self.read("content", path)
+    }
+
+    /// Stored content of `path` and its validators, if both are stored
+    pub fn validated_content(&self, path: &str) -> io::Result<Option<(Vec<u8>, Validators)>> {
+        let Some(stored) = self.read_json::<StoredValidators>("content-validators", path)? else {
+            return Ok(None);
+        };
+        let validators = Validators {
+            etag: stored.etag,
+            last_modified: stored.last_modified,
+        };
+        Ok(self.content(path)?.map(|data| (data, validators)))
+    }
+
+    /// Drop everything stored for a path, and the path from its parent's
+    /// listing. Entries below a folder stay, but are unreachable by listing.
+    pub fn remove(&self, path: &str) -> io::Result<()> {
+        let path = egnyte_path::normalize(path);
+        for kind in ["entries", "listings", "content", "content-validators"] {
+            self.forget(kind, &path)?;
+        }
+        if let Some(parent) = parent_of(&path) {
+            if let Some(mut listing) = self.listing(&parent)? {
+                listing.retain(|child| egnyte_path::normalize(&child.path) != path);
+                self.put_listing(&parent, &listing)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Entry for a file first seen through an upload
+fn new_file(path: &str) -> EgnyteEntry {
+    EgnyteEntry {
+        name: Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        path: path.to_string(),
+        is_folder: false,
+        size: 0,
+        modified_time: SystemTime::now(),
+        entry_id: None,
+        permission: None,
+        validators: Validators::default(),
+    }
+}
+
+/// Log a failure to update the offline copies; the online operation it
+/// belongs to still succeeds
+fn warn_on_failure(what: &str, path: &str, result: io::Result<()>) {
+    if let Err(e) = result {
+        warn!("Cannot store offline copy of {} {}: {}", what, path, e);
+    }
+}
+
+/// Error for an operation that needs the service
+fn offline(what: &str) -> anyhow::Error {
+    ApiError::Offline(what.to_string()).into()
+}
+
+/// An [`EgnyteAPI`] in front of the offline copies.
+///
+/// Recording (`CachedAPI::recording`) passes every call through to the real
+/// API and keeps what it returns: listings, entries and downloaded files.
+/// Offline (`CachedAPI::offline`) never touches the network: reads come
+/// only from those copies (ENOENT for an unknown path, EIO for a file
+/// never downloaded), uploads go to the spool and into the copies so the
+/// change shows up right away, and anything else that needs the service
+/// (delete, move, copy, events) fails with EIO.
+pub struct CachedAPI {
+    /// The real API; None when offline
+    inner: Option<Arc<dyn EgnyteAPI>>,
+    cache: OfflineCache,
+    spool: Option<Spool>,
+}
+
+impl CachedAPI {
+    /// Pass calls through to `inner`, keeping copies of what it returns
+    pub fn recording(inner: Arc<dyn EgnyteAPI>, cache: OfflineCache) -> Self {
+        Self {
+            inner: Some(inner),
+            cache,
+            spool: None,
+        }
+    }
+
+    /// Answer from the copies only, spooling uploads for a later online mount
+    pub fn offline(cache: OfflineCache, spool: Spool) -> Self {
+        Self {
+            inner: None,
+            cache,
+            spool: Some(spool),
+        }
+    }
+
+    /// Store an offline upload: spooled for the service, and into the copies
+    fn upload_offline(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+        let known = self.cache.entry(path)?;
+        // The version the changes are based on, for conflict detection once
+        // the spool is retried; it also stays the entry's id meanwhile
+        let base = known.as_ref().and_then(|entry| entry.entry_id.clone());
+        if let Some(spool) = &self.spool {
+            spool.save(path, base.as_deref(), data)?;
+        }
+        let entry = known.unwrap_or_else(|| new_file(path));
+        self.cache.put_file(
+            &EgnyteEntry {
+                modified_time: SystemTime::now(),
+                ..entry
+            },
+            data,
+        )?;
+        Ok(base)
+    }
+
+    /// Keep the copies in step with a successful upload
+    fn record_upload(&self, path: &str, data: &[u8], entry_id: &Option<String>) {
+        let entry = match self.cache.entry(path) {
+            Ok(Some(entry)) => entry,
+            _ => new_file(path),
+        };
+        let entry = EgnyteEntry {
+            modified_time: SystemTime::now(),
+            entry_id: entry_id.clone(),
+            ..entry
+        };
+        warn_on_failure("file", path, self.cache.put_file(&entry, data));
+    }
+}
+
+#[async_trait::async_trait]
+impl EgnyteAPI for CachedAPI {
+    async fn list_folder(&self, path: &str) -> Result<Vec<EgnyteEntry>> {
+        let Some(inner) = &self.inner else {
+            return self
+                .cache
+                .listing(path)?
+                .ok_or_else(|| ApiError::NotFound(path.to_string()).into());
+        };
+        let entries = inner.list_folder(path).await?;
+        warn_on_failure("listing", path, self.cache.put_listing(path, &entries));
+        Ok(entries)
+    }
+
+    async fn get_file_info(&self, path: &str) -> Result<EgnyteEntry> {
+        let Some(inner) = &self.inner else {
+            return self
+                .cache
+                .entry(path)?
+                .ok_or_else(|| ApiError::NotFound(path.to_string()).into());
+        };
+        let entry = inner.get_file_info(path).await?;
+        warn_on_failure("entry", path, self.cache.put_entry(&entry));
+        Ok(entry)
+    }
+
+    async fn file_info_if_changed(
+        &self,
+        path: &str,
+        known: &EgnyteEntry,
+    ) -> Result<Option<EgnyteEntry>> {
+        let Some(inner) = &self.inner else {
+            return self.get_file_info(path).await.map(Some);
+        };
+        let changed = inner.file_info_if_changed(path, known).await?;
+        if let Some(entry) = &changed {
+            warn_on_failure("entry", path, self.cache.put_entry(entry));
+        }
+        Ok(changed)
+    }
+
+    /// Online, a copy downloaded before is revalidated rather than
+    /// downloaded again
+    async fn download_file(&self, path: &str) -> Result<Vec<u8>> {
+        let Some(inner) = &self.inner else {
+            return self.cache.content(path)?.ok_or_else(|| offline(path));
+        };
+        let (cached, known) = match self.cache.validated_content(path) {
+            Ok(Some((data, validators))) => (Some(data), validators),
+            _ => (None, Validators::default()),
+        };
+        match inner.download_if_changed(path, &known).await? {
+            Some((data, validators)) => {
+                if let Ok(Some(entry)) = self.cache.entry(path) {
+                    warn_on_failure(
+                        "file",
+                        path,
+                        self.cache.put_download(&entry, &data, &validators),
+                    );
+                }
+                Ok(data)
+            }
+            None => cached.ok_or_else(|| {
+                anyhow::anyhow!("{} not modified, but no copy of it is stored", path)
+            }),
+        }
+    }
+
+    async fn download_range(
+        &self,
+        path: &str,
+        version: Option<&str>,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>> {
+        match &self.inner {
+            // Only whole files are kept, so a range online is not recorded
+            Some(inner) => inner.download_range(path, version, offset, len).await,
+            None => {
+                // The copy kept may be of another version than the one asked for
+                if let Some(version) = version {
+                    let stored = self.cache.entry(path)?.and_then(|entry| entry.entry_id);
+                    if stored.as_deref() != Some(version) {
+                        return Err(ApiError::VersionGone {
+                            path: path.to_string(),
+                            version: version.to_string(),
+                        }
+                        .into());
+                    }
+                }
+                let data = self.cache.content(path)?.ok_or_else(|| offline(path))?;
+                Ok(slice_range(&data, offset, len).to_vec())
+            }
+        }
+    }
+
+    async fn copy_entry(&self, from: &str, to: &str) -> Result<()> {
+        let inner = self.inner.as_ref().ok_or_else(|| offline(from))?;
+        inner.copy_entry(from, to).await
+    }
+
+    async fn delete_entry(&self, path: &str) -> Result<()> {
+        let inner = self.inner.as_ref().ok_or_else(|| offline(path))?;
+        inner.delete_entry(path).await?;
+        warn_on_failure("deleted", path, self.cache.remove(path));
+        Ok(())
+    }
+
+    async fn move_entry(&self, from: &str, to: &str) -> Result<()> {
+        let inner = self.inner.as_ref().ok_or_else(|| offline(from))?;
+        inner.move_entry(from, to).await?;
+        warn_on_failure("moved", from, self.cache.remove(from));
+        Ok(())
+    }
+
+    async fn upload_file(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+        let Some(inner) = &self.inner else {
+            return self.upload_offline(path, data);
+        };
+        let entry_id = inner.upload_file(path, data).await?;
+        self.record_upload(path, data, &entry_id);
+        Ok(entry_id)
+    }
+
+    async fn upload_chunked(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+        let Some(inner) = &self.inner else {
+            return self.upload_offline(path, data);
+        };
+        let entry_id = inner.upload_chunked(path, data).await?;
+        self.record_upload(path, data, &entry_id);
+        Ok(entry_id)
+    }
+
+    async fn latest_event_cursor(&self) -> Result<u64> {
+        let inner = self.inner.as_ref().ok_or_else(|| offline("events"))?;
+        inner.latest_event_cursor().await
+    }
+
+    async fn events_since(&self, cursor: u64) -> Result<EventBatch> {
+        let inner = self.inner.as_ref().ok_or_else(|| offline("events"))?;
+        inner.events_since(cursor).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::error;
+    use crate::fs::testutil::ScriptedMockAPI;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "egnyte-fuse-offline-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_recording_revalidates_downloaded_copies() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let dir = test_dir("revalidate");
+        let cache = OfflineCache::new(dir.join("cache"));
+        let egnyte = Arc::new(
+            ScriptedMockAPI::new()
+                .with_etags()
+                .with_file("/Shared/a.txt", b"one"),
+        );
+        let api = CachedAPI::recording(egnyte.clone(), cache);
+        rt.block_on(async {
+            api.get_file_info("/Shared/a.txt").await.unwrap();
+            for _ in 0..2 {
+                assert_eq!(api.download_file("/Shared/a.txt").await.unwrap(), b"one");
+            }
+            // Only the second asked whether the copy is current
+            assert_eq!(egnyte.calls("download_if_changed"), 2);
+            assert_eq!(egnyte.calls("not_modified"), 1);
+
+            egnyte.change_remotely("/Shared/a.txt", b"two");
+            assert_eq!(api.download_file("/Shared/a.txt").await.unwrap(), b"two");
+            assert_eq!(egnyte.calls("not_modified"), 1);
+            assert_eq!(api.download_file("/Shared/a.txt").await.unwrap(), b"two");
+            assert_eq!(egnyte.calls("not_modified"), 2);
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_recorded_copies_serve_offline() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let dir = test_dir("serve");
+        let cache = OfflineCache::new(dir.join("cache"));
+        let api = Arc::new(
+            ScriptedMockAPI::new()
+                .with_file("/Shared/a.txt", b"hello")
+                .with_file("/Shared/b.txt", b"never read"),
+        );
+        let recording = CachedAPI::recording(api.clone(), cache.clone());
+        rt.block_on(async {
+            recording.list_folder("/Shared").await.unwrap();
+            recording.download_file("/Shared/a.txt").await.unwrap();
+        });
+
+        let offline = CachedAPI::offline(cache, Spool::new(dir.join("spool")));
+        let calls = api.total_calls();
+        rt.block_on(async {
+            let names: Vec<String> = offline
+                .list_folder("/Shared")
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.name)
+                .collect();
+            assert_eq!(names, vec!["a.txt", "b.txt"]);
+            let entry = offline.get_file_info("/Shared/a.txt").await.unwrap();
+            assert_eq!(entry.size, 5);
+            assert_eq!(
+                offline.download_file("/Shared/a.txt").await.unwrap(),
+                b"hello"
+            );
+            assert_eq!(
+                offline
+                    .download_range("/Shared/a.txt", None, 1, 3)
+                    .await
+                    .unwrap(),
+                b"ell"
+            );
+
+            // Known but never downloaded, and never seen at all
+            let err = offline.download_file("/Shared/b.txt").await.unwrap_err();
+            assert_eq!(error::errno(&err), libc::EIO);
+            let err = offline.get_file_info("/Private").await.unwrap_err();
+            assert_eq!(error::errno(&err), libc::ENOENT);
+            let err = offline.list_folder("/Private").await.unwrap_err();
+            assert_eq!(error::errno(&err), libc::ENOENT);
+        });
+        assert_eq!(api.total_calls(), calls);
+    }
+
+    #[test]
+    fn test_offline_uploads_go_to_spool_and_copies() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let dir = test_dir("upload");
+        let cache = OfflineCache::new(dir.join("cache"));
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/Shared/a.txt", b"hello"));
+        let base = rt.block_on(async {
+            let recording = CachedAPI::recording(api.clone(), cache.clone());
+            recording.list_folder("/Shared").await.unwrap();
+            recording
+                .get_file_info("/Shared/a.txt")
+                .await
+                .unwrap()
+                .entry_id
+        });
+
+        let spool = Spool::new(dir.join("spool"));
+        let offline = CachedAPI::offline(cache, spool.clone());
+        rt.block_on(async {
+            let entry_id = offline
+                .upload_file("/Shared/a.txt", b"edited")
+                .await
+                .unwrap();
+            assert_eq!(entry_id, base);
+            offline
+                .upload_file("/Shared/new.txt", b"new")
+                .await
+                .unwrap();
+
+            assert_eq!(
+                offline.download_file("/Shared/a.txt").await.unwrap(),
+                b"edited"
+            );
+            assert_eq!(
+                offline.get_file_info("/Shared/a.txt").await.unwrap().size,
+                6
+            );
+            let listing = offline.list_folder("/Shared").await.unwrap();
+            assert_eq!(listing.len(), 2);
+
+            let err = offline.delete_entry("/Shared/a.txt").await.unwrap_err();
+            assert_eq!(error::errno(&err), libc::EIO);
+        });
+
+        let pending = spool.list().unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].remote_path, "/Shared/a.txt");
+        assert_eq!(pending[0].base_entry_id, base);
+        // Nothing reached the service
+        assert_eq!(api.content("/Shared/a.txt").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_recording_follows_changes() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let cache = OfflineCache::new(test_dir("changes"));
+        let api = Arc::new(ScriptedMockAPI::new().with_file("/Shared/a.txt", b"hello"));
+        let recording = CachedAPI::recording(api, cache.clone());
+        rt.block_on(async {
+            recording.list_folder("/Shared").await.unwrap();
+            recording
+                .upload_file("/Shared/b.txt", b"bee")
+                .await
+                .unwrap();
+            recording.delete_entry("/Shared/a.txt").await.unwrap();
+        });
+
+        let listing = cache.listing("/Shared").unwrap().unwrap();
+        let names: Vec<&str> = listing.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, vec!["b.txt"]);
+        assert_eq!(cache.content("/Shared/b.txt").unwrap().unwrap(), b"bee");
+        assert!(cache.entry("/Shared/a.txt").unwrap().is_none());
+    }
+}
@@ -0,0 +1,354 @@
+use crate::fs::error;
+use crate::fs::fuse_ops::{self, EgnyteAPI};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Number used to name the next spooled upload
+static NEXT_PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// First wait between background retries, doubled after each failed round
+pub const RETRY_INITIAL_DELAY: Duration = Duration::from_secs(10);
+/// Longest wait between background retries
+pub const RETRY_MAX_DELAY: Duration = Duration::from_secs(600);
+
+/// Default spool directory: ~/.cache/egnyte-fuse/pending, or the system temp
+/// directory without a cache directory
+pub fn default_spool_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("egnyte-fuse")
+        .join("pending")
+}
+
+/// An upload that failed on close and waits in the spool
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingUpload {
+    /// Name of the entry's files in the spool directory
+    pub id: String,
+    /// Egnyte path to upload to
+    pub remote_path: String,
+    /// entry_id of the version the changes are based on, to detect
+    /// conflicting uploads by others
+    pub base_entry_id: Option<String>,
+    /// Bytes of content
+    pub size: u64,
+    /// Unix time the upload was spooled
+    pub spooled_at: u64,
+    /// Retries so far, and why the last one failed
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+/// Outcome of one round of retries: the paths uploaded, and the paths still
+/// waiting with the reason
+#[derive(Debug, Default, PartialEq)]
+pub struct RetryReport {
+    pub uploaded: Vec<String>,
+    pub failed: Vec<(String, String)>,
+}
+
+/// Uploads that failed on close, kept on disk until they go through.
+///
+/// Each entry is two files: `<id>.data` with the content and `<id>.json`
+/// describing it. The description is written last, by rename, so an entry
+/// without one is incomplete and ignored. Entries survive unmounts and
+/// crashes; the next mount (or `sync retry`) picks them up.
+#[derive(Debug, Clone)]
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.data", id))
+    }
+
+    fn meta_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    /// Store `data` to be uploaded to `remote_path`. Older entries for the
+    /// same path are superseded and removed.
+    pub fn save(
+        &self,
+        remote_path: &str,
+        base_entry_id: Option<&str>,
+        data: &[u8],
+    ) -> io::Result<PendingUpload> {
+        std::fs::create_dir_all(&self.dir)?;
+        let superseded: Vec<PendingUpload> = self
+            .list()?
+            .into_iter()
+            .filter(|pending| pending.remote_path == remote_path)
+            .collect();
+
+        let spooled_at = fuse_ops::unix_time();
+        let id = format!(
+            "{}-{}-{}",
+            spooled_at,
+            std::process::id(),
+            NEXT_PENDING.fetch_add(1, Ordering::Relaxed)
+        );
+        let pending = PendingUpload {
+            id,
+            remote_path: remote_path.to_string(),
+            base_entry_id: base_entry_id.map(str::to_string),
+            size: data.len() as u64,
+            spooled_at,
+            attempts: 0,
+            last_error: None,
+        };
+        std::fs::write(self.data_path(&pending.id), data)?;
+        self.write_meta(&pending)?;
+
+        for old in &superseded {
+            self.remove(old)?;
+        }
+        Ok(pending)
+    }
+
+    fn write_meta(&self, pending: &PendingUpload) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(pending)?;
+        let tmp = self.dir.join(format!("{}.json.tmp", pending.id));
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, self.meta_path(&pending.id))
+    }
+
+    /// Every complete entry, oldest first; empty if nothing was ever spooled
+    pub fn list(&self) -> io::Result<Vec<PendingUpload>> {
+        let dir = match std::fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut pending = Vec::new();
+        for file in dir {
+            let path = file?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let parsed = std::fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|json| serde_json::from_slice(&json).map_err(|e| e.to_string()));
+            match parsed {
+                Ok(entry) => pending.push(entry),
+                Err(e) => warn!("Skipping unreadable spool entry {}: {}", path.display(), e),
+            }
+        }
+        pending.sort_by(|a: &PendingUpload, b| (a.spooled_at, &a.id).cmp(&(b.spooled_at, &b.id)));
+        Ok(pending)
+    }
+
+    /// The entry waiting for `remote_path`, if any
+    pub fn find(&self, remote_path: &str) -> io::Result<Option<PendingUpload>> {
+        Ok(self
+            .list()?
+            .into_iter()
+            .rfind(|pending| pending.remote_path == remote_path))
+    }
+
+    /// Content of an entry
+    pub fn data(&self, pending: &PendingUpload) -> io::Result<Vec<u8>> {
+        std::fs::read(self.data_path(&pending.id))
+    }
+
+    /// Drop an entry; one already gone is not an error
+    pub fn remove(&self, pending: &PendingUpload) -> io::Result<()> {
+        for path in [self.meta_path(&pending.id), self.data_path(&pending.id)] {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Try every entry once, oldest first. An entry that uploads is removed.
+    /// If someone else uploaded a new version meanwhile, theirs is left alone
+    /// and ours goes to a `.conflict-<time>` copy next to it, as on flush.
+    /// A transient failure (the service unreachable) ends the round early,
+    /// since the rest would fail the same way.
+    pub async fn retry_all(&self, api: &dyn EgnyteAPI) -> io::Result<RetryReport> {
+        let mut report = RetryReport::default();
+        for mut pending in self.list()? {
+            match self.retry(api, &pending).await {
+                Ok(target) => {
+                    self.remove(&pending)?;
+                    info!("Uploaded spooled changes to {}", target);
+                    report.uploaded.push(target);
+                }
+                Err(e) => {
+                    pending.attempts += 1;
+                    pending.last_error = Some(format!("{:#}", e));
+                    // The entry may have been taken meanwhile; nothing to update then
+                    if self.meta_path(&pending.id).exists() {
+                        self.write_meta(&pending)?;
+                    }
+                    report
+                        .failed
+                        .push((pending.remote_path.clone(), format!("{:#}", e)));
+                    if error::is_transient(&e) {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Upload one entry; returns the path it went to
+    async fn retry(&self, api: &dyn EgnyteAPI, pending: &PendingUpload) -> anyhow::Result<String> {
+        let data = self.data(pending)?;
+        let target = match fuse_ops::check_conflict(
+            api,
+            &pending.remote_path,
+            pending.base_entry_id.as_deref(),
+        )
+        .await
+        {
+            Ok(()) => pending.remote_path.clone(),
+            Err(e) if e.downcast_ref::<error::ApiError>().is_some_and(is_conflict) => {
+                let copy = format!("{}.conflict-{}", pending.remote_path, fuse_ops::unix_time());
+                warn!("{}; saving spooled changes to {}", e, copy);
+                copy
+            }
+            Err(e) => return Err(e),
+        };
+        fuse_ops::upload_whole(api, &target, &data).await?;
+        Ok(target)
+    }
+
+    /// Retry every entry in the background until the process exits, waiting
+    /// [`RETRY_INITIAL_DELAY`] between rounds and doubling that, up to
+    /// [`RETRY_MAX_DELAY`], while uploads keep failing. `uploaded` is called
+    /// with each Egnyte path that went through.
+    pub async fn run_retrier(
+        self,
+        api: std::sync::Arc<dyn EgnyteAPI>,
+        uploaded: impl Fn(&str) + Send + 'static,
+    ) {
+        let mut delay = RETRY_INITIAL_DELAY;
+        loop {
+            tokio::time::sleep(delay).await;
+            match self.retry_all(api.as_ref()).await {
+                Ok(report) => {
+                    report.uploaded.iter().for_each(|path| uploaded(path));
+                    delay = if report.failed.is_empty() {
+                        RETRY_INITIAL_DELAY
+                    } else {
+                        (delay * 2).min(RETRY_MAX_DELAY)
+                    };
+                }
+                Err(e) => {
+                    warn!("Cannot read spool {}: {}", self.dir.display(), e);
+                    delay = (delay * 2).min(RETRY_MAX_DELAY);
+                }
+            }
+        }
+    }
+}
+
+fn is_conflict(err: &error::ApiError) -> bool {
+    matches!(err, error::ApiError::Conflict { .. })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::testutil::ScriptedMockAPI;
+
+    fn test_spool(name: &str) -> Spool {
+        let dir =
+            std::env::temp_dir().join(format!("egnyte-fuse-spool-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        Spool::new(dir)
+    }
+
+    #[test]
+    fn test_save_list_and_remove() {
+        let spool = test_spool("save");
+        assert!(spool.list().unwrap().is_empty());
+
+        let first = spool.save("/Shared/a.txt", Some("v1"), b"one").unwrap();
+        let second = spool.save("/Shared/b.txt", None, b"two").unwrap();
+        assert_eq!(spool.list().unwrap(), vec![first.clone(), second.clone()]);
+        assert_eq!(spool.data(&first).unwrap(), b"one");
+        assert_eq!(first.size, 3);
+        assert_eq!(first.base_entry_id.as_deref(), Some("v1"));
+
+        // Newer changes to a path replace the older ones
+        let newer = spool
+            .save("/Shared/a.txt", Some("v1"), b"one more")
+            .unwrap();
+        assert_eq!(spool.find("/Shared/a.txt").unwrap(), Some(newer.clone()));
+        assert_eq!(spool.list().unwrap().len(), 2);
+
+        spool.remove(&second).unwrap();
+        spool.remove(&second).unwrap();
+        assert_eq!(spool.list().unwrap(), vec![newer]);
+        assert_eq!(spool.find("/Shared/b.txt").unwrap(), None);
+    }
+
+    #[test]
+    fn test_incomplete_entries_are_ignored() {
+        let spool = test_spool("incomplete");
+        std::fs::create_dir_all(spool.dir()).unwrap();
+        std::fs::write(spool.dir().join("1-1-1.data"), b"orphan").unwrap();
+        std::fs::write(spool.dir().join("1-1-2.json"), b"{not json").unwrap();
+        assert!(spool.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_retry_uploads_and_removes() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let spool = test_spool("retry");
+        let api = ScriptedMockAPI::new().with_folder("/Shared");
+        spool.save("/Shared/a.txt", None, b"saved").unwrap();
+
+        api.set_offline(true);
+        let report = rt.block_on(spool.retry_all(&api)).unwrap();
+        assert!(report.uploaded.is_empty());
+        assert_eq!(report.failed.len(), 1);
+        let pending = &spool.list().unwrap()[0];
+        assert_eq!(pending.attempts, 1);
+        assert!(pending.last_error.as_deref().unwrap().contains("503"));
+
+        api.set_offline(false);
+        let report = rt.block_on(spool.retry_all(&api)).unwrap();
+        assert_eq!(report.uploaded, vec!["/Shared/a.txt".to_string()]);
+        assert_eq!(api.content("/Shared/a.txt").unwrap(), b"saved");
+        assert!(spool.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_retry_after_remote_change_keeps_both() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let spool = test_spool("conflict");
+        let api = ScriptedMockAPI::new().with_file("/a.txt", b"original");
+        let base = rt
+            .block_on(api.get_file_info("/a.txt"))
+            .unwrap()
+            .entry_id
+            .unwrap();
+        spool.save("/a.txt", Some(&base), b"mine").unwrap();
+        api.change_remotely("/a.txt", b"theirs");
+
+        let report = rt.block_on(spool.retry_all(&api)).unwrap();
+        assert_eq!(report.uploaded.len(), 1);
+        assert!(report.uploaded[0].starts_with("/a.txt.conflict-"));
+        assert_eq!(api.content("/a.txt").unwrap(), b"theirs");
+        assert_eq!(api.content(&report.uploaded[0]).unwrap(), b"mine");
+    }
+}
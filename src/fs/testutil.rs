@@ -0,0 +1,641 @@
+//! Test doubles shared by the test modules
+
+use crate::fs::budget;
+use crate::fs::egnyte_path;
+use crate::fs::error::ApiError;
+use crate::fs::fuse_ops::{slice_range, EgnyteAPI, EgnyteEntry, EventBatch, Validators};
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// A file or folder in the mock's tree
+#[derive(Debug, Clone)]
+enum Node {
+    Folder,
+    File { content: Vec<u8>, entry_id: String },
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Normalized Egnyte paths; the root folder is always there
+    tree: BTreeMap<String, Node>,
+    /// Paths changed so far, in order; the cursor is the count
+    events: Vec<String>,
+    /// Versions handed out so far, for entry ids
+    versions: u64,
+    /// Content of every version still kept, by entry id
+    history: HashMap<String, Vec<u8>>,
+    /// Names listings give instead of the real ones, by path
+    listed_names: HashMap<String, String>,
+    /// Errors reads of a path are answered with instead, by path
+    failing: HashMap<String, ApiError>,
+    /// Paths moves from which fail with a 500 (once past the checks that
+    /// the source exists and the destination is free)
+    failing_moves: Vec<String>,
+    /// Give files ETags and answer conditional requests
+    etags: bool,
+}
+
+impl State {
+    fn next_entry_id(&mut self) -> String {
+        self.versions += 1;
+        format!("v{}", self.versions)
+    }
+
+    /// Add a file, creating missing parent folders
+    fn put_file(&mut self, path: &str, content: &[u8]) -> String {
+        let mut parent = parent_of(path);
+        while let Some(folder) = parent {
+            self.tree.entry(folder.clone()).or_insert(Node::Folder);
+            parent = parent_of(&folder);
+        }
+        let entry_id = self.next_entry_id();
+        self.history.insert(entry_id.clone(), content.to_vec());
+        self.tree.insert(
+            path.to_string(),
+            Node::File {
+                content: content.to_vec(),
+                entry_id: entry_id.clone(),
+            },
+        );
+        entry_id
+    }
+
+    /// `path` and everything under it
+    fn subtree(&self, path: &str) -> Vec<String> {
+        let prefix = format!("{}/", path);
+        self.tree
+            .keys()
+            .filter(|p| *p == path || p.starts_with(&prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// ETag of the file at `path`, when the domain gives them
+    fn etag(&self, path: &str) -> Option<String> {
+        match self.tree.get(path)? {
+            Node::File { entry_id, .. } if self.etags => Some(format!("\"etag-{}\"", entry_id)),
+            _ => None,
+        }
+    }
+
+    /// Whether `known` names the current version of `path`
+    fn is_current(&self, path: &str, known: &Validators) -> bool {
+        known.etag.is_some() && known.etag == self.etag(path)
+    }
+
+    fn entry(&self, path: &str) -> Option<EgnyteEntry> {
+        let node = self.tree.get(path)?;
+        let (is_folder, size, entry_id) = match node {
+            Node::Folder => (true, 0, None),
+            Node::File { content, entry_id } => {
+                (false, content.len() as u64, Some(entry_id.clone()))
+            }
+        };
+        Some(EgnyteEntry {
+            name: path.rsplit('/').next().unwrap_or_default().to_string(),
+            path: path.to_string(),
+            is_folder,
+            size,
+            modified_time: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            entry_id,
+            permission: None,
+            validators: Validators {
+                etag: self.etag(path),
+                last_modified: None,
+            },
+        })
+    }
+}
+
+fn parent_of(path: &str) -> Option<String> {
+    if path == "/" {
+        return None;
+    }
+    let (parent, _) = path.rsplit_once('/')?;
+    Some(egnyte_path::normalize(parent))
+}
+
+/// `len` bytes of the content of a file made by `with_large_file`, starting
+/// at `offset`
+pub(crate) fn pattern(offset: usize, len: usize) -> Vec<u8> {
+    // 251 is prime, so blocks of power-of-two sizes all differ
+    (offset..offset + len).map(|i| (i % 251) as u8).collect()
+}
+
+fn not_found(path: &str) -> anyhow::Error {
+    ApiError::NotFound(path.to_string()).into()
+}
+
+/// In-memory Egnyte domain for tests.
+///
+/// Holds a tree of folders and files that every trait method reads and
+/// changes the way the server would: uploads give files a new entry id,
+/// moves and copies take subtrees along, and each change is recorded as an
+/// event for `events_since`. Calls are counted per method, and the ranges
+/// asked of `download_range` are kept. Uploads can be made to fail as if the
+/// service were down, or with a given error.
+#[derive(Debug)]
+pub(crate) struct ScriptedMockAPI {
+    state: Mutex<State>,
+    calls: Mutex<HashMap<&'static str, usize>>,
+    /// (path, offset, len) of every `download_range` call, in order
+    ranges: Mutex<Vec<(String, u64, u64)>>,
+    /// Fail every upload with a 503
+    offline: AtomicBool,
+    /// Fail every upload with this error, e.g. over quota
+    upload_failure: Mutex<Option<ApiError>>,
+    /// Have every range download need one retry
+    retry_ranges: AtomicBool,
+}
+
+impl Default for ScriptedMockAPI {
+    fn default() -> Self {
+        let mut state = State::default();
+        state.tree.insert("/".to_string(), Node::Folder);
+        Self {
+            state: Mutex::new(state),
+            calls: Mutex::new(HashMap::new()),
+            ranges: Mutex::new(Vec::new()),
+            offline: AtomicBool::new(false),
+            upload_failure: Mutex::new(None),
+            retry_ranges: AtomicBool::new(false),
+        }
+    }
+}
+
+impl ScriptedMockAPI {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a folder, and any missing parent folders
+    pub(crate) fn with_folder(self, path: &str) -> Self {
+        {
+            let mut state = self.state.lock().unwrap();
+            let mut folder = Some(egnyte_path::normalize(path));
+            while let Some(path) = folder {
+                folder = parent_of(&path);
+                state.tree.entry(path).or_insert(Node::Folder);
+            }
+        }
+        self
+    }
+
+    /// Add a file, and any missing parent folders
+    pub(crate) fn with_file(self, path: &str, content: &[u8]) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .put_file(&egnyte_path::normalize(path), content);
+        self
+    }
+
+    /// Add a file of `size` bytes whose content is a repeating pattern, so
+    /// any range of it can be checked with [`pattern`]
+    pub(crate) fn with_large_file(self, path: &str, size: usize) -> Self {
+        self.with_file(path, &pattern(0, size))
+    }
+
+    /// Replace a file as another client would, giving it a new version and
+    /// recording an event
+    pub(crate) fn change_remotely(&self, path: &str, content: &[u8]) {
+        let path = egnyte_path::normalize(path);
+        let mut state = self.state.lock().unwrap();
+        state.put_file(&path, content);
+        state.events.push(path);
+    }
+
+    /// Forget every version but the current one of each file, as a server
+    /// that keeps no version history would
+    pub(crate) fn drop_old_versions(&self) {
+        let mut state = self.state.lock().unwrap();
+        let current: Vec<String> = state
+            .tree
+            .values()
+            .filter_map(|node| match node {
+                Node::File { entry_id, .. } => Some(entry_id.clone()),
+                Node::Folder => None,
+            })
+            .collect();
+        state
+            .history
+            .retain(|entry_id, _| current.contains(entry_id));
+    }
+
+    /// Give files ETags and answer conditional requests with "not modified"
+    /// when they quote the current one (counted as `not_modified` calls)
+    pub(crate) fn with_etags(self) -> Self {
+        self.state.lock().unwrap().etags = true;
+        self
+    }
+
+    /// Have listings name the entry at `path` `name`, as a misbehaving
+    /// server might
+    pub(crate) fn list_as(self, path: &str, name: &str) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .listed_names
+            .insert(egnyte_path::normalize(path), name.to_string());
+        self
+    }
+
+    /// Answer reads of `path` with HTTP `status`, or normally again if None
+    pub(crate) fn fail_reads(&self, path: &str, status: Option<u16>) {
+        let path = egnyte_path::normalize(path);
+        let mut state = self.state.lock().unwrap();
+        match status {
+            Some(status) => state
+                .failing
+                .insert(path.clone(), ApiError::from_status(status, path)),
+            None => state.failing.remove(&path),
+        };
+    }
+
+    /// Answer reads of `path` with `err`, e.g. a timeout
+    pub(crate) fn fail_reads_with(&self, path: &str, err: ApiError) {
+        let path = egnyte_path::normalize(path);
+        self.state.lock().unwrap().failing.insert(path, err);
+    }
+
+    /// Fail moves of `path` with HTTP 500, or let them through again
+    pub(crate) fn fail_moves_from(&self, path: &str, fail: bool) {
+        let path = egnyte_path::normalize(path);
+        let mut state = self.state.lock().unwrap();
+        state.failing_moves.retain(|p| *p != path);
+        if fail {
+            state.failing_moves.push(path);
+        }
+    }
+
+    /// The failure set for reads of `path` by [`Self::fail_reads`], if any
+    fn read_failure(&self, path: &str) -> Result<()> {
+        match self.state.lock().unwrap().failing.get(path) {
+            Some(err) => Err(err.clone().into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Make uploads fail with a 503 until called again with false
+    pub(crate) fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::Relaxed);
+    }
+
+    /// Fail uploads with `err`, e.g. a quota error, or let them through
+    /// again with None
+    pub(crate) fn fail_uploads_with(&self, err: Option<ApiError>) {
+        *self.upload_failure.lock().unwrap() = err;
+    }
+
+    /// Have every range download need one retry, as over a stale pooled
+    /// connection: it goes through only if the current operation's budget
+    /// grants one, and fails with a 429 otherwise
+    pub(crate) fn retry_ranges(self) -> Self {
+        self.retry_ranges.store(true, Ordering::Relaxed);
+        self
+    }
+
+    /// Content of a file, None if it is missing or a folder
+    pub(crate) fn content(&self, path: &str) -> Option<Vec<u8>> {
+        match self.state.lock().unwrap().tree.get(path)? {
+            Node::File { content, .. } => Some(content.clone()),
+            Node::Folder => None,
+        }
+    }
+
+    /// Whether a file or folder exists at `path`
+    pub(crate) fn exists(&self, path: &str) -> bool {
+        self.state.lock().unwrap().tree.contains_key(path)
+    }
+
+    /// How many times `method` was called
+    pub(crate) fn calls(&self, method: &str) -> usize {
+        self.calls.lock().unwrap().get(method).copied().unwrap_or(0)
+    }
+
+    /// How many calls were made to any method
+    pub(crate) fn total_calls(&self) -> usize {
+        self.calls.lock().unwrap().values().sum()
+    }
+
+    /// The (path, offset, len) of every `download_range` call so far
+    pub(crate) fn ranges(&self) -> Vec<(String, u64, u64)> {
+        self.ranges.lock().unwrap().clone()
+    }
+
+    fn record(&self, method: &'static str) {
+        *self.calls.lock().unwrap().entry(method).or_insert(0) += 1;
+    }
+
+    fn upload(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+        if self.offline.load(Ordering::Relaxed) {
+            return Err(ApiError::Status {
+                status: 503,
+                body: "Service Unavailable".to_string(),
+            }
+            .into());
+        }
+        if let Some(err) = self.upload_failure.lock().unwrap().clone() {
+            return Err(err.into());
+        }
+        let mut state = self.state.lock().unwrap();
+        if matches!(state.tree.get(path), Some(Node::Folder)) {
+            return Err(ApiError::AlreadyExists(path.to_string()).into());
+        }
+        let entry_id = state.put_file(path, data);
+        state.events.push(path.to_string());
+        Ok(Some(entry_id))
+    }
+}
+
+#[async_trait::async_trait]
+impl EgnyteAPI for ScriptedMockAPI {
+    async fn list_folder(&self, path: &str) -> Result<Vec<EgnyteEntry>> {
+        self.record("list_folder");
+        self.read_failure(path)?;
+        let state = self.state.lock().unwrap();
+        if !matches!(state.tree.get(path), Some(Node::Folder)) {
+            return Err(not_found(path));
+        }
+        Ok(state
+            .tree
+            .keys()
+            .filter(|p| *p != "/" && parent_of(p).as_deref() == Some(path))
+            .filter_map(|p| {
+                let mut entry = state.entry(p)?;
+                if let Some(name) = state.listed_names.get(p) {
+                    entry.name = name.clone();
+                }
+                Some(entry)
+            })
+            .collect())
+    }
+
+    async fn get_file_info(&self, path: &str) -> Result<EgnyteEntry> {
+        self.record("get_file_info");
+        self.read_failure(path)?;
+        self.state
+            .lock()
+            .unwrap()
+            .entry(path)
+            .ok_or_else(|| not_found(path))
+    }
+
+    async fn download_file(&self, path: &str) -> Result<Vec<u8>> {
+        self.record("download_file");
+        self.read_failure(path)?;
+        self.content(path).ok_or_else(|| not_found(path))
+    }
+
+    async fn file_info_if_changed(
+        &self,
+        path: &str,
+        known: &EgnyteEntry,
+    ) -> Result<Option<EgnyteEntry>> {
+        self.record("file_info_if_changed");
+        self.read_failure(path)?;
+        let state = self.state.lock().unwrap();
+        if state.is_current(path, &known.validators) {
+            self.record("not_modified");
+            return Ok(None);
+        }
+        state.entry(path).map(Some).ok_or_else(|| not_found(path))
+    }
+
+    async fn download_if_changed(
+        &self,
+        path: &str,
+        known: &Validators,
+    ) -> Result<Option<(Vec<u8>, Validators)>> {
+        self.record("download_if_changed");
+        self.read_failure(path)?;
+        let state = self.state.lock().unwrap();
+        if state.is_current(path, known) {
+            self.record("not_modified");
+            return Ok(None);
+        }
+        let Some(Node::File { content, .. }) = state.tree.get(path) else {
+            return Err(not_found(path));
+        };
+        let validators = Validators {
+            etag: state.etag(path),
+            last_modified: None,
+        };
+        Ok(Some((content.clone(), validators)))
+    }
+
+    async fn download_range(
+        &self,
+        path: &str,
+        version: Option<&str>,
+        offset: u64,
+        len: u64,
+    ) -> Result<Vec<u8>> {
+        self.record("download_range");
+        self.read_failure(path)?;
+        if self.retry_ranges.load(Ordering::Relaxed) && !budget::may_retry(Duration::ZERO) {
+            return Err(ApiError::from_status(429, path.to_string()).into());
+        }
+        self.ranges
+            .lock()
+            .unwrap()
+            .push((path.to_string(), offset, len));
+        let content = match version {
+            Some(version) => {
+                let history = &self.state.lock().unwrap().history;
+                history
+                    .get(version)
+                    .cloned()
+                    .ok_or_else(|| ApiError::VersionGone {
+                        path: path.to_string(),
+                        version: version.to_string(),
+                    })?
+            }
+            None => self.content(path).ok_or_else(|| not_found(path))?,
+        };
+        Ok(slice_range(&content, offset, len).to_vec())
+    }
+
+    async fn copy_entry(&self, from: &str, to: &str) -> Result<()> {
+        self.record("copy_entry");
+        let mut state = self.state.lock().unwrap();
+        if !state.tree.contains_key(from) {
+            return Err(not_found(from));
+        }
+        if state.tree.contains_key(to) {
+            return Err(ApiError::AlreadyExists(to.to_string()).into());
+        }
+        for path in state.subtree(from) {
+            let copy = format!("{}{}", to, &path[from.len()..]);
+            let node = match state.tree[&path].clone() {
+                Node::File { content, .. } => {
+                    let entry_id = state.next_entry_id();
+                    state.history.insert(entry_id.clone(), content.clone());
+                    Node::File { content, entry_id }
+                }
+                Node::Folder => Node::Folder,
+            };
+            state.tree.insert(copy, node);
+        }
+        state.events.push(to.to_string());
+        Ok(())
+    }
+
+    async fn delete_entry(&self, path: &str) -> Result<()> {
+        self.record("delete_entry");
+        let mut state = self.state.lock().unwrap();
+        if path == "/" || !state.tree.contains_key(path) {
+            return Err(not_found(path));
+        }
+        for path in state.subtree(path) {
+            state.tree.remove(&path);
+        }
+        state.events.push(path.to_string());
+        Ok(())
+    }
+
+    async fn move_entry(&self, from: &str, to: &str) -> Result<()> {
+        self.record("move_entry");
+        let mut state = self.state.lock().unwrap();
+        if !state.tree.contains_key(from) {
+            return Err(not_found(from));
+        }
+        if state.tree.contains_key(to) {
+            return Err(ApiError::AlreadyExists(to.to_string()).into());
+        }
+        if state.failing_moves.iter().any(|p| p == from) {
+            return Err(ApiError::from_status(500, from.to_string()).into());
+        }
+        for path in state.subtree(from) {
+            let node = state.tree.remove(&path).unwrap();
+            state
+                .tree
+                .insert(format!("{}{}", to, &path[from.len()..]), node);
+        }
+        state.events.push(from.to_string());
+        state.events.push(to.to_string());
+        Ok(())
+    }
+
+    async fn upload_file(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+        self.record("upload_file");
+        self.upload(path, data)
+    }
+
+    async fn upload_chunked(&self, path: &str, data: &[u8]) -> Result<Option<String>> {
+        self.record("upload_chunked");
+        self.upload(path, data)
+    }
+
+    async fn latest_event_cursor(&self) -> Result<u64> {
+        self.record("latest_event_cursor");
+        Ok(self.state.lock().unwrap().events.len() as u64)
+    }
+
+    async fn events_since(&self, cursor: u64) -> Result<EventBatch> {
+        self.record("events_since");
+        let state = self.state.lock().unwrap();
+        let start = (cursor as usize).min(state.events.len());
+        Ok(EventBatch {
+            paths: state.events[start..].to_vec(),
+            cursor: state.events.len() as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(entries: &[EgnyteEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_tree_and_call_counts() {
+        let api = ScriptedMockAPI::new()
+            .with_folder("/Shared/Empty")
+            .with_file("/Shared/a.txt", b"hello");
+
+        assert_eq!(names(&api.list_folder("/").await.unwrap()), vec!["Shared"]);
+        let listing = api.list_folder("/Shared").await.unwrap();
+        assert_eq!(names(&listing), vec!["Empty", "a.txt"]);
+        assert!(listing[0].is_folder);
+        assert_eq!(listing[1].size, 5);
+        assert_eq!(api.download_file("/Shared/a.txt").await.unwrap(), b"hello");
+
+        let missing = api.get_file_info("/Shared/b.txt").await.unwrap_err();
+        assert_eq!(crate::fs::error::errno(&missing), libc::ENOENT);
+        assert!(api.list_folder("/Shared/a.txt").await.is_err());
+
+        assert_eq!(api.calls("list_folder"), 3);
+        assert_eq!(api.calls("get_file_info"), 1);
+        assert_eq!(api.total_calls(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_ranges() {
+        let api = ScriptedMockAPI::new().with_large_file("/big.bin", 10 << 20);
+        let block = api.download_range("/big.bin", None, 4 << 20, 1 << 20).await;
+        assert_eq!(block.unwrap(), pattern(4 << 20, 1 << 20));
+        let tail = api
+            .download_range("/big.bin", None, (10 << 20) - 5, 100)
+            .await;
+        assert_eq!(tail.unwrap().len(), 5);
+        let past = api.download_range("/big.bin", None, 11 << 20, 100).await;
+        assert!(past.unwrap().is_empty());
+        assert!(api.download_range("/missing", None, 0, 1).await.is_err());
+
+        assert_eq!(
+            api.ranges()[..2],
+            [
+                ("/big.bin".to_string(), 4 << 20, 1 << 20),
+                ("/big.bin".to_string(), (10 << 20) - 5, 100)
+            ]
+        );
+        assert_eq!(api.calls("download_range"), 4);
+        assert_eq!(api.calls("download_file"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_mutations_and_events() {
+        let api = ScriptedMockAPI::new().with_file("/Shared/a.txt", b"hello");
+        let cursor = api.latest_event_cursor().await.unwrap();
+
+        let first = api.upload_file("/Shared/b.txt", b"new").await.unwrap();
+        let second = api.upload_chunked("/Shared/b.txt", b"newer").await.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(api.content("/Shared/b.txt").unwrap(), b"newer");
+
+        api.move_entry("/Shared", "/Moved").await.unwrap();
+        assert!(!api.exists("/Shared/a.txt"));
+        assert_eq!(api.content("/Moved/a.txt").unwrap(), b"hello");
+        let exists = api.move_entry("/Moved/a.txt", "/Moved/b.txt").await;
+        assert_eq!(crate::fs::error::errno(&exists.unwrap_err()), libc::EEXIST);
+
+        api.copy_entry("/Moved", "/Copy").await.unwrap();
+        api.delete_entry("/Moved").await.unwrap();
+        assert!(!api.exists("/Moved") && !api.exists("/Moved/a.txt"));
+        assert_eq!(api.content("/Copy/b.txt").unwrap(), b"newer");
+
+        api.change_remotely("/Copy/a.txt", b"theirs");
+        let batch = api.events_since(cursor).await.unwrap();
+        assert_eq!(
+            batch.paths,
+            vec![
+                "/Shared/b.txt",
+                "/Shared/b.txt",
+                "/Shared",
+                "/Moved",
+                "/Copy",
+                "/Moved",
+                "/Copy/a.txt"
+            ]
+        );
+        let batch = api.events_since(batch.cursor).await.unwrap();
+        assert!(batch.paths.is_empty());
+    }
+}
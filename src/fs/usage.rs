@@ -0,0 +1,183 @@
+use crate::fs::egnyte_path;
+use crate::fs::fuse_ops::{EgnyteAPI, EgnyteEntry};
+use anyhow::{Context, Result};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::collections::HashMap;
+
+/// Default number of folders `du` lists at once
+pub const DEFAULT_DU_CONCURRENCY: usize = 8;
+
+/// Space taken by a folder and everything under it
+#[derive(Debug, Default, PartialEq)]
+pub struct FolderUsage {
+    pub path: String,
+    /// Bytes in this folder and all its subfolders
+    pub bytes: u64,
+    /// Files in this folder and all its subfolders
+    pub files: u64,
+    /// Subfolders, by name
+    pub subfolders: Vec<FolderUsage>,
+}
+
+/// What [`disk_usage`] found: the sizes, and the subfolders it could not
+/// list (left out of the sums) with why
+#[derive(Debug)]
+pub struct DiskUsage {
+    pub root: FolderUsage,
+    pub unreadable: Vec<(String, String)>,
+}
+
+/// Sizes under `path`, listing each folder once, at most `concurrency` at a
+/// time; the client's rate limiter spaces out the requests.
+///
+/// Fails only when `path` itself cannot be listed.
+pub async fn disk_usage(api: &dyn EgnyteAPI, path: &str, concurrency: usize) -> Result<DiskUsage> {
+    let root = egnyte_path::normalize(path);
+    let mut listings: HashMap<String, Vec<EgnyteEntry>> = HashMap::new();
+    let mut unreadable = Vec::new();
+    let mut pending = vec![root.clone()];
+    let mut listing = FuturesUnordered::new();
+    loop {
+        while listing.len() < concurrency.max(1) {
+            let Some(folder) = pending.pop() else {
+                break;
+            };
+            listing.push(async move {
+                let entries = api.list_folder(&folder).await;
+                (folder, entries)
+            });
+        }
+        let Some((folder, entries)) = listing.next().await else {
+            break;
+        };
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(e) if folder == root => {
+                return Err(e.context(format!("Failed to list {}", folder)));
+            }
+            Err(e) => {
+                unreadable.push((folder, format!("{:#}", e)));
+                continue;
+            }
+        };
+        pending.extend(
+            entries
+                .iter()
+                .filter(|entry| entry.is_folder)
+                .map(|entry| child_path(&folder, &entry.name)),
+        );
+        listings.insert(folder, entries);
+    }
+    unreadable.sort();
+    let root = tally(&root, &listings).context("Root folder was not listed")?;
+    Ok(DiskUsage { root, unreadable })
+}
+
+fn child_path(folder: &str, name: &str) -> String {
+    format!("{}/{}", folder.trim_end_matches('/'), name)
+}
+
+/// Sums for `folder` from the listings; None if it was not listed
+fn tally(folder: &str, listings: &HashMap<String, Vec<EgnyteEntry>>) -> Option<FolderUsage> {
+    let entries = listings.get(folder)?;
+    let mut usage = FolderUsage {
+        path: folder.to_string(),
+        ..Default::default()
+    };
+    for entry in entries {
+        if !entry.is_folder {
+            usage.bytes += entry.size;
+            usage.files += 1;
+        } else if let Some(sub) = tally(&child_path(folder, &entry.name), listings) {
+            usage.bytes += sub.bytes;
+            usage.files += sub.files;
+            usage.subfolders.push(sub);
+        }
+    }
+    usage.subfolders.sort_by(|a, b| a.path.cmp(&b.path));
+    Some(usage)
+}
+
+impl FolderUsage {
+    /// This folder and its subfolders down to `depth` levels below it (all
+    /// of them if None), deepest first as `du` prints them
+    pub fn down_to(&self, depth: Option<usize>) -> Vec<&FolderUsage> {
+        let mut out = Vec::new();
+        self.collect(depth, &mut out);
+        out
+    }
+
+    fn collect<'a>(&'a self, depth: Option<usize>, out: &mut Vec<&'a FolderUsage>) {
+        if depth != Some(0) {
+            for sub in &self.subfolders {
+                sub.collect(depth.map(|depth| depth - 1), out);
+            }
+        }
+        out.push(self);
+    }
+}
+
+/// `bytes` as `du -h` shows it: 512, 1.5K, 12M, ...
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["K", "M", "G", "T", "P", "E"];
+    if bytes < 1024 {
+        return bytes.to_string();
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if size < 10.0 {
+        format!("{:.1}{}", size, UNITS[unit])
+    } else {
+        format!("{:.0}{}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::testutil::ScriptedMockAPI;
+
+    #[tokio::test]
+    async fn test_disk_usage() {
+        let api = ScriptedMockAPI::new()
+            .with_file("/Shared/a.txt", b"12345")
+            .with_file("/Shared/docs/b.txt", b"123")
+            .with_file("/Shared/docs/old/c.txt", b"1234567")
+            .with_folder("/Shared/empty");
+
+        let usage = disk_usage(&api, "/Shared/", 2).await.unwrap();
+        assert!(usage.unreadable.is_empty());
+        assert_eq!(usage.root.path, "/Shared");
+        assert_eq!((usage.root.bytes, usage.root.files), (15, 3));
+        // Every folder was listed exactly once
+        assert_eq!(api.calls("list_folder"), 4);
+
+        let shown: Vec<(&str, u64)> = usage
+            .root
+            .down_to(Some(1))
+            .iter()
+            .map(|folder| (folder.path.as_str(), folder.bytes))
+            .collect();
+        assert_eq!(
+            shown,
+            vec![("/Shared/docs", 10), ("/Shared/empty", 0), ("/Shared", 15)]
+        );
+        assert_eq!(usage.root.down_to(None).len(), 4);
+        assert_eq!(usage.root.down_to(Some(0)).len(), 1);
+
+        assert!(disk_usage(&api, "/Nowhere", 2).await.is_err());
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(0), "0");
+        assert_eq!(human_size(1023), "1023");
+        assert_eq!(human_size(1536), "1.5K");
+        assert_eq!(human_size(12 * 1024 * 1024), "12M");
+        assert_eq!(human_size(3 * 1024 * 1024 * 1024 * 1024), "3.0T");
+    }
+}
@@ -0,0 +1,234 @@
+//! Log output: a size-rotated log file, and scrubbing of credentials from
+//! everything logged, whatever the level.
+
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Default size at which the log file is rotated
+pub const DEFAULT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// Default number of rotated files kept next to the current one
+pub const DEFAULT_LOG_KEEP: usize = 5;
+
+/// Names whose values are credentials, in query strings, JSON or headers
+const SECRET_KEYS: [&str; 5] = [
+    "access_token",
+    "refresh_token",
+    "client_secret",
+    "password",
+    "api_key",
+];
+
+const REDACTED: &str = "[REDACTED]";
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "-._~+/=%".contains(c)
+}
+
+/// `text` with bearer tokens and the values of [`SECRET_KEYS`] replaced by
+/// `[REDACTED]`
+pub fn redact(text: &str) -> Cow<'_, str> {
+    let mut out = String::new();
+    let mut copied = 0;
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        let value_start = if rest.starts_with("Bearer ") {
+            Some(i + "Bearer ".len())
+        } else {
+            secret_value_start(text, i)
+        };
+        let Some(start) = value_start else {
+            i += rest.chars().next().map_or(1, char::len_utf8);
+            continue;
+        };
+        let end = text[start..]
+            .find(|c: char| !is_token_char(c))
+            .map_or(text.len(), |len| start + len);
+        if end == start {
+            i = start;
+            continue;
+        }
+        out.push_str(&text[copied..start]);
+        out.push_str(REDACTED);
+        copied = end;
+        i = end;
+    }
+    if copied == 0 {
+        return Cow::Borrowed(text);
+    }
+    out.push_str(&text[copied..]);
+    Cow::Owned(out)
+}
+
+/// If a secret key starts at `at` (as a whole word) and is followed by `=`,
+/// `:` or `": "`, where its value starts
+fn secret_value_start(text: &str, at: usize) -> Option<usize> {
+    let before = text[..at].chars().next_back();
+    if before.is_some_and(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    let key = SECRET_KEYS
+        .iter()
+        .find(|key| text[at..].starts_with(*key))?;
+    let after = at + key.len();
+    // Closing quote of a JSON key, the separator, then spaces and an
+    // opening quote
+    let mut separated = false;
+    for (pos, c) in text[after..].char_indices() {
+        match c {
+            '"' | '\'' | ' ' => {}
+            '=' | ':' if !separated => separated = true,
+            _ => return separated.then_some(after + pos),
+        }
+    }
+    None
+}
+
+/// A writer that scrubs credentials from what passes through it
+pub struct Redacting<W>(pub W);
+
+impl<W: Write> Write for Redacting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match std::str::from_utf8(buf) {
+            Ok(text) => self.0.write_all(redact(text).as_bytes())?,
+            Err(_) => self.0.write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// A log file that is renamed to `<path>.1` once it reaches `max_size`,
+/// shifting older ones up to `<path>.<keep>`; the oldest past that is deleted
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl RotatingFile {
+    /// Append to `path`, creating it (and its directory) if needed
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                match std::fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+            self.file = open_append(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("Authorization: Bearer abc.DEF-123 sent"),
+            "Authorization: Bearer [REDACTED] sent"
+        );
+        assert_eq!(
+            redact(r#"{"access_token":"xyz789","expires_in":3600}"#),
+            r#"{"access_token":"[REDACTED]","expires_in":3600}"#
+        );
+        assert_eq!(
+            redact("POST grant_type=refresh_token&refresh_token=r3fr35h&client_secret=s3"),
+            "POST grant_type=refresh_token&refresh_token=[REDACTED]&client_secret=[REDACTED]"
+        );
+        assert_eq!(redact("password: hunter2"), "password: [REDACTED]");
+        // Lookalike words and plain text pass through untouched
+        assert!(matches!(
+            redact("Listing /Shared/passwords.txt (status code: 404)"),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(redact("no secrets here"), "no secrets here");
+    }
+
+    #[test]
+    fn test_redacting_writer() {
+        let mut out = Redacting(Vec::new());
+        writeln!(out, "token refresh: access_token=abc").unwrap();
+        assert_eq!(out.0, b"token refresh: access_token=[REDACTED]\n");
+    }
+
+    #[test]
+    fn test_rotation_keeps_last_files() {
+        let dir = std::env::temp_dir().join(format!("egnyte-fuse-log-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("egnyte-fuse.log");
+        let mut log = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["line one\n", "line two\n", "line 3\n", "line 4\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+        log.flush().unwrap();
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("egnyte-fuse.log"), "line 4\n");
+        assert_eq!(read("egnyte-fuse.log.1"), "line 3\n");
+        assert_eq!(read("egnyte-fuse.log.2"), "line two\n");
+        assert!(!dir.join("egnyte-fuse.log.3").exists());
+
+        // Reopening appends and counts what is already there
+        let mut log = RotatingFile::open(&path, 10, 2).unwrap();
+        log.write_all(b"line 5\n").unwrap();
+        assert_eq!(read("egnyte-fuse.log"), "line 5\n");
+        assert_eq!(read("egnyte-fuse.log.1"), "line 4\n");
+    }
+}
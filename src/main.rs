@@ -1,53 +1,1692 @@
 use anyhow::{Context, Result};
-use egnyte_fuse::fs::api_client::EgnyteAPIClient;
-use egnyte_fuse::fs::fuse_ops::EgnyteFuse;
+use egnyte_fuse::fs::api_client::{
+    run_checks, Check, EgnyteAPIClient, LinkKind, LinkOptions, Progress,
+};
+use egnyte_fuse::fs::budget::DEFAULT_OP_DEADLINE;
+use egnyte_fuse::fs::error;
+use egnyte_fuse::fs::error_cache::{ErrorCachingAPI, DEFAULT_ERROR_TTL};
+use egnyte_fuse::fs::fuse_ops::{
+    AttrOptions, EgnyteAPI, EgnyteFuseBuilder, DEFAULT_ATTR_TTL, DEFAULT_BLOCK_SIZE,
+    DEFAULT_ENTRY_TTL, DEFAULT_IGNORED_NAMES, DEFAULT_MAX_READAHEAD, DEFAULT_MAX_WRITE,
+    DEFAULT_NEGATIVE_TTL,
+};
+use egnyte_fuse::fs::health::DegradableAPI;
+use egnyte_fuse::fs::metrics::{MeteredAPI, Metrics, MetricsServer};
+use egnyte_fuse::fs::offline::{self, CachedAPI, OfflineCache};
+use egnyte_fuse::fs::spool::{self, PendingUpload, Spool};
+use egnyte_fuse::fs::usage::{self, DEFAULT_DU_CONCURRENCY};
+use egnyte_fuse::logging::{Redacting, RotatingFile, DEFAULT_LOG_KEEP, DEFAULT_LOG_MAX_SIZE};
 use fuser::MountOption;
 use std::env;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::runtime::Runtime;
 
+/// Command-line arguments
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    mountpoint: String,
+    read_only: bool,
+    strict_parsing: bool,
+    /// Bypass the kernel page cache for file data
+    direct_io: bool,
+    /// Resolve names regardless of case
+    case_insensitive: bool,
+    /// Cache nothing: every operation asks Egnyte
+    no_cache: bool,
+    /// Serve only the offline copies; uploads go to the spool
+    offline: bool,
+    /// Keep offline copies of what is listed and read
+    offline_cache: bool,
+    /// Let every local user use the mount, with the mounting user's Egnyte
+    /// access (needs user_allow_other in /etc/fuse.conf unless run as root)
+    allow_other: bool,
+    /// Let root use the mount too (same fuse.conf requirement)
+    allow_root: bool,
+    /// Create the mountpoint if it does not exist
+    mkdir: bool,
+    /// Unmount what a crashed run left at the mountpoint
+    force: bool,
+    page_size: Option<usize>,
+    max_entries: Option<usize>,
+    attr_options: AttrOptions,
+    /// Seconds between polls for remote changes (None: no polling)
+    poll_interval: Option<u64>,
+    /// Seconds a dirty file may sit idle before it is uploaded (None: only
+    /// on close or fsync)
+    autoflush_interval: Option<u64>,
+    /// Egnyte folder to mount as the root (None: the whole domain)
+    root: Option<String>,
+    /// Seconds the kernel may cache names
+    entry_ttl: Option<u64>,
+    /// Seconds the kernel may cache attributes
+    attr_ttl: Option<u64>,
+    /// Seconds the kernel may cache a name as nonexistent
+    negative_ttl: Option<u64>,
+    /// Bytes of kernel readahead per file
+    max_readahead: Option<u32>,
+    /// Largest write request from the kernel, in bytes
+    max_write: Option<u32>,
+    /// Block size reported in attributes, in bytes
+    block_size: Option<u32>,
+    /// Seconds one operation may spend on metadata requests and retries
+    op_deadline: Option<u64>,
+    /// Names looked up as missing without asking Egnyte (None: the defaults)
+    ignored_names: Option<Vec<String>>,
+    /// Where to serve Prometheus metrics (None: not at all)
+    metrics_addr: Option<SocketAddr>,
+    /// Log to this file instead of stderr
+    log_file: Option<PathBuf>,
+    /// Bytes at which the log file is rotated
+    log_max_size: Option<u32>,
+    /// Rotated log files to keep
+    log_keep: Option<usize>,
+}
+
+/// What the binary was asked to do
+#[derive(Debug, PartialEq)]
+enum Command {
+    /// Mount the filesystem (the default when no command is named)
+    Mount(Box<Args>),
+    /// Copy an entry server-side: `copy <src> <dst>`
+    Copy { from: String, to: String },
+    /// Create a shared link: `link <path> [--expiry DAYS] [--password]
+    /// [--preview]`
+    Link { path: String, options: LinkOptions },
+    /// Show all the metadata the API has on an entry: `info <path> [--json]`
+    Info { path: String, json: bool },
+    /// Sum file sizes per folder: `du <path> [--depth N] [--human-readable]`
+    Du {
+        path: String,
+        depth: Option<usize>,
+        human_readable: bool,
+    },
+    /// Check config, keyring, and connectivity, and with `--write-test` the
+    /// write path in a folder: `doctor [--write-test FOLDER]`
+    Doctor { write_test: Option<String> },
+    /// List uploads waiting in the spool: `sync status`
+    SyncStatus,
+    /// Upload everything in the spool now: `sync retry`
+    SyncRetry,
+    /// Print the version and build information: `version` or `--version`
+    Version,
+}
+
+/// Parse the command line (without the program name). Anything that is not a
+/// known command is taken as mount arguments.
+fn parse_command(args: &[String]) -> Result<Command, String> {
+    match args.first().map(String::as_str) {
+        Some("copy") => match &args[1..] {
+            [from, to] => Ok(Command::Copy {
+                from: from.clone(),
+                to: to.clone(),
+            }),
+            _ => Err("Usage: copy <src> <dst>".to_string()),
+        },
+        Some("link") => parse_link(&args[1..]),
+        Some("info") => match &args[1..] {
+            [path] => Ok(Command::Info {
+                path: path.clone(),
+                json: false,
+            }),
+            [path, flag] | [flag, path] if flag == "--json" => Ok(Command::Info {
+                path: path.clone(),
+                json: true,
+            }),
+            _ => Err("Usage: info <path> [--json]".to_string()),
+        },
+        Some("du") => parse_du(&args[1..]),
+        Some("doctor") => match &args[1..] {
+            [] => Ok(Command::Doctor { write_test: None }),
+            [flag, folder] if flag == "--write-test" => Ok(Command::Doctor {
+                write_test: Some(parse_root(flag, Some(folder))?),
+            }),
+            _ => Err("Usage: doctor [--write-test FOLDER]".to_string()),
+        },
+        Some("version" | "--version") => match &args[1..] {
+            [] => Ok(Command::Version),
+            _ => Err("Usage: version".to_string()),
+        },
+        Some("sync") => match &args[1..] {
+            [command] if command == "status" => Ok(Command::SyncStatus),
+            [command] if command == "retry" => Ok(Command::SyncRetry),
+            _ => Err("Usage: sync status|retry".to_string()),
+        },
+        _ => parse_args(args).map(|args| Command::Mount(Box::new(args))),
+    }
+}
+
+/// Take `--config PATH`, which any command accepts, out of `args`
+fn take_config_flag(args: &[String]) -> Result<(Option<PathBuf>, Vec<String>), String> {
+    let Some(at) = args.iter().position(|arg| arg == "--config") else {
+        return Ok((None, args.to_vec()));
+    };
+    let path = args
+        .get(at + 1)
+        .filter(|path| !path.is_empty())
+        .ok_or_else(|| "--config needs a value".to_string())?;
+    let rest = args[..at].iter().chain(&args[at + 2..]).cloned().collect();
+    Ok((Some(PathBuf::from(path)), rest))
+}
+
+/// Parse the arguments of `link`
+fn parse_link(args: &[String]) -> Result<Command, String> {
+    const USAGE: &str = "Usage: link <path> [--expiry DAYS] [--password] [--preview]";
+    let mut path = None;
+    let mut options = LinkOptions::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--expiry" => {
+                let days = parse_count(arg, iter.next())?;
+                options.expiry_days =
+                    Some(u32::try_from(days).map_err(|_| format!("Invalid value for {}", arg))?);
+            }
+            "--password" => options.password = true,
+            "--preview" => options.kind = LinkKind::Preview,
+            _ if arg.starts_with("--") || path.is_some() => return Err(USAGE.to_string()),
+            _ => path = Some(arg.clone()),
+        }
+    }
+    let path = path.ok_or_else(|| USAGE.to_string())?;
+    Ok(Command::Link { path, options })
+}
+
+/// Parse the arguments of `du`
+fn parse_du(args: &[String]) -> Result<Command, String> {
+    const USAGE: &str = "Usage: du <path> [--depth N] [--human-readable]";
+    let mut path = None;
+    let mut depth = None;
+    let mut human_readable = false;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--depth" | "-d" => depth = Some(parse_depth(arg, iter.next())?),
+            "--human-readable" | "-h" => human_readable = true,
+            _ if arg.starts_with('-') || path.is_some() => return Err(USAGE.to_string()),
+            _ => path = Some(arg.clone()),
+        }
+    }
+    let path = path.ok_or_else(|| USAGE.to_string())?;
+    Ok(Command::Du {
+        path,
+        depth,
+        human_readable,
+    })
+}
+
+/// Parse mount arguments
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut mountpoint = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--read-only" => parsed.read_only = true,
+            "--direct-io" => parsed.direct_io = true,
+            "--case-insensitive" => parsed.case_insensitive = true,
+            "--no-cache" => parsed.no_cache = true,
+            "--offline" => parsed.offline = true,
+            "--offline-cache" => parsed.offline_cache = true,
+            "--allow-other" => parsed.allow_other = true,
+            "--allow-root" => parsed.allow_root = true,
+            "--mkdir" => parsed.mkdir = true,
+            "--force" => parsed.force = true,
+            "--strict-parsing" => parsed.strict_parsing = true,
+            "--page-size" => parsed.page_size = Some(parse_count(arg, iter.next())?),
+            "--max-entries" => parsed.max_entries = Some(parse_count(arg, iter.next())?),
+            "--poll-interval" => parsed.poll_interval = Some(parse_count(arg, iter.next())? as u64),
+            "--autoflush-interval" => {
+                parsed.autoflush_interval = Some(parse_count(arg, iter.next())? as u64)
+            }
+            "--root" => parsed.root = Some(parse_root(arg, iter.next())?),
+            "--entry-ttl" => parsed.entry_ttl = Some(parse_seconds(arg, iter.next())?),
+            "--attr-ttl" => parsed.attr_ttl = Some(parse_seconds(arg, iter.next())?),
+            "--attr-timeout" => {
+                let ttl = parse_seconds(arg, iter.next())?;
+                parsed.entry_ttl = Some(ttl);
+                parsed.attr_ttl = Some(ttl);
+                parsed.negative_ttl = Some(ttl);
+            }
+            "--negative-ttl" => parsed.negative_ttl = Some(parse_seconds(arg, iter.next())?),
+            "--max-readahead" => parsed.max_readahead = Some(parse_size(arg, iter.next())?),
+            "--max-write" => parsed.max_write = Some(parse_size(arg, iter.next())?),
+            "--op-deadline" => parsed.op_deadline = Some(parse_count(arg, iter.next())? as u64),
+            "--block-size" => parsed.block_size = Some(parse_block_size(arg, iter.next())?),
+            "--ignore-names" => {
+                let list = iter
+                    .next()
+                    .ok_or_else(|| format!("{} needs a value", arg))?;
+                parsed.ignored_names = Some(
+                    list.split(',')
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .map(String::from)
+                        .collect(),
+                );
+            }
+            "--metrics-addr" => parsed.metrics_addr = Some(parse_addr(arg, iter.next())?),
+            "--log-file" => {
+                let path = iter
+                    .next()
+                    .ok_or_else(|| format!("{} needs a value", arg))?;
+                parsed.log_file = Some(PathBuf::from(path));
+            }
+            "--log-max-size" => parsed.log_max_size = Some(parse_size(arg, iter.next())?),
+            "--log-keep" => parsed.log_keep = Some(parse_count(arg, iter.next())?),
+            "--uid" => parsed.attr_options.uid = Some(parse_id(arg, iter.next())?),
+            "--gid" => parsed.attr_options.gid = Some(parse_id(arg, iter.next())?),
+            "--file-mask" => parsed.attr_options.file_mask = Some(parse_mask(arg, iter.next())?),
+            "--dir-mask" => parsed.attr_options.dir_mask = Some(parse_mask(arg, iter.next())?),
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+            _ if mountpoint.is_none() => mountpoint = Some(arg.clone()),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    if parsed.allow_other && parsed.allow_root {
+        return Err("--allow-other and --allow-root cannot be combined".to_string());
+    }
+    if parsed.no_cache && (parsed.offline || parsed.offline_cache) {
+        return Err("--no-cache cannot be combined with --offline or --offline-cache".to_string());
+    }
+    parsed.mountpoint = mountpoint.ok_or("Missing mountpoint")?;
+    Ok(parsed)
+}
+
+/// Whether a fuse.conf lets users other than root mount with allow_other
+/// or allow_root
+fn fuse_conf_allows_other(conf: &str) -> bool {
+    conf.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .any(|line| line == "user_allow_other")
+}
+
+/// Undo the octal escapes (`\040` for a space, ...) /proc/self/mounts
+/// writes in paths
+fn unescape_mount_path(field: &str) -> String {
+    let mut out = Vec::new();
+    let bytes = field.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 4).filter(|_| bytes[i] == b'\\');
+        match escaped
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok())
+        {
+            Some(byte) => {
+                out.push(byte);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Whether a /proc/self/mounts listing has a FUSE filesystem mounted at
+/// `path`
+fn is_fuse_mounted(mounts: &str, path: &Path) -> bool {
+    mounts.lines().any(|line| {
+        let mut fields = line.split(' ').skip(1);
+        match (fields.next(), fields.next()) {
+            (Some(target), Some(fstype)) => {
+                fstype.starts_with("fuse") && Path::new(&unescape_mount_path(target)) == path
+            }
+            _ => false,
+        }
+    })
+}
+
+/// Unmount the stale mount at `path` with the first of fusermount3,
+/// fusermount and umount that is installed and succeeds
+fn unmount_stale(path: &Path) -> Result<()> {
+    let mut failures = Vec::new();
+    for (program, args) in [
+        ("fusermount3", &["-u"][..]),
+        ("fusermount", &["-u"][..]),
+        ("umount", &[][..]),
+    ] {
+        match std::process::Command::new(program)
+            .args(args)
+            .arg(path)
+            .output()
+        {
+            Ok(output) if output.status.success() => {
+                println!(
+                    "Unmounted the stale mount at {} ({})",
+                    path.display(),
+                    program
+                );
+                return Ok(());
+            }
+            Ok(output) => failures.push(format!(
+                "{}: {}",
+                program,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => failures.push(format!("{}: {}", program, e)),
+        }
+    }
+    if failures.is_empty() {
+        anyhow::bail!(
+            "Found no fusermount3, fusermount or umount to unmount {}",
+            path.display()
+        );
+    }
+    anyhow::bail!(
+        "Failed to unmount {}: {}",
+        path.display(),
+        failures.join("; ")
+    )
+}
+
+/// Check there is a directory to mount on at `path` (creating it if asked
+/// to) with nothing mounted there, so a mount fails with a message saying
+/// what to do rather than a bare error from the kernel. A directory that is
+/// not empty only gets a warning: its files are hidden while mounted.
+///
+/// A mount left by a crashed run (stat fails with ENOTCONN) is unmounted
+/// if `force` is set; otherwise the error says how.
+fn check_mountpoint(path: &Path, mounts: &str, mkdir: bool, force: bool) -> Result<()> {
+    match std::fs::metadata(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && mkdir => {
+            std::fs::create_dir_all(path)
+                .with_context(|| format!("Failed to create mountpoint {}", path.display()))?;
+            println!("Created mountpoint {}", path.display());
+            return Ok(());
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => anyhow::bail!(
+            "Mountpoint {} does not exist; create it, or pass --mkdir",
+            path.display()
+        ),
+        // What stat on a FUSE mount whose process is gone fails with
+        Err(e) if e.raw_os_error() == Some(libc::ENOTCONN) && force => {
+            unmount_stale(path)?;
+            // What was under the mount is the mountpoint now
+            let mounts = std::fs::read_to_string("/proc/self/mounts").unwrap_or_default();
+            return check_mountpoint(path, &mounts, mkdir, false);
+        }
+        Err(e) if e.raw_os_error() == Some(libc::ENOTCONN) => anyhow::bail!(
+            "{} is still mounted, but the filesystem serving it has stopped (a previous run \
+             crashed?); pass --force to unmount it first, or run: fusermount -u {}",
+            path.display(),
+            path.display()
+        ),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Cannot use mountpoint {}", path.display()))
+        }
+        Ok(meta) if !meta.is_dir() => {
+            anyhow::bail!("Mountpoint {} is not a directory", path.display())
+        }
+        Ok(_) => {}
+    }
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if is_fuse_mounted(mounts, &canonical) {
+        anyhow::bail!(
+            "{} is already mounted; unmount it first with: fusermount -u {}",
+            path.display(),
+            path.display()
+        );
+    }
+    let not_empty = std::fs::read_dir(path)
+        .with_context(|| format!("Cannot read mountpoint {}", path.display()))?
+        .next()
+        .is_some();
+    if not_empty {
+        eprintln!(
+            "Warning: {} is not empty; its contents are hidden while Egnyte is mounted there",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Parse the positive integer value of a numeric option
+fn parse_count(option: &str, value: Option<&String>) -> Result<usize, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", option))?;
+    value
+        .parse::<usize>()
+        .ok()
+        .filter(|count| *count > 0)
+        .ok_or_else(|| format!("Invalid value for {}: {}", option, value))
+}
+
+/// Parse a number of folder levels; zero is allowed
+fn parse_depth(option: &str, value: Option<&String>) -> Result<usize, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", option))?;
+    value
+        .parse::<usize>()
+        .map_err(|_| format!("Invalid value for {}: {}", option, value))
+}
+
+/// Parse an absolute Egnyte folder path
+fn parse_root(option: &str, value: Option<&String>) -> Result<String, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", option))?;
+    if !value.starts_with('/') {
+        return Err(format!(
+            "{} must be an absolute Egnyte path: {}",
+            option, value
+        ));
+    }
+    Ok(value.clone())
+}
+
+/// Parse an IP address and port, e.g. 127.0.0.1:9100
+fn parse_addr(option: &str, value: Option<&String>) -> Result<SocketAddr, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", option))?;
+    value
+        .parse::<SocketAddr>()
+        .map_err(|_| format!("Invalid value for {} (expected IP:PORT): {}", option, value))
+}
+
+/// Parse a duration in whole seconds; zero is allowed
+fn parse_seconds(option: &str, value: Option<&String>) -> Result<u64, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", option))?;
+    value
+        .parse::<u64>()
+        .map_err(|_| format!("Invalid value for {}: {}", option, value))
+}
+
+/// Parse a positive byte count, optionally with a K or M suffix (1024-based)
+fn parse_size(option: &str, value: Option<&String>) -> Result<u32, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", option))?;
+    let (digits, unit) = match value.char_indices().last() {
+        Some((at, 'K' | 'k')) => (&value[..at], 1024),
+        Some((at, 'M' | 'm')) => (&value[..at], 1024 * 1024),
+        _ => (value.as_str(), 1),
+    };
+    digits
+        .parse::<u32>()
+        .ok()
+        .and_then(|count| count.checked_mul(unit))
+        .filter(|size| *size > 0)
+        .ok_or_else(|| format!("Invalid value for {}: {}", option, value))
+}
+
+/// Parse a block size: a size that is a power of two, at least 512
+fn parse_block_size(option: &str, value: Option<&String>) -> Result<u32, String> {
+    let size = parse_size(option, value)?;
+    if size < 512 || !size.is_power_of_two() {
+        return Err(format!(
+            "{} must be a power of two of at least 512, not {}",
+            option, size
+        ));
+    }
+    Ok(size)
+}
+
+/// Parse a numeric user or group id
+fn parse_id(option: &str, value: Option<&String>) -> Result<u32, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", option))?;
+    value
+        .parse::<u32>()
+        .map_err(|_| format!("Invalid value for {}: {}", option, value))
+}
+
+/// Parse an octal permission mask such as 022 or 0o022
+fn parse_mask(option: &str, value: Option<&String>) -> Result<u16, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", option))?;
+    let digits = value.strip_prefix("0o").unwrap_or(value);
+    u16::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mask| *mask <= 0o777)
+        .ok_or_else(|| format!("Invalid value for {}: {}", option, value))
+}
+
+fn print_usage(program: &str) {
+    eprintln!("Usage: {} [OPTIONS] <mountpoint>", program);
+    eprintln!("       {} copy <src> <dst>", program);
+    eprintln!(
+        "       {} link <path> [--expiry DAYS] [--password] [--preview]",
+        program
+    );
+    eprintln!("       {} info <path> [--json]", program);
+    eprintln!(
+        "       {} du <path> [--depth N] [--human-readable]",
+        program
+    );
+    eprintln!("       {} doctor [--write-test FOLDER]", program);
+    eprintln!("       {} sync status|retry", program);
+    eprintln!("       {} version", program);
+    eprintln!("\nOptions:");
+    eprintln!("  --config PATH      Read PATH instead of ~/.config/egnyte-desktop/config.json,");
+    eprintln!("                     and the tokens.json next to it (any command)");
+    eprintln!("  --read-only        Mount read-only; all writes fail with EROFS");
+    eprintln!("  --direct-io        Bypass the kernel page cache: no double buffering of large");
+    eprintln!("                     files, but no readahead or caching, so small reads are slower");
+    eprintln!("  --case-insensitive Names differing only in case are the same file, as on");
+    eprintln!("                     Egnyte; each keeps the case it was first listed with");
+    eprintln!("  --no-cache         Cache no metadata, listings or content, here or in the");
+    eprintln!("                     kernel, so every operation asks Egnyte (slow; for");
+    eprintln!("                     debugging, or when nothing may be stale)");
+    eprintln!("  --offline-cache    Keep copies of listings and files read, for --offline");
+    eprintln!("  --offline          Serve only those copies, without the network; uploads wait");
+    eprintln!("                     in the spool for the next online mount or sync retry");
+    eprintln!("  --allow-other      Let all local users use the mount; they all act with");
+    eprintln!("                     your Egnyte account and permissions, so only use it on");
+    eprintln!("                     machines you trust (needs user_allow_other in");
+    eprintln!("                     /etc/fuse.conf unless mounting as root)");
+    eprintln!("  --allow-root       Let root use the mount as well as you (same requirement)");
+    eprintln!("  --mkdir            Create the mountpoint if it does not exist");
+    eprintln!("  --force            Unmount a mount a crashed run left at the mountpoint");
+    eprintln!("                     (\"Transport endpoint is not connected\") first");
+    eprintln!("  --strict-parsing   Fail listings with malformed entries instead of warning");
+    eprintln!("  --page-size N      Items requested per folder listing page (default 1000)");
+    eprintln!("  --max-entries N    Most entries shown per folder (default 50000)");
+    eprintln!("  --poll-interval S  Check Egnyte for remote changes every S seconds (default off)");
+    eprintln!("  --autoflush-interval S");
+    eprintln!(
+        "                     Upload files left unsaved and idle for S seconds (default off)"
+    );
+    eprintln!("  --root PATH        Mount this Egnyte folder instead of the whole domain");
+    eprintln!("  --entry-ttl S      Seconds the kernel caches names, and the attributes found");
+    eprintln!("                     with them by lookups and listings (default 1)");
+    eprintln!("  --attr-ttl S       Seconds the kernel caches attributes it stats (default 1)");
+    eprintln!("  --negative-ttl S   Seconds the kernel caches missing names (default 1, 0 = off)");
+    eprintln!("  --attr-timeout S   All three of the above; 0 caches nothing, here or in the");
+    eprintln!("                     kernel, so every stat sees Egnyte's current state (and is");
+    eprintln!("                     slower)");
+    eprintln!("  --max-readahead N  Kernel readahead per file, e.g. 4M (default 256K)");
+    eprintln!("  --max-write N      Largest write request from the kernel (default 1M)");
+    eprintln!("  --op-deadline S    Seconds one file operation may spend on requests and");
+    eprintln!("                     retries before failing (default 30)");
+    eprintln!("  --block-size N     Block size reported to stat and du, e.g. 4K (default 512)");
+    eprintln!("  --ignore-names LIST");
+    eprintln!("                     Comma-separated names (a trailing * matches any rest) that");
+    eprintln!("                     are reported missing without asking Egnyte; \"\" for none");
+    eprintln!(
+        "                     (default {})",
+        DEFAULT_IGNORED_NAMES.join(",")
+    );
+    eprintln!("  --metrics-addr IP:PORT");
+    eprintln!("                     Serve Prometheus metrics at http://IP:PORT/metrics");
+    eprintln!("  --log-file PATH    Log to PATH instead of stderr, rotating it as it grows");
+    eprintln!("  --log-max-size N   Size at which the log file is rotated, e.g. 50M (default 10M)");
+    eprintln!("  --log-keep N       Rotated log files kept as PATH.1 .. PATH.N (default 5)");
+    eprintln!("  --uid N, --gid N   Owner and group reported for every entry");
+    eprintln!("  --file-mask MASK   Octal bits cleared from file modes (default 022)");
+    eprintln!("  --dir-mask MASK    Octal bits cleared from folder modes (default 022)");
+    eprintln!("\nExample: {} /mnt/egnyte", program);
+    eprintln!("\nNote: Make sure you have:");
+    eprintln!("  1. Configured domain: egnyte-cli config set domain YOUR_DOMAIN");
+    eprintln!("  2. Authenticated: egnyte-cli auth login");
+    eprintln!("\nThe refresh token and client secret come from EGNYTE_REFRESH_TOKEN and");
+    eprintln!("EGNYTE_CLIENT_SECRET when set, and from the keyring otherwise; without a");
+    eprintln!("keyring (containers, CI), set both. EGNYTE_DOMAIN (or EGNYTE_BASE_URL) and");
+    eprintln!("EGNYTE_CLIENT_ID replace config.json, and EGNYTE_ACCESS_TOKEN tokens.json.");
+}
+
+/// What `version` prints: this build, then the libraries most bug reports
+/// depend on
+fn version_text() -> String {
+    format!(
+        "egnyte-fuse {} ({})\nfuser {}\nreqwest {}",
+        env!("CARGO_PKG_VERSION"),
+        env!("GIT_HASH"),
+        env!("FUSER_VERSION"),
+        env!("REQWEST_VERSION")
+    )
+}
+
 fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <mountpoint>", args[0]);
-        eprintln!("\nExample: {} /mnt/egnyte", args[0]);
-        eprintln!("\nNote: Make sure you have:");
-        eprintln!("  1. Configured domain: egnyte-cli config set domain YOUR_DOMAIN");
-        eprintln!("  2. Authenticated: egnyte-cli auth login");
+    let argv: Vec<String> = env::args().collect();
+    let parsed =
+        take_config_flag(&argv[1..]).and_then(|(config, args)| Ok((config, parse_command(&args)?)));
+    let (config, command) = match parsed {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            eprintln!("{}\n", message);
+            print_usage(&argv[0]);
+            std::process::exit(1);
+        }
+    };
+
+    // Log to stderr, or the mount's log file, with credentials scrubbed
+    // whatever the level; RUST_LOG overrides the default level
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("warn"));
+    let log_file = match &command {
+        Command::Mount(args) => args.log_file.as_ref().map(|path| (path, args)),
+        _ => None,
+    };
+    match log_file {
+        Some((path, args)) => {
+            let file = RotatingFile::open(
+                path,
+                args.log_max_size.map_or(DEFAULT_LOG_MAX_SIZE, u64::from),
+                args.log_keep.unwrap_or(DEFAULT_LOG_KEEP),
+            )
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+            tracing_subscriber::fmt()
+                .with_env_filter(filter)
+                .with_ansi(false)
+                .with_writer(Mutex::new(Redacting(file)))
+                .init();
+        }
+        None => tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_writer(|| Redacting(std::io::stderr()))
+            .init(),
+    }
+
+    // The one Tokio runtime of the process: the API client, the filesystem
+    // and every background task run on it
+    let rt = Arc::new(Runtime::new().context("Failed to create Tokio runtime")?);
+
+    let config = config.as_deref();
+    match command {
+        Command::Mount(args) => mount(&rt, config, &args),
+        Command::Copy { from, to } => copy(&rt, config, &from, &to),
+        Command::Link { path, options } => link(&rt, config, &path, options),
+        Command::Info { path, json } => info(&rt, config, &path, json),
+        Command::Du {
+            path,
+            depth,
+            human_readable,
+        } => du(&rt, config, &path, depth, human_readable),
+        Command::Doctor { write_test } => doctor(&rt, config, write_test.as_deref()),
+        Command::SyncStatus => sync_status(),
+        Command::SyncRetry => sync_retry(&rt, config),
+        Command::Version => {
+            println!("{}", version_text());
+            Ok(())
+        }
+    }
+}
+
+/// One line of `doctor` output
+fn check_line(check: &Check) -> String {
+    match &check.result {
+        Ok(detail) => format!("[PASS] {}: {}", check.name, detail),
+        Err(problem) => format!("[FAIL] {}: {}", check.name, problem),
+    }
+}
+
+/// Run every check and print one line each; exit 1 if any failed
+fn doctor(rt: &Runtime, config: Option<&Path>, write_test: Option<&str>) -> Result<()> {
+    let checks = rt.block_on(run_checks(config, write_test));
+    for check in &checks {
+        println!("{}", check_line(check));
+    }
+    if !checks.iter().all(Check::passed) {
+        std::process::exit(1);
+    }
+    println!("All checks passed; ready to mount.");
+    Ok(())
+}
+
+/// Create the real API client (loads config and tokens from ~/.config/egnyte-desktop/,
+/// or from `config` and the directory it is in)
+fn connect(rt: &Runtime, config: Option<&Path>) -> Result<EgnyteAPIClient> {
+    rt.block_on(async {
+        EgnyteAPIClient::with_config_file(config).await.context(
+            "Failed to create API client. Make sure you have configured and authenticated.",
+        )
+    })
+}
+
+/// Copy an entry on the server, without moving its bytes through this machine
+fn copy(rt: &Runtime, config: Option<&Path>, from: &str, to: &str) -> Result<()> {
+    let api_client = connect(rt, config)?;
+    match rt.block_on(api_client.copy_entry(from, to)) {
+        Ok(()) => {
+            println!("Copied {} to {}", from, to);
+            Ok(())
+        }
+        Err(e) if error::errno(&e) == libc::EEXIST => {
+            eprintln!("Destination already exists: {}", to);
+            std::process::exit(1);
+        }
+        Err(e) if error::errno(&e) == libc::ENOENT => {
+            eprintln!("Source not found: {}", from);
+            std::process::exit(1);
+        }
+        Err(e) => Err(e.context(format!("Failed to copy {} to {}", from, to))),
+    }
+}
+
+/// Create a shared link and print it, with its password and expiry if any
+fn link(rt: &Runtime, config: Option<&Path>, path: &str, options: LinkOptions) -> Result<()> {
+    let api_client = connect(rt, config)?;
+    match rt.block_on(api_client.create_link(path, options)) {
+        Ok(link) => {
+            println!("{}", link.url);
+            if let Some(password) = &link.password {
+                println!("Password: {}", password);
+            }
+            if let Some(date) = &link.expiry_date {
+                println!("Expires: {}", date);
+            }
+            Ok(())
+        }
+        Err(e) if error::errno(&e) == libc::EACCES => {
+            eprintln!(
+                "Cannot share {}: links are disabled for this domain or not allowed here",
+                path
+            );
+            std::process::exit(1);
+        }
+        Err(e) if error::errno(&e) == libc::ENOENT => {
+            eprintln!("Not found: {}", path);
+            std::process::exit(1);
+        }
+        Err(e) => Err(e.context(format!("Failed to create a link to {}", path))),
+    }
+}
+
+/// Print an entry's metadata, as JSON or one field per line
+fn info(rt: &Runtime, config: Option<&Path>, path: &str, json: bool) -> Result<()> {
+    let api_client = connect(rt, config)?;
+    match rt.block_on(api_client.file_metadata(path)) {
+        Ok(metadata) if json => {
+            println!("{}", serde_json::to_string_pretty(&metadata)?);
+            Ok(())
+        }
+        Ok(metadata) => {
+            for line in metadata_lines(&metadata, 0) {
+                println!("{}", line);
+            }
+            Ok(())
+        }
+        Err(e) if error::errno(&e) == libc::ENOENT => {
+            eprintln!("Not found: {}", path);
+            std::process::exit(1);
+        }
+        Err(e) => Err(e.context(format!("Failed to get info for {}", path))),
+    }
+}
+
+/// Print the size of `path` and of its subfolders down to `depth`, deepest
+/// first like du(1); exit 1 if some subfolder could not be listed
+fn du(
+    rt: &Runtime,
+    config: Option<&Path>,
+    path: &str,
+    depth: Option<usize>,
+    human_readable: bool,
+) -> Result<()> {
+    let api_client = connect(rt, config)?;
+    let found = match rt.block_on(usage::disk_usage(&api_client, path, DEFAULT_DU_CONCURRENCY)) {
+        Ok(found) => found,
+        Err(e) if error::errno(&e) == libc::ENOENT => {
+            eprintln!("Not found: {}", path);
+            std::process::exit(1);
+        }
+        Err(e) => return Err(e),
+    };
+    for (folder, problem) in &found.unreadable {
+        eprintln!("Cannot list {}: {}", folder, problem);
+    }
+    for folder in found.root.down_to(depth) {
+        let size = if human_readable {
+            usage::human_size(folder.bytes)
+        } else {
+            folder.bytes.to_string()
+        };
+        println!("{}\t{}", size, folder.path);
+    }
+    if !found.unreadable.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// `info` output for a JSON value: `key: value` per field, nested objects
+/// and lists indented under their key
+fn metadata_lines(value: &serde_json::Value, indent: usize) -> Vec<String> {
+    let pad = "  ".repeat(indent);
+    let scalar = |value: &serde_json::Value| match value {
+        serde_json::Value::String(text) => Some(text.clone()),
+        serde_json::Value::Object(_) | serde_json::Value::Array(_) => None,
+        other => Some(other.to_string()),
+    };
+    let mut lines = Vec::new();
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (key, value) in fields {
+                match scalar(value) {
+                    Some(text) => lines.push(format!("{}{}: {}", pad, key, text)),
+                    None => {
+                        lines.push(format!("{}{}:", pad, key));
+                        lines.extend(metadata_lines(value, indent + 1));
+                    }
+                }
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                match scalar(item) {
+                    Some(text) => lines.push(format!("{}- {}", pad, text)),
+                    None => {
+                        lines.push(format!("{}-", pad));
+                        lines.extend(metadata_lines(item, indent + 1));
+                    }
+                }
+            }
+        }
+        other => lines.extend(scalar(other).map(|text| format!("{}{}", pad, text))),
+    }
+    lines
+}
+
+/// One line of `sync status` output
+fn pending_line(pending: &PendingUpload, now: u64) -> String {
+    let mut line = format!(
+        "{} ({} bytes, waiting {}s, {} attempts)",
+        pending.remote_path,
+        pending.size,
+        now.saturating_sub(pending.spooled_at),
+        pending.attempts
+    );
+    if let Some(error) = &pending.last_error {
+        line.push_str(&format!(": {}", error));
+    }
+    line
+}
+
+/// List the uploads waiting in the spool
+fn sync_status() -> Result<()> {
+    let spool = Spool::new(spool::default_spool_dir());
+    let pending = spool
+        .list()
+        .with_context(|| format!("Failed to read {}", spool.dir().display()))?;
+    if pending.is_empty() {
+        println!("Nothing waiting to upload.");
+        return Ok(());
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    for entry in &pending {
+        println!("{}", pending_line(entry, now));
+    }
+    Ok(())
+}
+
+/// Upload everything in the spool now, instead of waiting for a mount's
+/// retrier; exit 1 if anything is still waiting
+fn sync_retry(rt: &Runtime, config: Option<&Path>) -> Result<()> {
+    let (progress, updates) = tokio::sync::mpsc::unbounded_channel();
+    let api_client = connect(rt, config)?.with_progress(progress);
+    let spool = Spool::new(spool::default_spool_dir());
+    let shown = rt.spawn(show_progress(updates));
+    let report = rt.block_on(async {
+        let report = spool.retry_all(&api_client).await;
+        // Closes the channel, so the progress line is finished
+        drop(api_client);
+        let _ = shown.await;
+        report
+    });
+    let report = report.with_context(|| format!("Failed to read {}", spool.dir().display()))?;
+    for path in &report.uploaded {
+        println!("Uploaded {}", path);
+    }
+    for (path, problem) in &report.failed {
+        eprintln!("Still waiting: {}: {}", path, problem);
+    }
+    if !report.failed.is_empty() {
         std::process::exit(1);
     }
+    if report.uploaded.is_empty() {
+        println!("Nothing waiting to upload.");
+    }
+    Ok(())
+}
+
+/// One progress line: bytes so far, and of how many when known
+fn progress_line(progress: &Progress) -> String {
+    let transferred = usage::human_size(progress.transferred);
+    match progress.total {
+        Some(total) => format!("{} of {}", transferred, usage::human_size(total)),
+        None => transferred,
+    }
+}
+
+/// Keep a progress line on a terminal's stderr up to date until `updates`
+/// closes; nothing when stderr is not a terminal
+async fn show_progress(mut updates: tokio::sync::mpsc::UnboundedReceiver<Progress>) {
+    use std::io::IsTerminal;
+    let terminal = std::io::stderr().is_terminal();
+    let mut shown = false;
+    while let Some(progress) = updates.recv().await {
+        if terminal {
+            eprint!("\r\x1b[K{}", progress_line(&progress));
+            shown = true;
+        }
+    }
+    if shown {
+        eprint!("\r\x1b[K");
+    }
+}
 
-    let mountpoint = &args[1];
+fn mount(rt: &Arc<Runtime>, config: Option<&Path>, args: &Args) -> Result<()> {
+    let mountpoint = &args.mountpoint;
     let mount_path = PathBuf::from(mountpoint);
+    let mounts = std::fs::read_to_string("/proc/self/mounts").unwrap_or_default();
+    check_mountpoint(&mount_path, &mounts, args.mkdir, args.force)?;
+
+    let api_client = connect(rt, config)?.with_strict_parsing(args.strict_parsing);
+    let api_client = match args.page_size {
+        Some(page_size) => api_client.with_page_size(page_size),
+        None => api_client,
+    };
+    let api_client = match args.max_entries {
+        Some(max_entries) => api_client.with_max_entries(max_entries),
+        None => api_client,
+    };
 
-    // Create Tokio runtime for async operations
-    let rt = Runtime::new().context("Failed to create Tokio runtime")?;
+    // Command-line TTLs win over config.json
+    let entry_ttl = args
+        .entry_ttl
+        .map(Duration::from_secs)
+        .or(api_client.configured_entry_ttl())
+        .unwrap_or(DEFAULT_ENTRY_TTL);
+    let attr_ttl = args
+        .attr_ttl
+        .map(Duration::from_secs)
+        .or(api_client.configured_attr_ttl())
+        .unwrap_or(DEFAULT_ATTR_TTL);
+    let negative_ttl = args
+        .negative_ttl
+        .map(Duration::from_secs)
+        .or(api_client.configured_negative_ttl())
+        .unwrap_or(DEFAULT_NEGATIVE_TTL);
+    // Permissions show in the mode bits, so they go stale with the attributes
+    let api_client = api_client.with_permission_ttl(if args.no_cache {
+        Duration::ZERO
+    } else {
+        attr_ttl
+    });
 
-    // Create real API client (loads config and tokens from ~/.config/egnyte-desktop/)
-    let api_client = rt.block_on(async {
-        EgnyteAPIClient::new()
-            .await
-            .context("Failed to create API client. Make sure you have configured and authenticated.")
-    })?;
+    let max_readahead = args
+        .max_readahead
+        .or(api_client.configured_max_readahead())
+        .unwrap_or(DEFAULT_MAX_READAHEAD);
+    let max_write = args
+        .max_write
+        .or(api_client.configured_max_write())
+        .unwrap_or(DEFAULT_MAX_WRITE);
 
-    // Create FUSE filesystem
-    let api_client: Arc<dyn egnyte_fuse::fs::fuse_ops::EgnyteAPI> = Arc::new(api_client);
-    let fs = EgnyteFuse::new(api_client)?;
+    // Create FUSE filesystem, counting the real API calls for metrics
+    let metrics = Arc::new(Metrics::new());
+    let api_client: Arc<dyn EgnyteAPI> = Arc::new(api_client);
+    let api_client: Arc<dyn EgnyteAPI> = match args.metrics_addr {
+        Some(_) => Arc::new(MeteredAPI::new(api_client, Arc::clone(&metrics))),
+        None => api_client,
+    };
+    // While Egnyte is down for maintenance, answer from the offline copies
+    // (if kept) and EAGAIN, checking in the background for its return
+    let api_client: Arc<dyn EgnyteAPI> = if args.offline {
+        api_client
+    } else {
+        let fallback: Option<Arc<dyn EgnyteAPI>> = args.offline_cache.then(|| {
+            Arc::new(CachedAPI::offline(
+                OfflineCache::new(offline::default_offline_dir()),
+                Spool::new(spool::default_spool_dir()),
+            )) as Arc<dyn EgnyteAPI>
+        });
+        let degradable = Arc::new(DegradableAPI::new(api_client, fallback));
+        rt.spawn(Arc::clone(&degradable).probe_while_degraded());
+        degradable
+    };
+    let api_client: Arc<dyn EgnyteAPI> = if args.offline {
+        Arc::new(CachedAPI::offline(
+            OfflineCache::new(offline::default_offline_dir()),
+            Spool::new(spool::default_spool_dir()),
+        ))
+    } else if args.offline_cache {
+        Arc::new(CachedAPI::recording(
+            api_client,
+            OfflineCache::new(offline::default_offline_dir()),
+        ))
+    } else {
+        api_client
+    };
+    // Answer reads of paths that just failed for good (403, 404) from memory,
+    // unless a zero attribute TTL asks for Egnyte's current state every time
+    let api_client: Arc<dyn EgnyteAPI> = if args.offline || args.no_cache || attr_ttl.is_zero() {
+        api_client
+    } else {
+        Arc::new(ErrorCachingAPI::new(api_client, DEFAULT_ERROR_TTL))
+    };
+    let builder = EgnyteFuseBuilder::new()
+        .runtime(Arc::clone(rt))
+        .read_only(args.read_only)
+        .attr_options(args.attr_options)
+        .entry_ttl(entry_ttl)
+        .attr_ttl(attr_ttl)
+        .negative_ttl(negative_ttl)
+        .max_readahead(max_readahead)
+        .max_write(max_write)
+        .block_size(args.block_size.unwrap_or(DEFAULT_BLOCK_SIZE))
+        .op_deadline(
+            args.op_deadline
+                .map_or(DEFAULT_OP_DEADLINE, Duration::from_secs),
+        )
+        .direct_io(args.direct_io)
+        .case_insensitive(args.case_insensitive)
+        .caching_enabled(!args.no_cache)
+        .spool_dir(spool::default_spool_dir());
+    let builder = match &args.ignored_names {
+        Some(names) => builder.ignored_names(names.clone()),
+        None => builder,
+    };
+    let builder = match &args.root {
+        Some(root) => builder.root(root),
+        None => builder,
+    };
+    // Offline copies are of whole files: every file opened is downloaded
+    // whole to leave one behind, or read whole from one offline
+    let builder = if args.offline || args.offline_cache {
+        builder.ranged_read_threshold(u64::MAX)
+    } else {
+        builder
+    };
+    let fs = builder.build(api_client)?;
 
-    // Mount options: writeback cache, parallel dirops
+    // Mount options (writeback cache and parallel dirops are negotiated in init)
     // Note: AutoUnmount would require 'user_allow_other' in /etc/fuse.conf - omit for compatibility
-    let options = vec![
-        MountOption::RW,
+    let mut options = vec![
+        if args.read_only {
+            MountOption::RO
+        } else {
+            MountOption::RW
+        },
         MountOption::FSName("egnyte".to_string()),
         MountOption::Subtype("egnyte-fuse".to_string()),
     ];
+    if args.allow_other || args.allow_root {
+        // Without this the kernel mount fails with a bare "Operation not
+        // permitted"; root may always share its mounts
+        let conf = std::fs::read_to_string("/etc/fuse.conf").unwrap_or_default();
+        // SAFETY: geteuid has no preconditions and cannot fail
+        if unsafe { libc::geteuid() } != 0 && !fuse_conf_allows_other(&conf) {
+            anyhow::bail!(
+                "{} needs user_allow_other in /etc/fuse.conf; add that line (as root) or \
+                 mount without it",
+                if args.allow_other {
+                    "--allow-other"
+                } else {
+                    "--allow-root"
+                }
+            );
+        }
+        options.push(if args.allow_other {
+            MountOption::AllowOther
+        } else {
+            MountOption::AllowRoot
+        });
+    }
+
+    if let Some(seconds) = args.autoflush_interval {
+        fs.start_autoflush(Duration::from_secs(seconds));
+    }
+    // Offline, polling has nothing to ask and a retry would only spool again
+    if !args.offline {
+        if let Some(seconds) = args.poll_interval {
+            fs.start_change_poller(Duration::from_secs(seconds));
+        }
+        fs.start_upload_retrier();
+    }
+    let invalidator = fs.invalidator();
+    let metrics_server = match args.metrics_addr {
+        Some(addr) => Some(
+            MetricsServer::start(rt, addr, metrics)
+                .with_context(|| format!("Failed to serve metrics on {}", addr))?,
+        ),
+        None => None,
+    };
 
     println!("Mounting Egnyte filesystem at {}...", mountpoint);
     println!("Press Ctrl+C to unmount (then run: fusermount -u {})", mountpoint);
 
-    // Mount the filesystem (this blocks until unmounted)
-    fuser::mount2(fs, mount_path, &options)?;
+    // Mount the filesystem, then hand its notifier to the invalidator before
+    // serving requests (this blocks until unmounted)
+    let mut session = fuser::Session::new(fs, &mount_path, &options)?;
+    invalidator.attach(Box::new(session.notifier()))?;
+    session.run()?;
 
+    // Unmounted: stop serving metrics
+    drop(metrics_server);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_mountpoint() {
+        let parsed = parse_args(&args(&["/mnt/egnyte"])).unwrap();
+        assert_eq!(parsed.mountpoint, "/mnt/egnyte");
+        assert!(!parsed.read_only);
+    }
+
+    #[test]
+    fn test_parse_read_only() {
+        let parsed = parse_args(&args(&["--read-only", "/mnt/egnyte"])).unwrap();
+        assert!(parsed.read_only);
+        assert!(parse_args(&args(&["--bogus", "/mnt/egnyte"])).is_err());
+        assert!(parse_args(&args(&["--read-only"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_direct_io() {
+        assert!(!parse_args(&args(&["/mnt/egnyte"])).unwrap().direct_io);
+        let parsed = parse_args(&args(&["--direct-io", "/mnt/egnyte"])).unwrap();
+        assert!(parsed.direct_io);
+    }
+
+    #[test]
+    fn test_take_config_flag() {
+        let (config, rest) = take_config_flag(&args(&["doctor"])).unwrap();
+        assert_eq!((config, rest), (None, args(&["doctor"])));
+        let (config, rest) = take_config_flag(&args(&[
+            "--read-only",
+            "--config",
+            "/etc/egnyte.json",
+            "/mnt/e",
+        ]))
+        .unwrap();
+        assert_eq!(config, Some(PathBuf::from("/etc/egnyte.json")));
+        assert_eq!(rest, args(&["--read-only", "/mnt/e"]));
+        assert!(parse_command(&rest).is_ok());
+        assert!(take_config_flag(&args(&["doctor", "--config"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_case_insensitive() {
+        assert!(
+            !parse_args(&args(&["/mnt/egnyte"]))
+                .unwrap()
+                .case_insensitive
+        );
+        let parsed = parse_args(&args(&["--case-insensitive", "/mnt/egnyte"])).unwrap();
+        assert!(parsed.case_insensitive);
+    }
+
+    #[test]
+    fn test_parse_offline() {
+        let parsed = parse_args(&args(&["--offline", "/mnt/egnyte"])).unwrap();
+        assert!(parsed.offline);
+        assert!(!parsed.offline_cache);
+        let parsed = parse_args(&args(&["--offline-cache", "/mnt/egnyte"])).unwrap();
+        assert!(parsed.offline_cache);
+        assert!(!parsed.offline);
+    }
+
+    #[test]
+    fn test_parse_no_cache() {
+        let parsed = parse_args(&args(&["--no-cache", "/mnt/egnyte"])).unwrap();
+        assert!(parsed.no_cache);
+        assert!(!parse_args(&args(&["/mnt/egnyte"])).unwrap().no_cache);
+        assert!(parse_args(&args(&["--no-cache", "--offline-cache", "/mnt/egnyte"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_allow_other() {
+        let parsed = parse_args(&args(&["/mnt/egnyte"])).unwrap();
+        assert!(!parsed.allow_other && !parsed.allow_root);
+        assert!(
+            parse_args(&args(&["--allow-other", "/mnt/egnyte"]))
+                .unwrap()
+                .allow_other
+        );
+        assert!(
+            parse_args(&args(&["--allow-root", "/mnt/egnyte"]))
+                .unwrap()
+                .allow_root
+        );
+        assert!(parse_args(&args(&["--allow-other", "--allow-root", "/mnt/egnyte"])).is_err());
+    }
+
+    #[test]
+    fn test_fuse_conf_allows_other() {
+        assert!(fuse_conf_allows_other(
+            "# mount_max = 1000\nuser_allow_other\n"
+        ));
+        assert!(fuse_conf_allows_other("  user_allow_other  # for egnyte\n"));
+        assert!(!fuse_conf_allows_other("#user_allow_other\n"));
+        assert!(!fuse_conf_allows_other(""));
+    }
+
+    #[test]
+    fn test_check_mountpoint() {
+        let dir = std::env::temp_dir().join(format!("egnyte-fuse-mnt-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mnt = dir.join("My Egnyte");
+
+        let err = check_mountpoint(&mnt, "", false, false).unwrap_err();
+        assert!(err.to_string().contains("does not exist"), "{}", err);
+        check_mountpoint(&mnt, "", true, false).unwrap();
+        assert!(mnt.is_dir());
+        check_mountpoint(&mnt, "", false, false).unwrap();
+
+        let mounts = format!(
+            "proc /proc proc rw 0 0\negnyte {} fuse.egnyte-fuse rw 0 0\n",
+            mnt.canonicalize()
+                .unwrap()
+                .display()
+                .to_string()
+                .replace(' ', "\\040")
+        );
+        let err = check_mountpoint(&mnt, &mounts, false, false).unwrap_err();
+        assert!(err.to_string().contains("already mounted"), "{}", err);
+        // Only a FUSE mount counts
+        let other = mounts.replace("fuse.egnyte-fuse", "tmpfs");
+        check_mountpoint(&mnt, &other, false, false).unwrap();
+
+        // Not empty is only a warning; a file is no mountpoint
+        let file = mnt.join("notes.txt");
+        std::fs::write(&file, b"x").unwrap();
+        check_mountpoint(&mnt, "", false, false).unwrap();
+        let err = check_mountpoint(&file, "", true, false).unwrap_err();
+        assert!(err.to_string().contains("not a directory"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_mkdir_and_force() {
+        assert!(
+            parse_args(&args(&["--mkdir", "/mnt/egnyte"]))
+                .unwrap()
+                .mkdir
+        );
+        assert!(
+            parse_args(&args(&["--force", "/mnt/egnyte"]))
+                .unwrap()
+                .force
+        );
+        let parsed = parse_args(&args(&["/mnt/egnyte"])).unwrap();
+        assert!(!parsed.mkdir && !parsed.force);
+    }
+
+    #[test]
+    fn test_parse_metrics_addr() {
+        let parsed =
+            parse_args(&args(&["--metrics-addr", "127.0.0.1:9100", "/mnt/egnyte"])).unwrap();
+        assert_eq!(parsed.metrics_addr, Some("127.0.0.1:9100".parse().unwrap()));
+        assert!(parse_args(&args(&["--metrics-addr", "localhost", "/mnt/egnyte"])).is_err());
+        assert!(parse_args(&args(&["/mnt/egnyte", "--metrics-addr"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_log_file() {
+        let parsed = parse_args(&args(&[
+            "--log-file",
+            "/var/log/egnyte-fuse.log",
+            "--log-max-size",
+            "50M",
+            "--log-keep",
+            "3",
+            "/mnt/egnyte",
+        ]))
+        .unwrap();
+        assert_eq!(
+            parsed.log_file,
+            Some(PathBuf::from("/var/log/egnyte-fuse.log"))
+        );
+        assert_eq!(parsed.log_max_size, Some(50 * 1024 * 1024));
+        assert_eq!(parsed.log_keep, Some(3));
+        assert!(parse_args(&args(&["/mnt/egnyte", "--log-file"])).is_err());
+        assert!(parse_args(&args(&["--log-keep", "0", "/mnt/egnyte"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_strict_parsing() {
+        let parsed = parse_args(&args(&["/mnt/egnyte", "--strict-parsing"])).unwrap();
+        assert!(parsed.strict_parsing);
+        assert!(!parsed.read_only);
+    }
+
+    #[test]
+    fn test_parse_page_size() {
+        let parsed = parse_args(&args(&["--page-size", "200", "/mnt/egnyte"])).unwrap();
+        assert_eq!(parsed.page_size, Some(200));
+        assert_eq!(parsed.mountpoint, "/mnt/egnyte");
+        assert!(parse_args(&args(&["--page-size", "0", "/mnt/egnyte"])).is_err());
+        assert!(parse_args(&args(&["/mnt/egnyte", "--page-size"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_link() {
+        assert_eq!(
+            parse_command(&args(&["link", "/Shared/a.pdf"])).unwrap(),
+            Command::Link {
+                path: "/Shared/a.pdf".to_string(),
+                options: LinkOptions::default(),
+            }
+        );
+        assert_eq!(
+            parse_command(&args(&[
+                "link",
+                "--expiry",
+                "7",
+                "/Shared/a.pdf",
+                "--password",
+                "--preview"
+            ]))
+            .unwrap(),
+            Command::Link {
+                path: "/Shared/a.pdf".to_string(),
+                options: LinkOptions {
+                    kind: LinkKind::Preview,
+                    expiry_days: Some(7),
+                    password: true,
+                },
+            }
+        );
+        assert!(parse_command(&args(&["link"])).is_err());
+        assert!(parse_command(&args(&["link", "/a", "/b"])).is_err());
+        assert!(parse_command(&args(&["link", "/a", "--expiry", "0"])).is_err());
+        assert!(parse_command(&args(&["link", "/a", "--public"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_info() {
+        let info = |json| Command::Info {
+            path: "/Shared/a.pdf".to_string(),
+            json,
+        };
+        assert_eq!(
+            parse_command(&args(&["info", "/Shared/a.pdf"])).unwrap(),
+            info(false)
+        );
+        assert_eq!(
+            parse_command(&args(&["info", "--json", "/Shared/a.pdf"])).unwrap(),
+            info(true)
+        );
+        assert!(parse_command(&args(&["info"])).is_err());
+        assert!(parse_command(&args(&["info", "/a", "/b"])).is_err());
+    }
+
+    #[test]
+    fn test_progress_line() {
+        let progress = Progress {
+            transferred: 512,
+            total: Some(3 * 1024 * 1024),
+        };
+        assert_eq!(progress_line(&progress), "512 of 3.0M");
+        let progress = Progress {
+            transferred: 2048,
+            total: None,
+        };
+        assert_eq!(progress_line(&progress), "2.0K");
+    }
+
+    #[test]
+    fn test_parse_du() {
+        assert_eq!(
+            parse_command(&args(&["du", "/Shared"])).unwrap(),
+            Command::Du {
+                path: "/Shared".to_string(),
+                depth: None,
+                human_readable: false,
+            }
+        );
+        assert_eq!(
+            parse_command(&args(&["du", "--depth", "0", "-h", "/Shared"])).unwrap(),
+            Command::Du {
+                path: "/Shared".to_string(),
+                depth: Some(0),
+                human_readable: true,
+            }
+        );
+        assert!(parse_command(&args(&["du"])).is_err());
+        assert!(parse_command(&args(&["du", "/a", "--depth"])).is_err());
+        assert!(parse_command(&args(&["du", "/a", "--depth", "x"])).is_err());
+        assert!(parse_command(&args(&["du", "/a", "/b"])).is_err());
+    }
+
+    #[test]
+    fn test_metadata_lines() {
+        let metadata = serde_json::json!({
+            "checksum": "abc123",
+            "is_folder": false,
+            "size": 42,
+            "versions": [{"entry_id": "v1", "size": 40}],
+        });
+        assert_eq!(
+            metadata_lines(&metadata, 0),
+            vec![
+                "checksum: abc123",
+                "is_folder: false",
+                "size: 42",
+                "versions:",
+                "  -",
+                "    entry_id: v1",
+                "    size: 40",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_copy() {
+        assert_eq!(
+            parse_command(&args(&["copy", "/Shared/a", "/Shared/b"])).unwrap(),
+            Command::Copy {
+                from: "/Shared/a".to_string(),
+                to: "/Shared/b".to_string(),
+            }
+        );
+        assert!(parse_command(&args(&["copy", "/Shared/a"])).is_err());
+        assert_eq!(
+            parse_command(&args(&["doctor"])).unwrap(),
+            Command::Doctor { write_test: None }
+        );
+        assert_eq!(
+            parse_command(&args(&["doctor", "--write-test", "/Shared/tmp"])).unwrap(),
+            Command::Doctor {
+                write_test: Some("/Shared/tmp".to_string())
+            }
+        );
+        assert!(parse_command(&args(&["doctor", "--write-test"])).is_err());
+        assert!(parse_command(&args(&["doctor", "--write-test", "tmp"])).is_err());
+        assert!(parse_command(&args(&["doctor", "extra"])).is_err());
+        assert_eq!(
+            parse_command(&args(&["sync", "status"])).unwrap(),
+            Command::SyncStatus
+        );
+        assert_eq!(
+            parse_command(&args(&["sync", "retry"])).unwrap(),
+            Command::SyncRetry
+        );
+        assert!(parse_command(&args(&["sync"])).is_err());
+        assert_eq!(
+            parse_command(&args(&["version"])).unwrap(),
+            Command::Version
+        );
+        assert_eq!(
+            parse_command(&args(&["--version"])).unwrap(),
+            Command::Version
+        );
+        assert!(parse_command(&args(&["sync", "now"])).is_err());
+        assert!(matches!(
+            parse_command(&args(&["/mnt/egnyte"])).unwrap(),
+            Command::Mount(_)
+        ));
+    }
+
+    #[test]
+    fn test_check_line() {
+        let pass = Check {
+            name: "list /",
+            result: Ok("12 entries".to_string()),
+        };
+        assert_eq!(check_line(&pass), "[PASS] list /: 12 entries");
+        let fail = Check {
+            name: "token refresh",
+            result: Err("Token refresh failed".to_string()),
+        };
+        assert_eq!(
+            check_line(&fail),
+            "[FAIL] token refresh: Token refresh failed"
+        );
+    }
+
+    #[test]
+    fn test_version_text() {
+        let text = version_text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with(&format!("egnyte-fuse {} (", env!("CARGO_PKG_VERSION"))));
+        assert!(lines[1].starts_with("fuser "));
+        assert!(lines[2].starts_with("reqwest "));
+    }
+
+    #[test]
+    fn test_pending_line() {
+        let mut pending = PendingUpload {
+            id: "1".to_string(),
+            remote_path: "/Shared/a.txt".to_string(),
+            base_entry_id: None,
+            size: 5,
+            spooled_at: 100,
+            attempts: 0,
+            last_error: None,
+        };
+        assert_eq!(
+            pending_line(&pending, 160),
+            "/Shared/a.txt (5 bytes, waiting 60s, 0 attempts)"
+        );
+        pending.attempts = 2;
+        pending.last_error = Some("API request timed out".to_string());
+        assert_eq!(
+            pending_line(&pending, 160),
+            "/Shared/a.txt (5 bytes, waiting 60s, 2 attempts): API request timed out"
+        );
+    }
+
+    #[test]
+    fn test_parse_ownership() {
+        let parsed = parse_args(&args(&[
+            "--uid",
+            "1001",
+            "--gid",
+            "100",
+            "--file-mask",
+            "077",
+            "--dir-mask",
+            "0o027",
+            "/mnt/egnyte",
+        ]))
+        .unwrap();
+        assert_eq!(parsed.attr_options.uid, Some(1001));
+        assert_eq!(parsed.attr_options.gid, Some(100));
+        assert_eq!(parsed.attr_options.file_mask, Some(0o077));
+        assert_eq!(parsed.attr_options.dir_mask, Some(0o027));
+        assert!(parse_args(&args(&["--file-mask", "999", "/mnt/egnyte"])).is_err());
+        assert!(parse_args(&args(&["--uid", "-1", "/mnt/egnyte"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_poll_interval() {
+        let parsed = parse_args(&args(&["/mnt/egnyte"])).unwrap();
+        assert_eq!(parsed.poll_interval, None);
+        let parsed = parse_args(&args(&["--poll-interval", "30", "/mnt/egnyte"])).unwrap();
+        assert_eq!(parsed.poll_interval, Some(30));
+    }
+
+    #[test]
+    fn test_parse_autoflush_interval() {
+        let parsed = parse_args(&args(&["/mnt/egnyte"])).unwrap();
+        assert_eq!(parsed.autoflush_interval, None);
+        let parsed = parse_args(&args(&["--autoflush-interval", "5", "/mnt/egnyte"])).unwrap();
+        assert_eq!(parsed.autoflush_interval, Some(5));
+        assert!(parse_args(&args(&["--autoflush-interval", "soon", "/mnt/egnyte"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_root() {
+        let parsed = parse_args(&args(&["--root", "/Shared/Projects", "/mnt/egnyte"])).unwrap();
+        assert_eq!(parsed.root.as_deref(), Some("/Shared/Projects"));
+        assert!(parse_args(&args(&["--root", "Shared", "/mnt/egnyte"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_request_sizes() {
+        let parsed = parse_args(&args(&[
+            "--max-readahead",
+            "4M",
+            "--max-write",
+            "131072",
+            "/mnt/egnyte",
+        ]))
+        .unwrap();
+        assert_eq!(parsed.max_readahead, Some(4 * 1024 * 1024));
+        assert_eq!(parsed.max_write, Some(131072));
+        let parsed = parse_args(&args(&["--max-write", "128k", "/mnt/egnyte"])).unwrap();
+        assert_eq!(parsed.max_write, Some(128 * 1024));
+        assert!(parse_args(&args(&["--max-write", "0", "/mnt/egnyte"])).is_err());
+        assert!(parse_args(&args(&["--max-write", "M", "/mnt/egnyte"])).is_err());
+        assert!(parse_args(&args(&["--max-readahead", "8192M", "/mnt/egnyte"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_ignore_names() {
+        assert_eq!(
+            parse_args(&args(&["/mnt/egnyte"])).unwrap().ignored_names,
+            None
+        );
+        let parsed =
+            parse_args(&args(&["--ignore-names", ".Trash*, desktop.ini", "/mnt/e"])).unwrap();
+        assert_eq!(
+            parsed.ignored_names,
+            Some(vec![".Trash*".to_string(), "desktop.ini".to_string()])
+        );
+        let parsed = parse_args(&args(&["--ignore-names", "", "/mnt/e"])).unwrap();
+        assert_eq!(parsed.ignored_names, Some(vec![]));
+    }
+
+    #[test]
+    fn test_parse_block_size() {
+        let parsed = parse_args(&args(&["--block-size", "4K", "/mnt/egnyte"])).unwrap();
+        assert_eq!(parsed.block_size, Some(4096));
+        assert!(parse_args(&args(&["--block-size", "256", "/mnt/egnyte"])).is_err());
+        assert!(parse_args(&args(&["--block-size", "3000", "/mnt/egnyte"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_ttls() {
+        let parsed = parse_args(&args(&[
+            "--entry-ttl",
+            "600",
+            "--attr-ttl",
+            "300",
+            "--negative-ttl",
+            "0",
+            "/mnt/egnyte",
+        ]))
+        .unwrap();
+        assert_eq!(parsed.entry_ttl, Some(600));
+        assert_eq!(parsed.attr_ttl, Some(300));
+        assert_eq!(parsed.negative_ttl, Some(0));
+        assert!(parse_args(&args(&["--attr-ttl", "-1", "/mnt/egnyte"])).is_err());
+        let parsed = parse_args(&args(&["--attr-timeout", "0", "/mnt/egnyte"])).unwrap();
+        assert_eq!((parsed.entry_ttl, parsed.attr_ttl), (Some(0), Some(0)));
+        assert_eq!(parsed.negative_ttl, Some(0));
+        let parsed = parse_args(&args(&["--op-deadline", "10", "/mnt/egnyte"])).unwrap();
+        assert_eq!(parsed.op_deadline, Some(10));
+        assert!(parse_args(&args(&["--op-deadline", "0", "/mnt/egnyte"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_max_entries() {
+        let parsed = parse_args(&args(&["/mnt/egnyte", "--max-entries", "1000"])).unwrap();
+        assert_eq!(parsed.max_entries, Some(1000));
+        assert_eq!(parsed.page_size, None);
+        assert!(parse_args(&args(&["--max-entries", "lots", "/mnt/egnyte"])).is_err());
+    }
+}